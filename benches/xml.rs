@@ -0,0 +1,61 @@
+//! Time spent parsing the XML of one scrape: the device description and
+//! SCPD read at discovery, and the SOAP responses read on every scrape.
+//!
+//! Run with `cargo bench --bench xml`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use upnp_wan_exporter_rs::description;
+use upnp_wan_exporter_rs::soap::{self, SoapFault};
+
+const DESCRIPTION: &str = include_str!("../tests/fixtures/igd-description.xml");
+const SCPD: &str = include_str!("../tests/fixtures/wancommon-scpd.xml");
+const RESPONSE: &str = include_str!("../tests/fixtures/get-addon-infos-response.xml");
+const FAULT: &str = include_str!("../tests/fixtures/soap-fault.xml");
+
+const ITERATIONS: u32 = 20_000;
+
+fn bench(name: &str, mut parse: impl FnMut()) {
+    // Warm up caches and the allocator before timing
+    for _ in 0..ITERATIONS / 10 {
+        parse();
+    }
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        parse();
+    }
+    let per_iteration: Duration = started.elapsed() / ITERATIONS;
+    println!("{:<24} {:>8.2} µs", name, per_iteration.as_secs_f64() * 1e6);
+}
+
+fn main() {
+    bench("description::parse", || {
+        black_box(
+            description::parse(
+                black_box(DESCRIPTION),
+                "http://192.168.178.1:49000/igd2desc.xml",
+            )
+            .unwrap(),
+        );
+    });
+    bench("parse_scpd_actions", || {
+        black_box(description::parse_scpd_actions(black_box(SCPD)));
+    });
+    bench("body_responses", || {
+        black_box(soap::body_responses(black_box(RESPONSE)));
+    });
+    bench("SoapFault (success)", || {
+        black_box(SoapFault::from_response(
+            "GetAddonInfos",
+            200,
+            black_box(RESPONSE),
+        ));
+    });
+    bench("SoapFault (fault)", || {
+        black_box(SoapFault::from_response(
+            "GetAddonInfos",
+            500,
+            black_box(FAULT),
+        ));
+    });
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Bake the git revision and the rustc version into the build for
+/// `upnp_wan_exporter_build_info`; either is "unknown" when it cannot be found
+fn main() {
+    // Builds outside a git checkout (release tarballs, Docker) can pass it in
+    println!("cargo:rerun-if-env-changed=UPNP_WAN_EXPORTER_REVISION");
+    let revision = std::env::var("UPNP_WAN_EXPORTER_REVISION")
+        .ok()
+        .filter(|revision| !revision.is_empty())
+        .or_else(git_revision)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=UPNP_WAN_EXPORTER_REVISION={}", revision);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"])
+        .and_then(|version| version.split_whitespace().nth(1).map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=UPNP_WAN_EXPORTER_RUSTC={}", rustc_version);
+}
+
+fn git_revision() -> Option<String> {
+    let git_dir = command_output("git", &["rev-parse", "--git-dir"])?;
+    // HEAD moves on checkout, the branch ref on commit
+    let head = Path::new(&git_dir).join("HEAD");
+    println!("cargo:rerun-if-changed={}", head.display());
+    // A packed ref has no file of its own to watch
+    if let Some(reference) = std::fs::read_to_string(&head)
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+        .map(|reference| Path::new(&git_dir).join(reference))
+        .filter(|reference| reference.exists())
+    {
+        println!("cargo:rerun-if-changed={}", reference.display());
+    }
+    command_output("git", &["rev-parse", "--short", "HEAD"])
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,87 @@
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use digest_auth::{AuthContext, HttpMethod, WwwAuthenticateHeader};
+use reqwest::Url;
+use std::sync::Mutex;
+
+/// Scheme the gateway asked for in its last WWW-Authenticate challenge
+enum Challenge {
+    Basic,
+    Digest(WwwAuthenticateHeader),
+}
+
+/// Answers HTTP Basic and Digest challenges with the configured credentials.
+/// The last challenge is kept so later requests authenticate up front
+/// instead of paying a 401 round trip each.
+pub struct Authenticator {
+    username: String,
+    password: String,
+    challenge: Mutex<Option<Challenge>>,
+}
+
+impl Authenticator {
+    pub fn new(username: String, password: String) -> Self {
+        Self {
+            username,
+            password,
+            challenge: Mutex::new(None),
+        }
+    }
+
+    /// Remember the challenge of a 401 response
+    pub fn set_challenge(&self, www_authenticate: &str) -> Result<()> {
+        let scheme = www_authenticate
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        let challenge = if scheme.eq_ignore_ascii_case("Basic") {
+            Challenge::Basic
+        } else if scheme.eq_ignore_ascii_case("Digest") {
+            Challenge::Digest(
+                digest_auth::parse(www_authenticate)
+                    .map_err(|e| anyhow!("Unusable digest challenge: {}", e))?,
+            )
+        } else {
+            return Err(anyhow!("Unsupported authentication scheme {}", scheme));
+        };
+        *self.challenge.lock().unwrap() = Some(challenge);
+        Ok(())
+    }
+
+    /// Authorization header for a request to `url` answering the last
+    /// challenge, `None` before the gateway has sent one
+    pub fn authorization(&self, method: &str, url: &str, body: Option<&[u8]>) -> Option<String> {
+        let mut challenge = self.challenge.lock().unwrap();
+        match challenge.as_mut()? {
+            Challenge::Basic => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", self.username, self.password))
+            )),
+            Challenge::Digest(prompt) => {
+                let context = AuthContext::new_with_method(
+                    self.username.as_str(),
+                    self.password.as_str(),
+                    request_uri(url),
+                    body,
+                    HttpMethod(method.to_string().into()),
+                );
+                prompt
+                    .respond(&context)
+                    .ok()
+                    .map(|header| header.to_header_string())
+            }
+        }
+    }
+}
+
+/// The request target digest responses are computed over: path and query
+fn request_uri(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
@@ -1,13 +1,168 @@
-use anyhow::Result;
-use upnp_wan_exporter_rs::{Config, run_server};
+use anyhow::{Result, bail};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use upnp_wan_exporter_rs::server::DebugConfig;
+use upnp_wan_exporter_rs::{Config, SsdpResponse, UpnpClient, UpnpDevice, coherence, run_server};
+
+const CONFIG_PATH: &str = "config.toml";
+const USAGE: &str =
+    "Usage: upnp-wan-exporter-rs [discover [--json] | coherence [--json] | config diff]";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Try to load config from file, fallback to default
-    let config = Config::from_file("config.toml").unwrap_or_else(|_| {
+    // Load config from file if present, fallback to default
+    let config_path = Path::new(CONFIG_PATH)
+        .exists()
+        .then(|| PathBuf::from(CONFIG_PATH));
+    let config = if config_path.is_some() {
+        Config::from_file(CONFIG_PATH)?
+    } else {
         eprintln!("Warning: Could not load config.toml, using defaults");
         Config::default()
-    });
+    };
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => run_server(config, config_path).await,
+        Some("discover") => {
+            let json = args[1..].iter().any(|arg| arg == "--json");
+            run_discover(config, json).await
+        }
+        Some("coherence") => {
+            let json = args[1..].iter().any(|arg| arg == "--json");
+            run_coherence(config, json).await
+        }
+        Some("config") if args.get(1).map(String::as_str) == Some("diff") => {
+            run_config_diff(config).await
+        }
+        Some(_) => bail!(USAGE),
+    }
+}
+
+#[derive(Serialize)]
+struct DiscoverReport<'a> {
+    responders: Vec<Responder>,
+    selected: &'a UpnpDevice,
+}
+
+#[derive(Serialize)]
+struct Responder {
+    location: String,
+    #[serde(flatten)]
+    response: SsdpResponse,
+}
+
+/// Discover gateways and print the selected device with its WAN services;
+/// fails (non-zero exit) when no usable gateway is found
+async fn run_discover(config: Config, json: bool) -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut client = UpnpClient::from_config(&config.upnp)?;
+    let responders: Vec<Responder> = client
+        .discover_all()
+        .await?
+        .into_iter()
+        .map(|(location, response)| Responder { location, response })
+        .collect();
+    let Some(device) = client.device() else {
+        bail!("No usable InternetGatewayDevice found");
+    };
+
+    if json {
+        let report = DiscoverReport {
+            responders,
+            selected: device,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    if !responders.is_empty() {
+        println!("SSDP responders:");
+        for responder in &responders {
+            println!(
+                "  {} (USN {}, SERVER {})",
+                responder.location,
+                responder.response.usn.as_deref().unwrap_or("-"),
+                responder.response.server.as_deref().unwrap_or("-")
+            );
+        }
+    }
+    println!("Selected device: {}", device.location);
+    let info = &device.info;
+    for (label, value) in [
+        ("friendlyName", &info.friendly_name),
+        ("manufacturer", &info.manufacturer),
+        ("modelName", &info.model_name),
+        ("modelNumber", &info.model_number),
+        ("serialNumber", &info.serial_number),
+    ] {
+        if let Some(value) = value {
+            println!("  {}: {}", label, value);
+        }
+    }
+    for interface in &device.wan_interfaces {
+        println!(
+            "  WAN interface {}{}",
+            interface.index,
+            interface
+                .name
+                .as_ref()
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default()
+        );
+        println!(
+            "    {} -> {}",
+            interface.common.service_type, interface.common.control_url
+        );
+        if let Some(connection) = &interface.connection {
+            println!(
+                "    {} -> {}",
+                connection.service.service_type, connection.service.control_url
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the byte counters back to back and report how often they change
+async fn run_coherence(config: Config, json: bool) -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut client = UpnpClient::from_config(&config.upnp)?;
+    client.ensure_device().await?;
+
+    let report = coherence::measure(
+        &client,
+        coherence::DEFAULT_DURATION,
+        coherence::DEFAULT_INTERVAL,
+    )
+    .await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("{}", report.summary());
+    }
+
+    Ok(())
+}
+
+/// Compare config.toml with the configuration of the exporter running on this host
+async fn run_config_diff(config: Config) -> Result<()> {
+    let url = format!("http://127.0.0.1:{}/debug/config", config.server.port);
+    let body = reqwest::get(&url).await?.error_for_status()?.text().await?;
+    let debug: DebugConfig = serde_json::from_str(&body)?;
+
+    let differences = debug.running.diff(&config)?;
+    if differences.is_empty() {
+        println!("{} matches the running configuration", CONFIG_PATH);
+    } else {
+        println!("Running configuration -> {}:", CONFIG_PATH);
+        for difference in differences {
+            println!("  {}", difference);
+        }
+    }
 
-    run_server(config).await
+    Ok(())
 }
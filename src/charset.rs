@@ -0,0 +1,128 @@
+use encoding_rs::{Encoding, UTF_8};
+use tracing::debug;
+
+// The XML declaration, if any, must sit at the very start of the document
+const DECLARATION_SCAN_LIMIT: usize = 256;
+
+/// Decode an XML document to UTF-8, honouring (in this order) a byte order
+/// mark, the encoding in the XML declaration and the HTTP charset. The
+/// declaration is rewritten to say UTF-8 so the parser does not decode twice.
+pub fn decode_xml(body: &[u8], http_charset: Option<&str>) -> String {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(body) {
+        let (text, _) = encoding.decode_without_bom_handling(&body[bom_len..]);
+        return declare_utf8(&text);
+    }
+
+    let encoding = declared_encoding(body)
+        .or_else(|| http_charset.and_then(|label| Encoding::for_label(label.as_bytes())))
+        .unwrap_or(UTF_8);
+    if encoding != UTF_8 {
+        debug!("Decoding XML from {}", encoding.name());
+    }
+    let (text, _) = encoding.decode_without_bom_handling(body);
+    declare_utf8(&text)
+}
+
+/// The charset parameter of a Content-Type header value
+pub fn content_type_charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+fn declared_encoding(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(DECLARATION_SCAN_LIMIT)];
+    // Every encoding a declaration can name is ASCII-compatible up to here
+    let head = String::from_utf8_lossy(head);
+    let (start, end) = encoding_value_range(&head)?;
+    Encoding::for_label(head[start..end].as_bytes())
+}
+
+/// Byte range of the encoding value inside a leading XML declaration
+fn encoding_value_range(text: &str) -> Option<(usize, usize)> {
+    let declaration = text.strip_prefix("<?xml")?;
+    let declaration = &declaration[..declaration.find("?>")?];
+    let attr = declaration.find("encoding")?;
+    let after = &declaration[attr + "encoding".len()..];
+    let eq = after.find('=')?;
+    let value = after[eq + 1..].trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let len = value[1..].find(quote)?;
+
+    let offset = "<?xml".len() + attr + "encoding".len() + (after.len() - value.len()) + 1;
+    Some((offset, offset + len))
+}
+
+fn declare_utf8(text: &str) -> String {
+    match encoding_value_range(text) {
+        Some((start, end)) => format!("{}UTF-8{}", &text[..start], &text[end..]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_declared_encoding() {
+        let body = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><name>M\xf3dem</name>";
+        assert_eq!(
+            decode_xml(body, None),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><name>M\u{f3}dem</name>"
+        );
+    }
+
+    #[test]
+    fn declaration_wins_over_http_charset() {
+        let body = b"<?xml version='1.0' encoding='latin1'?><name>\xd1</name>";
+        assert_eq!(
+            decode_xml(body, Some("utf-8")),
+            "<?xml version='1.0' encoding='UTF-8'?><name>\u{d1}</name>"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_http_charset_then_utf8() {
+        let body = b"<name>\xe9</name>";
+        assert_eq!(decode_xml(body, Some("iso-8859-1")), "<name>\u{e9}</name>");
+        assert_eq!(
+            decode_xml("<name>\u{e9}</name>".as_bytes(), None),
+            "<name>\u{e9}</name>"
+        );
+    }
+
+    #[test]
+    fn bom_wins_and_is_stripped() {
+        let mut body = b"\xef\xbb\xbf".to_vec();
+        body.extend_from_slice(
+            "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><a>\u{c9}</a>".as_bytes(),
+        );
+        assert_eq!(
+            decode_xml(&body, Some("iso-8859-1")),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a>\u{c9}</a>"
+        );
+
+        let mut utf16 = vec![0xff, 0xfe];
+        for unit in "<a>\u{c9}</a>".encode_utf16() {
+            utf16.extend_from_slice(&unit.to_le_bytes());
+        }
+        assert_eq!(decode_xml(&utf16, None), "<a>\u{c9}</a>");
+    }
+
+    #[test]
+    fn finds_the_content_type_charset() {
+        assert_eq!(
+            content_type_charset("text/xml; charset=\"ISO-8859-1\""),
+            Some("ISO-8859-1")
+        );
+        assert_eq!(
+            content_type_charset("text/xml;CHARSET=utf-8"),
+            Some("utf-8")
+        );
+        assert_eq!(content_type_charset("text/xml"), None);
+    }
+}
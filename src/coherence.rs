@@ -0,0 +1,84 @@
+use crate::provider::WanStatsProvider;
+use anyhow::Result;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the gateway's byte counters actually change when read back to back
+#[derive(Debug, Clone, Serialize)]
+pub struct CoherenceReport {
+    pub samples: usize,
+    pub duration_seconds: f64,
+    pub changes: usize,
+    /// Mean time between observed counter changes, if at least two were seen
+    pub estimated_update_interval_seconds: Option<f64>,
+    pub guidance: String,
+}
+
+impl CoherenceReport {
+    pub fn summary(&self) -> String {
+        let interval = match self.estimated_update_interval_seconds {
+            Some(seconds) => format!("~{:.1}s", seconds),
+            None => "unknown".to_string(),
+        };
+        format!(
+            "Samples: {}\nDuration: {:.1}s\nCounter changes: {}\nEstimated update interval: {}\n{}",
+            self.samples, self.duration_seconds, self.changes, interval, self.guidance
+        )
+    }
+}
+
+/// Read the byte counters every `interval` for `duration` and estimate how
+/// often the router refreshes them. Meant for one-shot diagnostics only.
+pub async fn measure(
+    client: &impl WanStatsProvider,
+    duration: Duration,
+    interval: Duration,
+) -> Result<CoherenceReport> {
+    let started = Instant::now();
+    let mut samples = 0;
+    let mut last = None;
+    let mut change_times = Vec::new();
+
+    while started.elapsed() < duration {
+        let counters = client.byte_counters().await?;
+        samples += 1;
+        if last.is_some_and(|last| last != counters) {
+            change_times.push(started.elapsed());
+        }
+        last = Some(counters);
+        tokio::time::sleep(interval).await;
+    }
+
+    let estimated = match change_times.as_slice() {
+        [first, .., last] => Some((*last - *first).as_secs_f64() / (change_times.len() - 1) as f64),
+        _ => None,
+    };
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let guidance = match estimated {
+        Some(seconds) if seconds >= 5.0 => format!(
+            "Counters update at most every ~{:.0}s; poll intervals below that add no information",
+            seconds
+        ),
+        Some(_) => "Counters update continuously; short poll intervals are meaningful".to_string(),
+        None if change_times.len() == 1 => format!(
+            "Counters changed once in {:.0}s; the router likely caches them for longer than that",
+            elapsed
+        ),
+        None => format!(
+            "Counters did not change in {:.0}s; the link may be idle or the router caches them",
+            elapsed
+        ),
+    };
+
+    Ok(CoherenceReport {
+        samples,
+        duration_seconds: elapsed,
+        changes: change_times.len(),
+        estimated_update_interval_seconds: estimated,
+        guidance,
+    })
+}
@@ -0,0 +1,46 @@
+use crate::config::CompatMode;
+use prometheus::proto::MetricFamily;
+
+/// Metric names used by the Python upnp-internet-exporter, keyed by our own
+/// names without namespace and subsystem
+const PYTHON_UPNP_EXPORTER_NAMES: &[(&str, &str)] = &[
+    ("bytes_sent_total", "upnp_total_bytes_sent"),
+    ("bytes_received_total", "upnp_total_bytes_received"),
+    ("packets_sent_total", "upnp_total_packets_sent"),
+    ("packets_received_total", "upnp_total_packets_received"),
+    ("connection_status", "upnp_physical_link_up"),
+];
+
+fn name_table(mode: CompatMode) -> &'static [(&'static str, &'static str)] {
+    match mode {
+        CompatMode::PythonUpnpExporter => PYTHON_UPNP_EXPORTER_NAMES,
+    }
+}
+
+/// Copies of the gathered families renamed to the legacy names of `mode`,
+/// carrying the same values as the originals. `full_name` turns a table key
+/// into the name the family was registered under.
+pub fn alias_families(
+    families: &[MetricFamily],
+    mode: CompatMode,
+    full_name: impl Fn(&str) -> String,
+) -> Vec<MetricFamily> {
+    let table: Vec<_> = name_table(mode)
+        .iter()
+        .map(|(name, legacy_name)| (full_name(name), *legacy_name))
+        .collect();
+    families
+        .iter()
+        .filter_map(|family| {
+            let (_, legacy_name) = table.iter().find(|(name, _)| name == family.get_name())?;
+            let mut alias = family.clone();
+            alias.set_name(legacy_name.to_string());
+            alias.set_help(format!(
+                "Compatibility alias for {}: {}",
+                family.get_name(),
+                family.get_help()
+            ));
+            Some(alias)
+        })
+        .collect()
+}
@@ -0,0 +1,568 @@
+use crate::soap;
+use anyhow::{Result, anyhow};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use reqwest::Url;
+use serde::Serialize;
+use tracing::{debug, error};
+
+/// Identity fields of the root device in a device description
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    /// deviceType, e.g. "urn:schemas-upnp-org:device:InternetGatewayDevice:2"
+    pub device_type: Option<String>,
+    pub friendly_name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model_name: Option<String>,
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    /// Firmware version from the vendor extensions some gateways add to the
+    /// root device (firmwareVersion or softwareVersion); UPnP has no standard field
+    pub firmware_version: Option<String>,
+    /// Unique device name, e.g. "uuid:2f8a...", the stable part of the SSDP USN
+    pub udn: Option<String>,
+}
+
+/// A service from the description, with its URLs resolved against the description location
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UpnpService {
+    /// serviceType as advertised, e.g. "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:2"
+    pub service_type: String,
+    /// serviceId, e.g. "urn:upnp-org:serviceId:WANIPConn1"
+    pub service_id: Option<String>,
+    pub control_url: String,
+    /// Service description listing the actions the device implements
+    pub scpd_url: Option<String>,
+    /// GENA subscription URL for evented state variables
+    pub event_sub_url: Option<String>,
+    /// Actions named in the SCPD, `None` if it was not fetched or could not be read
+    pub actions: Option<Vec<String>>,
+}
+
+impl UpnpService {
+    /// A service known only by its type and control URL
+    pub fn new(service_type: &str, control_url: String) -> Self {
+        Self {
+            service_type: service_type.to_string(),
+            service_id: None,
+            control_url,
+            scpd_url: None,
+            event_sub_url: None,
+            actions: None,
+        }
+    }
+
+    /// Whether the device advertises `action`, assuming it does when the SCPD is unknown
+    pub fn supports(&self, action: &str) -> bool {
+        self.actions
+            .as_ref()
+            .is_none_or(|actions| actions.iter().any(|a| a == action))
+    }
+}
+
+/// The connection service a gateway exposes: WANIPConnection for routed
+/// (DHCP/static) uplinks, WANPPPConnection for PPPoE/PPPoA
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum WanConnectionKind {
+    Ip,
+    Ppp,
+}
+
+impl WanConnectionKind {
+    pub fn service_name(self) -> &'static str {
+        match self {
+            Self::Ip => "WANIPConnection",
+            Self::Ppp => "WANPPPConnection",
+        }
+    }
+
+    fn from_service_type(service_type: &str) -> Option<Self> {
+        if service_type.contains("WANIPConnection") {
+            Some(Self::Ip)
+        } else if service_type.contains("WANPPPConnection") {
+            Some(Self::Ppp)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WanConnection {
+    pub kind: WanConnectionKind,
+    pub service: UpnpService,
+    /// UDN of the WANConnectionDevice offering the service
+    pub device_udn: Option<String>,
+}
+
+impl WanConnection {
+    /// Whether this is the service a Layer3Forwarding default connection
+    /// ("deviceUUID,serviceId") points at
+    pub fn is_default_connection(&self, default_connection: &str) -> bool {
+        let Some((udn, service_id)) = default_connection.trim().split_once(',') else {
+            return false;
+        };
+        // Some firmwares append the device type to the UDN ("uuid:...:WANConnectionDevice:1")
+        let udn_matches = self.device_udn.as_deref().is_some_and(|own| {
+            let udn = udn.trim();
+            udn.eq_ignore_ascii_case(own)
+                || udn.get(..own.len()).is_some_and(|prefix| {
+                    prefix.eq_ignore_ascii_case(own) && udn[own.len()..].starts_with(':')
+                })
+        });
+        udn_matches && self.service.service_id.as_deref() == Some(service_id.trim())
+    }
+}
+
+/// One WANDevice with its interface config and the connection of its WANConnectionDevice
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WanInterface {
+    /// Position among the usable WANDevices of the description, starting at 0
+    pub index: usize,
+    /// friendlyName of the WANDevice
+    pub name: Option<String>,
+    pub common: UpnpService,
+    /// The connection service queried for this interface
+    pub connection: Option<WanConnection>,
+    /// Every connection service of the WANDevice, in description order
+    pub connections: Vec<WanConnection>,
+    /// WANEthernetLinkConfig, a link status source for gateways without GetCommonLinkProperties
+    pub ethernet_link: Option<UpnpService>,
+    /// WANDSLLinkConfig of DSL gateways
+    pub dsl_link: Option<UpnpService>,
+    /// WANCableLinkConfig of DOCSIS cable gateways
+    pub cable_link: Option<UpnpService>,
+    /// WANPOTSLinkConfig of dial-up and mobile (e.g. LTE backup) uplinks
+    pub pots_link: Option<UpnpService>,
+    /// Number of connections GetActiveConnection listed at setup, `None`
+    /// when the gateway lacks it
+    pub active_connections: Option<u64>,
+    /// Whether the gateway answered QueryStateVariable for
+    /// NumberOfActiveConnections at setup, so scrapes can read the count
+    pub queries_active_connections: bool,
+}
+
+impl WanInterface {
+    /// Kind of uplink by the link config service the WANDevice offers:
+    /// "pots", "dsl", "cable", "ethernet" or "unknown"
+    pub fn link_kind(&self) -> &'static str {
+        if self.pots_link.is_some() {
+            "pots"
+        } else if self.dsl_link.is_some() {
+            "dsl"
+        } else if self.cable_link.is_some() {
+            "cable"
+        } else if self.ethernet_link.is_some() {
+            "ethernet"
+        } else {
+            "unknown"
+        }
+    }
+}
+
+/// The parts of a device description the exporter uses
+#[derive(Debug, Clone, Default)]
+pub struct Description {
+    pub info: DeviceInfo,
+    pub wan_interfaces: Vec<WanInterface>,
+    /// Layer3Forwarding service of the root device, naming the default connection
+    pub layer3_forwarding: Option<UpnpService>,
+}
+
+#[derive(Default)]
+struct DeviceFrame {
+    /// Index into the pending interfaces when this device is a WANDevice
+    interface: Option<usize>,
+    udn: Option<String>,
+}
+
+/// A <service> element as written in the description
+#[derive(Default)]
+struct RawService {
+    service_type: String,
+    service_id: String,
+    control_url: String,
+    scpd_url: String,
+    event_sub_url: String,
+}
+
+#[derive(Default)]
+struct PendingInterface {
+    name: Option<String>,
+    common: Option<UpnpService>,
+    connections: Vec<WanConnection>,
+    ethernet_link: Option<UpnpService>,
+    dsl_link: Option<UpnpService>,
+    cable_link: Option<UpnpService>,
+    pots_link: Option<UpnpService>,
+}
+
+/// Split the zone of a bracketed IPv6 literal ("%eth0" or "%25eth0") off a
+/// URL, since URL parsers reject it. Returns the URL without the zone and
+/// the zone exactly as written.
+pub fn split_zone_id(url: &str) -> (String, Option<String>) {
+    let zone = url.find('[').and_then(|open| {
+        let close = open + url[open..].find(']')?;
+        let percent = open + url[open..close].find('%')?;
+        Some((percent, close))
+    });
+    match zone {
+        Some((start, end)) => (
+            format!("{}{}", &url[..start], &url[end..]),
+            Some(url[start..end].to_string()),
+        ),
+        None => (url.to_string(), None),
+    }
+}
+
+/// Resolve a (possibly relative) URL from the device description against
+/// its location, keeping the zone of a link-local location on same-host URLs
+pub fn resolve_url(base_url: &str, url: &str) -> Result<String> {
+    let (plain_base, zone) = split_zone_id(base_url);
+    let base =
+        Url::parse(&plain_base).map_err(|e| anyhow!("Invalid base URL {}: {}", base_url, e))?;
+    let resolved = base
+        .join(url.trim())
+        .map_err(|e| anyhow!("Invalid service URL {}: {}", url, e))?;
+
+    let resolved_text = resolved.to_string();
+    match zone {
+        Some(zone) if resolved.host_str() == base.host_str() => {
+            let Some(close) = resolved_text.find(']') else {
+                return Ok(resolved_text);
+            };
+            Ok(format!(
+                "{}{}{}",
+                &resolved_text[..close],
+                zone,
+                &resolved_text[close..]
+            ))
+        }
+        _ => Ok(resolved_text),
+    }
+}
+
+/// Parse the root device identity and group the WAN services by the
+/// WANDevice they belong to. Services outside any WANDevice (flat
+/// descriptions from sloppy firmwares) form one implicit interface.
+/// Devices without any WANCommonInterfaceConfig yield no interfaces.
+pub fn parse(xml: &str, base_url: &str) -> Result<Description> {
+    let mut reader = Reader::from_str(xml);
+    let mut info = DeviceInfo::default();
+    let mut pending: Vec<PendingInterface> = Vec::new();
+    let mut implicit_interface: Option<usize> = None;
+    let mut layer3_forwarding: Option<UpnpService> = None;
+    let mut devices: Vec<DeviceFrame> = Vec::new();
+    let mut service: Option<RawService> = None;
+    let mut text = String::new();
+
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => {
+                error!("XML parsing error: {}", e);
+                break;
+            }
+        };
+        // An empty element `<x/>` opens and closes at once
+        let (opened, closed) = match &event {
+            Event::Start(element) => (Some(element.local_name()), None),
+            Event::Empty(element) => (Some(element.local_name()), Some(element.local_name())),
+            Event::End(element) => (None, Some(element.local_name())),
+            Event::Text(chars) => {
+                match chars.unescape() {
+                    Ok(chars) => text.push_str(&chars),
+                    Err(e) => {
+                        error!("XML parsing error: {}", e);
+                        break;
+                    }
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        if let Some(name) = opened {
+            text.clear();
+            match name.as_ref() {
+                b"device" => devices.push(DeviceFrame::default()),
+                b"service" => service = Some(RawService::default()),
+                _ => {}
+            }
+        }
+        if let Some(name) = closed {
+            let name = str::from_utf8(name.into_inner()).unwrap_or_default();
+            let value = text.trim().to_string();
+            text.clear();
+
+            if let Some(raw) = service.as_mut() {
+                match name {
+                    "serviceType" => raw.service_type = value,
+                    "serviceId" => raw.service_id = value,
+                    "controlURL" => raw.control_url = value,
+                    "SCPDURL" => raw.scpd_url = value,
+                    "eventSubURL" => raw.event_sub_url = value,
+                    "service" => {
+                        let raw = service.take().unwrap_or_default();
+                        if raw.service_type.contains("Layer3Forwarding") {
+                            let forwarding = resolve_service(&raw, base_url)?;
+                            debug!(
+                                "Found Layer3Forwarding service at: {}",
+                                forwarding.control_url
+                            );
+                            layer3_forwarding = Some(forwarding);
+                            continue;
+                        }
+                        let udn = devices.iter().rev().find_map(|d| d.udn.clone());
+                        let owner = devices
+                            .iter()
+                            .rev()
+                            .find_map(|d| d.interface)
+                            .unwrap_or_else(|| {
+                                *implicit_interface.get_or_insert_with(|| {
+                                    pending.push(PendingInterface::default());
+                                    pending.len() - 1
+                                })
+                            });
+                        add_service(&mut pending[owner], raw, udn, base_url)?;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let depth = devices.len();
+            match name {
+                "device" => {
+                    devices.pop();
+                }
+                "UDN" => {
+                    if depth == 1 {
+                        info.udn = Some(value.clone());
+                    }
+                    if let Some(frame) = devices.last_mut() {
+                        frame.udn = Some(value);
+                    }
+                }
+                "deviceType" if value.contains("WANDevice") => {
+                    if let Some(frame) = devices.last_mut() {
+                        pending.push(PendingInterface::default());
+                        frame.interface = Some(pending.len() - 1);
+                    }
+                }
+                "friendlyName" if depth > 1 => {
+                    if let Some(index) = devices.last().and_then(|d| d.interface) {
+                        pending[index].name = Some(value);
+                    }
+                }
+                field if depth == 1 => match field {
+                    "deviceType" => info.device_type = Some(value),
+                    "friendlyName" => info.friendly_name = Some(value),
+                    "manufacturer" => info.manufacturer = Some(value),
+                    "modelName" => info.model_name = Some(value),
+                    "modelNumber" => info.model_number = Some(value),
+                    "serialNumber" => info.serial_number = Some(value),
+                    "firmwareVersion" | "softwareVersion" => info.firmware_version = Some(value),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let wan_interfaces: Vec<WanInterface> = pending
+        .into_iter()
+        .filter_map(|mut interface| {
+            if interface.common.is_none() {
+                debug!(
+                    "Skipping WANDevice {} without WANCommonInterfaceConfig",
+                    interface.name.as_deref().unwrap_or("(unnamed)")
+                );
+            }
+            Some((interface.common.take()?, interface))
+        })
+        .enumerate()
+        .map(|(index, (common, interface))| WanInterface {
+            index,
+            name: interface.name,
+            common,
+            connection: preferred_connection(&interface.connections),
+            connections: interface.connections,
+            ethernet_link: interface.ethernet_link,
+            dsl_link: interface.dsl_link,
+            cable_link: interface.cable_link,
+            pots_link: interface.pots_link,
+            active_connections: None,
+            queries_active_connections: false,
+        })
+        .collect();
+
+    Ok(Description {
+        info,
+        wan_interfaces,
+        layer3_forwarding,
+    })
+}
+
+/// Gateways offering both keep WANIPConnection as the primary service
+fn preferred_connection(connections: &[WanConnection]) -> Option<WanConnection> {
+    connections
+        .iter()
+        .find(|c| c.kind == WanConnectionKind::Ip)
+        .or_else(|| connections.first())
+        .cloned()
+}
+
+fn resolve_service(raw: &RawService, base_url: &str) -> Result<UpnpService> {
+    let optional = |value: &str| -> Result<Option<String>> {
+        if value.is_empty() {
+            Ok(None)
+        } else {
+            resolve_url(base_url, value).map(Some)
+        }
+    };
+    Ok(UpnpService {
+        service_type: raw.service_type.clone(),
+        service_id: (!raw.service_id.is_empty()).then(|| raw.service_id.clone()),
+        control_url: resolve_url(base_url, &raw.control_url)?,
+        scpd_url: optional(&raw.scpd_url)?,
+        event_sub_url: optional(&raw.event_sub_url)?,
+        actions: None,
+    })
+}
+
+fn add_service(
+    interface: &mut PendingInterface,
+    raw: RawService,
+    device_udn: Option<String>,
+    base_url: &str,
+) -> Result<()> {
+    if raw.service_type.contains("WANCommonInterfaceConfig") {
+        let service = resolve_service(&raw, base_url)?;
+        debug!(
+            "Found WANCommonInterfaceConfig service at: {}",
+            service.control_url
+        );
+        interface.common = Some(service);
+    } else if raw.service_type.contains("WANEthernetLinkConfig") {
+        let service = resolve_service(&raw, base_url)?;
+        debug!(
+            "Found WANEthernetLinkConfig service at: {}",
+            service.control_url
+        );
+        interface.ethernet_link = Some(service);
+    } else if raw.service_type.contains("WANDSLLinkConfig") {
+        let service = resolve_service(&raw, base_url)?;
+        debug!("Found WANDSLLinkConfig service at: {}", service.control_url);
+        interface.dsl_link = Some(service);
+    } else if raw.service_type.contains("WANCableLinkConfig") {
+        let service = resolve_service(&raw, base_url)?;
+        debug!(
+            "Found WANCableLinkConfig service at: {}",
+            service.control_url
+        );
+        interface.cable_link = Some(service);
+    } else if raw.service_type.contains("WANPOTSLinkConfig") {
+        let service = resolve_service(&raw, base_url)?;
+        debug!(
+            "Found WANPOTSLinkConfig service at: {}",
+            service.control_url
+        );
+        interface.pots_link = Some(service);
+    } else if let Some(kind) = WanConnectionKind::from_service_type(&raw.service_type) {
+        let service = resolve_service(&raw, base_url)?;
+        debug!(
+            "Found {} service at: {}",
+            kind.service_name(),
+            service.control_url
+        );
+        interface.connections.push(WanConnection {
+            kind,
+            service,
+            device_udn,
+        });
+    }
+    Ok(())
+}
+
+/// Names of the actions listed in a service description (SCPD)
+pub fn parse_scpd_actions(xml: &str) -> Vec<String> {
+    let mut reader = soap::xml_reader(xml);
+    let mut actions = Vec::new();
+    // Element names from the document root down to the current element
+    let mut path: Vec<String> = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                path.push(soap::local_name(&element).to_string());
+                text.clear();
+            }
+            Ok(Event::Text(chars)) => match chars.unescape() {
+                Ok(chars) => text.push_str(&chars),
+                Err(e) => {
+                    error!("XML parsing error: {}", e);
+                    break;
+                }
+            },
+            Ok(Event::End(_)) => {
+                // Only <actionList><action><name>, not the names of arguments
+                if path.len() >= 3 && path[path.len() - 3..] == ["actionList", "action", "name"] {
+                    actions.push(text.trim().to_string());
+                }
+                path.pop();
+                text.clear();
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                error!("XML parsing error: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scpd_actions_skip_argument_names() {
+        let scpd = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <actionList>
+    <action>
+      <name>GetTotalBytesSent</name>
+      <argumentList>
+        <argument><name>NewTotalBytesSent</name><direction>out</direction></argument>
+      </argumentList>
+    </action>
+    <action><name> GetAddonInfos </name></action>
+  </actionList>
+  <serviceStateTable>
+    <stateVariable><name>TotalBytesSent</name></stateVariable>
+  </serviceStateTable>
+</scpd>"#;
+        assert_eq!(
+            parse_scpd_actions(scpd),
+            ["GetTotalBytesSent", "GetAddonInfos"]
+        );
+    }
+
+    #[test]
+    fn scpd_actions_with_prefixed_elements() {
+        let scpd = r#"<s:scpd xmlns:s="urn:schemas-upnp-org:service-1-0"><s:actionList><s:action><s:name>GetStatusInfo</s:name></s:action></s:actionList></s:scpd>"#;
+        assert_eq!(parse_scpd_actions(scpd), ["GetStatusInfo"]);
+    }
+
+    #[test]
+    fn scpd_actions_up_to_unreadable_xml() {
+        let scpd = "<scpd><actionList><action><name>A</name></action><action><name>B</nam";
+        assert_eq!(parse_scpd_actions(scpd), ["A"]);
+    }
+}
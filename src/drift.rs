@@ -0,0 +1,110 @@
+use crate::config::Config;
+use crate::metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+pub const DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Outcome of the last comparison between the config file and the running configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DriftStatus {
+    pub drifted: bool,
+    /// Settings that differ, as "key: running -> file"
+    pub differences: Vec<String>,
+    /// Why the file could not be read or validated
+    pub error: Option<String>,
+}
+
+/// Tracks whether the config file on disk still matches what the process runs with.
+/// Every setting is applied at startup only, so any difference needs a restart.
+pub struct ConfigDrift {
+    path: Option<PathBuf>,
+    running: Config,
+    status: Mutex<DriftStatus>,
+    /// Where `upnp_wan_config_drift` is reported, if anywhere
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ConfigDrift {
+    pub fn new(running: Config, path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            running,
+            status: Mutex::new(DriftStatus::default()),
+            metrics: None,
+        }
+    }
+
+    /// Also report the outcome of every check in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn running(&self) -> &Config {
+        &self.running
+    }
+
+    pub fn status(&self) -> DriftStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Re-read and re-validate the config file and compare it with the running configuration
+    pub fn check(&self) -> DriftStatus {
+        let Some(path) = &self.path else {
+            return DriftStatus::default();
+        };
+
+        let status = match Config::from_file(&path.to_string_lossy()) {
+            Ok(on_disk) => match self.running.diff(&on_disk) {
+                Ok(differences) => DriftStatus {
+                    drifted: !differences.is_empty(),
+                    differences,
+                    error: None,
+                },
+                Err(e) => DriftStatus {
+                    drifted: true,
+                    differences: Vec::new(),
+                    error: Some(format!("Failed to compare configurations: {}", e)),
+                },
+            },
+            Err(e) => DriftStatus {
+                drifted: true,
+                differences: Vec::new(),
+                error: Some(format!("{} is not a valid config: {}", path.display(), e)),
+            },
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.set_config_drift(status.drifted);
+        }
+        *self.status.lock().unwrap() = status.clone();
+        status
+    }
+}
+
+/// Periodically compare the config file against the running configuration
+pub async fn run_drift_check(drift: Arc<ConfigDrift>) {
+    let mut interval = tokio::time::interval(DRIFT_CHECK_INTERVAL);
+    let mut was_drifted = false;
+
+    loop {
+        interval.tick().await;
+        let status = drift.check();
+        if status.drifted && !was_drifted {
+            match &status.error {
+                Some(error) => warn!("Config drift: {}", error),
+                None => warn!(
+                    "Config file differs from the running configuration (restart to apply): {}",
+                    status.differences.join(", ")
+                ),
+            }
+        } else if !status.drifted {
+            debug!("Config file matches the running configuration");
+        }
+        was_drifted = status.drifted;
+    }
+}
@@ -0,0 +1,248 @@
+use crate::soap::SoapFault;
+use crate::upnp::{DiscoveryError, DiscoveryFailure, ProxyError};
+use std::io;
+
+pub type UpnpResult<T> = Result<T, UpnpError>;
+
+/// Errors returned by the public methods of `UpnpClient`
+#[derive(Debug, thiserror::Error)]
+pub enum UpnpError {
+    /// Settings that cannot work, rejected when the client is built
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// No gateway answered M-SEARCH before the discovery deadline
+    #[error("{0}")]
+    DiscoveryTimeout(String),
+    /// Gateways answered, but none is usable or matches the selection policy
+    #[error("{0}")]
+    NoIgdFound(String),
+    /// The device or a service description could not be downloaded
+    #[error("Failed to fetch the device description: {0}")]
+    DescriptionFetch(reqwest::Error),
+    /// The description lacks a service the exporter needs
+    #[error("{0} service not found")]
+    ServiceNotFound(&'static str),
+    /// The service does not list the action in its SCPD
+    #[error("{service_type} does not implement {action}")]
+    UnsupportedAction {
+        service_type: String,
+        action: String,
+    },
+    /// The gateway rejected a SOAP request
+    #[error(transparent)]
+    Soap(#[from] SoapFault),
+    /// A response lacked an expected value or carried an unreadable one
+    #[error("Cannot read {element}: {message}")]
+    Parse { element: String, message: String },
+    /// The gateway answered with the response of another action, as
+    /// firmwares replaying a cached reply do
+    #[error("{action} was answered with {found}")]
+    ResponseMismatch { action: String, found: String },
+    /// The gateway answered 401 to a request, with or after credentials
+    #[error("{url} {}", if *.credentials_configured {
+        "rejected the configured credentials"
+    } else {
+        "requires authentication, set upnp.username and upnp.password"
+    })]
+    AuthenticationFailed {
+        url: String,
+        credentials_configured: bool,
+    },
+    /// A request to the gateway did not complete within the configured timeout
+    #[error("{stage} to the gateway timed out after {seconds}s")]
+    Timeout {
+        stage: &'static str,
+        seconds: u64,
+        source: reqwest::Error,
+    },
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// None of the SOAP requests of a scrape succeeded
+    #[error("No SOAP request to {url} succeeded: {error}")]
+    NoResponse { url: String, error: Box<UpnpError> },
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+impl UpnpError {
+    pub(crate) fn parse(element: &str, message: impl ToString) -> Self {
+        Self::Parse {
+            element: element.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Name of the variant, as shown in scrape error bodies
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Config(_) => "Config",
+            Self::DiscoveryTimeout(_) => "DiscoveryTimeout",
+            Self::NoIgdFound(_) => "NoIgdFound",
+            Self::DescriptionFetch(_) => "DescriptionFetch",
+            Self::ServiceNotFound(_) => "ServiceNotFound",
+            Self::UnsupportedAction { .. } => "UnsupportedAction",
+            Self::Soap(_) => "Soap",
+            Self::Parse { .. } => "Parse",
+            Self::ResponseMismatch { .. } => "ResponseMismatch",
+            Self::AuthenticationFailed { .. } => "AuthenticationFailed",
+            Self::Timeout { .. } => "Timeout",
+            Self::Http(_) => "Http",
+            Self::Proxy(_) => "Proxy",
+            Self::Io(_) => "Io",
+            Self::NoResponse { .. } => "NoResponse",
+            Self::Other(_) => "Other",
+        }
+    }
+
+    /// Classification for the `reason` label of the scrape error counter
+    pub fn reason(&self) -> ScrapeErrorReason {
+        match self {
+            Self::DiscoveryTimeout(_) => ScrapeErrorReason::DiscoveryTimeout,
+            Self::NoIgdFound(_) | Self::ServiceNotFound(_) => ScrapeErrorReason::NoIgd,
+            Self::Soap(fault) if fault.code.is_some() || fault.fault_string.is_some() => {
+                ScrapeErrorReason::SoapFault
+            }
+            Self::Timeout { .. } => ScrapeErrorReason::Timeout,
+            Self::Http(e) if e.is_timeout() => ScrapeErrorReason::Timeout,
+            Self::Soap(_)
+            | Self::Http(_)
+            | Self::DescriptionFetch(_)
+            | Self::Proxy(_)
+            | Self::Io(_) => ScrapeErrorReason::Http,
+            Self::Parse { .. } | Self::ResponseMismatch { .. } => ScrapeErrorReason::Parse,
+            Self::AuthenticationFailed { .. } => ScrapeErrorReason::Auth,
+            Self::NoResponse { error, .. } => error.reason(),
+            Self::Config(_) | Self::UnsupportedAction { .. } | Self::Other(_) => {
+                ScrapeErrorReason::Other
+            }
+        }
+    }
+
+    /// Classification for the `kind` label of the SOAP error counter:
+    /// "timeout", "fault" (the gateway answered with a SOAP fault), "parse"
+    /// (an unreadable or mismatched response), "http" (the request failed,
+    /// or the gateway answered an error status without a fault) or "other"
+    pub fn soap_error_kind(&self) -> &'static str {
+        match self {
+            Self::Timeout { .. } => "timeout",
+            Self::Http(e) if e.is_timeout() => "timeout",
+            Self::Soap(fault) if fault.code.is_some() || fault.fault_string.is_some() => "fault",
+            Self::Parse { .. } | Self::ResponseMismatch { .. } => "parse",
+            Self::Soap(_)
+            | Self::Http(_)
+            | Self::Proxy(_)
+            | Self::Io(_)
+            | Self::AuthenticationFailed { .. } => "http",
+            Self::NoResponse { error, .. } => error.soap_error_kind(),
+            _ => "other",
+        }
+    }
+
+    /// Whether a failed request is worth repeating: the connection broke, the
+    /// server erred without a UPnP fault or replayed another action's reply.
+    /// Faults and 4xx are deterministic.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::Soap(fault) => {
+                fault.code.is_none() && fault.fault_string.is_none() && fault.status >= 500
+            }
+            Self::Http(e) => !e.is_timeout() && (e.is_connect() || e.is_request()),
+            Self::Proxy(_) | Self::ResponseMismatch { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Convert an error raised during discovery, where HTTP failures come
+    /// from fetching the device description
+    pub(crate) fn from_discovery(error: anyhow::Error) -> Self {
+        match error.downcast::<DiscoveryError>() {
+            Ok(DiscoveryError { reason, source }) => match reason {
+                DiscoveryFailure::Timeout => Self::DiscoveryTimeout(source.to_string()),
+                DiscoveryFailure::NoMatch => Self::NoIgdFound(source.to_string()),
+                DiscoveryFailure::Socket | DiscoveryFailure::Parse | DiscoveryFailure::Http => {
+                    Self::from(source)
+                }
+            },
+            Err(error) => match Self::from(error) {
+                Self::Http(e) => Self::DescriptionFetch(e),
+                other => other,
+            },
+        }
+    }
+}
+
+impl From<anyhow::Error> for UpnpError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<UpnpError>() {
+            Ok(e) => return e,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<SoapFault>() {
+            Ok(fault) => return Self::Soap(fault),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<ProxyError>() {
+            Ok(e) => return Self::Proxy(e),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<reqwest::Error>() {
+            Ok(e) => return Self::Http(e),
+            Err(error) => error,
+        };
+        match error.downcast::<io::Error>() {
+            Ok(e) => Self::Io(e),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+/// Why a scrape failed, as exposed in the `reason` label of the scrape error
+/// counter; a fixed set, so the counter's cardinality stays bounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrapeErrorReason {
+    /// No gateway answered M-SEARCH in time
+    DiscoveryTimeout,
+    /// Gateways answered, but none with the WAN services the exporter needs
+    NoIgd,
+    /// The request failed or the gateway answered an error status without a fault
+    Http,
+    /// The request got no answer in time
+    Timeout,
+    /// The gateway answered with a SOAP fault, e.g. UPnP error 606
+    SoapFault,
+    /// The answer could not be read
+    Parse,
+    /// The gateway rejected the credentials
+    Auth,
+    Other,
+}
+
+impl ScrapeErrorReason {
+    pub const ALL: [Self; 8] = [
+        Self::DiscoveryTimeout,
+        Self::NoIgd,
+        Self::Http,
+        Self::Timeout,
+        Self::SoapFault,
+        Self::Parse,
+        Self::Auth,
+        Self::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DiscoveryTimeout => "discovery_timeout",
+            Self::NoIgd => "no_igd",
+            Self::Http => "http",
+            Self::Timeout => "timeout",
+            Self::SoapFault => "soap_fault",
+            Self::Parse => "parse",
+            Self::Auth => "auth",
+            Self::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::fmt::Write;
+
+const OPENMETRICS_MEDIA_TYPE: &str = "application/openmetrics-text";
+const OPENMETRICS_VERSION: &str = "1.0.0";
+
+/// Format of the `/metrics` response, negotiated from the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpositionFormat {
+    /// The classic Prometheus text format 0.0.4
+    #[default]
+    Text,
+    /// OpenMetrics 1.0.0, with `_created` series and the final `# EOF`
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    /// The format the client prefers by quality, the text format when it
+    /// names neither; equal qualities go to the one listed first
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let mut best: Option<(Self, f32)> = None;
+        for range in accept.unwrap_or_default().split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut version = None;
+            for parameter in parts {
+                let Some((name, value)) = parameter.split_once('=') else {
+                    continue;
+                };
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "q" => quality = value.trim().parse().unwrap_or(0.0),
+                    "version" => version = Some(value.trim().trim_matches('"').to_string()),
+                    _ => {}
+                }
+            }
+            let format = match media_type.as_str() {
+                OPENMETRICS_MEDIA_TYPE
+                    if version.as_deref().is_none_or(|v| v == OPENMETRICS_VERSION) =>
+                {
+                    Self::OpenMetrics
+                }
+                "text/plain" | "text/*" | "*/*" => Self::Text,
+                _ => continue,
+            };
+            if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format).unwrap_or_default()
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => "text/plain; version=0.0.4; charset=utf-8",
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Identity of a series for looking up its creation time: the family name
+/// and the label pairs, which the registry keeps sorted
+pub fn series_key(family: &str, metric: &Metric) -> String {
+    let mut key = family.to_string();
+    for label in metric.get_label() {
+        let _ = write!(key, ",{}={}", label.get_name(), label.get_value());
+    }
+    key
+}
+
+/// Whether OpenMetrics exposes a `_created` series for `family`
+pub fn has_created(family: &MetricFamily) -> bool {
+    matches!(
+        family.get_field_type(),
+        MetricType::COUNTER | MetricType::HISTOGRAM | MetricType::SUMMARY
+    )
+}
+
+/// Encode `families` in the OpenMetrics text format. `created` gives the
+/// Unix time a counter, histogram or summary series was created, if known.
+pub fn encode_openmetrics(
+    families: &[MetricFamily],
+    created: impl Fn(&str, &Metric) -> Option<f64>,
+) -> String {
+    let mut output = String::new();
+    for family in families {
+        let full_name = family.get_name();
+        let (name, metric_type) = match family.get_field_type() {
+            // The family is named without the suffix its samples carry
+            MetricType::COUNTER => (
+                full_name.strip_suffix("_total").unwrap_or(full_name),
+                "counter",
+            ),
+            MetricType::GAUGE => (full_name, "gauge"),
+            MetricType::HISTOGRAM => (full_name, "histogram"),
+            MetricType::SUMMARY => (full_name, "summary"),
+            MetricType::UNTYPED => (full_name, "unknown"),
+        };
+        let _ = writeln!(output, "# HELP {} {}", name, escape(family.get_help()));
+        let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let sample =
+                |output: &mut String, suffix: &str, extra: Option<(&str, &str)>, value: f64| {
+                    output.push_str(name);
+                    output.push_str(suffix);
+                    write_labels(
+                        output,
+                        labels.iter().map(|l| (l.get_name(), l.get_value())),
+                        extra,
+                    );
+                    let _ = writeln!(output, " {}", format_value(value));
+                };
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    sample(
+                        &mut output,
+                        "_total",
+                        None,
+                        metric.get_counter().get_value(),
+                    );
+                }
+                MetricType::GAUGE => sample(&mut output, "", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => {
+                    sample(&mut output, "", None, metric.get_untyped().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut has_infinite_bucket = false;
+                    for bucket in histogram.get_bucket() {
+                        let upper_bound = bucket.get_upper_bound();
+                        has_infinite_bucket |= upper_bound == f64::INFINITY;
+                        sample(
+                            &mut output,
+                            "_bucket",
+                            Some(("le", &format_value(upper_bound))),
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    if !has_infinite_bucket {
+                        sample(
+                            &mut output,
+                            "_bucket",
+                            Some(("le", "+Inf")),
+                            histogram.get_sample_count() as f64,
+                        );
+                    }
+                    sample(
+                        &mut output,
+                        "_count",
+                        None,
+                        histogram.get_sample_count() as f64,
+                    );
+                    sample(&mut output, "_sum", None, histogram.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        sample(
+                            &mut output,
+                            "",
+                            Some(("quantile", &format_value(quantile.get_quantile()))),
+                            quantile.get_value(),
+                        );
+                    }
+                    sample(
+                        &mut output,
+                        "_count",
+                        None,
+                        summary.get_sample_count() as f64,
+                    );
+                    sample(&mut output, "_sum", None, summary.get_sample_sum());
+                }
+            }
+            if has_created(family)
+                && let Some(created) = created(full_name, metric)
+            {
+                sample(&mut output, "_created", None, created);
+            }
+        }
+    }
+    output.push_str("# EOF\n");
+    output
+}
+
+fn write_labels<'a>(
+    output: &mut String,
+    labels: impl Iterator<Item = (&'a str, &'a str)>,
+    extra: Option<(&'a str, &'a str)>,
+) {
+    let mut labels = labels.chain(extra).peekable();
+    if labels.peek().is_none() {
+        return;
+    }
+    output.push('{');
+    for (index, (name, value)) in labels.enumerate() {
+        if index > 0 {
+            output.push(',');
+        }
+        let _ = write!(output, "{}=\"{}\"", name, escape(value));
+    }
+    output.push('}');
+}
+
+/// Escape backslashes, double quotes and newlines of help texts and label values
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_accept() {
+        use ExpositionFormat::{OpenMetrics, Text};
+        for (accept, expected) in [
+            (None, Text),
+            (Some(""), Text),
+            (Some("*/*"), Text),
+            (Some("text/plain; version=0.0.4"), Text),
+            (Some("application/openmetrics-text"), OpenMetrics),
+            (
+                Some("application/openmetrics-text; version=1.0.0"),
+                OpenMetrics,
+            ),
+            (
+                Some("Application/OpenMetrics-Text;Version=\"1.0.0\""),
+                OpenMetrics,
+            ),
+            // What Prometheus sends
+            (
+                Some(
+                    "application/openmetrics-text;version=1.0.0;q=0.5,application/openmetrics-text;version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,*/*;q=0.2",
+                ),
+                OpenMetrics,
+            ),
+            (
+                Some("text/plain;q=0.9,application/openmetrics-text;version=1.0.0;q=0.5"),
+                Text,
+            ),
+            (Some("application/openmetrics-text;q=0"), Text),
+            (Some("application/openmetrics-text; version=0.0.1"), Text),
+            (Some("application/json"), Text),
+        ] {
+            assert_eq!(
+                ExpositionFormat::from_accept(accept),
+                expected,
+                "{accept:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn openmetrics_framing() {
+        let registry = prometheus::Registry::new();
+        let counter = prometheus::IntCounterVec::new(
+            prometheus::Opts::new("requests_total", "Requests \"served\"\nso far"),
+            &["path"],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.with_label_values(&["/a\\b"]).inc_by(3);
+        let gauge = prometheus::Gauge::new("temperature", "Degrees").unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.set(f64::NAN);
+
+        let output = encode_openmetrics(&registry.gather(), |family, metric| {
+            assert_eq!(series_key(family, metric), "requests_total,path=/a\\b");
+            Some(1700000000.5)
+        });
+        assert_eq!(
+            output,
+            "# HELP requests Requests \\\"served\\\"\\nso far\n\
+             # TYPE requests counter\n\
+             requests_total{path=\"/a\\\\b\"} 3\n\
+             requests_created{path=\"/a\\\\b\"} 1700000000.5\n\
+             # HELP temperature Degrees\n\
+             # TYPE temperature gauge\n\
+             temperature NaN\n\
+             # EOF\n"
+        );
+    }
+}
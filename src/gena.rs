@@ -0,0 +1,372 @@
+use crate::config::UpnpConfig;
+use crate::metrics::Metrics;
+use crate::soap;
+use crate::upnp::UpnpClient;
+use anyhow::{Result, anyhow};
+use axum::{
+    Router,
+    extract::State,
+    http::{HeaderMap, Method, StatusCode},
+    routing::any,
+};
+use quick_xml::events::Event;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+/// Route of this server the gateway delivers NOTIFY requests to
+pub const EVENT_PATH: &str = "/upnp/events";
+/// How long to poll instead after the gateway refused SUBSCRIBE
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(300);
+/// How often to check whether a device has been resolved to subscribe to
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A subscription the gateway accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub event_sub_url: String,
+    /// Subscription identifier, e.g. "uuid:2f8a..."
+    pub sid: String,
+    /// Seconds the gateway granted, which may be less than requested
+    pub timeout: u64,
+}
+
+/// Last evented values of the subscribed WAN connection service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventedValues {
+    pub connection_status: Option<String>,
+    pub external_ip: Option<String>,
+    pub possible_connection_types: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Subscribed {
+    subscription: Option<Subscription>,
+    values: EventedValues,
+}
+
+/// The subscription and the values its events delivered, shared by the
+/// subscriber task, the NOTIFY route and `UpnpClient`, which reads evented
+/// values instead of polling them while a subscription is active
+#[derive(Debug, Default)]
+pub struct EventState {
+    inner: Mutex<Subscribed>,
+}
+
+impl EventState {
+    /// Evented values of the service subscribed at `event_sub_url`, `None`
+    /// without an active subscription to it
+    pub fn values_for(&self, event_sub_url: &str) -> Option<EventedValues> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .subscription
+            .as_ref()
+            .filter(|subscription| subscription.event_sub_url == event_sub_url)
+            .map(|_| inner.values.clone())
+    }
+
+    pub fn subscription(&self) -> Option<Subscription> {
+        self.inner.lock().unwrap().subscription.clone()
+    }
+
+    fn activate(&self, subscription: Subscription) {
+        *self.inner.lock().unwrap() = Subscribed {
+            subscription: Some(subscription),
+            values: EventedValues::default(),
+        };
+    }
+
+    /// Keep the values delivered so far across a renewal
+    fn renewed(&self, subscription: Subscription) {
+        self.inner.lock().unwrap().subscription = Some(subscription);
+    }
+
+    fn deactivate(&self) -> Option<Subscription> {
+        std::mem::take(&mut *self.inner.lock().unwrap()).subscription
+    }
+
+    /// Apply the properties of a NOTIFY for `sid`, returning the updated
+    /// values; `None` for an unknown SID
+    fn apply(&self, sid: &str, properties: Vec<(String, String)>) -> Option<EventedValues> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.subscription.as_ref()?.sid != sid {
+            return None;
+        }
+        for (name, value) in properties {
+            match name.as_str() {
+                "ConnectionStatus" => inner.values.connection_status = Some(value),
+                "ExternalIPAddress" => inner.values.external_ip = Some(value),
+                "PossibleConnectionTypes" => {
+                    inner.values.possible_connection_types = Some(
+                        value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|t| !t.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    )
+                }
+                _ => {}
+            }
+        }
+        Some(inner.values.clone())
+    }
+}
+
+/// The NOTIFY route, to be merged into the exporter's router; `device` is
+/// the label of the subscribed gateway's series in `metrics`
+pub fn routes(state: Arc<EventState>, device: &str, metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route(EVENT_PATH, any(notify_handler))
+        .with_state((state, Arc::from(device), metrics))
+}
+
+async fn notify_handler(
+    State((state, device, metrics)): State<(Arc<EventState>, Arc<str>, Arc<Metrics>)>,
+    method: Method,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    if method.as_str() != "NOTIFY" {
+        return StatusCode::METHOD_NOT_ALLOWED;
+    }
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    if header("NT") != Some("upnp:event") || header("NTS") != Some("upnp:propchange") {
+        return StatusCode::BAD_REQUEST;
+    }
+    let Some(sid) = header("SID") else {
+        return StatusCode::PRECONDITION_FAILED;
+    };
+
+    let properties = parse_propertyset(&body);
+    debug!(
+        "GENA event {} for {}: {:?}",
+        header("SEQ").unwrap_or("?"),
+        sid,
+        properties
+    );
+    match state.apply(sid, properties) {
+        Some(values) => {
+            metrics.set_evented_connection(&device, &values);
+            StatusCode::OK
+        }
+        // A subscription we dropped, or one from before a restart
+        None => StatusCode::PRECONDITION_FAILED,
+    }
+}
+
+/// Name and text of each variable in an `<e:propertyset>` body
+fn parse_propertyset(xml: &str) -> Vec<(String, String)> {
+    let mut reader = soap::xml_reader(xml);
+    let mut properties = Vec::new();
+    let mut in_property = false;
+    let mut variable: Option<(String, String)> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                let name = soap::local_name(&element);
+                if name == "property" {
+                    in_property = true;
+                } else if in_property && variable.is_none() {
+                    variable = Some((name.to_string(), String::new()));
+                }
+            }
+            Ok(Event::Text(text)) => {
+                if let Some((_, value)) = variable.as_mut() {
+                    match text.unescape() {
+                        Ok(text) => value.push_str(&text),
+                        Err(e) => {
+                            warn!("Malformed GENA event body: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(Event::End(element)) => {
+                if element.local_name().as_ref() == b"property" {
+                    in_property = false;
+                } else if let Some((variable_name, value)) = variable.take() {
+                    properties.push((variable_name, value.trim().to_string()));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                warn!("Malformed GENA event body: {}", e);
+                break;
+            }
+            _ => {}
+        }
+    }
+    properties
+}
+
+/// Keep a subscription to the events of the primary WAN connection service,
+/// renewing it halfway through each granted period. Gateways that refuse
+/// SUBSCRIBE are polled as usual and asked again later.
+pub async fn run_event_subscriber(
+    client: Arc<RwLock<UpnpClient>>,
+    config: UpnpConfig,
+    server_port: u16,
+) {
+    let state = client.read().await.events();
+    loop {
+        let Some(event_sub_url) = client.read().await.event_sub_url() else {
+            tokio::time::sleep(DEVICE_POLL_INTERVAL).await;
+            continue;
+        };
+        let callback = match &config.gena_callback_url {
+            Some(callback) => callback.clone(),
+            None => match callback_url(&event_sub_url, server_port, config.bind_address) {
+                Ok(callback) => callback,
+                Err(e) => {
+                    warn!("Cannot subscribe to {}: {}", event_sub_url, e);
+                    tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                    continue;
+                }
+            },
+        };
+
+        let result = client
+            .read()
+            .await
+            .subscribe(&event_sub_url, &callback, config.gena_timeout)
+            .await;
+        match result {
+            Ok(subscription) => {
+                info!(
+                    "Subscribed to events of {} for {}s, delivered to {}",
+                    event_sub_url, subscription.timeout, callback
+                );
+                state.activate(subscription);
+                keep_renewed(&client, &state, config.gena_timeout).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Gateway refused SUBSCRIBE to {}, polling instead: {}",
+                    event_sub_url, e
+                );
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Renew the active subscription until renewal fails or the device moved
+async fn keep_renewed(client: &RwLock<UpnpClient>, state: &EventState, timeout: u64) {
+    while let Some(subscription) = state.subscription() {
+        tokio::time::sleep(Duration::from_secs((subscription.timeout / 2).max(30))).await;
+
+        let client = client.read().await;
+        if client.event_sub_url().as_deref() != Some(subscription.event_sub_url.as_str()) {
+            debug!("Device changed, dropping subscription {}", subscription.sid);
+            state.deactivate();
+            if let Err(e) = client.unsubscribe(&subscription).await {
+                debug!("UNSUBSCRIBE of {} failed: {}", subscription.sid, e);
+            }
+            return;
+        }
+        match client.renew_subscription(&subscription, timeout).await {
+            Ok(renewed) => {
+                debug!(
+                    "Renewed subscription {} for {}s",
+                    renewed.sid, renewed.timeout
+                );
+                state.renewed(renewed);
+            }
+            Err(e) => {
+                warn!("Renewing subscription {} failed: {}", subscription.sid, e);
+                state.deactivate();
+                return;
+            }
+        }
+    }
+}
+
+/// Cancel the active subscription, on shutdown
+pub async fn unsubscribe(client: &RwLock<UpnpClient>) {
+    let client = client.read().await;
+    let Some(subscription) = client.events().deactivate() else {
+        return;
+    };
+    match client.unsubscribe(&subscription).await {
+        Ok(()) => info!("Unsubscribed from events of {}", subscription.event_sub_url),
+        Err(e) => warn!("UNSUBSCRIBE of {} failed: {}", subscription.sid, e),
+    }
+}
+
+/// This server's event route at the local address the host uses to reach
+/// the gateway
+fn callback_url(event_sub_url: &str, server_port: u16, bind: Option<IpAddr>) -> Result<String> {
+    let url = Url::parse(&crate::description::split_zone_id(event_sub_url).0)?;
+    let gateway = url
+        .socket_addrs(|| Some(80))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{} has no address", event_sub_url))?;
+    let local = bind.unwrap_or(match gateway {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    // Connecting a UDP socket only picks the route, nothing is sent
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(gateway)?;
+    let local = socket.local_addr()?.ip();
+    Ok(format!(
+        "http://{}{}",
+        SocketAddr::new(local, server_port),
+        EVENT_PATH
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn propertyset_variables() {
+        let body = r#"<?xml version="1.0"?>
+<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0">
+  <e:property>
+    <ConnectionStatus> Connected </ConnectionStatus>
+  </e:property>
+  <e:property>
+    <ExternalIPAddress>203.0.113.7</ExternalIPAddress>
+  </e:property>
+  <e:property><PossibleConnectionTypes/></e:property>
+</e:propertyset>"#;
+        assert_eq!(
+            parse_propertyset(body),
+            [
+                ("ConnectionStatus".to_string(), "Connected".to_string()),
+                ("ExternalIPAddress".to_string(), "203.0.113.7".to_string()),
+                ("PossibleConnectionTypes".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn propertyset_unescapes_values() {
+        let body = "<e:propertyset xmlns:e=\"urn:x\"><e:property><LastChange>&lt;Event/&gt;</LastChange></e:property></e:propertyset>";
+        assert_eq!(
+            parse_propertyset(body),
+            [("LastChange".to_string(), "<Event/>".to_string())]
+        );
+    }
+
+    #[test]
+    fn propertyset_up_to_unreadable_xml() {
+        let body = "<e:propertyset xmlns:e=\"urn:x\"><e:property><A>1</A></e:property><e:property><B>2</C>";
+        assert_eq!(
+            parse_propertyset(body),
+            [("A".to_string(), "1".to_string())]
+        );
+    }
+}
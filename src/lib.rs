@@ -1,35 +1,253 @@
+pub mod auth;
+pub mod charset;
+pub mod coherence;
+pub mod compat;
 pub mod config;
+pub mod description;
+pub mod drift;
+pub mod error;
+pub mod exposition;
+pub mod gena;
 pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod natpmp;
+pub mod notify;
+pub mod polling;
+pub mod provider;
+pub mod rediscovery;
 pub mod server;
+pub mod soap;
+pub mod ssdp;
 pub mod upnp;
 
-pub use config::Config;
-pub use metrics::{MetricsCollector, init_metrics};
+pub use config::{Backend, Config, MetricsConfig, PollingConfig, UpnpConfig};
+pub use description::{DeviceInfo, UpnpService, WanConnectionKind, WanInterface};
+pub use error::{ScrapeErrorReason, UpnpError, UpnpResult};
+pub use metrics::{ExporterCollector, Metrics, MetricsCollector, Throughput};
+#[allow(deprecated)]
+pub use metrics::{init_metrics, init_metrics_with};
+pub use natpmp::NatPmpClient;
+pub use provider::WanStatsProvider;
 pub use server::create_app;
-pub use upnp::{TrafficStats, UpnpClient, UpnpDevice};
+pub use ssdp::SsdpResponse;
+pub use upnp::{
+    ActiveConnection, ConnectionStatusInfo, PortMapping, ProxyError, TrafficStats, Uplink,
+    UpnpClient, UpnpClientBuilder, UpnpDevice,
+};
 
 use anyhow::Result;
+use axum::Router;
+use drift::ConfigDrift;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Initialize and run the UPnP WAN exporter server
-pub async fn run_server(config: Config) -> Result<()> {
+/// Initialize and run the UPnP WAN exporter server. When the configuration
+/// was loaded from `config_path`, that file is watched for drift.
+pub async fn run_server(config: Config, config_path: Option<PathBuf>) -> Result<()> {
     // Initialize tracing
     tracing_subscriber::fmt::init();
 
-    // Initialize Prometheus metrics
-    init_metrics();
-
     tracing::info!("Starting UPnP WAN Exporter");
+    if config.upnp.tls_insecure_skip_verify {
+        tracing::warn!(
+            "upnp.tls_insecure_skip_verify is set: certificates of https gateway URLs are NOT verified"
+        );
+    }
+
+    log_disabled_actions("upnp", &config.upnp);
+    for (index, device) in config.devices.iter().enumerate() {
+        log_disabled_actions(&format!("devices[{}]", index), device);
+    }
+
+    // One set of metrics for all gateways, served from one registry
+    let metrics = Arc::new(Metrics::new(&config.metrics));
+
+    let watch_config = config_path.is_some();
+    let drift =
+        Arc::new(ConfigDrift::new(config.clone(), config_path).with_metrics(metrics.clone()));
+    if watch_config {
+        tokio::spawn(drift::run_drift_check(drift.clone()));
+    }
+
+    let devices = device_collectors(&config, &metrics)?;
+    for (device, device_config) in devices.iter().zip(&config.devices) {
+        spawn_rediscovery(device, device_config);
+    }
+
+    if config.upnp.backend == Backend::Natpmp {
+        let client = NatPmpClient::new(&config.upnp);
+        let collector = Arc::new(
+            MetricsCollector::with_metrics(client, &config, metrics).with_devices(devices),
+        );
+        if let Some(interval) = config.polling.interval {
+            tokio::spawn(polling::run_poller(
+                collector.clone(),
+                interval.as_duration(),
+            ));
+        }
+        serve(create_app(collector.clone(), drift), config.server.port).await?;
+        collector.save_state();
+        return Ok(());
+    }
 
     // Build the router
-    let app = create_app();
+    let client = UpnpClient::builder().config(config.upnp.clone()).build()?;
+    let collector = Arc::new(
+        MetricsCollector::with_metrics(client, &config, metrics.clone()).with_devices(devices),
+    );
+    if let Some(interval) = config.polling.interval {
+        tokio::spawn(polling::run_poller(
+            collector.clone(),
+            interval.as_duration(),
+        ));
+    }
+    if config.upnp.notify_listener {
+        tokio::spawn(notify::run_notify_listener(
+            collector.client(),
+            collector.device_name().to_string(),
+        ));
+    }
+    spawn_rediscovery(&collector, &config.upnp);
+    let mut app = create_app(collector.clone(), drift);
+    let subscriber = if config.upnp.gena_subscriptions {
+        app = app.merge(gena::routes(
+            collector.client().read().await.events(),
+            collector.device_name(),
+            metrics,
+        ));
+        Some(tokio::spawn(gena::run_event_subscriber(
+            collector.client(),
+            config.upnp.clone(),
+            config.server.port,
+        )))
+    } else {
+        None
+    };
+
+    serve(app, config.server.port).await?;
+
+    if let Some(subscriber) = subscriber {
+        subscriber.abort();
+        gena::unsubscribe(&collector.client()).await;
+    }
+    collector.save_state();
+    Ok(())
+}
+
+/// Name the actions a gateway skips, warning about those the exporter never sends
+fn log_disabled_actions(section: &str, config: &UpnpConfig) {
+    if config.disabled_actions.is_empty() {
+        return;
+    }
+    for action in &config.disabled_actions {
+        if !upnp::ACTIONS.contains(&action.as_str()) {
+            tracing::warn!(
+                "{}.disabled_actions: {} is not an action the exporter sends",
+                section,
+                action
+            );
+        }
+    }
+    tracing::info!(
+        "{}: skipping disabled actions: {}",
+        section,
+        config.disabled_actions.join(", ")
+    );
+}
+
+/// Collectors of the `[[devices]]` gateways, recording into `metrics`
+fn device_collectors(
+    config: &Config,
+    metrics: &Arc<Metrics>,
+) -> Result<Vec<Arc<MetricsCollector>>> {
+    config
+        .devices
+        .iter()
+        .map(|device| {
+            let mut device_config = config.clone();
+            device_config.upnp = device.clone();
+            device_config.devices = Vec::new();
+            // Each gateway keeps its totals in a file of its own
+            device_config.metrics.state_file = config
+                .metrics
+                .state_file
+                .as_deref()
+                .map(|path| device_state_file(path, device.device_name()));
+            let client = UpnpClient::builder().config(device.clone()).build()?;
+            Ok(Arc::new(MetricsCollector::with_metrics(
+                client,
+                &device_config,
+                metrics.clone(),
+            )))
+        })
+        .collect()
+}
 
-    // Start the server
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.server.port));
+/// `counters.json` becomes `counters.<device>.json`
+fn device_state_file(path: &Path, device: &str) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(device);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+fn spawn_rediscovery(collector: &MetricsCollector, config: &UpnpConfig) {
+    if let Some(minutes) = config.rediscovery_minutes
+        && config.wan_common_control_url.is_none()
+    {
+        tokio::spawn(rediscovery::run_rediscovery(
+            collector.client(),
+            config.clone(),
+            Duration::from_secs(minutes * 60),
+        ));
+    }
+}
+
+/// Serve `app` until Ctrl+C or SIGTERM
+async fn serve(app: Router, port: u16) -> Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("Server listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
-
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
     Ok(())
 }
+
+/// Resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Cannot listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutting down");
+}
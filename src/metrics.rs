@@ -1,85 +1,1913 @@
-use crate::upnp::{TrafficStats, UpnpClient};
-use lazy_static::lazy_static;
-use prometheus::{Gauge, Registry, TextEncoder};
+use crate::coherence::{self, CoherenceReport};
+use crate::compat;
+use crate::config::{CompatMode, Config, MetricsConfig, UpnpConfig};
+use crate::description::DeviceInfo;
+use crate::error::{ScrapeErrorReason, UpnpError, UpnpResult};
+use crate::exposition::{self, ExpositionFormat};
+use crate::gena::EventedValues;
+use crate::provider::WanStatsProvider;
+use crate::upnp::{self, DiscoveryFailure, TrafficStats, UpnpClient, usable_external_ip};
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinSet;
 use tracing::debug;
-use tracing::error;
-
-lazy_static! {
-    static ref REGISTRY: Registry = Registry::new();
-    static ref BYTES_SENT: Gauge = Gauge::new(
-        "upnp_wan_bytes_sent_total",
-        "Total bytes sent through WAN connection"
-    )
-    .expect("metric can be created");
-    static ref BYTES_RECEIVED: Gauge = Gauge::new(
-        "upnp_wan_bytes_received_total",
-        "Total bytes received through WAN connection"
-    )
-    .expect("metric can be created");
-    static ref PACKETS_SENT: Gauge = Gauge::new(
-        "upnp_wan_packets_sent_total",
-        "Total packets sent through WAN connection"
-    )
-    .expect("metric can be created");
-    static ref PACKETS_RECEIVED: Gauge = Gauge::new(
-        "upnp_wan_packets_received_total",
-        "Total packets received through WAN connection"
-    )
-    .expect("metric can be created");
-    static ref CONNECTION_STATUS: Gauge = Gauge::new(
-        "upnp_wan_connection_status",
-        "WAN connection status (1 = connected, 0 = disconnected)"
-    )
-    .expect("metric can be created");
-    static ref SCRAPE_ERROR: Gauge = Gauge::new(
-        "upnp_wan_scrape_error",
-        "Indicates if there was an error scraping UPnP metrics (1 = error, 0 = success)"
-    )
-    .expect("metric can be created");
-}
-
-pub struct MetricsCollector;
+use tracing::{error, info, warn};
+
+// From a quick LAN gateway to the longest discovery plus SOAP retries
+const SCRAPE_DURATION_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0];
+// Some gateways put whole sentences into friendlyName
+const MAX_INFO_LABEL_CHARS: usize = 64;
+const LOCK_HOLD_WARN_THRESHOLD: Duration = Duration::from_secs(5);
+
+// Average packet sizes outside this range hint at counters in other units
+const MIN_AVG_PACKET_SIZE: u64 = 64;
+const MAX_AVG_PACKET_SIZE: u64 = 20000;
+// Standard IGD counters are 32-bit; readings beyond this come from 64-bit counters
+const COUNTER_MODULUS: u64 = 1 << 32;
+// Losing a minute of totals to a crash only costs the increase of that minute
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+// Consecutive implausible polls before suggesting counter_scale
+const PACKET_SIZE_WARN_POLLS: u32 = 10;
+// NewLinkStatus values defined by WANDSLLinkConfig
+const DSL_LINK_STATES: [&str; 4] = ["Up", "Down", "Initializing", "Unavailable"];
+// NewCableLinkConfigState values defined by WANCableLinkConfig, in DOCSIS order;
+// anything else is exported as "other"
+const CABLE_LINK_STATES: [&str; 10] = [
+    "notReady",
+    "dsSyncComplete",
+    "usParamAcquired",
+    "rangingComplete",
+    "ipComplete",
+    "todEstablished",
+    "paramTransferComplete",
+    "registrationComplete",
+    "operational",
+    "accessDenied",
+];
+// NewConnectionStatus values defined by WANIPConnection and WANPPPConnection;
+// anything else is exported as "other"
+const CONNECTION_STATES: [&str; 7] = [
+    "Unconfigured",
+    "Connecting",
+    "Authenticating",
+    "Connected",
+    "PendingDisconnect",
+    "Disconnecting",
+    "Disconnected",
+];
+
+/// Labels of the exporter's own metrics, which `metrics.labels` cannot use;
+/// `le` and `quantile` are taken by histograms and summaries
+pub(crate) const RESERVED_LABEL_NAMES: &[&str] = &[
+    "action",
+    "device",
+    "firmware",
+    "friendly_name",
+    "interface",
+    "ip",
+    "kind",
+    "link",
+    "manufacturer",
+    "model",
+    "name",
+    "phase",
+    "reason",
+    "revision",
+    "rustc",
+    "state",
+    "type",
+    "version",
+    "le",
+    "quantile",
+];
+
+/// Naming and constant labels shared by every metric
+#[derive(Clone)]
+struct MetricOptions {
+    namespace: String,
+    subsystem: String,
+    const_labels: HashMap<String, String>,
+}
+
+impl MetricOptions {
+    fn from_config(config: &MetricsConfig) -> Self {
+        Self {
+            namespace: config.namespace.clone(),
+            subsystem: config.subsystem.clone(),
+            const_labels: config
+                .labels
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Opts of an exporter-wide metric, e.g. `upnp_discovery_attempts_total`
+    fn opts(&self, name: &str, help: &str) -> Opts {
+        Opts::new(name, help)
+            .namespace(self.namespace.clone())
+            .const_labels(self.const_labels.clone())
+    }
+
+    /// Opts of a metric of the WAN connection, e.g. `upnp_wan_bytes_sent_total`
+    fn wan_opts(&self, name: &str, help: &str) -> Opts {
+        self.opts(name, help).subsystem(self.subsystem.clone())
+    }
+
+    /// Full name of the WAN connection metric `name`, as built by `wan_opts`
+    fn wan_metric_name(&self, name: &str) -> String {
+        [self.namespace.as_str(), self.subsystem.as_str(), name]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .copied()
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+}
+
+/// The exported metrics and the registry they are gathered from. Instances
+/// are independent of each other, so several exporters can share a process;
+/// the gateways of one exporter share one instance.
+pub struct Metrics {
+    registry: Registry,
+    collector: ExporterCollector,
+    /// Unix time each counter, histogram and summary series was first
+    /// gathered, for the `_created` series of OpenMetrics
+    created: Mutex<HashMap<String, f64>>,
+    /// The four traffic totals count in u64, but every sample is gathered and encoded as
+    /// an f64 like Prometheus stores it: totals above 2^53 (about 9 PB) are
+    /// exported rounded to the nearest representable value
+    bytes_sent: IntCounterVec,
+    bytes_received: IntCounterVec,
+    packets_sent: IntCounterVec,
+    packets_received: IntCounterVec,
+    connection_status: GaugeVec,
+    ip_connection_status: GaugeVec,
+    connection_state: GaugeVec,
+    connection_uptime: GaugeVec,
+    layer1_upstream_max_bitrate: GaugeVec,
+    layer1_downstream_max_bitrate: GaugeVec,
+    ppp_upstream_max_bitrate: GaugeVec,
+    ppp_downstream_max_bitrate: GaugeVec,
+    byte_send_rate: GaugeVec,
+    byte_receive_rate: GaugeVec,
+    send_bytes_per_second: GaugeVec,
+    receive_bytes_per_second: GaugeVec,
+    send_packets_per_second: GaugeVec,
+    receive_packets_per_second: GaugeVec,
+    nat_enabled: GaugeVec,
+    rsip_available: GaugeVec,
+    active_connections: GaugeVec,
+    connection_type_info: GaugeVec,
+    dsl_link_status: GaugeVec,
+    dsl_link_type_info: GaugeVec,
+    cable_link_state: GaugeVec,
+    active_uplink_info: GaugeVec,
+    dsl_auto_config: GaugeVec,
+    device_info: GaugeVec,
+    external_ip_info: GaugeVec,
+    scrape_error: GaugeVec,
+    last_success_timestamp: GaugeVec,
+    last_scrape_timestamp: GaugeVec,
+    data_stale: GaugeVec,
+    data_age: GaugeVec,
+    scrape_partial_error: GaugeVec,
+    counters_stalled: GaugeVec,
+    build_info: GaugeVec,
+    config_drift: Gauge,
+    scrape_errors: IntCounterVec,
+    forced_rediscoveries: IntCounterVec,
+    discovery_attempts: IntCounterVec,
+    discovery_failures: IntCounterVec,
+    scrape_duration: HistogramVec,
+    scrape_phase_duration: HistogramVec,
+    discovery_duration: HistogramVec,
+    soap_requests: IntCounterVec,
+    soap_errors: IntCounterVec,
+    soap_requests_in_flight: GaugeVec,
+    device_lock_wait: HistogramVec,
+    device_lock_hold: HistogramVec,
+}
+
+impl Metrics {
+    /// Create the metrics, named after `metrics.namespace` and
+    /// `metrics.subsystem` and carrying the `metrics.labels`
+    pub fn new(config: &MetricsConfig) -> Self {
+        let options = MetricOptions::from_config(config);
+        let mut collectors: Vec<Box<dyn Collector>> = Vec::new();
+        let metrics = Self {
+            bytes_sent: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "bytes_sent_total",
+                        "Total bytes sent through WAN connection",
+                    ),
+                    &["device"],
+                ),
+            ),
+            bytes_received: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "bytes_received_total",
+                        "Total bytes received through WAN connection",
+                    ),
+                    &["device"],
+                ),
+            ),
+            packets_sent: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "packets_sent_total",
+                        "Total packets sent through WAN connection",
+                    ),
+                    &["device"],
+                ),
+            ),
+            packets_received: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "packets_received_total",
+                        "Total packets received through WAN connection",
+                    ),
+                    &["device"],
+                ),
+            ),
+            connection_status: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "connection_status",
+                        "WAN connection status (1 = connected, 0 = disconnected)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            ip_connection_status: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "ip_connection_status",
+                        "WAN IP/PPP connection status from GetStatusInfo (1 = Connected, 0 = otherwise)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            connection_state: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "connection_state",
+                        "WAN IP/PPP connection status from GetStatusInfo, 1 for the current state and 0 for the others",
+                    ),
+                    &["device", "state"],
+                ),
+            ),
+            connection_uptime: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "connection_uptime_seconds",
+                        "Seconds since the WAN IP/PPP connection was established",
+                    ),
+                    &["device"],
+                ),
+            ),
+            layer1_upstream_max_bitrate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "layer1_upstream_max_bitrate_bps",
+                        "Layer-1 upstream max bit rate of the WAN link, 0 if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            layer1_downstream_max_bitrate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "layer1_downstream_max_bitrate_bps",
+                        "Layer-1 downstream max bit rate of the WAN link, 0 if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            ppp_upstream_max_bitrate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "ppp_upstream_max_bitrate_bps",
+                        "Upstream max bit rate negotiated by the PPP link layer, 0 if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            ppp_downstream_max_bitrate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "ppp_downstream_max_bitrate_bps",
+                        "Downstream max bit rate negotiated by the PPP link layer, 0 if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            byte_send_rate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "byte_send_rate",
+                        "Current upstream rate in bytes per second as reported by the gateway",
+                    ),
+                    &["device"],
+                ),
+            ),
+            byte_receive_rate: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "byte_receive_rate",
+                        "Current downstream rate in bytes per second as reported by the gateway",
+                    ),
+                    &["device"],
+                ),
+            ),
+            send_bytes_per_second: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "send_bytes_per_second",
+                        "Bytes sent per second between the last two readings of the counters",
+                    ),
+                    &["device"],
+                ),
+            ),
+            receive_bytes_per_second: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "receive_bytes_per_second",
+                        "Bytes received per second between the last two readings of the counters",
+                    ),
+                    &["device"],
+                ),
+            ),
+            send_packets_per_second: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "send_packets_per_second",
+                        "Packets sent per second between the last two readings of the counters",
+                    ),
+                    &["device"],
+                ),
+            ),
+            receive_packets_per_second: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "receive_packets_per_second",
+                        "Packets received per second between the last two readings of the counters",
+                    ),
+                    &["device"],
+                ),
+            ),
+            nat_enabled: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "nat_enabled",
+                        "Whether NAT is enabled on the WAN connection (1 = enabled, 0 = disabled), absent if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            rsip_available: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "rsip_available",
+                        "Whether the WAN connection supports RSIP (1 = available, 0 = not), absent if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            active_connections: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "active_connections",
+                        "Number of active WAN connections, from NumberOfActiveConnections or else as GetActiveConnection listed them at setup, absent if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            connection_type_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "connection_type_info",
+                        "Current type of the WAN connection from GetConnectionTypeInfo, e.g. IP_Routed or IP_Bridged",
+                    ),
+                    &["device", "type"],
+                ),
+            ),
+            dsl_link_status: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "dsl_link_status",
+                        "DSL link status from GetDSLLinkInfo, 1 for the current state and 0 for the others",
+                    ),
+                    &["device", "state"],
+                ),
+            ),
+            dsl_link_type_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "dsl_link_type_info",
+                        "DSL link type from GetDSLLinkInfo, e.g. PPPoE or EoA",
+                    ),
+                    &["device", "type"],
+                ),
+            ),
+            cable_link_state: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "cable_link_state",
+                        "DOCSIS initialization stage from GetCableLinkConfigInfo, 1 for the current stage and 0 for the others",
+                    ),
+                    &["device", "state"],
+                ),
+            ),
+            active_uplink_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "active_uplink_info",
+                        "WANDevice carrying the default connection, on gateways with a WANPOTSLinkConfig backup uplink",
+                    ),
+                    &["device", "interface", "name", "link"],
+                ),
+            ),
+            dsl_auto_config: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "dsl_auto_config",
+                        "Whether the DSL link is configured automatically (1 = yes, 0 = no), absent if not reported",
+                    ),
+                    &["device"],
+                ),
+            ),
+            device_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.opts(
+                        "device_info",
+                        "Identity of the gateway from its device description",
+                    ),
+                    &["device", "friendly_name", "manufacturer", "model", "firmware"],
+                ),
+            ),
+            external_ip_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "external_ip_info",
+                        "External IP address of the WAN connection, absent while disconnected",
+                    ),
+                    &["device", "ip"],
+                ),
+            ),
+            scrape_error: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "scrape_error",
+                        "Indicates if there was an error scraping UPnP metrics (1 = error, 0 = success)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            last_success_timestamp: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "last_success_timestamp_seconds",
+                        "Unix time of the last reading of the gateway that succeeded, fully or partially",
+                    ),
+                    &["device"],
+                ),
+            ),
+            last_scrape_timestamp: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "last_scrape_timestamp_seconds",
+                        "Unix time of the last attempt to read the gateway, whatever its outcome",
+                    ),
+                    &["device"],
+                ),
+            ),
+            data_stale: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "data_stale",
+                        "Indicates if the exported readings are from an earlier scrape because the last one failed (1 = stale, 0 = fresh)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            data_age: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "data_age_seconds",
+                        "Seconds since the successful reading the exported values are from: 0 after a successful scrape, growing while readings fail; with polling.interval, measured when /metrics is served",
+                    ),
+                    &["device"],
+                ),
+            ),
+            scrape_partial_error: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "scrape_partial_error",
+                        "Indicates if some but not all UPnP values could be read in the last scrape (1 = partial, 0 = complete)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            counters_stalled: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "counters_stalled",
+                        "Indicates if the WAN byte counters stopped changing while the link is up (1 = stalled, 0 = ok)",
+                    ),
+                    &["device"],
+                ),
+            ),
+            build_info: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "exporter_build_info",
+                        "Version, git revision and rustc version the exporter was built from, always 1",
+                    ),
+                    &["version", "revision", "rustc"],
+                ),
+            ),
+            config_drift: registered(
+                &mut collectors,
+                Gauge::with_opts(options.wan_opts(
+                    "config_drift",
+                    "Indicates if the config file differs from the running configuration (1 = drift, 0 = in sync)",
+                )),
+            ),
+            scrape_errors: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "scrape_errors_total",
+                        "Number of failed scrapes, by reason (discovery_timeout, no_igd, http, timeout, soap_fault, parse, auth or other)",
+                    ),
+                    &["device", "reason"],
+                ),
+            ),
+            forced_rediscoveries: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.wan_opts(
+                        "forced_rediscoveries_total",
+                        "Number of times the device was re-discovered after consecutive scrape failures",
+                    ),
+                    &["device"],
+                ),
+            ),
+            discovery_attempts: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.opts(
+                        "discovery_attempts_total",
+                        "Number of device discoveries started",
+                    ),
+                    &["device"],
+                ),
+            ),
+            discovery_failures: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.opts(
+                        "discovery_failures_total",
+                        "Number of failed device discoveries, by reason",
+                    ),
+                    &["device", "reason"],
+                ),
+            ),
+            scrape_duration: registered(
+                &mut collectors,
+                HistogramVec::new(
+                    HistogramOpts::from(options.opts(
+                        "scrape_duration_seconds",
+                        "Time taken to read a gateway, failed readings included",
+                    ))
+                    .buckets(SCRAPE_DURATION_BUCKETS.to_vec()),
+                    &["device"],
+                ),
+            ),
+            scrape_phase_duration: registered(
+                &mut collectors,
+                HistogramVec::new(
+                    HistogramOpts::from(options.opts(
+                        "scrape_phase_duration_seconds",
+                        "Time taken by each phase of reading a gateway: discovery (only when it ran) or soap",
+                    ))
+                    .buckets(SCRAPE_DURATION_BUCKETS.to_vec()),
+                    &["device", "phase"],
+                ),
+            ),
+            discovery_duration: registered(
+                &mut collectors,
+                HistogramVec::new(
+                    HistogramOpts::from(options.opts(
+                        "discovery_duration_seconds",
+                        "Time spent discovering the device and reading its description",
+                    )),
+                    &["device"],
+                ),
+            ),
+            soap_requests: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.opts(
+                        "soap_requests_total",
+                        "Number of SOAP actions invoked, by action, retries not counted separately",
+                    ),
+                    &["device", "action"],
+                ),
+            ),
+            soap_errors: registered(
+                &mut collectors,
+                IntCounterVec::new(
+                    options.opts(
+                        "soap_errors_total",
+                        "Number of failed SOAP actions, by action and kind (timeout, fault, parse, http or other)",
+                    ),
+                    &["device", "action", "kind"],
+                ),
+            ),
+            soap_requests_in_flight: registered(
+                &mut collectors,
+                GaugeVec::new(
+                    options.wan_opts(
+                        "soap_requests_in_flight",
+                        "SOAP requests currently sent to the gateway, capped by upnp.max_concurrent_soap_requests",
+                    ),
+                    &["device"],
+                ),
+            ),
+            device_lock_wait: registered(
+                &mut collectors,
+                HistogramVec::new(
+                    HistogramOpts::from(options.wan_opts(
+                        "device_lock_wait_seconds",
+                        "Time spent waiting to acquire the shared device lock",
+                    )),
+                    &["device"],
+                ),
+            ),
+            device_lock_hold: registered(
+                &mut collectors,
+                HistogramVec::new(
+                    HistogramOpts::from(options.wan_opts(
+                        "device_lock_hold_seconds",
+                        "Time the shared device lock was held, by lock kind (read or write)",
+                    )),
+                    &["device", "kind"],
+                ),
+            ),
+            registry: Registry::new(),
+            collector: ExporterCollector {
+                metrics: Arc::new(collectors),
+                options,
+                compat: config.compat,
+            },
+            created: Mutex::new(HashMap::new()),
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.collector()))
+            .expect("collector can be registered");
+        if config.process_metrics {
+            metrics.register_process_collector();
+        }
+        metrics
+            .build_info
+            .with_label_values(&[
+                env!("CARGO_PKG_VERSION"),
+                env!("UPNP_WAN_EXPORTER_REVISION"),
+                env!("UPNP_WAN_EXPORTER_RUSTC"),
+            ])
+            .set(1.0);
+        // The series without labels exist from now on
+        metrics.gather();
+        metrics
+    }
+
+    /// The registry the metrics are gathered from, for adding collectors of
+    /// an embedding application
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// The exporter's metrics as one collector, for registering them with
+    /// the registry of an embedding application instead
+    pub fn collector(&self) -> ExporterCollector {
+        self.collector.clone()
+    }
+
+    /// Encode the metrics in the Prometheus text format, compat aliases included
+    pub fn encode(&self) -> prometheus::Result<String> {
+        self.encode_as(ExpositionFormat::Text)
+    }
+
+    /// Encode the metrics in `format`, compat aliases included
+    pub fn encode_as(&self, format: ExpositionFormat) -> prometheus::Result<String> {
+        let metric_families = self.gather();
+        match format {
+            ExpositionFormat::Text => TextEncoder::new().encode_to_string(&metric_families),
+            ExpositionFormat::OpenMetrics => {
+                let created = self.created.lock().unwrap();
+                Ok(exposition::encode_openmetrics(
+                    &metric_families,
+                    |family, metric| {
+                        created
+                            .get(&exposition::series_key(family, metric))
+                            .copied()
+                    },
+                ))
+            }
+        }
+    }
+
+    fn gather(&self) -> Vec<MetricFamily> {
+        let mut metric_families = self.registry.gather();
+        sort_metric_families(&mut metric_families);
+        self.update_created(&metric_families);
+        metric_families
+    }
+
+    /// Remember when each counter, histogram and summary series first
+    /// showed up; a series that disappears starts over when it returns
+    fn update_created(&self, metric_families: &[MetricFamily]) {
+        let now = unix_time();
+        let mut created = self.created.lock().unwrap();
+        let previous = std::mem::take(&mut *created);
+        for family in metric_families
+            .iter()
+            .filter(|f| exposition::has_created(f))
+        {
+            for metric in family.get_metric() {
+                let key = exposition::series_key(family.get_name(), metric);
+                let time = previous.get(&key).copied().unwrap_or(now);
+                created.insert(key, time);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn register_process_collector(&self) {
+        self.registry
+            .register(Box::new(
+                prometheus::process_collector::ProcessCollector::for_self(),
+            ))
+            .expect("collector can be registered");
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn register_process_collector(&self) {
+        warn!("metrics.process_metrics is only supported on Linux");
+    }
+
+    fn update_metrics(&self, device: &str, stats: &TrafficStats) {
+        // The totals only grow, so each counter catches up with its total;
+        // a value the gateway did not answer leaves the counter as it is
+        for (counter, total) in [
+            (&self.bytes_sent, stats.bytes_sent),
+            (&self.bytes_received, stats.bytes_received),
+            (&self.packets_sent, stats.packets_sent),
+            (&self.packets_received, stats.packets_received),
+        ] {
+            if let Some(total) = total {
+                let counter = counter.with_label_values(&[device]);
+                counter.inc_by(total.saturating_sub(counter.get()));
+            }
+        }
+        if stats.connection_status.is_some() {
+            self.connection_status
+                .with_label_values(&[device])
+                .set(if stats.is_link_up() { 1.0 } else { 0.0 });
+        } else {
+            remove_device_series(&self.connection_status, device);
+        }
+        let unavailable = stats.unavailable_fields();
+        if !unavailable.is_empty() {
+            debug!(
+                "Gateway {} did not answer {}",
+                device,
+                unavailable.join(", ")
+            );
+        }
+        self.scrape_partial_error
+            .with_label_values(&[device])
+            .set(if unavailable.is_empty() { 0.0 } else { 1.0 });
+        // An unanswered value is unknown, not 0: its series goes away
+        remove_device_series(&self.ip_connection_status, device);
+        if let Some(status) = stats.ip_connection_status.as_deref() {
+            self.ip_connection_status
+                .with_label_values(&[device])
+                .set(if status == "Connected" { 1.0 } else { 0.0 });
+        }
+        self.set_connection_state(device, stats.ip_connection_status.as_deref());
+        for (gauge, value) in [
+            (&self.connection_uptime, stats.uptime_seconds),
+            (
+                &self.layer1_upstream_max_bitrate,
+                stats.link_up_max_bitrate_bps,
+            ),
+            (
+                &self.layer1_downstream_max_bitrate,
+                stats.link_down_max_bitrate_bps,
+            ),
+            (&self.ppp_upstream_max_bitrate, stats.ppp_up_max_bitrate_bps),
+            (
+                &self.ppp_downstream_max_bitrate,
+                stats.ppp_down_max_bitrate_bps,
+            ),
+            (&self.byte_send_rate, stats.byte_send_rate),
+            (&self.byte_receive_rate, stats.byte_receive_rate),
+        ] {
+            match value {
+                Some(value) => gauge.with_label_values(&[device]).set(value as f64),
+                None => remove_device_series(gauge, device),
+            }
+        }
+        remove_device_series(&self.active_connections, device);
+        if let Some(count) = stats.active_connections {
+            self.active_connections
+                .with_label_values(&[device])
+                .set(count as f64);
+        }
+        for (gauge, flag) in [
+            (&self.nat_enabled, stats.nat_enabled),
+            (&self.rsip_available, stats.rsip_available),
+        ] {
+            remove_device_series(gauge, device);
+            if let Some(flag) = flag {
+                gauge
+                    .with_label_values(&[device])
+                    .set(if flag { 1.0 } else { 0.0 });
+            }
+        }
+        // Only the current type is exported, so a flip to bridged mode
+        // replaces the old series instead of adding one
+        remove_device_series(&self.connection_type_info, device);
+        if let Some(connection_type) = stats.connection_type.as_deref().map(sanitize_label_value)
+            && !connection_type.is_empty()
+        {
+            self.connection_type_info
+                .with_label_values(&[device, &connection_type])
+                .set(1.0);
+        }
+        self.update_dsl_metrics(device, stats);
+        self.update_cable_metrics(device, stats);
+        remove_device_series(&self.active_uplink_info, device);
+        if let Some(uplink) = &stats.active_uplink {
+            let name = uplink
+                .name
+                .as_deref()
+                .map(sanitize_label_value)
+                .unwrap_or_default();
+            self.active_uplink_info
+                .with_label_values(&[device, &uplink.index.to_string(), &name, &uplink.link])
+                .set(1.0);
+        }
+        // At most one series per device: the old address goes when it changes,
+        // and an unset or 0.0.0.0 address from any provider leaves none
+        remove_device_series(&self.external_ip_info, device);
+        if let Some(ip) = stats.external_ip.as_deref().and_then(usable_external_ip) {
+            self.external_ip_info
+                .with_label_values(&[device, &ip])
+                .set(1.0);
+        }
+    }
+
+    /// Replace the info series of `device`, so that a firmware update or a
+    /// different gateway never leaves the old identity behind
+    fn update_device_info(&self, device: &str, info: Option<&DeviceInfo>) {
+        remove_device_series(&self.device_info, device);
+        let Some(info) = info else {
+            return;
+        };
+        let label =
+            |value: &Option<String>| value.as_deref().map(info_label_value).unwrap_or_default();
+        self.device_info
+            .with_label_values(&[
+                device,
+                &label(&info.friendly_name),
+                &label(&info.manufacturer),
+                &label(&info.model_name),
+                &label(&info.firmware_version),
+            ])
+            .set(1.0);
+    }
+
+    /// Gateways without WANDSLLinkConfig export none of the DSL series
+    fn update_dsl_metrics(&self, device: &str, stats: &TrafficStats) {
+        remove_device_series(&self.dsl_link_status, device);
+        if let Some(status) = stats.dsl_link_status.as_deref() {
+            for state in DSL_LINK_STATES {
+                self.dsl_link_status
+                    .with_label_values(&[device, state])
+                    .set(if state == status { 1.0 } else { 0.0 });
+            }
+            let status = sanitize_label_value(status);
+            if !DSL_LINK_STATES.contains(&status.as_str()) && !status.is_empty() {
+                self.dsl_link_status
+                    .with_label_values(&[device, &status])
+                    .set(1.0);
+            }
+        }
+        remove_device_series(&self.dsl_link_type_info, device);
+        if let Some(link_type) = stats.dsl_link_type.as_deref().map(sanitize_label_value)
+            && !link_type.is_empty()
+        {
+            self.dsl_link_type_info
+                .with_label_values(&[device, &link_type])
+                .set(1.0);
+        }
+        remove_device_series(&self.dsl_auto_config, device);
+        if let Some(auto_config) = stats.dsl_auto_config {
+            self.dsl_auto_config
+                .with_label_values(&[device])
+                .set(if auto_config { 1.0 } else { 0.0 });
+        }
+    }
+
+    /// Gateways without WANCableLinkConfig export no cable series
+    fn update_cable_metrics(&self, device: &str, stats: &TrafficStats) {
+        remove_device_series(&self.cable_link_state, device);
+        if let Some(state) = stats.cable_link_state.as_deref() {
+            let known = CABLE_LINK_STATES
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(state));
+            for label in CABLE_LINK_STATES {
+                self.cable_link_state
+                    .with_label_values(&[device, label])
+                    .set(if label.eq_ignore_ascii_case(state) {
+                        1.0
+                    } else {
+                        0.0
+                    });
+            }
+            self.cable_link_state
+                .with_label_values(&[device, "other"])
+                .set(if known { 0.0 } else { 1.0 });
+        }
+    }
+
+    /// A rate is only exported once its counter has been read twice
+    fn update_throughput(&self, device: &str, throughput: &Throughput) {
+        for (gauge, rate) in [
+            (
+                &self.send_bytes_per_second,
+                throughput.send_bytes_per_second,
+            ),
+            (
+                &self.receive_bytes_per_second,
+                throughput.receive_bytes_per_second,
+            ),
+            (
+                &self.send_packets_per_second,
+                throughput.send_packets_per_second,
+            ),
+            (
+                &self.receive_packets_per_second,
+                throughput.receive_packets_per_second,
+            ),
+        ] {
+            match rate {
+                Some(rate) => gauge.with_label_values(&[device]).set(rate),
+                None => remove_device_series(gauge, device),
+            }
+        }
+    }
+
+    /// One series per known state with only the current one set, so that
+    /// transitional states like "Connecting" can be told apart from a loss
+    fn set_connection_state(&self, device: &str, status: Option<&str>) {
+        remove_device_series(&self.connection_state, device);
+        let Some(status) = status else {
+            return;
+        };
+        let known = CONNECTION_STATES
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(status));
+        for state in CONNECTION_STATES {
+            self.connection_state
+                .with_label_values(&[device, state])
+                .set(if state.eq_ignore_ascii_case(status) {
+                    1.0
+                } else {
+                    0.0
+                });
+        }
+        self.connection_state
+            .with_label_values(&[device, "other"])
+            .set(if known { 0.0 } else { 1.0 });
+    }
+
+    /// Drop every series read from `device`, so that a reading too old to
+    /// export shows up as absent rather than as frozen values
+    fn remove_readings(&self, device: &str) {
+        for counter in [
+            &self.bytes_sent,
+            &self.bytes_received,
+            &self.packets_sent,
+            &self.packets_received,
+        ] {
+            remove_device_series(counter, device);
+        }
+        for gauge in [
+            &self.connection_status,
+            &self.ip_connection_status,
+            &self.connection_state,
+            &self.connection_uptime,
+            &self.layer1_upstream_max_bitrate,
+            &self.layer1_downstream_max_bitrate,
+            &self.ppp_upstream_max_bitrate,
+            &self.ppp_downstream_max_bitrate,
+            &self.byte_send_rate,
+            &self.byte_receive_rate,
+            &self.send_bytes_per_second,
+            &self.receive_bytes_per_second,
+            &self.send_packets_per_second,
+            &self.receive_packets_per_second,
+            &self.nat_enabled,
+            &self.rsip_available,
+            &self.active_connections,
+            &self.connection_type_info,
+            &self.dsl_link_status,
+            &self.dsl_link_type_info,
+            &self.dsl_auto_config,
+            &self.cable_link_state,
+            &self.active_uplink_info,
+            &self.external_ip_info,
+            &self.counters_stalled,
+        ] {
+            remove_device_series(gauge, device);
+        }
+    }
+
+    /// Report the WAN connection of `device` as down after the gateway
+    /// announced its departure
+    pub(crate) fn set_device_gone(&self, device: &str) {
+        self.connection_status.with_label_values(&[device]).set(0.0);
+        self.ip_connection_status
+            .with_label_values(&[device])
+            .set(0.0);
+        // The state it left in is unknown
+        remove_device_series(&self.connection_state, device);
+        remove_device_series(&self.external_ip_info, device);
+    }
+
+    /// Apply a GENA event of the WAN connection service of `device` without
+    /// waiting for the next scrape
+    pub(crate) fn set_evented_connection(&self, device: &str, values: &EventedValues) {
+        if let Some(status) = &values.connection_status {
+            self.ip_connection_status
+                .with_label_values(&[device])
+                .set(if status == "Connected" { 1.0 } else { 0.0 });
+            self.set_connection_state(device, Some(status));
+        }
+        if let Some(address) = &values.external_ip {
+            remove_device_series(&self.external_ip_info, device);
+            if let Some(ip) = usable_external_ip(address) {
+                self.external_ip_info
+                    .with_label_values(&[device, &ip])
+                    .set(1.0);
+            }
+        }
+    }
+
+    fn observe_phase(&self, device: &str, phase: &str, started: Instant) {
+        self.scrape_phase_duration
+            .with_label_values(&[device, phase])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Record one discovery run of `device` and, if it failed, why
+    pub(crate) fn observe_discovery(
+        &self,
+        device: &str,
+        duration: Duration,
+        failure: Option<DiscoveryFailure>,
+    ) {
+        self.discovery_attempts.with_label_values(&[device]).inc();
+        self.discovery_duration
+            .with_label_values(&[device])
+            .observe(duration.as_secs_f64());
+        if let Some(reason) = failure {
+            self.discovery_failures
+                .with_label_values(&[device, reason.as_str()])
+                .inc();
+        }
+    }
+
+    /// Every reason of `device` starts at 0, so increase() sees the first
+    /// failure of each, and its in-flight gauge is there before any request
+    fn init_device_series(&self, device: &str) {
+        for reason in ScrapeErrorReason::ALL {
+            self.scrape_errors
+                .with_label_values(&[device, reason.as_str()]);
+        }
+        self.soap_requests_in_flight.with_label_values(&[device]);
+    }
+
+    fn count_scrape_error(&self, device: &str, reason: ScrapeErrorReason) {
+        self.scrape_errors
+            .with_label_values(&[device, reason.as_str()])
+            .inc();
+    }
+
+    pub(crate) fn count_soap_request(&self, device: &str, action: &str) {
+        self.soap_requests
+            .with_label_values(&[device, action])
+            .inc();
+    }
+
+    pub(crate) fn count_soap_error(&self, device: &str, action: &str, kind: &str) {
+        self.soap_errors
+            .with_label_values(&[device, action, kind])
+            .inc();
+    }
+
+    pub(crate) fn add_soap_requests_in_flight(&self, device: &str, delta: f64) {
+        self.soap_requests_in_flight
+            .with_label_values(&[device])
+            .add(delta);
+    }
+
+    pub(crate) fn set_config_drift(&self, drifted: bool) {
+        self.config_drift.set(if drifted { 1.0 } else { 0.0 });
+    }
+}
+
+/// A newly created metric, once registered with `registry`
+fn registered<C: Collector + Clone + 'static>(
+    collectors: &mut Vec<Box<dyn Collector>>,
+    metric: prometheus::Result<C>,
+) -> C {
+    let metric = metric.expect("metric can be created");
+    collectors.push(Box::new(metric.clone()));
+    metric
+}
+
+/// The metrics of a `Metrics` as a `prometheus` collector, compat aliases
+/// included. Collecting never reads the gateway: it returns the values left
+/// by the last reading, like any other collector of a registry.
+#[derive(Clone)]
+pub struct ExporterCollector {
+    metrics: Arc<Vec<Box<dyn Collector>>>,
+    options: MetricOptions,
+    compat: Option<CompatMode>,
+}
+
+impl Collector for ExporterCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metrics
+            .iter()
+            .flat_map(|metric| metric.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut metric_families: Vec<_> = self
+            .metrics
+            .iter()
+            .flat_map(|metric| metric.collect())
+            .collect();
+        if let Some(mode) = self.compat {
+            let aliases = compat::alias_families(&metric_families, mode, |name| {
+                self.options.wan_metric_name(name)
+            });
+            metric_families.extend(aliases);
+        }
+        metric_families
+    }
+}
+
+/// Lock guard that records how long the device lock was held once dropped
+struct TimedGuard<G> {
+    guard: G,
+    kind: &'static str,
+    acquired_at: Instant,
+    hold: Histogram,
+}
+
+impl<G> TimedGuard<G> {
+    fn new(
+        guard: G,
+        kind: &'static str,
+        wait_started: Instant,
+        metrics: &Metrics,
+        device: &str,
+    ) -> Self {
+        let acquired_at = Instant::now();
+        metrics
+            .device_lock_wait
+            .with_label_values(&[device])
+            .observe((acquired_at - wait_started).as_secs_f64());
+        Self {
+            guard,
+            kind,
+            acquired_at,
+            hold: metrics.device_lock_hold.with_label_values(&[device, kind]),
+        }
+    }
+}
+
+impl<G: Deref> Deref for TimedGuard<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for TimedGuard<G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for TimedGuard<G> {
+    fn drop(&mut self) {
+        let held = self.acquired_at.elapsed();
+        self.hold.observe(held.as_secs_f64());
+        if self.kind == "write" && held > LOCK_HOLD_WARN_THRESHOLD {
+            warn!("Device write lock held for {:.2}s", held.as_secs_f64());
+        }
+    }
+}
+
+/// Coalesces concurrent runs of an operation: callers arriving while it runs
+/// wait for it and share its outcome instead of starting another run
+struct SingleFlight<T> {
+    last: tokio::sync::Mutex<Option<T>>,
+    completed: AtomicU64,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            last: tokio::sync::Mutex::new(None),
+            completed: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    async fn run<F: Future<Output = T>>(&self, operation: impl FnOnce() -> F) -> T {
+        let seen = self.completed.load(Ordering::Acquire);
+        let mut last = self.last.lock().await;
+        // A run completed while we waited for the lock, so it was in flight
+        // when we arrived
+        if self.completed.load(Ordering::Acquire) != seen
+            && let Some(outcome) = &*last
+        {
+            return outcome.clone();
+        }
+        let outcome = operation().await;
+        *last = Some(outcome.clone());
+        self.completed.fetch_add(1, Ordering::Release);
+        outcome
+    }
+}
+
+/// Detects byte counters that stop moving while the router claims the link is up
+#[derive(Default)]
+struct StallDetector {
+    last_bytes: Option<(u64, u64)>,
+    unchanged_since: Option<Instant>,
+    unchanged_polls: u32,
+    stalled: bool,
+}
+
+impl StallDetector {
+    fn observe(&mut self, stats: &TrafficStats, config: &MetricsConfig) -> bool {
+        let bytes = stats.bytes_sent.zip(stats.bytes_received);
+        // Idle links and routers that never counted any packets are not stalls
+        let active = stats.is_link_up()
+            && (stats.packets_sent.unwrap_or(0) > 0 || stats.packets_received.unwrap_or(0) > 0);
+
+        if !active || bytes.is_none() || self.last_bytes != bytes {
+            if self.stalled {
+                info!("WAN byte counters are moving again");
+            }
+            self.last_bytes = bytes;
+            self.unchanged_since = Some(Instant::now());
+            self.unchanged_polls = 0;
+            self.stalled = false;
+            return false;
+        }
+
+        self.unchanged_polls += 1;
+        let unchanged_for = self.unchanged_since.map_or(Duration::ZERO, |t| t.elapsed());
+        if !self.stalled
+            && self.unchanged_polls >= config.stall_polls
+            && unchanged_for >= Duration::from_secs(config.stall_seconds)
+        {
+            warn!(
+                "WAN byte counters unchanged for {}s over {} polls while the link is up",
+                unchanged_for.as_secs(),
+                self.unchanged_polls
+            );
+            self.stalled = true;
+        }
+        self.stalled
+    }
+}
+
+/// Flags byte counters whose implied average packet size is implausible
+/// for many polls in a row, which points at counters reported in
+/// kilobytes or blocks rather than bytes
+#[derive(Default)]
+struct PacketSizeCheck {
+    last: Option<TrafficStats>,
+    implausible_polls: u32,
+    warned: bool,
+}
+
+impl PacketSizeCheck {
+    fn observe(&mut self, stats: &TrafficStats) {
+        let Some(last) = self.last.replace(stats.clone()) else {
+            return;
+        };
+        let total = |sent: Option<u64>, received: Option<u64>| {
+            sent.zip(received)
+                .map(|(sent, received)| sent.saturating_add(received))
+        };
+        let delta = |now: Option<u64>, before: Option<u64>| now?.checked_sub(before?);
+        let bytes = delta(
+            total(stats.bytes_sent, stats.bytes_received),
+            total(last.bytes_sent, last.bytes_received),
+        );
+        let packets = delta(
+            total(stats.packets_sent, stats.packets_received),
+            total(last.packets_sent, last.packets_received),
+        );
+        // Counter resets, unanswered counters and idle polls say nothing about the unit
+        let (Some(bytes), Some(packets)) = (bytes, packets) else {
+            return;
+        };
+        if packets == 0 {
+            return;
+        }
+
+        let average = bytes / packets;
+        if (MIN_AVG_PACKET_SIZE..=MAX_AVG_PACKET_SIZE).contains(&average) {
+            self.implausible_polls = 0;
+            return;
+        }
+
+        self.implausible_polls += 1;
+        if !self.warned && self.implausible_polls >= PACKET_SIZE_WARN_POLLS {
+            warn!(
+                "Average packet size of {} bytes over {} polls: counters may be scaled; consider counter_scale",
+                average, self.implausible_polls
+            );
+            self.warned = true;
+        }
+    }
+}
+
+/// Turns a raw gateway counter into a total that only ever increases
+#[derive(Default, Deserialize, Serialize)]
+struct CounterTotal {
+    last_raw: Option<u64>,
+    total: u64,
+}
+
+impl CounterTotal {
+    /// Account for a new raw reading of a counter that wraps at `modulus`;
+    /// `reset` says the gateway restarted and the counter began again at 0
+    fn observe(&mut self, raw: u64, modulus: u64, reset: bool) -> u64 {
+        let Some(last) = self.last_raw.replace(raw) else {
+            self.total = raw;
+            return self.total;
+        };
+
+        let delta = if raw >= last {
+            raw - last
+        } else if !reset && last < modulus && modulus - last + raw <= modulus / 2 {
+            // A wrap moves the counter a little, a reset a lot
+            debug!("Counter wrapped from {} to {}", last, raw);
+            modulus - last + raw
+        } else {
+            debug!("Counter reset from {} to {}", last, raw);
+            raw
+        };
+        self.total = self.total.saturating_add(delta);
+        self.total
+    }
+
+    /// Totals start at the first reading and only grow from there
+    fn is_consistent(&self) -> bool {
+        self.last_raw.is_none_or(|raw| raw <= self.total)
+    }
+}
+
+/// Accumulates the 32-bit counters most gateways report into 64-bit totals
+#[derive(Default, Deserialize, Serialize)]
+struct CounterWraps {
+    /// UDN of the device the totals were read from
+    device: Option<String>,
+    bytes_sent: CounterTotal,
+    bytes_received: CounterTotal,
+    packets_sent: CounterTotal,
+    packets_received: CounterTotal,
+    last_uptime: Option<u64>,
+}
+
+impl CounterWraps {
+    fn accumulate(&mut self, mut stats: TrafficStats, counter_scale: u64) -> TrafficStats {
+        // The connection uptime going backwards means the gateway rebooted or redialled
+        let reset = match (self.last_uptime, stats.uptime_seconds) {
+            (Some(last), Some(uptime)) => uptime < last,
+            _ => false,
+        };
+        if stats.uptime_seconds.is_some() {
+            self.last_uptime = stats.uptime_seconds;
+        }
+
+        let byte_modulus = COUNTER_MODULUS.saturating_mul(counter_scale.max(1));
+        // Unanswered counters keep their last total until the next reading
+        stats.bytes_sent = stats
+            .bytes_sent
+            .map(|raw| self.bytes_sent.observe(raw, byte_modulus, reset));
+        stats.bytes_received = stats
+            .bytes_received
+            .map(|raw| self.bytes_received.observe(raw, byte_modulus, reset));
+        stats.packets_sent = stats
+            .packets_sent
+            .map(|raw| self.packets_sent.observe(raw, COUNTER_MODULUS, reset));
+        stats.packets_received = stats
+            .packets_received
+            .map(|raw| self.packets_received.observe(raw, COUNTER_MODULUS, reset));
+        stats
+    }
+
+    /// Totals saved by a previous run, `None` without a usable state file
+    fn load(path: &Path) -> Option<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Cannot read counter state {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<Self>(&data) {
+            Ok(wraps) if wraps.is_consistent() => Some(wraps),
+            Ok(_) => {
+                warn!(
+                    "Discarding counter state {}: totals are below their raw readings",
+                    path.display()
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Discarding corrupt counter state {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Write the totals through a temporary file, so a crash mid-write
+    /// leaves the previous state intact
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.device.is_some()
+            && [
+                &self.bytes_sent,
+                &self.bytes_received,
+                &self.packets_sent,
+                &self.packets_received,
+            ]
+            .iter()
+            .all(|counter| counter.is_consistent())
+    }
+}
+
+/// Traffic per second between the last two readings of each counter,
+/// `None` until a counter has been read twice
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Throughput {
+    pub send_bytes_per_second: Option<f64>,
+    pub receive_bytes_per_second: Option<f64>,
+    pub send_packets_per_second: Option<f64>,
+    pub receive_packets_per_second: Option<f64>,
+}
+
+/// Readings closer together than this are not divided by their interval;
+/// the next reading spans both
+const THROUGHPUT_MIN_WINDOW: Duration = Duration::from_secs(5);
+/// Time constant of the moving average of the rates
+const THROUGHPUT_SMOOTHING: Duration = Duration::from_secs(60);
+
+/// Derives `Throughput` from consecutive readings, with totals of its own
+/// so that wraps and resets of the raw counters give no bogus spikes
+#[derive(Default)]
+struct ThroughputTracker {
+    totals: CounterWraps,
+    /// Total and time of the last reading of each counter that a rate was
+    /// taken from
+    last: [Option<(u64, Instant)>; 4],
+    throughput: Throughput,
+}
+
+impl ThroughputTracker {
+    fn observe(&mut self, stats: TrafficStats, counter_scale: u64, read_at: Instant) {
+        let totals = self.totals.accumulate(stats, counter_scale);
+        let Throughput {
+            send_bytes_per_second,
+            receive_bytes_per_second,
+            send_packets_per_second,
+            receive_packets_per_second,
+        } = self.throughput;
+        let mut rates = [
+            send_bytes_per_second,
+            receive_bytes_per_second,
+            send_packets_per_second,
+            receive_packets_per_second,
+        ];
+        for ((total, last), rate) in [
+            totals.bytes_sent,
+            totals.bytes_received,
+            totals.packets_sent,
+            totals.packets_received,
+        ]
+        .into_iter()
+        .zip(&mut self.last)
+        .zip(&mut rates)
+        {
+            // An unanswered counter has no rate; the next reading spans both
+            // intervals and starts the average afresh
+            let Some(total) = total else {
+                *rate = None;
+                continue;
+            };
+            let Some((last_total, last_read_at)) = *last else {
+                *last = Some((total, read_at));
+                continue;
+            };
+            let elapsed = read_at.saturating_duration_since(last_read_at);
+            if elapsed < THROUGHPUT_MIN_WINDOW {
+                continue;
+            }
+            *last = Some((total, read_at));
+            let current = total.saturating_sub(last_total) as f64 / elapsed.as_secs_f64();
+            // Weighted by the interval, so the average does not depend on how often it is read
+            let weight = 1.0 - (-elapsed.as_secs_f64() / THROUGHPUT_SMOOTHING.as_secs_f64()).exp();
+            *rate = Some(match *rate {
+                Some(average) => average + weight * (current - average),
+                None => current,
+            });
+        }
+        let [send_bytes, receive_bytes, send_packets, receive_packets] = rates;
+        self.throughput = Throughput {
+            send_bytes_per_second: send_bytes,
+            receive_bytes_per_second: receive_bytes,
+            send_packets_per_second: send_packets,
+            receive_packets_per_second: receive_packets,
+        };
+    }
+}
+
+/// Turns readings of a `WanStatsProvider` (the gateway's `UpnpClient` unless
+/// built with `with_provider`) into the exported metrics
+pub struct MetricsCollector<P = UpnpClient> {
+    client: Arc<RwLock<P>>,
+    config: UpnpConfig,
+    metrics_config: MetricsConfig,
+    stall_detector: Mutex<StallDetector>,
+    packet_size_check: Mutex<PacketSizeCheck>,
+    counter_wraps: Mutex<CounterWraps>,
+    /// Totals loaded from `metrics.state_file`, adopted at the first reading
+    /// of the same device
+    restored_wraps: Mutex<Option<CounterWraps>>,
+    last_state_save: Mutex<Option<Instant>>,
+    consecutive_failures: AtomicU32,
+    /// Set with `polling.interval`: `/metrics` and `/stats` are served from
+    /// the reading of the background poller instead of reading the gateway
+    polling: bool,
+    last_poll: Mutex<Option<(TrafficStats, Instant)>>,
+    /// When the readings currently exported were taken; they are served
+    /// as stale after failed readings until `metrics.max_data_age`
+    last_success: Mutex<Option<Instant>>,
+    /// Fed the readings of polls only, so that `/stats` requests in
+    /// between do not shorten its window
+    throughput: Mutex<ThroughputTracker>,
+    /// Value of the `device` label of this gateway's series
+    device: String,
+    /// Gateways of `[[devices]]`, read along with this one
+    devices: Vec<Arc<MetricsCollector>>,
+    metrics: Arc<Metrics>,
+    /// Concurrent scrapes share one poll, and `/stats` one reading, of the gateway
+    poll_flight: SingleFlight<()>,
+    fetch_flight: SingleFlight<Result<TrafficStats, String>>,
+    /// Requests arriving during a coherence measurement get its report
+    /// instead of reading the gateway back to back once more
+    coherence_flight: SingleFlight<Result<CoherenceReport, String>>,
+}
 
 impl MetricsCollector {
-    pub async fn collect_metrics() -> (String, bool) {
-        // Try to get fresh metrics
-        let mut client = UpnpClient::new();
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let client = UpnpClient::builder().config(config.upnp.clone()).build()?;
+        Ok(Self::with_provider(client, config))
+    }
+}
+
+impl<P: WanStatsProvider> MetricsCollector<P> {
+    /// Collector with metrics of its own
+    pub fn with_provider(provider: P, config: &Config) -> Self {
+        let metrics = Arc::new(Metrics::new(&config.metrics));
+        Self::with_metrics(provider, config, metrics)
+    }
+
+    /// Collector recording into `metrics`, which other collectors may share
+    pub fn with_metrics(mut provider: P, config: &Config, metrics: Arc<Metrics>) -> Self {
+        provider.attach_metrics(metrics.clone(), config.upnp.device_name());
+        metrics.init_device_series(config.upnp.device_name());
+        Self {
+            client: Arc::new(RwLock::new(provider)),
+            config: config.upnp.clone(),
+            metrics_config: config.metrics.clone(),
+            stall_detector: Mutex::new(StallDetector::default()),
+            packet_size_check: Mutex::new(PacketSizeCheck::default()),
+            counter_wraps: Mutex::new(CounterWraps::default()),
+            restored_wraps: Mutex::new(
+                config
+                    .metrics
+                    .state_file
+                    .as_deref()
+                    .and_then(CounterWraps::load),
+            ),
+            last_state_save: Mutex::new(None),
+            consecutive_failures: AtomicU32::new(0),
+            polling: config.polling.interval.is_some(),
+            last_poll: Mutex::new(None),
+            last_success: Mutex::new(None),
+            throughput: Mutex::new(ThroughputTracker::default()),
+            device: config.upnp.device_name().to_string(),
+            devices: Vec::new(),
+            metrics,
+            poll_flight: SingleFlight::default(),
+            fetch_flight: SingleFlight::default(),
+            coherence_flight: SingleFlight::default(),
+        }
+    }
+
+    /// Also read the gateways of `[[devices]]` whenever this one is read.
+    /// Their series only show up in this collector's output when they were
+    /// built `with_metrics` of this one.
+    pub fn with_devices(mut self, devices: Vec<Arc<MetricsCollector>>) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// Value of the `device` label of this gateway's series
+    pub fn device_name(&self) -> &str {
+        &self.device
+    }
+
+    /// One of the further gateways, by name
+    pub fn device(&self, name: &str) -> Option<&Arc<MetricsCollector>> {
+        self.devices.iter().find(|device| device.device == name)
+    }
+
+    pub fn client(&self) -> Arc<RwLock<P>> {
+        self.client.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    async fn read_client(&self) -> TimedGuard<RwLockReadGuard<'_, P>> {
+        let wait_started = Instant::now();
+        let guard = self.client.read().await;
+        TimedGuard::new(guard, "read", wait_started, &self.metrics, &self.device)
+    }
+
+    async fn write_client(&self) -> TimedGuard<RwLockWriteGuard<'_, P>> {
+        let wait_started = Instant::now();
+        let guard = self.client.write().await;
+        TimedGuard::new(guard, "write", wait_started, &self.metrics, &self.device)
+    }
+
+    pub async fn collect_metrics(&self) -> (String, bool) {
+        self.collect_metrics_as(ExpositionFormat::Text).await
+    }
+
+    pub async fn collect_metrics_as(&self, format: ExpositionFormat) -> (String, bool) {
+        self.refresh().await;
+        self.encode_metrics(format)
+    }
+
+    /// Bring the metrics up to date for a scrape: read the gateway unless
+    /// the background poller does, and age the polled readings. Gathering
+    /// the registry afterwards does no I/O.
+    pub async fn refresh(&self) {
+        if !self.polling {
+            self.poll().await;
+        }
+        self.set_data_age();
+    }
+
+    /// Read the gateway and the further devices, and update the metrics
+    /// from the readings. Calls made while a poll is in flight wait for it
+    /// rather than polling again.
+    pub async fn poll(&self) {
+        self.poll_flight.run(|| self.poll_all()).await
+    }
+
+    async fn poll_all(&self) {
+        let mut polls = JoinSet::new();
+        for device in &self.devices {
+            let device = device.clone();
+            polls.spawn(async move { device.poll_device().await });
+        }
+        self.poll_device().await;
+        while polls.join_next().await.is_some() {}
+    }
+
+    async fn poll_device(&self) {
+        let started = Instant::now();
+        self.read_device().await;
+        self.metrics
+            .scrape_duration
+            .with_label_values(&[&self.device])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    async fn read_device(&self) {
+        let device = self.device.as_str();
         let mut has_error = false;
 
-        match client.discover_device().await {
-            Ok(()) => match client.get_traffic_stats().await {
-                Ok(stats) => {
-                    Self::update_metrics(&stats);
-                    debug!(
-                        "Updated metrics: bytes_sent={}, bytes_received={}, packets_sent={}, packets_received={}, connection={}",
-                        stats.bytes_sent,
-                        stats.bytes_received,
-                        stats.packets_sent,
-                        stats.packets_received,
-                        stats.connection_status
-                    );
-                }
-                Err(e) => {
-                    error!("Failed to get traffic stats: {}", e);
-                    has_error = true;
-                    CONNECTION_STATUS.set(0.0);
+        let result = self.fetch_stats().await;
+        let now = unix_time();
+        self.metrics
+            .last_scrape_timestamp
+            .with_label_values(&[device])
+            .set(now);
+        let info = self.read_client().await.device_info();
+        self.metrics.update_device_info(device, info.as_ref());
+        match result {
+            Ok(stats) => {
+                self.metrics
+                    .last_success_timestamp
+                    .with_label_values(&[device])
+                    .set(now);
+                if self.polling {
+                    *self.last_poll.lock().unwrap() = Some((stats.clone(), Instant::now()));
                 }
-            },
+                self.throughput.lock().unwrap().observe(
+                    stats.clone(),
+                    self.config.counter_scale,
+                    Instant::now(),
+                );
+                let identity = self.state_identity(info);
+                // Exported counters are wrap-corrected totals, not the raw readings
+                let stats = {
+                    let mut wraps = self.counter_wraps.lock().unwrap();
+                    self.restore_wraps(&mut wraps, identity);
+                    let stats = wraps.accumulate(stats, self.config.counter_scale);
+                    self.save_wraps(&wraps, false);
+                    stats
+                };
+                self.metrics.update_metrics(device, &stats);
+                self.metrics
+                    .update_throughput(device, &self.throughput.lock().unwrap().throughput);
+                let stalled = self
+                    .stall_detector
+                    .lock()
+                    .unwrap()
+                    .observe(&stats, &self.metrics_config);
+                self.metrics
+                    .counters_stalled
+                    .with_label_values(&[device])
+                    .set(if stalled { 1.0 } else { 0.0 });
+                self.packet_size_check.lock().unwrap().observe(&stats);
+                *self.last_success.lock().unwrap() = Some(Instant::now());
+                self.metrics
+                    .data_stale
+                    .with_label_values(&[device])
+                    .set(0.0);
+                self.metrics.data_age.with_label_values(&[device]).set(0.0);
+                debug!(
+                    "Updated metrics: bytes_sent={:?}, bytes_received={:?}, packets_sent={:?}, packets_received={:?}, connection={:?}",
+                    stats.bytes_sent,
+                    stats.bytes_received,
+                    stats.packets_sent,
+                    stats.packets_received,
+                    stats.connection_status
+                );
+            }
             Err(e) => {
-                error!("Failed to discover UPnP device: {}", e);
+                error!(device, "{}", e);
                 has_error = true;
-                CONNECTION_STATUS.set(0.0);
+                // A transient failure leaves the last good readings exported
+                let age = self.last_success.lock().unwrap().map(|read| read.elapsed());
+                if age
+                    .is_none_or(|age| age > Duration::from_secs(self.metrics_config.max_data_age.0))
+                {
+                    self.metrics.remove_readings(device);
+                }
+                self.metrics
+                    .data_stale
+                    .with_label_values(&[device])
+                    .set(1.0);
+                match age {
+                    Some(age) => self
+                        .metrics
+                        .data_age
+                        .with_label_values(&[device])
+                        .set(age.as_secs_f64()),
+                    None => remove_device_series(&self.metrics.data_age, device),
+                }
+                self.metrics
+                    .scrape_partial_error
+                    .with_label_values(&[device])
+                    .set(0.0);
             }
         }
 
         // Set error metric
-        SCRAPE_ERROR.set(if has_error { 1.0 } else { 0.0 });
+        self.metrics
+            .scrape_error
+            .with_label_values(&[device])
+            .set(if has_error { 1.0 } else { 0.0 });
+    }
 
-        // Encode metrics in Prometheus format
-        let encoder = TextEncoder::new();
-        let metric_families = REGISTRY.gather();
+    /// Polled readings age between polls, so their age is taken as they are served
+    fn set_data_age(&self) {
+        if self.polling {
+            match *self.last_success.lock().unwrap() {
+                Some(read) => self
+                    .metrics
+                    .data_age
+                    .with_label_values(&[&self.device])
+                    .set(read.elapsed().as_secs_f64()),
+                None => remove_device_series(&self.metrics.data_age, &self.device),
+            }
+        }
+        for device in &self.devices {
+            device.set_data_age();
+        }
+    }
 
-        match encoder.encode_to_string(&metric_families) {
+    /// Encode the registry as it was left by the last reading
+    fn encode_metrics(&self, format: ExpositionFormat) -> (String, bool) {
+        match self.metrics.encode_as(format) {
             Ok(output) => (output, false),
             Err(e) => {
                 error!("Failed to encode metrics: {}", e);
@@ -88,54 +1916,879 @@ impl MetricsCollector {
         }
     }
 
-    fn update_metrics(stats: &TrafficStats) {
-        BYTES_SENT.set(stats.bytes_sent as f64);
-        BYTES_RECEIVED.set(stats.bytes_received as f64);
-        PACKETS_SENT.set(stats.packets_sent as f64);
-        PACKETS_RECEIVED.set(stats.packets_received as f64);
-        CONNECTION_STATUS.set(if stats.connection_status == "Up" {
-            1.0
+    /// What the counter state is kept under: the UDN of the device, or for
+    /// gateways without a description, the device name and configured URL
+    fn state_identity(&self, info: Option<DeviceInfo>) -> Option<String> {
+        info.and_then(|info| info.udn).or_else(|| {
+            let url = self
+                .config
+                .wan_common_control_url
+                .as_ref()
+                .or(self.config.location.as_ref())?;
+            Some(format!("{}@{}", self.device, url))
+        })
+    }
+
+    /// Continue the totals of a previous run when the state file belongs to
+    /// `device`; a different device starts over
+    fn restore_wraps(&self, wraps: &mut CounterWraps, device: Option<String>) {
+        if let Some(restored) = self.restored_wraps.lock().unwrap().take() {
+            if device.is_some() && restored.device == device {
+                info!(
+                    "Continuing counter totals of {} from the state file",
+                    restored.device.as_deref().unwrap_or_default()
+                );
+                *wraps = restored;
+            } else {
+                warn!(
+                    "Discarding counter state of {}: the gateway is now {}",
+                    restored.device.as_deref().unwrap_or("-"),
+                    device.as_deref().unwrap_or("unidentified")
+                );
+            }
+        }
+        wraps.device = device;
+    }
+
+    /// Persist the totals to `metrics.state_file`, at most every
+    /// `STATE_SAVE_INTERVAL` unless `force`d
+    fn save_wraps(&self, wraps: &CounterWraps, force: bool) {
+        let Some(path) = &self.metrics_config.state_file else {
+            return;
+        };
+        if wraps.device.is_none() {
+            return;
+        }
+        let mut last_save = self.last_state_save.lock().unwrap();
+        if !force && last_save.is_some_and(|saved| saved.elapsed() < STATE_SAVE_INTERVAL) {
+            return;
+        }
+        *last_save = Some(Instant::now());
+        if let Err(e) = wraps.save(path) {
+            warn!("Cannot save counter state to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Write the current totals to the state file, on shutdown
+    pub fn save_state(&self) {
+        self.save_wraps(&self.counter_wraps.lock().unwrap(), true);
+        for device in &self.devices {
+            device.save_state();
+        }
+    }
+
+    async fn try_ensure_device(&self) -> UpnpResult<()> {
+        // Only take the write lock when the cached device needs (re-)discovery
+        let needs_discovery = !self.read_client().await.has_valid_device();
+        if needs_discovery {
+            let started = Instant::now();
+            let result = self.write_client().await.discover().await;
+            self.metrics
+                .observe_phase(&self.device, "discovery", started);
+            result?;
+        }
+        Ok(())
+    }
+
+    async fn ensure_device(&self) -> Result<(), String> {
+        self.try_ensure_device()
+            .await
+            .map_err(|e| self.discovery_error(&e))
+    }
+
+    fn discovery_error(&self, e: &UpnpError) -> String {
+        match &self.config.location {
+            Some(location) => format!(
+                "Device description fetch from {} failed: {} [{}]",
+                location,
+                e,
+                e.kind()
+            ),
+            None => format!("Device discovery failed: {} [{}]", e, e.kind()),
+        }
+    }
+
+    async fn read_traffic_stats(&self) -> UpnpResult<TrafficStats> {
+        let started = Instant::now();
+        let result = self.read_client().await.traffic_stats().await;
+        self.metrics.observe_phase(&self.device, "soap", started);
+        result
+    }
+
+    /// Read the gateway, or wait for the reading already in flight
+    async fn fetch_stats(&self) -> Result<TrafficStats, String> {
+        self.fetch_flight.run(|| self.read_stats()).await
+    }
+
+    async fn read_stats(&self) -> Result<TrafficStats, String> {
+        let deadline = Instant::now() + Duration::from_secs(self.config.scrape_timeout);
+        upnp::with_scrape_deadline(deadline, self.read_stats_until(deadline)).await
+    }
+
+    async fn read_stats_until(&self, deadline: Instant) -> Result<TrafficStats, String> {
+        if let Err(e) = self.try_ensure_device().await {
+            self.metrics.count_scrape_error(&self.device, e.reason());
+            return Err(self.discovery_error(&e));
+        }
+
+        let mut result = self.read_traffic_stats().await;
+        if result.is_ok() {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
         } else {
-            0.0
+            let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= self.config.rediscover_after_failures.max(1) {
+                // The gateway may have restarted with new control URLs
+                warn!(
+                    "Forcing re-discovery after {} consecutive failed scrapes",
+                    failures
+                );
+                self.metrics
+                    .forced_rediscoveries
+                    .with_label_values(&[&self.device])
+                    .inc();
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                self.write_client().await.invalidate_device();
+
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining < Duration::from_secs(self.config.discovery_timeout) {
+                    info!(
+                        "Only {:.1}s of upnp.scrape_timeout left, re-discovering on the next scrape",
+                        remaining.as_secs_f64()
+                    );
+                } else {
+                    result = match self.try_ensure_device().await {
+                        Ok(()) => self.read_traffic_stats().await,
+                        Err(e) => Err(e),
+                    };
+                    match &result {
+                        Ok(_) => info!("Re-discovery recovered the device"),
+                        Err(e) => warn!("Re-discovery did not recover the device: {}", e),
+                    }
+                }
+            }
+        }
+        result.map_err(|e| {
+            self.metrics.count_scrape_error(&self.device, e.reason());
+            match &self.config.wan_common_control_url {
+                Some(url) => format!(
+                    "Failed to get traffic stats from configured upnp.wan_common_control_url {}: {} [{}]",
+                    url,
+                    e,
+                    e.kind()
+                ),
+                None => format!("Failed to get traffic stats: {} [{}]", e, e.kind()),
+            }
+        })
+    }
+
+    /// Traffic per second from the last readings of the gateway
+    pub fn throughput(&self) -> Throughput {
+        self.throughput.lock().unwrap().throughput
+    }
+
+    pub async fn get_stats(&self) -> Result<TrafficStats, String> {
+        if self.polling {
+            return match &*self.last_poll.lock().unwrap() {
+                Some((stats, _)) => Ok(stats.clone()),
+                None => Err("The gateway has not been polled successfully yet".to_string()),
+            };
+        }
+        self.fetch_stats().await.inspect_err(|e| error!("{}", e))
+    }
+
+    /// Identity of the currently resolved device, if any
+    pub async fn device_info(&self) -> Option<DeviceInfo> {
+        self.read_client().await.device_info()
+    }
+
+    /// Read the byte counters back to back for `coherence::DEFAULT_DURATION`.
+    /// Calls made while a measurement runs share its report.
+    pub async fn measure_coherence(&self) -> Result<CoherenceReport, String> {
+        self.coherence_flight
+            .run(|| self.measure_coherence_once())
+            .await
+    }
+
+    async fn measure_coherence_once(&self) -> Result<CoherenceReport, String> {
+        self.ensure_device().await?;
+
+        let client = self.read_client().await;
+        coherence::measure(
+            &*client,
+            coherence::DEFAULT_DURATION,
+            coherence::DEFAULT_INTERVAL,
+        )
+        .await
+        .map_err(|e| format!("Coherence measurement failed: {}", e))
+    }
+}
+
+/// Drop the series of `device` from `vec`, keeping those of other gateways
+fn remove_device_series<T: MetricVecBuilder>(vec: &MetricVec<T>, device: &str) {
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            if labels.get("device") == Some(&device) {
+                let _ = vec.remove(&labels);
+            }
+        }
+    }
+}
+
+/// A free-text description value as a label: whitespace runs, newlines
+/// included, become single spaces, and overlong values are cut
+fn info_label_value(value: &str) -> String {
+    let value: String = value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_INFO_LABEL_CHARS)
+        .collect();
+    value.trim_end().to_string()
+}
+
+/// Reduce a gateway-supplied string to `[A-Za-z0-9_-]`, as some gateways pad
+/// or decorate the values they report
+fn sanitize_label_value(value: &str) -> String {
+    value
+        .trim_matches(|c: char| !c.is_ascii_alphanumeric())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Order families by name and their metrics by label values so that
+/// identical inputs always encode to byte-identical output
+fn sort_metric_families(families: &mut [MetricFamily]) {
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    for family in families.iter_mut() {
+        family.mut_metric().sort_by(|a, b| {
+            let a_values = a.get_label().iter().map(|l| l.get_value());
+            let b_values = b.get_label().iter().map(|l| l.get_value());
+            a_values.cmp(b_values)
         });
     }
+}
 
-    pub async fn get_stats() -> Result<TrafficStats, String> {
-        let mut client = UpnpClient::new();
+/// Formerly registered the global metrics. Each `MetricsCollector` now creates
+/// or shares a `Metrics`, so there is nothing left to initialize.
+#[deprecated(note = "metrics are created with the MetricsCollector; share them with Metrics::new")]
+pub fn init_metrics() {}
 
-        match client.discover_device().await {
-            Ok(()) => match client.get_traffic_stats().await {
-                Ok(stats) => Ok(stats),
-                Err(e) => {
-                    error!("Failed to get stats: {}", e);
-                    Err(format!("Error: {}", e))
-                }
-            },
-            Err(e) => {
-                error!("Failed to discover device: {}", e);
-                Err(format!("Device discovery failed: {}", e))
-            }
-        }
-    }
-}
-
-pub fn init_metrics() {
-    REGISTRY
-        .register(Box::new(BYTES_SENT.clone()))
-        .expect("collector can be registered");
-    REGISTRY
-        .register(Box::new(BYTES_RECEIVED.clone()))
-        .expect("collector can be registered");
-    REGISTRY
-        .register(Box::new(PACKETS_SENT.clone()))
-        .expect("collector can be registered");
-    REGISTRY
-        .register(Box::new(PACKETS_RECEIVED.clone()))
-        .expect("collector can be registered");
-    REGISTRY
-        .register(Box::new(CONNECTION_STATUS.clone()))
-        .expect("collector can be registered");
-    REGISTRY
-        .register(Box::new(SCRAPE_ERROR.clone()))
-        .expect("collector can be registered");
+/// Formerly registered the global metrics under the names of `config`
+#[deprecated(note = "metrics are created with the MetricsCollector; share them with Metrics::new")]
+pub fn init_metrics_with(_config: &MetricsConfig) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockProvider;
+
+    fn bytes_sent(total: u64) -> TrafficStats {
+        TrafficStats {
+            bytes_sent: Some(total),
+            ..TrafficStats::default()
+        }
+    }
+
+    fn gateway_stats(bytes: u64) -> TrafficStats {
+        TrafficStats {
+            bytes_sent: Some(bytes),
+            bytes_received: Some(bytes * 2),
+            packets_sent: Some(bytes / 100),
+            connection_status: Some("Up".to_string()),
+            ip_connection_status: Some("Connected".to_string()),
+            ..TrafficStats::default()
+        }
+    }
+
+    #[test]
+    fn exposition_is_byte_identical_for_identical_inputs() {
+        let config = MetricsConfig::default();
+        let first = Metrics::new(&config);
+        for device in ["wan", "lte", "backup"] {
+            first.update_metrics(device, &gateway_stats(1_000));
+        }
+        // The same readings, arriving in another order
+        let second = Metrics::new(&config);
+        for device in ["backup", "wan", "lte"] {
+            second.update_metrics(device, &gateway_stats(1_000));
+        }
+        let output = first.encode().unwrap();
+        assert_eq!(output, first.encode().unwrap());
+        assert_eq!(output, second.encode().unwrap());
+        // The _created samples of OpenMetrics differ between the instances
+        let output = first.encode_as(ExpositionFormat::OpenMetrics).unwrap();
+        assert_eq!(
+            output,
+            first.encode_as(ExpositionFormat::OpenMetrics).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn instances_do_not_share_series() {
+        #[allow(deprecated)]
+        {
+            init_metrics();
+            init_metrics();
+        }
+        let config = Config::default();
+        let first = MetricsCollector::with_provider(MockProvider::new(bytes_sent(1_000)), &config);
+        let second =
+            MetricsCollector::with_provider(MockProvider::new(bytes_sent(50_000)), &config);
+        let (first_output, _) = Box::pin(first.collect_metrics()).await;
+        let (second_output, _) = Box::pin(second.collect_metrics()).await;
+        const SERIES: &str = "upnp_wan_bytes_sent_total{device=\"default\"}";
+        assert_eq!(sample(&first_output, SERIES), 1_000.0);
+        assert_eq!(sample(&second_output, SERIES), 50_000.0);
+
+        // Readings of one instance leave the other as it was
+        let idle = Metrics::new(&config.metrics);
+        let before = idle.encode().unwrap();
+        first
+            .metrics()
+            .update_metrics("other", &gateway_stats(7_000));
+        assert_eq!(idle.encode().unwrap(), before);
+        assert!(!before.contains("upnp_wan_bytes_sent_total{"), "{before}");
+        assert!(
+            !second
+                .metrics()
+                .encode()
+                .unwrap()
+                .contains("device=\"other\"")
+        );
+    }
+
+    #[test]
+    fn unanswered_link_status_is_unknown() {
+        const STATUS: &str = "upnp_wan_connection_status{device=\"wan\"}";
+        let metrics = Metrics::new(&MetricsConfig::default());
+        metrics.update_metrics("wan", &gateway_stats(1_000));
+        assert_eq!(sample(&metrics.encode().unwrap(), STATUS), 1.0);
+
+        // GetCommonLinkProperties failed while the counters were answered
+        let partial = TrafficStats {
+            connection_status: None,
+            ..gateway_stats(2_000)
+        };
+        metrics.update_metrics("wan", &partial);
+        let output = metrics.encode().unwrap();
+        assert!(!output.contains(STATUS), "{output}");
+        assert_eq!(
+            sample(&output, "upnp_wan_scrape_partial_error{device=\"wan\"}"),
+            1.0
+        );
+        assert_eq!(
+            sample(&output, "upnp_wan_bytes_sent_total{device=\"wan\"}"),
+            2_000.0
+        );
+
+        metrics.update_metrics("wan", &gateway_stats(3_000));
+        assert_eq!(sample(&metrics.encode().unwrap(), STATUS), 1.0);
+    }
+
+    #[test]
+    fn series_are_sorted_by_label_values() {
+        let metrics = Metrics::new(&MetricsConfig::default());
+        for device in ["c", "a", "b"] {
+            metrics.update_metrics(device, &gateway_stats(1_000));
+        }
+        let output = metrics.encode().unwrap();
+        let devices: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("upnp_wan_bytes_sent_total{"))
+            .map(|line| &line["upnp_wan_bytes_sent_total{device=\"".len()..][..1])
+            .collect();
+        assert_eq!(devices, ["a", "b", "c"]);
+        let families: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .collect();
+        assert!(families.is_sorted(), "{families:?}");
+    }
+
+    #[test]
+    fn build_info_names_the_build() {
+        let output = Metrics::new(&MetricsConfig::default()).encode().unwrap();
+        let labels = output
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix("upnp_wan_exporter_build_info{")?
+                    .strip_suffix("} 1")
+            })
+            .unwrap_or_else(|| panic!("no build info in\n{output}"));
+        let label = |name: &str| {
+            labels
+                .split(',')
+                .find_map(|label| label.strip_prefix(name)?.strip_prefix("=\""))
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or_else(|| panic!("no {name} in {labels}"))
+        };
+        assert_eq!(label("version"), env!("CARGO_PKG_VERSION"));
+        assert!(!label("revision").is_empty());
+        let rustc = label("rustc");
+        assert!(
+            rustc == "unknown" || rustc.starts_with(|c: char| c.is_ascii_digit()),
+            "{rustc}"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn process_metrics_only_when_enabled() {
+        const PROCESS_METRICS: [&str; 3] = [
+            "process_resident_memory_bytes ",
+            "process_open_fds ",
+            "process_cpu_seconds_total ",
+        ];
+        let output = Metrics::new(&MetricsConfig::default()).encode().unwrap();
+        for metric in PROCESS_METRICS {
+            assert!(!output.contains(metric), "{metric} in\n{output}");
+        }
+
+        let config = MetricsConfig {
+            process_metrics: true,
+            ..MetricsConfig::default()
+        };
+        let output = Metrics::new(&config).encode().unwrap();
+        for metric in PROCESS_METRICS {
+            assert!(
+                output.lines().any(|line| line.starts_with(metric)),
+                "no {metric} in\n{output}"
+            );
+        }
+    }
+
+    #[test]
+    fn totals_are_exact_up_to_2_pow_53() {
+        for (total, exported) in [
+            ((1 << 53) - 1, "9007199254740991"),
+            (1 << 53, "9007199254740992"),
+            // Beyond 2^53 the f64 sample value cannot hold every integer
+            ((1 << 53) + 1, "9007199254740992"),
+        ] {
+            let metrics = Metrics::new(&MetricsConfig::default());
+            metrics.update_metrics("wan", &bytes_sent(total));
+            let series = format!("upnp_wan_bytes_sent_total{{device=\"wan\"}} {exported}\n");
+            for format in [ExpositionFormat::Text, ExpositionFormat::OpenMetrics] {
+                let output = metrics.encode_as(format).unwrap();
+                assert!(output.contains(&series), "{total} as {format:?}:\n{output}");
+            }
+        }
+    }
+
+    fn with_uptime(mut stats: TrafficStats, uptime: u64) -> TrafficStats {
+        stats.uptime_seconds = Some(uptime);
+        stats
+    }
+
+    /// The bytes sent totals `CounterWraps` reports for consecutive readings
+    fn sent_totals(
+        readings: impl IntoIterator<Item = TrafficStats>,
+        counter_scale: u64,
+    ) -> Vec<u64> {
+        let mut wraps = CounterWraps::default();
+        readings
+            .into_iter()
+            .map(|stats| wraps.accumulate(stats, counter_scale).bytes_sent.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn totals_follow_normal_growth() {
+        let totals = sent_totals([0, 1_000, 1_000, 250_000].map(bytes_sent), 1);
+        assert_eq!(totals, [0, 1_000, 1_000, 250_000]);
+    }
+
+    #[test]
+    fn totals_continue_across_a_wrap() {
+        let readings = [COUNTER_MODULUS - 1_000, COUNTER_MODULUS - 1, 500, 2_000].map(bytes_sent);
+        let totals = sent_totals(readings, 1);
+        assert_eq!(
+            totals,
+            [
+                COUNTER_MODULUS - 1_000,
+                COUNTER_MODULUS - 1,
+                COUNTER_MODULUS + 500,
+                COUNTER_MODULUS + 2_000
+            ]
+        );
+    }
+
+    #[test]
+    fn totals_continue_across_several_wraps() {
+        let mut wraps = CounterWraps::default();
+        let mut total = 0;
+        // Less than half the range per reading, two wraps
+        for raw in [
+            0,
+            2_000_000_000,
+            4_000_000_000,
+            1_500_000_000,
+            3_500_000_000,
+            1_000_000_000,
+        ] {
+            total = wraps.accumulate(bytes_sent(raw), 1).bytes_sent.unwrap();
+        }
+        assert_eq!(total, 2 * COUNTER_MODULUS + 1_000_000_000);
+    }
+
+    #[test]
+    fn large_backwards_jump_restarts_from_the_reading() {
+        // Too far back for a wrap: the counter began again at 0
+        let totals = sent_totals([1_000_000_000, 4_000, 9_000].map(bytes_sent), 1);
+        assert_eq!(totals, [1_000_000_000, 1_000_004_000, 1_000_009_000]);
+    }
+
+    #[test]
+    fn uptime_reset_is_no_wrap() {
+        // Close enough to the top for a wrap, but the gateway rebooted
+        let readings = [
+            with_uptime(bytes_sent(COUNTER_MODULUS - 1_000), 86_400),
+            with_uptime(bytes_sent(5_000), 30),
+            with_uptime(bytes_sent(6_000), 90),
+        ];
+        let totals = sent_totals(readings, 1);
+        assert_eq!(
+            totals,
+            [
+                COUNTER_MODULUS - 1_000,
+                COUNTER_MODULUS + 4_000,
+                COUNTER_MODULUS + 5_000
+            ]
+        );
+    }
+
+    #[test]
+    fn scaled_counters_wrap_at_the_scaled_modulus() {
+        // Counters in KiB wrap at 2^32 KiB
+        let modulus = COUNTER_MODULUS * 1024;
+        let totals = sent_totals([modulus - 1024, 2048].map(bytes_sent), 1024);
+        assert_eq!(totals, [modulus - 1024, modulus + 2048]);
+    }
+
+    #[test]
+    fn unanswered_counter_keeps_its_total() {
+        let mut wraps = CounterWraps::default();
+        wraps.accumulate(gateway_stats(COUNTER_MODULUS - 100), 1);
+        let stats = wraps.accumulate(TrafficStats::default(), 1);
+        assert_eq!(stats.bytes_sent, None);
+        let stats = wraps.accumulate(bytes_sent(100), 1);
+        assert_eq!(stats.bytes_sent, Some(COUNTER_MODULUS + 100));
+    }
+
+    /// Value of `series` in an exposition
+    fn sample(output: &str, series: &str) -> f64 {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {series} in\n{output}"))
+            .parse()
+            .unwrap()
+    }
+
+    /// The `upnp_wan_bytes_sent_total` a collector exports after each
+    /// reading, `None` standing for a gateway that does not answer
+    async fn exported_bytes_sent(readings: &[Option<TrafficStats>]) -> Vec<f64> {
+        let collector =
+            MetricsCollector::with_provider(MockProvider::unreachable(), &Config::default());
+        let mut exported = Vec::new();
+        for stats in readings {
+            collector.client().read().await.set_stats(stats.clone());
+            let (output, _) = Box::pin(collector.collect_metrics()).await;
+            assert!(
+                output.contains("# TYPE upnp_wan_bytes_sent_total counter\n"),
+                "{output}"
+            );
+            exported.push(sample(
+                &output,
+                "upnp_wan_bytes_sent_total{device=\"default\"}",
+            ));
+        }
+        exported
+    }
+
+    #[tokio::test]
+    async fn counter_starts_at_the_first_reading() {
+        let exported =
+            exported_bytes_sent(&[Some(bytes_sent(3_000_000)), Some(bytes_sent(3_500_000))]).await;
+        assert_eq!(exported, [3_000_000.0, 3_500_000.0]);
+    }
+
+    #[tokio::test]
+    async fn counter_follows_growth() {
+        let readings = [0, 1_000, 1_000, 250_000].map(|raw| Some(bytes_sent(raw)));
+        let exported = exported_bytes_sent(&readings).await;
+        assert_eq!(exported, [0.0, 1_000.0, 1_000.0, 250_000.0]);
+    }
+
+    #[tokio::test]
+    async fn counter_continues_across_a_reset() {
+        let readings = [
+            Some(with_uptime(bytes_sent(3_000_000_000), 86_400)),
+            Some(with_uptime(bytes_sent(4_000), 30)),
+            Some(with_uptime(bytes_sent(6_000), 90)),
+        ];
+        let exported = exported_bytes_sent(&readings).await;
+        assert_eq!(exported, [3e9, 3e9 + 4_000.0, 3e9 + 6_000.0]);
+    }
+
+    #[tokio::test]
+    async fn counter_continues_across_a_wrap() {
+        let readings = [COUNTER_MODULUS - 1_000, 500].map(|raw| Some(bytes_sent(raw)));
+        let exported = exported_bytes_sent(&readings).await;
+        let modulus = COUNTER_MODULUS as f64;
+        assert_eq!(exported, [modulus - 1_000.0, modulus + 500.0]);
+    }
+
+    #[tokio::test]
+    async fn counter_holds_through_failed_readings() {
+        let readings = [
+            Some(bytes_sent(5_000)),
+            None,
+            Some(TrafficStats {
+                packets_sent: Some(10),
+                ..TrafficStats::default()
+            }),
+            Some(bytes_sent(7_000)),
+        ];
+        let exported = exported_bytes_sent(&readings).await;
+        assert_eq!(exported, [5_000.0, 5_000.0, 5_000.0, 7_000.0]);
+    }
+
+    #[tokio::test]
+    async fn success_timestamp_stops_at_the_last_success() {
+        const SUCCESS: &str = "upnp_wan_last_success_timestamp_seconds{device=\"default\"}";
+        const SCRAPE: &str = "upnp_wan_last_scrape_timestamp_seconds{device=\"default\"}";
+        let collector = MetricsCollector::with_provider(
+            MockProvider::new(bytes_sent(1_000)),
+            &Config::default(),
+        );
+        let scrape = async || {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let (output, _) = Box::pin(collector.collect_metrics()).await;
+            (sample(&output, SUCCESS), sample(&output, SCRAPE))
+        };
+
+        let (first_success, first_scrape) = scrape().await;
+        assert_eq!(first_success, first_scrape);
+        // An answer with some values missing is still a success
+        collector
+            .client()
+            .read()
+            .await
+            .set_stats(Some(TrafficStats {
+                packets_sent: Some(10),
+                ..TrafficStats::default()
+            }));
+        let (last_success, last_scrape) = scrape().await;
+        assert!(last_success > first_success);
+        assert_eq!(last_success, last_scrape);
+
+        collector.client().read().await.set_stats(None);
+        let mut previous_scrape = last_scrape;
+        for _ in 0..2 {
+            let (success, scrape) = scrape().await;
+            assert_eq!(success, last_success);
+            assert!(scrape > previous_scrape);
+            previous_scrape = scrape;
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_current_external_ip_has_a_series() {
+        let collector =
+            MetricsCollector::with_provider(MockProvider::unreachable(), &Config::default());
+        for (address, expected) in [
+            (Some("203.0.113.7"), &["203.0.113.7"][..]),
+            (Some("198.51.100.23"), &["198.51.100.23"]),
+            (Some("0.0.0.0"), &[]),
+            (Some(""), &[]),
+            (Some(" 2001:db8::7 "), &["2001:db8::7"]),
+            (None, &[]),
+            (Some("203.0.113.7"), &["203.0.113.7"]),
+        ] {
+            collector
+                .client()
+                .read()
+                .await
+                .set_stats(Some(TrafficStats {
+                    external_ip: address.map(str::to_string),
+                    ..bytes_sent(1_000)
+                }));
+            let (output, _) = Box::pin(collector.collect_metrics()).await;
+            let series: Vec<&str> = output
+                .lines()
+                .filter_map(|line| {
+                    line.strip_prefix("upnp_wan_external_ip_info{device=\"default\",ip=\"")?
+                        .strip_suffix("\"} 1")
+                })
+                .collect();
+            assert_eq!(series, expected, "after {address:?}");
+        }
+    }
+
+    const STALE: &str = "upnp_wan_data_stale{device=\"default\"}";
+    const AGE: &str = "upnp_wan_data_age_seconds{device=\"default\"}";
+    const SENT: &str = "upnp_wan_bytes_sent_total{device=\"default\"}";
+
+    fn collector_with_max_data_age(seconds: u64) -> MetricsCollector<MockProvider> {
+        let mut config = Config::default();
+        config.metrics.max_data_age = crate::config::Seconds(seconds);
+        MetricsCollector::with_provider(MockProvider::new(bytes_sent(1_000)), &config)
+    }
+
+    async fn scrape(collector: &MetricsCollector<MockProvider>) -> String {
+        Box::pin(collector.collect_metrics()).await.0
+    }
+
+    #[tokio::test]
+    async fn fresh_readings_are_not_stale() {
+        let collector = collector_with_max_data_age(300);
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, SENT), 1_000.0);
+        assert_eq!(sample(&output, STALE), 0.0);
+        assert_eq!(sample(&output, AGE), 0.0);
+    }
+
+    #[tokio::test]
+    async fn failed_readings_serve_the_last_good_ones_as_stale() {
+        let collector = collector_with_max_data_age(300);
+        scrape(&collector).await;
+        collector.client().read().await.set_stats(None);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, SENT), 1_000.0);
+        assert_eq!(sample(&output, STALE), 1.0);
+        let first_age = sample(&output, AGE);
+        assert!(first_age >= 0.02, "{first_age}");
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, SENT), 1_000.0);
+        assert!(sample(&output, AGE) > first_age);
+
+        // The next good reading is fresh again
+        collector
+            .client()
+            .read()
+            .await
+            .set_stats(Some(bytes_sent(2_000)));
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, SENT), 2_000.0);
+        assert_eq!(sample(&output, STALE), 0.0);
+        assert_eq!(sample(&output, AGE), 0.0);
+    }
+
+    #[tokio::test]
+    async fn expired_readings_are_no_longer_served() {
+        let collector = collector_with_max_data_age(0);
+        scrape(&collector).await;
+        collector.client().read().await.set_stats(None);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let output = scrape(&collector).await;
+        assert!(!output.contains("upnp_wan_bytes_sent_total{"), "{output}");
+        assert_eq!(sample(&output, STALE), 1.0);
+        assert!(sample(&output, AGE) >= 0.02);
+    }
+
+    #[tokio::test]
+    async fn polled_readings_age_until_served() {
+        let mut config = Config::default();
+        config.polling.interval = Some(crate::config::Seconds(60));
+        let collector =
+            MetricsCollector::with_provider(MockProvider::new(bytes_sent(1_000)), &config);
+        let output = scrape(&collector).await;
+        assert!(!output.contains("upnp_wan_data_age_seconds{"), "{output}");
+
+        collector.poll().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, SENT), 1_000.0);
+        assert_eq!(sample(&output, STALE), 0.0);
+        assert!(sample(&output, AGE) >= 0.02, "{output}");
+        assert!(!output.contains("stats_age"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn no_age_before_the_first_good_reading() {
+        let collector =
+            MetricsCollector::with_provider(MockProvider::unreachable(), &Config::default());
+        let output = scrape(&collector).await;
+        assert_eq!(sample(&output, STALE), 1.0);
+        assert!(!output.contains("upnp_wan_data_age_seconds{"), "{output}");
+        assert!(!output.contains("upnp_wan_bytes_sent_total{"), "{output}");
+    }
+
+    #[test]
+    fn throughput_first_reading_has_no_rate() {
+        let mut tracker = ThroughputTracker::default();
+        tracker.observe(bytes_sent(1_000_000), 1, Instant::now());
+        assert_eq!(tracker.throughput.send_bytes_per_second, None);
+    }
+
+    #[test]
+    fn throughput_waits_for_the_minimum_window() {
+        let mut tracker = ThroughputTracker::default();
+        let start = Instant::now();
+        tracker.observe(bytes_sent(0), 1, start);
+        tracker.observe(bytes_sent(1_000), 1, start + Duration::from_secs(1));
+        assert_eq!(tracker.throughput.send_bytes_per_second, None);
+        tracker.observe(bytes_sent(10_000), 1, start + Duration::from_secs(10));
+        assert_eq!(tracker.throughput.send_bytes_per_second, Some(1_000.0));
+    }
+
+    #[test]
+    fn throughput_is_smoothed() {
+        let mut tracker = ThroughputTracker::default();
+        let start = Instant::now();
+        tracker.observe(bytes_sent(0), 1, start);
+        tracker.observe(bytes_sent(10_000), 1, start + Duration::from_secs(10));
+        // A one-off burst moves the average only part of the way
+        tracker.observe(bytes_sent(1_010_000), 1, start + Duration::from_secs(20));
+        let rate = tracker.throughput.send_bytes_per_second.unwrap();
+        assert!(rate > 1_000.0 && rate < 100_000.0, "{rate}");
+    }
+
+    #[test]
+    fn throughput_spans_counter_wraps() {
+        let mut tracker = ThroughputTracker::default();
+        let start = Instant::now();
+        tracker.observe(bytes_sent(COUNTER_MODULUS - 5_000), 1, start);
+        tracker.observe(bytes_sent(5_000), 1, start + Duration::from_secs(10));
+        assert_eq!(tracker.throughput.send_bytes_per_second, Some(1_000.0));
+    }
+
+    #[test]
+    fn unanswered_counter_has_no_rate() {
+        let mut tracker = ThroughputTracker::default();
+        let start = Instant::now();
+        tracker.observe(bytes_sent(0), 1, start);
+        tracker.observe(bytes_sent(10_000), 1, start + Duration::from_secs(10));
+        tracker.observe(TrafficStats::default(), 1, start + Duration::from_secs(20));
+        assert_eq!(tracker.throughput.send_bytes_per_second, None);
+        tracker.observe(bytes_sent(40_000), 1, start + Duration::from_secs(30));
+        assert_eq!(tracker.throughput.send_bytes_per_second, Some(1_500.0));
+    }
 }
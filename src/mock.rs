@@ -0,0 +1,111 @@
+use crate::description::DeviceInfo;
+use crate::error::{UpnpError, UpnpResult};
+use crate::provider::WanStatsProvider;
+use crate::upnp::TrafficStats;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+/// A `WanStatsProvider` serving canned stats, for exercising
+/// `MetricsCollector` and the HTTP handlers without a gateway.
+/// Without stats it behaves like a gateway that never answers discovery.
+#[derive(Debug, Default)]
+pub struct MockProvider {
+    stats: Mutex<Option<TrafficStats>>,
+    device_info: Option<DeviceInfo>,
+    discovered: AtomicBool,
+    discoveries: AtomicU32,
+    /// Time each reading of the stats takes, as on a slow gateway
+    delay: Duration,
+    readings: AtomicU32,
+}
+
+impl MockProvider {
+    pub fn new(stats: TrafficStats) -> Self {
+        Self {
+            stats: Mutex::new(Some(stats)),
+            ..Self::default()
+        }
+    }
+
+    /// A gateway that is never found
+    pub fn unreachable() -> Self {
+        Self::default()
+    }
+
+    pub fn with_device_info(mut self, info: DeviceInfo) -> Self {
+        self.device_info = Some(info);
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Replace the stats served from now on; `None` makes every call fail
+    pub fn set_stats(&self, stats: Option<TrafficStats>) {
+        *self.stats.lock().unwrap() = stats;
+    }
+
+    /// Number of times `discover` actually ran discovery
+    pub fn discoveries(&self) -> u32 {
+        self.discoveries.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the traffic stats were read
+    pub fn readings(&self) -> u32 {
+        self.readings.load(Ordering::Relaxed)
+    }
+
+    fn current(&self) -> UpnpResult<TrafficStats> {
+        self.stats
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| UpnpError::NoIgdFound("mock gateway is unreachable".to_string()))
+    }
+}
+
+impl WanStatsProvider for MockProvider {
+    fn has_valid_device(&self) -> bool {
+        self.discovered.load(Ordering::Relaxed)
+    }
+
+    async fn discover(&mut self) -> UpnpResult<()> {
+        if self.has_valid_device() {
+            return Ok(());
+        }
+        self.discoveries.fetch_add(1, Ordering::Relaxed);
+        self.current()?;
+        self.discovered.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn invalidate_device(&mut self) {
+        self.discovered.store(false, Ordering::Relaxed);
+    }
+
+    async fn traffic_stats(&self) -> UpnpResult<TrafficStats> {
+        self.readings.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(self.delay).await;
+        self.current()
+    }
+
+    async fn byte_counters(&self) -> UpnpResult<(u64, u64)> {
+        let stats = self.current()?;
+        stats
+            .bytes_sent
+            .zip(stats.bytes_received)
+            .ok_or_else(|| UpnpError::parse("NewTotalBytesSent", "not in the mock stats"))
+    }
+
+    async fn external_ip(&self) -> UpnpResult<Option<String>> {
+        Ok(self.current()?.external_ip)
+    }
+
+    fn device_info(&self) -> Option<DeviceInfo> {
+        self.has_valid_device()
+            .then(|| self.device_info.clone().unwrap_or_default())
+    }
+}
@@ -0,0 +1,215 @@
+use crate::config::UpnpConfig;
+use crate::description::DeviceInfo;
+use crate::error::{UpnpError, UpnpResult};
+use crate::provider::WanStatsProvider;
+use crate::upnp::TrafficStats;
+use anyhow::anyhow;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+use tracing::debug;
+
+/// Port NAT-PMP gateways listen on (RFC 6886)
+pub const NATPMP_PORT: u16 = 5351;
+// RFC 6886 3.1: start at 250ms and double on each retransmission
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(250);
+const OPCODE_PUBLIC_ADDRESS: u8 = 0;
+const RESPONSE_BIT: u8 = 128;
+const RESULT_SUCCESS: u16 = 0;
+// The gateway has no address on its WAN side yet, e.g. no DHCP lease
+const RESULT_NETWORK_FAILURE: u16 = 3;
+
+/// Answer to a public address request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicAddress {
+    /// Seconds since the gateway started or lost its mappings
+    pub epoch: u32,
+    /// `None` while the gateway has no external address
+    pub external_ip: Option<Ipv4Addr>,
+}
+
+/// Reads a gateway through NAT-PMP, for routers with UPnP IGD disabled. Only
+/// the external IP and the epoch are available; traffic counters stay unset.
+#[derive(Debug)]
+pub struct NatPmpClient {
+    config: UpnpConfig,
+    gateway: Option<Ipv4Addr>,
+}
+
+impl NatPmpClient {
+    pub fn new(config: &UpnpConfig) -> Self {
+        Self {
+            config: config.clone(),
+            gateway: config.natpmp_gateway,
+        }
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.gateway
+    }
+
+    /// Send a public address request, retransmitting with doubling delays
+    /// until `http_request_timeout` runs out
+    pub async fn public_address(&self) -> UpnpResult<PublicAddress> {
+        let gateway = self
+            .gateway
+            .ok_or_else(|| UpnpError::NoIgdFound("no NAT-PMP gateway resolved".to_string()))?;
+        let local = match self.config.bind_address {
+            Some(IpAddr::V4(address)) => address,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket
+            .connect(SocketAddr::from((gateway, NATPMP_PORT)))
+            .await?;
+
+        let deadline = Instant::now() + Duration::from_secs(self.config.http_request_timeout);
+        let mut delay = INITIAL_RETRY_DELAY;
+        let mut buf = [0u8; 16];
+        loop {
+            socket.send(&[0, OPCODE_PUBLIC_ADDRESS]).await?;
+            let attempt_deadline = (Instant::now() + delay).min(deadline);
+            while let Ok(received) = timeout_at(attempt_deadline, socket.recv(&mut buf)).await {
+                match parse_public_address(&buf[..received?]) {
+                    Some(answer) => return answer,
+                    None => debug!("Ignoring unexpected NAT-PMP packet from {}", gateway),
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(UpnpError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "NAT-PMP gateway {} did not answer within {}s",
+                        gateway, self.config.http_request_timeout
+                    ),
+                )));
+            }
+            delay *= 2;
+        }
+    }
+}
+
+/// `None` for packets that are not a public address response
+fn parse_public_address(packet: &[u8]) -> Option<UpnpResult<PublicAddress>> {
+    if packet.len() < 8 || packet[0] != 0 || packet[1] != RESPONSE_BIT | OPCODE_PUBLIC_ADDRESS {
+        return None;
+    }
+    let result = u16::from_be_bytes([packet[2], packet[3]]);
+    let epoch = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+    Some(match result {
+        RESULT_SUCCESS if packet.len() >= 12 => {
+            let address = Ipv4Addr::new(packet[8], packet[9], packet[10], packet[11]);
+            Ok(PublicAddress {
+                epoch,
+                external_ip: Some(address).filter(|a| !a.is_unspecified()),
+            })
+        }
+        RESULT_SUCCESS => Err(UpnpError::parse(
+            "public address response",
+            format!("{} bytes, expected 12", packet.len()),
+        )),
+        RESULT_NETWORK_FAILURE => Ok(PublicAddress {
+            epoch,
+            external_ip: None,
+        }),
+        code => Err(UpnpError::Other(anyhow!(
+            "NAT-PMP gateway refused the public address request: {}",
+            result_message(code)
+        ))),
+    })
+}
+
+fn result_message(code: u16) -> String {
+    match code {
+        1 => "unsupported version (1)".to_string(),
+        2 => "not authorized (2)".to_string(),
+        4 => "out of resources (4)".to_string(),
+        5 => "unsupported opcode (5)".to_string(),
+        code => format!("result code {}", code),
+    }
+}
+
+/// Gateway of the IPv4 default route, read from the Linux routing table
+fn default_gateway() -> UpnpResult<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").map_err(|e| {
+        UpnpError::Config(format!(
+            "cannot read the default route ({}), set upnp.natpmp_gateway",
+            e
+        ))
+    })?;
+    // Iface Destination Gateway Flags ..., addresses as hex words in host byte order
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let destination = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            (destination == 0 && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+        })
+        .next()
+        .ok_or_else(|| {
+            UpnpError::NoIgdFound(
+                "no IPv4 default route to find the NAT-PMP gateway, set upnp.natpmp_gateway"
+                    .to_string(),
+            )
+        })
+}
+
+impl WanStatsProvider for NatPmpClient {
+    fn has_valid_device(&self) -> bool {
+        self.gateway.is_some()
+    }
+
+    async fn discover(&mut self) -> UpnpResult<()> {
+        if self.gateway.is_none() {
+            let gateway = default_gateway()?;
+            debug!("Using NAT-PMP gateway {} of the default route", gateway);
+            self.gateway = Some(gateway);
+        }
+        Ok(())
+    }
+
+    fn invalidate_device(&mut self) {
+        // The default route may have moved; a configured gateway stays
+        self.gateway = self.config.natpmp_gateway;
+    }
+
+    async fn traffic_stats(&self) -> UpnpResult<TrafficStats> {
+        let answer = self.public_address().await?;
+        Ok(TrafficStats {
+            connection_status: Some(
+                if answer.external_ip.is_some() {
+                    "Up"
+                } else {
+                    "Down"
+                }
+                .to_string(),
+            ),
+            external_ip: answer.external_ip.map(|ip| ip.to_string()),
+            // The epoch restarts with the gateway, like the connection uptime
+            uptime_seconds: Some(u64::from(answer.epoch)),
+            ..TrafficStats::default()
+        })
+    }
+
+    async fn byte_counters(&self) -> UpnpResult<(u64, u64)> {
+        Err(UpnpError::Other(anyhow!(
+            "NAT-PMP provides no traffic counters"
+        )))
+    }
+
+    async fn external_ip(&self) -> UpnpResult<Option<String>> {
+        Ok(self
+            .public_address()
+            .await?
+            .external_ip
+            .map(|ip| ip.to_string()))
+    }
+
+    fn device_info(&self) -> Option<DeviceInfo> {
+        None
+    }
+}
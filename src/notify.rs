@@ -0,0 +1,111 @@
+use crate::ssdp::{SSDP_BUFFER_SIZE, SsdpNotify, UPNP_MULTICAST_ADDR, usn_uuid};
+use crate::upnp::UpnpClient;
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+/// Listen for SSDP NOTIFY advertisements and keep the cached device in sync:
+/// `ssdp:byebye` drops it, `ssdp:alive` with a new LOCATION re-resolves it.
+/// `device` is the label of the gateway's series.
+pub async fn run_notify_listener(client: Arc<RwLock<UpnpClient>>, device: String) {
+    let socket = match bind_notify_socket() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to start SSDP NOTIFY listener: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Listening for SSDP NOTIFY messages on {}",
+        UPNP_MULTICAST_ADDR
+    );
+
+    let mut buf = vec![0; SSDP_BUFFER_SIZE];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("SSDP NOTIFY listener receive error: {}", e);
+                continue;
+            }
+        };
+
+        let message = String::from_utf8_lossy(&buf[..len]);
+        if let Ok(notify) = SsdpNotify::parse(&message) {
+            debug!("Received SSDP NOTIFY from {}: {:?}", addr, notify);
+            handle_notify(&client, &device, notify).await;
+        }
+    }
+}
+
+fn bind_notify_socket() -> Result<UdpSocket> {
+    let multicast_addr: SocketAddrV4 = UPNP_MULTICAST_ADDR.parse()?;
+
+    // Other SSDP stacks on this host usually hold port 1900 as well
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, multicast_addr.port())).into())?;
+    socket.set_nonblocking(true)?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(*multicast_addr.ip(), Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+async fn handle_notify(client: &RwLock<UpnpClient>, device: &str, notify: SsdpNotify) {
+    let Some(uuid) = notify.usn.as_deref().map(usn_uuid) else {
+        return;
+    };
+
+    // Only announcements for the cached gateway are of interest
+    let cached_location = {
+        let client = client.read().await;
+        match client.device() {
+            Some(device) if device.usn.as_deref().map(usn_uuid) == Some(uuid) => {
+                device.location.clone()
+            }
+            _ => return,
+        }
+    };
+
+    match notify.nts.as_deref() {
+        Some("ssdp:byebye") => {
+            info!(
+                "Gateway {} announced ssdp:byebye, dropping cached device",
+                uuid
+            );
+            let mut client = client.write().await;
+            client.invalidate_device();
+            if let Some(metrics) = client.metrics() {
+                metrics.set_device_gone(device);
+            }
+        }
+        Some("ssdp:alive") => match notify.location {
+            Some(location) if location != cached_location => {
+                info!(
+                    "Gateway {} moved from {} to {}, re-resolving services",
+                    uuid, cached_location, location
+                );
+                let mut client = client.write().await;
+                match client.relocate_device(location).await {
+                    Ok(()) => {
+                        if let Some(max_age) = notify.cache_control_max_age {
+                            client.refresh_device_expiry(max_age);
+                        }
+                    }
+                    Err(e) => warn!("Failed to re-resolve gateway {}: {}", uuid, e),
+                }
+            }
+            _ => {
+                if let Some(max_age) = notify.cache_control_max_age {
+                    client.write().await.refresh_device_expiry(max_age);
+                }
+            }
+        },
+        _ => {}
+    }
+}
@@ -0,0 +1,25 @@
+use crate::metrics::MetricsCollector;
+use crate::provider::WanStatsProvider;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::info;
+
+/// Read the gateway every `interval` so that scrapes are answered from the
+/// last reading without waiting on the gateway. A failed reading is logged
+/// and the previous one stays in place, aging, until a later poll succeeds.
+pub async fn run_poller<P: WanStatsProvider>(
+    collector: Arc<MetricsCollector<P>>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // A slow gateway delays the next poll rather than causing a burst of them
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!("Polling the gateway every {}s", interval.as_secs());
+
+    loop {
+        // The first tick fires immediately, so the first reading is taken at startup
+        ticker.tick().await;
+        collector.poll().await;
+    }
+}
@@ -0,0 +1,74 @@
+use crate::description::DeviceInfo;
+use crate::error::UpnpResult;
+use crate::metrics::Metrics;
+use crate::upnp::{TrafficStats, UpnpClient};
+use std::future::Future;
+use std::sync::Arc;
+
+/// The data-fetching surface `MetricsCollector` and the HTTP handlers need
+/// from a gateway. `UpnpClient` is the real implementation; anything else
+/// (e.g. `mock::MockProvider`) lets them run without a router on the LAN.
+pub trait WanStatsProvider: Send + Sync + 'static {
+    /// Whether a device is resolved and its cache entry has not expired
+    fn has_valid_device(&self) -> bool;
+
+    /// Resolve the device unless a valid one is cached
+    fn discover(&mut self) -> impl Future<Output = UpnpResult<()>> + Send;
+
+    /// Forget the resolved device so the next `discover` finds it again
+    fn invalidate_device(&mut self);
+
+    /// Traffic stats of the primary WAN interface
+    fn traffic_stats(&self) -> impl Future<Output = UpnpResult<TrafficStats>> + Send;
+
+    /// Only the (bytes sent, bytes received) counters
+    fn byte_counters(&self) -> impl Future<Output = UpnpResult<(u64, u64)>> + Send;
+
+    /// Public IP address of the primary WAN connection, `None` while it has none
+    fn external_ip(&self) -> impl Future<Output = UpnpResult<Option<String>>> + Send;
+
+    /// Identity of the resolved device, if any
+    fn device_info(&self) -> Option<DeviceInfo>;
+
+    /// Record the provider's own activity (discoveries, SOAP requests) in
+    /// `metrics` under the `device` label; called when a collector takes the provider
+    fn attach_metrics(&mut self, _metrics: Arc<Metrics>, _device: &str) {}
+}
+
+impl WanStatsProvider for UpnpClient {
+    fn has_valid_device(&self) -> bool {
+        UpnpClient::has_valid_device(self)
+    }
+
+    async fn discover(&mut self) -> UpnpResult<()> {
+        self.ensure_device().await
+    }
+
+    fn invalidate_device(&mut self) {
+        UpnpClient::invalidate_device(self)
+    }
+
+    async fn traffic_stats(&self) -> UpnpResult<TrafficStats> {
+        self.get_traffic_stats().await
+    }
+
+    async fn byte_counters(&self) -> UpnpResult<(u64, u64)> {
+        self.get_byte_counters().await
+    }
+
+    async fn external_ip(&self) -> UpnpResult<Option<String>> {
+        self.get_external_ip().await
+    }
+
+    fn device_info(&self) -> Option<DeviceInfo> {
+        // A configured location stays cached even when its description
+        // could not be fetched; that is no identity
+        self.device()
+            .filter(|device| !device.wan_interfaces.is_empty())
+            .map(|device| device.info.clone())
+    }
+
+    fn attach_metrics(&mut self, metrics: Arc<Metrics>, device: &str) {
+        self.set_metrics(metrics, device)
+    }
+}
@@ -0,0 +1,63 @@
+use crate::config::UpnpConfig;
+use crate::upnp::UpnpClient;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+/// Periodically re-run discovery and swap in the result, so that control
+/// URLs that moved (e.g. miniupnpd picking a new port after a reboot) are
+/// picked up. Discovery runs on a separate client so scrapes keep using the
+/// last known-good device meanwhile, and a failed attempt leaves it in place.
+pub async fn run_rediscovery(
+    client: Arc<RwLock<UpnpClient>>,
+    config: UpnpConfig,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick fires immediately; the initial discovery happens on the first scrape
+    ticker.tick().await;
+    info!(
+        "Re-discovering the UPnP device every {}s",
+        interval.as_secs()
+    );
+
+    loop {
+        ticker.tick().await;
+
+        let mut fresh = match UpnpClient::from_config(&config) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                error!("Failed to create client for re-discovery: {}", e);
+                continue;
+            }
+        };
+        // Its discoveries count along with those of the scrapes
+        {
+            let client = client.read().await;
+            if let Some(metrics) = client.metrics() {
+                fresh.set_metrics(metrics, client.metrics_device());
+            }
+        }
+        if let Err(e) = fresh.discover_device().await {
+            warn!("Re-discovery failed, keeping the current device: {}", e);
+            continue;
+        }
+
+        let mut client = client.write().await;
+        let previous = client.device().map(|d| d.location.clone());
+        let current = fresh.device().map(|d| d.location.clone());
+        client.adopt_device(fresh);
+        match (previous, current) {
+            (Some(previous), Some(current)) if previous == current => {
+                debug!("Re-discovery confirmed the device at {}", current);
+            }
+            (previous, Some(current)) => info!(
+                "Re-discovery moved the device from {} to {}",
+                previous.as_deref().unwrap_or("(none)"),
+                current
+            ),
+            (_, None) => {}
+        }
+    }
+}
@@ -1,22 +1,34 @@
-use crate::metrics::MetricsCollector;
+use crate::config::{Config, Secret};
+use crate::description::DeviceInfo;
+use crate::drift::{ConfigDrift, DriftStatus};
+use crate::exposition::ExpositionFormat;
+use crate::metrics::{MetricsCollector, Throughput};
+use crate::provider::WanStatsProvider;
+use crate::upnp::TrafficStats;
 use axum::{
     Router,
-    extract::Query,
+    extract::{Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+const UNAVAILABLE: &str = "unavailable";
 
 fn format_bytes(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     let mut value = bytes as f64;
     let mut unit_index = 0;
-    
+
     while value >= 1024.0 && unit_index < UNITS.len() - 1 {
         value /= 1024.0;
         unit_index += 1;
     }
-    
+
     if unit_index == 0 {
         format!("{} {}", bytes, UNITS[unit_index])
     } else {
@@ -24,26 +36,51 @@ fn format_bytes(bytes: u64) -> String {
     }
 }
 
-pub fn create_app() -> Router {
+pub fn create_app<P: WanStatsProvider>(
+    collector: Arc<MetricsCollector<P>>,
+    drift: Arc<ConfigDrift>,
+) -> Router {
+    let admin_token = drift.running().server.admin_token.clone();
     Router::new()
-        .route("/metrics", get(metrics_handler))
+        .route("/admin/coherence", post(coherence_handler::<P>))
+        .route_layer(middleware::from_fn_with_state(
+            admin_token,
+            require_admin_token,
+        ))
+        .route("/metrics", get(metrics_handler::<P>))
         .route("/health", get(health_handler))
-        .route("/stats", get(stats_handler))
+        .route("/stats", get(stats_handler::<P>))
+        .with_state(collector)
+        .merge(
+            Router::new()
+                .route("/debug/config", get(debug_config_handler))
+                .with_state(drift),
+        )
 }
 
-async fn metrics_handler() -> Response {
-    let (output, has_error) = MetricsCollector::collect_metrics().await;
+async fn metrics_handler<P: WanStatsProvider>(
+    State(collector): State<Arc<MetricsCollector<P>>>,
+    headers: HeaderMap,
+) -> Response {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let format = ExpositionFormat::from_accept(accept);
+    collector.refresh().await;
 
-    if has_error {
-        axum::response::Response::builder()
-            .status(500)
-            .body(output.into())
-            .unwrap()
-    } else {
-        axum::response::Response::builder()
-            .header("Content-Type", "text/plain; charset=utf-8")
+    match collector.metrics().encode_as(format) {
+        Ok(output) => axum::response::Response::builder()
+            .header("Content-Type", format.content_type())
+            .header("Vary", "Accept")
             .body(output.into())
-            .unwrap()
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            axum::response::Response::builder()
+                .status(500)
+                .body("Internal Server Error".into())
+                .unwrap()
+        }
     }
 }
 
@@ -54,23 +91,98 @@ async fn health_handler() -> impl IntoResponse {
 #[derive(Deserialize)]
 struct StatsQuery {
     format: Option<String>,
+    /// Name of one of the `[[devices]]`, instead of the `[upnp]` gateway
+    device: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeviceQuery {
+    device: Option<String>,
+}
+
+fn unknown_device(name: &str) -> Response {
+    axum::response::Response::builder()
+        .status(404)
+        .body(format!("No device named {}", name).into())
+        .unwrap()
 }
 
-async fn stats_handler(Query(params): Query<StatsQuery>) -> Response {
-    match MetricsCollector::get_stats().await {
-        Ok(stats) => match params.format.as_deref() {
-            Some("json") => axum::response::Json(stats).into_response(),
+#[derive(Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    stats: TrafficStats,
+    #[serde(flatten)]
+    throughput: Throughput,
+    /// Values the gateway did not answer, shown as null above
+    unavailable: Vec<&'static str>,
+    device: Option<DeviceInfo>,
+}
+
+async fn stats_handler<P: WanStatsProvider>(
+    State(collector): State<Arc<MetricsCollector<P>>>,
+    Query(params): Query<StatsQuery>,
+) -> Response {
+    let format = params.format.as_deref();
+    match params.device.as_deref() {
+        Some(name) if name != collector.device_name() => match collector.device(name) {
+            Some(device) => stats_response(device, format).await,
+            None => unknown_device(name),
+        },
+        _ => stats_response(&collector, format).await,
+    }
+}
+
+async fn stats_response<P: WanStatsProvider>(
+    collector: &MetricsCollector<P>,
+    format: Option<&str>,
+) -> Response {
+    match collector.get_stats().await {
+        Ok(stats) => match format {
+            Some("json") => axum::response::Json(StatsResponse {
+                unavailable: stats.unavailable_fields(),
+                stats,
+                throughput: collector.throughput(),
+                device: collector.device_info().await,
+            })
+            .into_response(),
             _ => {
-                let output = format!(
-                    "Bytes Sent: {} / {}\nBytes Received: {} / {}\nPackets Sent: {}\nPackets Received: {}\nConnection: {}",
-                    stats.bytes_sent,
-                    format_bytes(stats.bytes_sent),
-                    stats.bytes_received,
-                    format_bytes(stats.bytes_received),
-                    stats.packets_sent,
-                    stats.packets_received,
-                    stats.connection_status
+                let bytes = |value: Option<u64>| match value {
+                    Some(value) => format!("{} / {}", value, format_bytes(value)),
+                    None => UNAVAILABLE.to_string(),
+                };
+                let count = |value: Option<u64>| {
+                    value.map_or_else(|| UNAVAILABLE.to_string(), |value| value.to_string())
+                };
+                let mut output = format!(
+                    "Bytes Sent: {}\nBytes Received: {}\nPackets Sent: {}\nPackets Received: {}\nConnection: {}",
+                    bytes(stats.bytes_sent),
+                    bytes(stats.bytes_received),
+                    count(stats.packets_sent),
+                    count(stats.packets_received),
+                    stats.connection_status.as_deref().unwrap_or(UNAVAILABLE)
                 );
+                if let Some(status) = &stats.ip_connection_status {
+                    output.push_str(&format!("\nIP Connection: {}", status));
+                }
+                if let Some(connection_type) = &stats.connection_type {
+                    output.push_str(&format!("\nConnection Type: {}", connection_type));
+                }
+                if let Some(uptime) = stats.uptime_seconds {
+                    output.push_str(&format!("\nUptime: {}s", uptime));
+                }
+                if let Some(error) = &stats.last_connection_error {
+                    output.push_str(&format!("\nLast Connection Error: {}", error));
+                }
+                if let Some(ip) = &stats.external_ip {
+                    output.push_str(&format!("\nExternal IP: {}", ip));
+                }
+                let throughput = collector.throughput();
+                if let Some(rate) = throughput.send_bytes_per_second {
+                    output.push_str(&format!("\nSend Rate: {}/s", format_bytes(rate as u64)));
+                }
+                if let Some(rate) = throughput.receive_bytes_per_second {
+                    output.push_str(&format!("\nReceive Rate: {}/s", format_bytes(rate as u64)));
+                }
 
                 axum::response::Response::builder()
                     .header("Content-Type", "text/plain")
@@ -84,3 +196,367 @@ async fn stats_handler(Query(params): Query<StatsQuery>) -> Response {
             .unwrap(),
     }
 }
+
+/// Lets requests through to the `/admin` endpoints only with
+/// `Authorization: Bearer <server.admin_token>`
+async fn require_admin_token(
+    State(admin_token): State<Option<Secret>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = admin_token else {
+        return (
+            StatusCode::FORBIDDEN,
+            "Set server.admin_token to enable the /admin endpoints",
+        )
+            .into_response();
+    };
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.expose().as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Invalid or missing admin token",
+        )
+            .into_response(),
+    }
+}
+
+/// Compares without stopping at the first differing byte, so response
+/// times do not reveal how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn coherence_handler<P: WanStatsProvider>(
+    State(collector): State<Arc<MetricsCollector<P>>>,
+    Query(params): Query<DeviceQuery>,
+) -> Response {
+    match params.device.as_deref() {
+        Some(name) if name != collector.device_name() => match collector.device(name) {
+            Some(device) => coherence_response(device).await,
+            None => unknown_device(name),
+        },
+        _ => coherence_response(&collector).await,
+    }
+}
+
+async fn coherence_response<P: WanStatsProvider>(collector: &MetricsCollector<P>) -> Response {
+    match collector.measure_coherence().await {
+        Ok(report) => axum::response::Json(report).into_response(),
+        Err(error_msg) => axum::response::Response::builder()
+            .status(500)
+            .body(error_msg.into())
+            .unwrap(),
+    }
+}
+
+/// Running configuration as served by `/debug/config`
+#[derive(Serialize, Deserialize)]
+pub struct DebugConfig {
+    pub running: Config,
+    pub drift: DriftStatus,
+}
+
+async fn debug_config_handler(State(drift): State<Arc<ConfigDrift>>) -> Response {
+    axum::response::Json(DebugConfig {
+        running: drift.running().clone(),
+        drift: drift.status(),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockProvider;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use http_body_util::BodyExt;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    fn app_with(config: Config, stats: TrafficStats) -> Router {
+        let collector = Arc::new(MetricsCollector::with_provider(
+            MockProvider::new(stats),
+            &config,
+        ));
+        create_app(collector, Arc::new(ConfigDrift::new(config, None)))
+    }
+
+    fn admin_app(admin_token: Option<&str>) -> Router {
+        let mut config = Config::default();
+        config.server.admin_token = admin_token.map(Secret::new);
+        app_with(config, TrafficStats::default())
+    }
+
+    async fn send(app: Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn coherence_request(method: Method, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().method(method).uri("/admin/coherence");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn stats() -> TrafficStats {
+        TrafficStats {
+            bytes_sent: Some(1024),
+            bytes_received: Some(5 * 1024 * 1024),
+            packets_sent: Some(10),
+            packets_received: Some(20),
+            connection_status: Some("Connected".to_string()),
+            external_ip: Some("203.0.113.7".to_string()),
+            ..TrafficStats::default()
+        }
+    }
+
+    fn app() -> Router {
+        app_with(Config::default(), stats())
+    }
+
+    fn unreachable_app() -> Router {
+        let collector = Arc::new(MetricsCollector::with_provider(
+            MockProvider::unreachable(),
+            &Config::default(),
+        ));
+        create_app(
+            collector,
+            Arc::new(ConfigDrift::new(Config::default(), None)),
+        )
+    }
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_are_read_from_the_provider() {
+        let response = app().oneshot(get_request("/metrics")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            ExpositionFormat::Text.content_type()
+        );
+        assert_eq!(response.headers()[header::VARY], "Accept");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("upnp_wan_bytes_sent_total{device=\"default\"} 1024\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("upnp_wan_packets_received_total{device=\"default\"} 20\n"),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_in_openmetrics_when_accepted() {
+        let request = Request::builder()
+            .uri("/metrics")
+            .header(
+                header::ACCEPT,
+                "application/openmetrics-text; version=1.0.0",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            ExpositionFormat::OpenMetrics.content_type()
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.ends_with("\n# EOF\n"), "{body}");
+        assert_eq!(body.matches("# EOF").count(), 1);
+        assert!(
+            body.contains("# TYPE upnp_wan_bytes_sent counter\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("upnp_wan_bytes_sent_total{device=\"default\"} 1024\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("upnp_wan_bytes_sent_created{device=\"default\"} "),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_in_text_format_otherwise() {
+        for accept in [None, Some("text/plain;version=0.0.4"), Some("*/*")] {
+            let mut request = Request::builder().uri("/metrics");
+            if let Some(accept) = accept {
+                request = request.header(header::ACCEPT, accept);
+            }
+            let response = app()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                ExpositionFormat::Text.content_type(),
+                "{accept:?}"
+            );
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(
+                body.contains("# TYPE upnp_wan_bytes_sent_total counter\n"),
+                "{body}"
+            );
+            assert!(!body.contains("# EOF"), "{body}");
+            assert!(!body.contains("_created"), "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_reading() {
+        let collector = Arc::new(MetricsCollector::with_provider(
+            MockProvider::new(stats()).with_delay(Duration::from_millis(200)),
+            &Config::default(),
+        ));
+        let app = create_app(
+            collector.clone(),
+            Arc::new(ConfigDrift::new(Config::default(), None)),
+        );
+
+        let responses = tokio::join!(
+            send(app.clone(), get_request("/metrics")),
+            send(app.clone(), get_request("/metrics")),
+            send(app.clone(), get_request("/metrics")),
+            send(app.clone(), get_request("/stats")),
+            send(app.clone(), get_request("/stats")),
+        );
+        for (status, body) in [
+            responses.0,
+            responses.1,
+            responses.2,
+            responses.3,
+            responses.4,
+        ] {
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        assert_eq!(collector.client().read().await.readings(), 1);
+
+        // A request after the reading completed reads the gateway again
+        send(app, get_request("/metrics")).await;
+        assert_eq!(collector.client().read().await.readings(), 2);
+    }
+
+    #[tokio::test]
+    async fn metrics_of_an_unreachable_gateway() {
+        let (status, body) = send(unreachable_app(), get_request("/metrics")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("upnp_wan_bytes_sent_total{"), "{body}");
+        assert!(
+            body.contains("upnp_wan_scrape_errors_total{device=\"default\""),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_as_text() {
+        let (status, body) = send(app(), get_request("/stats")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            "Bytes Sent: 1024 / 1.00 KB\n\
+             Bytes Received: 5242880 / 5.00 MB\n\
+             Packets Sent: 10\n\
+             Packets Received: 20\n\
+             Connection: Connected\n\
+             External IP: 203.0.113.7"
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_as_json() {
+        let (status, body) = send(app(), get_request("/stats?format=json")).await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["bytes_sent"], 1024);
+        assert_eq!(json["connection_status"], "Connected");
+        assert_eq!(json["send_bytes_per_second"], serde_json::Value::Null);
+        assert!(json["unavailable"].as_array().is_some());
+    }
+
+    #[tokio::test]
+    async fn stats_of_an_unreachable_gateway() {
+        let (status, _) = send(unreachable_app(), get_request("/stats")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn health_does_not_read_the_gateway() {
+        assert_eq!(
+            send(unreachable_app(), get_request("/health")).await,
+            (StatusCode::OK, "OK".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_of_an_unknown_device() {
+        let (status, body) = send(app(), get_request("/stats?device=lte")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "No device named lte");
+    }
+
+    #[tokio::test]
+    async fn debug_config_masks_secrets() {
+        let mut config = Config::default();
+        config.upnp.password = Some(Secret::new("hunter2"));
+        let (status, body) = send(app_with(config, stats()), get_request("/debug/config")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("hunter2"));
+        let debug: DebugConfig = serde_json::from_str(&body).unwrap();
+        assert!(!debug.drift.drifted);
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_are_refused_without_a_token_configured() {
+        let (status, _) = send(admin_app(None), coherence_request(Method::POST, Some("x"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_require_the_configured_token() {
+        let (status, _) = send(
+            admin_app(Some("s3cret")),
+            coherence_request(Method::POST, None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = coherence_request(Method::POST, Some("wrong"));
+        let (status, _) = send(admin_app(Some("s3cret")), request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn coherence_is_post_only() {
+        let request = coherence_request(Method::GET, Some("s3cret"));
+        let (status, _) = send(admin_app(Some("s3cret")), request).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3crex"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret!"));
+    }
+}
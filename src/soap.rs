@@ -0,0 +1,471 @@
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::borrow::Cow;
+use std::fmt;
+
+/// UPnP error codes a device returns when it does not know the action as
+/// addressed, which for v2-advertising devices often means "send the :1 URN"
+const VERSION_MISMATCH_ERROR_CODES: [u32; 2] = [401, 403];
+
+/// SpecifiedArrayIndexInvalid, marking the end of an indexed table such as the port mappings
+pub const ARRAY_INDEX_INVALID: u32 = 713;
+
+/// Largest SOAP response body read; even a full port mapping list stays far below
+pub const MAX_RESPONSE_BYTES: usize = 256 * 1024;
+
+/// How much of an unparseable payload is quoted in the error
+const EXCERPT_BYTES: usize = 200;
+
+/// A SOAP action invocation against a UPnP service
+#[derive(Debug, Clone)]
+pub struct Action {
+    service_type: String,
+    name: String,
+    args: Vec<(String, String)>,
+}
+
+impl Action {
+    pub fn new(service_type: &str, name: &str) -> Self {
+        Self {
+            service_type: service_type.to_string(),
+            name: name.to_string(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, name: &str, value: impl ToString) -> Self {
+        self.args.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn service_type(&self) -> &str {
+        &self.service_type
+    }
+
+    /// Value of the SOAPAction header, without the surrounding quotes
+    pub fn soap_action(&self) -> String {
+        format!("{}#{}", self.service_type, self.name)
+    }
+
+    pub fn envelope(&self) -> String {
+        let mut args = String::new();
+        for (name, value) in &self.args {
+            args.push_str(&format!("\n            <{name}>{}</{name}>", escape(value)));
+        }
+        let action = if args.is_empty() {
+            format!("<u:{} xmlns:u=\"{}\" />", self.name, self.service_type)
+        } else {
+            format!(
+                "<u:{name} xmlns:u=\"{}\">{args}\n        </u:{name}>",
+                self.service_type,
+                name = self.name
+            )
+        };
+
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+    <s:Body>
+        {}
+    </s:Body>
+</s:Envelope>"#,
+            action
+        )
+    }
+
+    /// The same action addressed to version 1 of the service, if this one
+    /// targets a later version
+    pub fn with_version_1(&self) -> Option<Self> {
+        let service_type = version_1_service_type(&self.service_type)?;
+        Some(Self {
+            service_type,
+            ..self.clone()
+        })
+    }
+}
+
+/// Map "urn:...:service:Name:N" to "urn:...:service:Name:1" for N > 1
+fn version_1_service_type(service_type: &str) -> Option<String> {
+    let (prefix, version) = service_type.rsplit_once(':')?;
+    match version.parse::<u32>() {
+        Ok(v) if v > 1 => Some(format!("{}:1", prefix)),
+        _ => None,
+    }
+}
+
+/// Local names of the action responses in the SOAP Body, e.g.
+/// ["GetTotalBytesSentResponse"]. Other Body children, such as the
+/// diagnostics some firmwares add, are skipped. Stops at unreadable XML.
+pub fn body_responses(body: &str) -> Vec<String> {
+    let mut reader = xml_reader(body);
+    let mut responses = Vec::new();
+    let mut depth = 0;
+    // Depth of the Body element once inside it
+    let mut body_depth: Option<usize> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                depth += 1;
+                let name = local_name(&element);
+                match body_depth {
+                    None if name == "Body" => body_depth = Some(depth),
+                    Some(level) if depth == level + 1 && name.ends_with("Response") => {
+                        responses.push(name.to_string())
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::End(_)) => {
+                if body_depth == Some(depth) {
+                    break;
+                }
+                depth -= 1;
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    responses
+}
+
+/// A reader over `xml` reporting `<x/>` as a start and an end element, so
+/// that callers need not tell empty elements apart
+pub fn xml_reader(xml: &str) -> Reader<&[u8]> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().expand_empty_elements = true;
+    reader
+}
+
+/// Name of `element` without its namespace prefix
+pub fn local_name<'a>(element: &'a BytesStart<'_>) -> &'a str {
+    // Names of a reader over a `str` are valid UTF-8
+    str::from_utf8(element.local_name().into_inner()).unwrap_or_default()
+}
+
+/// Undo the two breakages seen in the wild that make a response unparseable:
+/// bare `&` in text (e.g. "Up & Running") and trailing bytes after the
+/// closing Envelope tag. Well-formed responses are returned as they are.
+pub fn repair_response(body: &str) -> Cow<'_, str> {
+    let mut body = Cow::Borrowed(body);
+    if let Some(end) = envelope_end(&body)
+        && !body[end..].trim().is_empty()
+    {
+        body = Cow::Owned(body[..end].to_string());
+    }
+
+    if body
+        .match_indices('&')
+        .any(|(i, _)| !starts_with_entity(&body[i..]))
+    {
+        let mut escaped = String::with_capacity(body.len() + 16);
+        let mut rest = body.as_ref();
+        while let Some(i) = rest.find('&') {
+            escaped.push_str(&rest[..i]);
+            escaped.push_str(if starts_with_entity(&rest[i..]) {
+                "&"
+            } else {
+                "&amp;"
+            });
+            rest = &rest[i + 1..];
+        }
+        escaped.push_str(rest);
+        body = Cow::Owned(escaped);
+    }
+    body
+}
+
+/// Offset just past the closing `</prefix:Envelope>` tag, if there is one
+fn envelope_end(body: &str) -> Option<usize> {
+    let tag = body.rfind("Envelope>")?;
+    let open = body[..tag].rfind('<')?;
+    body[open..]
+        .starts_with("</")
+        .then_some(tag + "Envelope>".len())
+}
+
+/// Whether `text`, starting at a `&`, is a character or entity reference
+fn starts_with_entity(text: &str) -> bool {
+    let Some(end) = text[1..].find(';') else {
+        return false;
+    };
+    let name = &text[1..=end];
+    match name.strip_prefix('#') {
+        Some(code) => match code.strip_prefix(['x', 'X']) {
+            Some(hex) => !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            None => !code.is_empty() && code.chars().all(|c| c.is_ascii_digit()),
+        },
+        None => {
+            name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+                && name.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+    }
+}
+
+/// The start of a payload, cut at a character boundary, for error messages
+pub fn excerpt(body: &str) -> &str {
+    if body.len() <= EXCERPT_BYTES {
+        return body;
+    }
+    let mut end = EXCERPT_BYTES;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    &body[..end]
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A request the gateway rejected, either with a SOAP fault or a bare HTTP error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoapFault {
+    /// Name of the action that was invoked
+    pub action: String,
+    /// HTTP status of the response
+    pub status: u16,
+    /// UPnPError errorCode, e.g. 606
+    pub code: Option<u32>,
+    /// UPnPError errorDescription, e.g. "Action not authorized"
+    pub description: Option<String>,
+    /// SOAP faultstring, usually just "UPnPError"
+    pub fault_string: Option<String>,
+}
+
+impl SoapFault {
+    /// The fault carried by a response, `None` for a successful one
+    pub fn from_response(action: &str, status: u16, body: &str) -> Option<Self> {
+        let success = (200..300).contains(&status);
+        if success && !body.contains("Fault>") {
+            return None;
+        }
+
+        let mut fault = Self {
+            action: action.to_string(),
+            status,
+            code: None,
+            description: None,
+            fault_string: None,
+        };
+        let mut reader = xml_reader(body);
+        let mut current: Option<String> = None;
+        let mut is_fault = false;
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(element)) => {
+                    let name = local_name(&element);
+                    is_fault |= name == "Fault";
+                    current = Some(name.to_string());
+                }
+                Ok(Event::Text(text)) => {
+                    let Ok(text) = text.unescape() else {
+                        break;
+                    };
+                    // Indentation between elements is not the text of the element before
+                    let text = text.trim();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    match current.take().as_deref() {
+                        Some("errorCode") => fault.code = text.parse().ok(),
+                        Some("errorDescription") => fault.description = Some(text.to_string()),
+                        Some("faultstring") => fault.fault_string = Some(text.to_string()),
+                        _ => {}
+                    }
+                }
+                Ok(Event::End(_)) => current = None,
+                Ok(Event::Eof) | Err(_) => break,
+                _ => {}
+            }
+        }
+
+        (!success || is_fault).then_some(fault)
+    }
+
+    /// Whether the fault suggests the device rejected the advertised service version
+    pub fn is_version_mismatch(&self) -> bool {
+        self.code
+            .is_some_and(|code| VERSION_MISMATCH_ERROR_CODES.contains(&code))
+    }
+
+    /// Short classification for metric labels, e.g. "upnp_606" or "http_500"
+    pub fn reason(&self) -> String {
+        match self.code {
+            Some(code) => format!("upnp_{}", code),
+            None => format!("http_{}", self.status),
+        }
+    }
+}
+
+impl fmt::Display for SoapFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, &self.description, &self.fault_string) {
+            (Some(code), Some(description), _) => {
+                write!(f, "UPnP error {}: {} ({})", code, description, self.action)
+            }
+            (Some(code), None, _) => write!(f, "UPnP error {} ({})", code, self.action),
+            (None, _, Some(fault_string)) => {
+                write!(f, "SOAP fault: {} ({})", fault_string, self.action)
+            }
+            (None, _, None) => write!(f, "HTTP {} ({})", self.status, self.action),
+        }
+    }
+}
+
+impl std::error::Error for SoapFault {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAULT: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <s:Fault>
+      <faultcode>s:Client</faultcode>
+      <faultstring>UPnPError</faultstring>
+      <detail>
+        <UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+          <errorCode>606</errorCode>
+          <errorDescription>Action not authorized</errorDescription>
+        </UPnPError>
+      </detail>
+    </s:Fault>
+  </s:Body>
+</s:Envelope>"#;
+
+    #[test]
+    fn body_responses_lists_the_action_responses() {
+        let body = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+  <s:Body>
+    <u:GetTotalBytesSentResponse xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1">
+      <NewTotalBytesSent>42</NewTotalBytesSent>
+    </u:GetTotalBytesSentResponse>
+    <x:Diagnostics xmlns:x="urn:example"><x:NestedResponse/></x:Diagnostics>
+  </s:Body>
+</s:Envelope>"#;
+        assert_eq!(body_responses(body), ["GetTotalBytesSentResponse"]);
+    }
+
+    #[test]
+    fn body_responses_of_an_empty_response_element() {
+        let body = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:ForceTerminationResponse xmlns:u="urn:x"/></s:Body></s:Envelope>"#;
+        assert_eq!(body_responses(body), ["ForceTerminationResponse"]);
+    }
+
+    #[test]
+    fn body_responses_stops_at_unreadable_xml() {
+        assert!(body_responses("<s:Envelope><s:Body><oops").is_empty());
+        assert!(body_responses("not xml at all").is_empty());
+    }
+
+    #[test]
+    fn fault_is_read_from_the_detail() {
+        let fault = SoapFault::from_response("GetAddonInfos", 500, FAULT).unwrap();
+        assert_eq!(fault.code, Some(606));
+        assert_eq!(fault.description.as_deref(), Some("Action not authorized"));
+        assert_eq!(fault.fault_string.as_deref(), Some("UPnPError"));
+        assert_eq!(fault.reason(), "upnp_606");
+        assert_eq!(
+            fault.to_string(),
+            "UPnP error 606: Action not authorized (GetAddonInfos)"
+        );
+    }
+
+    #[test]
+    fn fault_in_a_200_response() {
+        let fault = SoapFault::from_response("GetAddonInfos", 200, FAULT).unwrap();
+        assert_eq!(fault.code, Some(606));
+    }
+
+    #[test]
+    fn successful_response_is_no_fault() {
+        let body = "<s:Envelope><s:Body><u:XResponse/></s:Body></s:Envelope>";
+        assert_eq!(SoapFault::from_response("X", 200, body), None);
+    }
+
+    #[test]
+    fn bare_http_error() {
+        let fault = SoapFault::from_response("X", 500, "Internal Server Error").unwrap();
+        assert_eq!(fault.code, None);
+        assert_eq!(fault.reason(), "http_500");
+        assert_eq!(fault.to_string(), "HTTP 500 (X)");
+    }
+
+    #[test]
+    fn version_mismatch_codes() {
+        let fault = SoapFault::from_response("X", 500, &FAULT.replace("606", "401")).unwrap();
+        assert!(fault.is_version_mismatch());
+    }
+
+    #[test]
+    fn repair_escapes_bare_ampersands_and_drops_trailing_bytes() {
+        let body = "<s:Envelope><a>Up & Running &amp; &#38; &#x26;</a></s:Envelope>\0\0garbage";
+        assert_eq!(
+            repair_response(body),
+            "<s:Envelope><a>Up &amp; Running &amp; &#38; &#x26;</a></s:Envelope>"
+        );
+        let body = "<s:Envelope><a>fine</a></s:Envelope>\n";
+        assert!(matches!(repair_response(body), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn envelope_addresses_version_1() {
+        let action = Action::new(
+            "urn:schemas-upnp-org:service:WANIPConnection:2",
+            "GetStatusInfo",
+        )
+        .arg("NewValue", "a<b");
+        assert!(action.envelope().contains("<NewValue>a&lt;b</NewValue>"));
+        assert_eq!(
+            action.with_version_1().unwrap().soap_action(),
+            "urn:schemas-upnp-org:service:WANIPConnection:1#GetStatusInfo"
+        );
+        assert!(action.with_version_1().unwrap().with_version_1().is_none());
+    }
+
+    #[test]
+    fn envelope_and_header_use_the_given_service_type() {
+        for service in [
+            "WANCommonInterfaceConfig",
+            "WANIPConnection",
+            "WANPPPConnection",
+        ] {
+            for version in 1..=2 {
+                let service_type = format!("urn:schemas-upnp-org:service:{service}:{version}");
+                let action = Action::new(&service_type, "GetStatusInfo");
+                assert_eq!(
+                    action.soap_action(),
+                    format!("{service_type}#GetStatusInfo")
+                );
+                assert_eq!(
+                    action.envelope(),
+                    format!(
+                        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+    <s:Body>
+        <u:GetStatusInfo xmlns:u="{service_type}" />
+    </s:Body>
+</s:Envelope>"#
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn excerpt_cuts_at_a_character_boundary() {
+        let body = "é".repeat(EXCERPT_BYTES);
+        assert_eq!(excerpt(&body).len(), EXCERPT_BYTES);
+        assert_eq!(excerpt("short"), "short");
+    }
+}
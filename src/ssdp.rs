@@ -0,0 +1,363 @@
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+pub const UPNP_MULTICAST_ADDR: &str = "239.255.255.250:1900";
+/// Link-local and site-local SSDP groups
+pub const UPNP_MULTICAST_ADDRS_V6: [&str; 2] = ["[FF02::C]:1900", "[FF05::C]:1900"];
+// SSDP messages are single datagrams, so this comfortably exceeds the path MTU
+pub const SSDP_BUFFER_SIZE: usize = 8192;
+const IGD_DEVICE_TYPE_PREFIX: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:";
+
+/// A parsed M-SEARCH response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SsdpResponse {
+    pub location: Option<String>,
+    pub usn: Option<String>,
+    pub st: Option<String>,
+    pub server: Option<String>,
+    pub cache_control_max_age: Option<u64>,
+    pub bootid: Option<u32>,
+}
+
+impl SsdpResponse {
+    pub fn parse(response: &str) -> Result<Self> {
+        let mut lines = response.lines();
+        let status_line = lines.next().unwrap_or_default().trim();
+        let mut status = status_line.split_whitespace();
+        let version = status.next().unwrap_or_default();
+        let code = status.next().unwrap_or_default();
+        if !version.to_ascii_uppercase().starts_with("HTTP/") || code != "200" {
+            return Err(anyhow!("Not an SSDP search response: {}", status_line));
+        }
+
+        let mut parsed = Self::default();
+        for (name, value) in parse_headers(lines) {
+            match name.as_str() {
+                "location" => parsed.location = Some(value),
+                "usn" => parsed.usn = Some(value),
+                "st" => parsed.st = Some(value),
+                "server" => parsed.server = Some(value),
+                "cache-control" => parsed.cache_control_max_age = parse_max_age(&value),
+                "bootid.upnp.org" => parsed.bootid = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Whether the response comes from an InternetGatewayDevice (any version)
+    /// rather than some other device answering the multicast search
+    pub fn is_gateway(&self) -> bool {
+        [&self.st, &self.usn]
+            .into_iter()
+            .flatten()
+            .any(|value| value.contains(IGD_DEVICE_TYPE_PREFIX))
+    }
+}
+
+/// A parsed NOTIFY advertisement
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SsdpNotify {
+    pub nt: Option<String>,
+    pub nts: Option<String>,
+    pub usn: Option<String>,
+    pub location: Option<String>,
+    pub cache_control_max_age: Option<u64>,
+}
+
+impl SsdpNotify {
+    pub fn parse(message: &str) -> Result<Self> {
+        let mut lines = message.lines();
+        let request_line = lines.next().unwrap_or_default().trim();
+        if !request_line.to_ascii_uppercase().starts_with("NOTIFY ") {
+            return Err(anyhow!("Not an SSDP NOTIFY message: {}", request_line));
+        }
+
+        let mut parsed = Self::default();
+        for (name, value) in parse_headers(lines) {
+            match name.as_str() {
+                "nt" => parsed.nt = Some(value),
+                "nts" => parsed.nts = Some(value),
+                "usn" => parsed.usn = Some(value),
+                "location" => parsed.location = Some(value),
+                "cache-control" => parsed.cache_control_max_age = parse_max_age(&value),
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// The device UUID part of a USN such as "uuid:1234::urn:schemas-upnp-org:service:..."
+pub fn usn_uuid(usn: &str) -> &str {
+    usn.split("::").next().unwrap_or(usn)
+}
+
+/// Parse HTTPU header lines into lowercase names and trimmed values,
+/// joining folded continuation lines onto the preceding header
+pub fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in lines {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            break;
+        }
+
+        // Indented lines continue the previous header, unless some sloppy
+        // stack merely indented a complete "Name: value" line
+        if line.starts_with([' ', '\t']) && !is_header_line(line) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        // Split on the first colon only so URLs with ports stay intact
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    headers
+}
+
+/// Whether a line looks like "Name: value", allowing whitespace around the
+/// name, but not like a bare "scheme://" URL continuing a folded value
+fn is_header_line(line: &str) -> bool {
+    let Some((name, value)) = line.split_once(':') else {
+        return false;
+    };
+    let name = name.trim();
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+        && !value.starts_with("//")
+}
+
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+    cache_control.split(',').find_map(|directive| {
+        let (name, value) = directive.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("max-age") {
+            value.trim().trim_matches('"').parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_response_larger_than_1024_bytes() {
+        let server = format!("Linux UPnP/1.0 Vendor/1.0 {}", "x".repeat(1200));
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nSERVER: {server}\r\n\
+             BOOTID.UPNP.ORG: 42\r\nCONFIGID.UPNP.ORG: 7\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+             LOCATION: http://192.168.1.1:49000/igddesc.xml\r\n\r\n"
+        );
+        assert!(response.len() > 1024);
+
+        let parsed = SsdpResponse::parse(&response).unwrap();
+        assert_eq!(
+            parsed.location.as_deref(),
+            Some("http://192.168.1.1:49000/igddesc.xml")
+        );
+        assert_eq!(parsed.server.as_deref(), Some(server.as_str()));
+        assert_eq!(parsed.bootid, Some(42));
+        assert_eq!(parsed.cache_control_max_age, Some(120));
+        assert!(parsed.is_gateway());
+    }
+
+    #[test]
+    fn response_without_location_parses_to_none() {
+        let response = "HTTP/1.1 200 OK\r\n\
+                        ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        let parsed = SsdpResponse::parse(response).unwrap();
+        assert_eq!(parsed.location, None);
+        assert!(parsed.is_gateway());
+    }
+
+    #[test]
+    fn parses_fritzbox_response() {
+        let response = "HTTP/1.1 200 OK\r\n\
+            LOCATION: http://192.168.178.1:49000/igd2desc.xml\r\n\
+            SERVER: FRITZ!Box 7590 UPnP/1.0 AVM FRITZ!Box 7590 154.07.57\r\n\
+            CACHE-CONTROL: max-age=1800\r\n\
+            EXT:\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:2\r\n\
+            USN: uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC::urn:schemas-upnp-org:device:InternetGatewayDevice:2\r\n\r\n";
+
+        let parsed = SsdpResponse::parse(response).unwrap();
+        assert_eq!(
+            parsed,
+            SsdpResponse {
+                location: Some("http://192.168.178.1:49000/igd2desc.xml".to_string()),
+                usn: Some(
+                    "uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC::urn:schemas-upnp-org:device:InternetGatewayDevice:2"
+                        .to_string()
+                ),
+                st: Some("urn:schemas-upnp-org:device:InternetGatewayDevice:2".to_string()),
+                server: Some("FRITZ!Box 7590 UPnP/1.0 AVM FRITZ!Box 7590 154.07.57".to_string()),
+                cache_control_max_age: Some(1800),
+                bootid: None,
+            }
+        );
+        assert_eq!(
+            usn_uuid(parsed.usn.as_deref().unwrap()),
+            "uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC"
+        );
+    }
+
+    #[test]
+    fn parses_miniupnpd_response() {
+        // miniupnpd sends mixed-case names and the UPnP 1.1 extension headers
+        let response = "HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=120\r\n\
+            St: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            Usn: uuid:2d150ae4-9a05-4a8c-b5b6-0123456789ab::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            EXT:\r\n\
+            Server: OpenWRT/23.05 UPnP/1.1 MiniUPnPd/2.3.3\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+            OPT: \"http://schemas.upnp.org/upnp/1/0/\"; ns=01\r\n\
+            01-NLS: 1712345678\r\n\
+            BOOTID.UPNP.ORG: 1712345678\r\n\
+            CONFIGID.UPNP.ORG: 1337\r\n\r\n";
+
+        let parsed = SsdpResponse::parse(response).unwrap();
+        assert_eq!(
+            parsed.location.as_deref(),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(
+            parsed.server.as_deref(),
+            Some("OpenWRT/23.05 UPnP/1.1 MiniUPnPd/2.3.3")
+        );
+        assert_eq!(parsed.cache_control_max_age, Some(120));
+        assert_eq!(parsed.bootid, Some(1712345678));
+        assert!(parsed.is_gateway());
+    }
+
+    #[test]
+    fn parses_dlink_response() {
+        // Lowercase status line, quoted max-age, no trailing blank line and a
+        // SERVER value folded onto a continuation line
+        let response = "http/1.1 200 OK\r\n\
+            cache-control: no-cache=\"Ext\", max-age = \"100\"\r\n\
+            date: Thu, 01 Jan 1970 00:04:03 GMT\r\n\
+            ext:\r\n\
+            location: http://192.168.0.1:49152/InternetGatewayDevice.xml\r\n\
+            server: Linux, UPnP/1.0,\r\n\
+            \x20DIR-842 Ver 1.00\r\n\
+            st: upnp:rootdevice\r\n\
+            usn: uuid:00000000-0000-0001-0000-b0c554aabbcc::urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+        let parsed = SsdpResponse::parse(response).unwrap();
+        assert_eq!(
+            parsed.location.as_deref(),
+            Some("http://192.168.0.1:49152/InternetGatewayDevice.xml")
+        );
+        assert_eq!(
+            parsed.server.as_deref(),
+            Some("Linux, UPnP/1.0, DIR-842 Ver 1.00")
+        );
+        assert_eq!(parsed.st.as_deref(), Some("upnp:rootdevice"));
+        assert_eq!(parsed.cache_control_max_age, Some(100));
+        assert_eq!(parsed.bootid, None);
+        // Identified as a gateway by its USN alone
+        assert!(parsed.is_gateway());
+    }
+
+    #[test]
+    fn indented_complete_header_is_not_folded() {
+        let response = "HTTP/1.1 200 OK\r\n\
+            SERVER: Vendor/1.0\r\n\
+            \x20LOCATION: http://10.0.0.1/desc.xml\r\n\r\n";
+        let parsed = SsdpResponse::parse(response).unwrap();
+        assert_eq!(parsed.server.as_deref(), Some("Vendor/1.0"));
+        assert_eq!(parsed.location.as_deref(), Some("http://10.0.0.1/desc.xml"));
+    }
+
+    #[test]
+    fn rejects_other_messages() {
+        assert!(SsdpResponse::parse("HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+        assert!(SsdpResponse::parse("M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n").is_err());
+        assert!(SsdpResponse::parse("").is_err());
+    }
+
+    #[test]
+    fn non_gateway_response_is_not_a_gateway() {
+        let response = "HTTP/1.1 200 OK\r\n\
+            ST: urn:schemas-upnp-org:device:MediaRenderer:1\r\n\
+            USN: uuid:abc::urn:schemas-upnp-org:device:MediaRenderer:1\r\n\
+            LOCATION: http://192.168.1.20:8080/desc.xml\r\n\r\n";
+        assert!(!SsdpResponse::parse(response).unwrap().is_gateway());
+    }
+
+    #[test]
+    fn finds_location_however_it_is_written() {
+        let cases = [
+            (
+                "upper case",
+                "LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n",
+                Some("http://192.168.1.1:5000/rootDesc.xml"),
+            ),
+            (
+                "mixed case",
+                "LoCaTiOn: http://192.168.1.1:5000/rootDesc.xml\r\n",
+                Some("http://192.168.1.1:5000/rootDesc.xml"),
+            ),
+            (
+                "space before colon",
+                "Location : http://10.0.0.138:80/root.sxml\r\n",
+                Some("http://10.0.0.138:80/root.sxml"),
+            ),
+            (
+                "no space after colon",
+                "LOCATION:http://10.0.0.138/root.sxml\r\n",
+                Some("http://10.0.0.138/root.sxml"),
+            ),
+            (
+                "leading whitespace",
+                "  Location:   http://192.168.0.1:49152/desc.xml  \r\n",
+                Some("http://192.168.0.1:49152/desc.xml"),
+            ),
+            (
+                "https with port",
+                "LOCATION: https://192.168.1.1:49443/igd.xml?v=2#x\r\n",
+                Some("https://192.168.1.1:49443/igd.xml?v=2#x"),
+            ),
+            (
+                "ipv6 with port",
+                "LOCATION: http://[fe80::1]:49000/igddesc.xml\r\n",
+                Some("http://[fe80::1]:49000/igddesc.xml"),
+            ),
+            (
+                "LF line endings",
+                "LOCATION: http://192.168.1.1:5000/rootDesc.xml\n",
+                Some("http://192.168.1.1:5000/rootDesc.xml"),
+            ),
+            ("no location", "SERVER: Vendor/1.0\r\n", None),
+        ];
+
+        for (case, header, expected) in cases {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n{header}EXT:\r\n\r\n"
+            );
+            let response = if header.ends_with("\r\n") {
+                response
+            } else {
+                response.replace("\r\n", "\n")
+            };
+            let parsed = SsdpResponse::parse(&response).unwrap();
+            assert_eq!(parsed.location.as_deref(), expected, "{case}");
+        }
+    }
+}
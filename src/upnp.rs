@@ -1,438 +1,3248 @@
+use crate::auth::Authenticator;
+use crate::charset;
+use crate::config::{DeviceSelection, IpVersion, Secret, SoapActionFormat, UpnpConfig};
+use crate::description::{
+    self, DeviceInfo, UpnpService, WanConnection, WanConnectionKind, WanInterface,
+};
+use crate::error::{UpnpError, UpnpResult};
+use crate::gena::{EventState, EventedValues, Subscription};
+use crate::metrics::Metrics;
+use crate::soap::{self, Action, SoapFault};
+use crate::ssdp::{
+    self, SSDP_BUFFER_SIZE, SsdpResponse, UPNP_MULTICAST_ADDR, UPNP_MULTICAST_ADDRS_V6,
+};
 use anyhow::{Result, anyhow};
-use reqwest::Client;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use regex::Regex;
+use reqwest::{
+    Certificate, Client, Method, Proxy, RequestBuilder, Response, StatusCode, Url, Version,
+    header::{self, HeaderMap, HeaderValue},
+    redirect,
+};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tracing::{debug, error, warn};
-use xml::reader::{EventReader, XmlEvent};
-
-const UPNP_MULTICAST_ADDR: &str = "239.255.255.250:1900";
-const UPNP_SEARCH_MSG: &str = concat!(
-    "M-SEARCH * HTTP/1.1\r\n",
-    "HOST: 239.255.255.250:1900\r\n",
-    "MAN: \"ssdp:discover\"\r\n",
-    "ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n",
-    "MX: 3\r\n\r\n"
-);
-
-#[derive(Debug, Clone)]
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, trace, warn};
+
+const SSDP_INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Used when a response carries no CACHE-CONTROL max-age (UDA recommends at least 1800s)
+const SSDP_DEFAULT_MAX_AGE: u64 = 1800;
+// Assumed for control URLs configured without a description to read them from
+const SERVICE_TYPE_PREFIX: &str = "urn:schemas-upnp-org:service:";
+const SOAP_CONTENT_TYPE: &str = "text/xml; charset=\"utf-8\"";
+// Enough for an http -> https hop plus a moved description path
+const MAX_REDIRECTS: usize = 3;
+// Firmwares that never answer SpecifiedArrayIndexInvalid would loop forever
+const MAX_PORT_MAPPINGS: u32 = 1000;
+// A WANDevice rarely has more than one or two connections up at once
+const MAX_ACTIVE_CONNECTIONS: u32 = 32;
+// Namespace of the control actions every UPnP 1.0 service answers
+const QUERY_SERVICE_TYPE: &str = "urn:schemas-upnp-org:control-1-0";
+const UPNP_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+tokio::task_local! {
+    /// End of the scrape the current task reads the gateway for
+    static SCRAPE_DEADLINE: Instant;
+}
+
+/// Run `reading` with SOAP retries bounded by `deadline`, the end of the
+/// scrape it belongs to. Outside of one, each action gets `upnp.scrape_timeout`.
+pub async fn with_scrape_deadline<F: Future>(deadline: Instant, reading: F) -> F::Output {
+    SCRAPE_DEADLINE.scope(deadline, reading).await
+}
+
+/// Every action the exporter may send, the names `upnp.disabled_actions` accepts
+pub const ACTIONS: &[&str] = &[
+    "GetActiveConnection",
+    "GetAddonInfos",
+    "GetAutoConfig",
+    "GetCableLinkConfigInfo",
+    "GetCommonLinkProperties",
+    "GetConnectionTypeInfo",
+    "GetDSLLinkInfo",
+    "GetDefaultConnectionService",
+    "GetEthernetLinkStatus",
+    "GetExternalIPAddress",
+    "GetGenericPortMappingEntry",
+    "GetLinkLayerMaxBitRates",
+    "GetListOfPortMappings",
+    "GetNATRSIPStatus",
+    "GetStatusInfo",
+    "GetTotalBytesReceived",
+    "GetTotalBytesSent",
+    "GetTotalPacketsReceived",
+    "GetTotalPacketsSent",
+    "QueryStateVariable",
+];
+
+/// A SOAP request in flight, counted in `upnp_wan_soap_requests_in_flight`
+struct SoapSlot<'a> {
+    _permit: SemaphorePermit<'a>,
+    metrics: Option<&'a Metrics>,
+    device: &'a str,
+}
+
+impl Drop for SoapSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.metrics {
+            metrics.add_soap_requests_in_flight(self.device, -1.0);
+        }
+    }
+}
+
+/// Read a response body, failing with `too_large` once it grows past `limit` bytes
+async fn read_limited(
+    mut response: Response,
+    limit: usize,
+    too_large: impl Fn() -> anyhow::Error,
+) -> Result<Vec<u8>> {
+    if response
+        .content_length()
+        .is_some_and(|len| len > limit as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn gena_method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("GENA method names are valid tokens")
+}
+
+/// The subscription a SUBSCRIBE response grants; without a TIMEOUT header
+/// the `requested` duration is assumed
+fn granted_subscription(
+    event_sub_url: &str,
+    response: Response,
+    requested: u64,
+) -> Result<Subscription> {
+    let response = response.error_for_status()?;
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    let sid = header("SID")
+        .filter(|sid| !sid.is_empty())
+        .ok_or_else(|| UpnpError::parse("SID", "missing from the SUBSCRIBE response"))?;
+    let timeout = header("TIMEOUT")
+        .and_then(|timeout| timeout.split_once('-'))
+        .filter(|(unit, _)| unit.eq_ignore_ascii_case("Second"))
+        .and_then(|(_, seconds)| seconds.parse().ok())
+        .unwrap_or(requested);
+    Ok(Subscription {
+        event_sub_url: event_sub_url.to_string(),
+        sid: sid.to_string(),
+        timeout,
+    })
+}
+
+/// A port mapping from the output arguments of GetGenericPortMappingEntry or
+/// an entry of a GetListOfPortMappings listing, which names two of them differently
+fn port_mapping_from_values(mut values: HashMap<String, String>) -> Result<PortMapping> {
+    let mut required = |name: &str| {
+        values
+            .remove(name)
+            .ok_or_else(|| UpnpError::parse(name, "not found in response"))
+    };
+    let number = |name: &str, value: String| value.parse().map_err(|e| UpnpError::parse(name, e));
+
+    let external_port = number("NewExternalPort", required("NewExternalPort")?)?;
+    let protocol = required("NewProtocol")?;
+    let internal_port = number("NewInternalPort", required("NewInternalPort")?)?;
+    let internal_client = required("NewInternalClient")?;
+    Ok(PortMapping {
+        remote_host: values
+            .remove("NewRemoteHost")
+            .filter(|host| !host.is_empty()),
+        external_port,
+        protocol,
+        internal_port,
+        internal_client,
+        enabled: values
+            .remove("NewEnabled")
+            .and_then(|enabled| parse_upnp_bool(&enabled))
+            .unwrap_or(false),
+        description: values
+            .remove("NewPortMappingDescription")
+            .or_else(|| values.remove("NewDescription"))
+            .unwrap_or_default(),
+        lease_duration: values
+            .remove("NewLeaseDuration")
+            .or_else(|| values.remove("NewLeaseTime"))
+            .and_then(|lease| lease.parse().ok())
+            .unwrap_or(0),
+    })
+}
+
+/// The entries of a `<p:PortMappingList>` document
+fn parse_port_mapping_list(xml: &str) -> Result<Vec<PortMapping>> {
+    let malformed = |e: &dyn fmt::Display| -> anyhow::Error {
+        UpnpError::parse(
+            "NewPortListing",
+            format!(
+                "malformed PortMappingList ({}), listing starts with {:?}",
+                e,
+                soap::excerpt(xml)
+            ),
+        )
+        .into()
+    };
+    let mut reader = soap::xml_reader(xml);
+    let mut mappings = Vec::new();
+    let mut depth = 0;
+    let mut entry: Option<HashMap<String, String>> = None;
+    let mut field: Option<(String, String)> = None;
+    loop {
+        match reader.read_event().map_err(|e| malformed(&e))? {
+            Event::Start(element) => {
+                depth += 1;
+                let name = soap::local_name(&element);
+                match &entry {
+                    None if name == "PortMappingEntry" => entry = Some(HashMap::new()),
+                    Some(_) => field = Some((name.to_string(), String::new())),
+                    None => {}
+                }
+            }
+            Event::Text(text) => {
+                if let Some((_, value)) = field.as_mut() {
+                    value.push_str(&text.unescape().map_err(|e| malformed(&e))?);
+                }
+            }
+            Event::End(element) => {
+                depth -= 1;
+                if element.local_name().as_ref() == b"PortMappingEntry" {
+                    if let Some(values) = entry.take() {
+                        mappings.push(port_mapping_from_values(values)?);
+                    }
+                } else if let (Some(values), Some((name, value))) = (entry.as_mut(), field.take()) {
+                    values.insert(name, value.trim().to_string());
+                }
+            }
+            Event::Eof if depth > 0 => return Err(malformed(&"unexpected end of document")),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(mappings)
+}
+
+/// Build an M-SEARCH request whose HOST header names the address it is sent to
+fn search_message(host: &str) -> String {
+    format!(
+        concat!(
+            "M-SEARCH * HTTP/1.1\r\n",
+            "HOST: {}\r\n",
+            "MAN: \"ssdp:discover\"\r\n",
+            "ST: {}\r\n",
+            "MX: 3\r\n\r\n"
+        ),
+        host, UPNP_SEARCH_TARGET
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct UpnpDevice {
     pub location: String,
-    pub wan_common_service_url: Option<String>,
-    pub wan_ip_service_url: Option<String>,
+    /// Unique service name from the SSDP response, absent for configured locations
+    pub usn: Option<String>,
+    /// SERVER header from the SSDP response
+    pub server: Option<String>,
+    /// The device is served by the host this exporter runs on
+    pub is_local: bool,
+    /// Identity of the root device from its description
+    pub info: DeviceInfo,
+    /// WAN interfaces in description order; the first one is the primary interface
+    pub wan_interfaces: Vec<WanInterface>,
+    /// Layer3Forwarding service of the root device, naming the default connection
+    pub layer3_forwarding: Option<UpnpService>,
+    /// The description document as fetched from `location`, at most
+    /// `max_description_size` bytes; absent for configured control URLs
+    #[serde(skip)]
+    pub description: Option<String>,
+}
+
+impl UpnpDevice {
+    pub fn primary_interface(&self) -> Option<&WanInterface> {
+        self.wan_interfaces.first()
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// One reading of the gateway; `None` marks a value the gateway did not answer
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct TrafficStats {
-    pub bytes_sent: u64,
-    pub bytes_received: u64,
-    pub packets_sent: u64,
-    pub packets_received: u64,
-    pub connection_status: String,
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    pub packets_sent: Option<u64>,
+    pub packets_received: Option<u64>,
+    /// NewPhysicalLinkStatus, e.g. "Up"
+    pub connection_status: Option<String>,
+    /// Public address of the WAN connection, `None` while disconnected
+    #[serde(default)]
+    pub external_ip: Option<String>,
+    /// NewConnectionStatus of the WANIP/WANPPPConnection, e.g. "Connected";
+    /// unlike `connection_status` (the physical link) it tracks the PPP/IP session
+    #[serde(default)]
+    pub ip_connection_status: Option<String>,
+    #[serde(default)]
+    pub uptime_seconds: Option<u64>,
+    /// NewLastConnectionError, e.g. "ERROR_NONE" or "ERROR_AUTHENTICATION_FAILURE"
+    #[serde(default)]
+    pub last_connection_error: Option<String>,
+    /// Layer-1 upstream rate (DSL sync rate), `None` when the gateway reports 0
+    #[serde(default)]
+    pub link_up_max_bitrate_bps: Option<u64>,
+    #[serde(default)]
+    pub link_down_max_bitrate_bps: Option<u64>,
+    /// PPP link-layer upstream rate from GetLinkLayerMaxBitRates (WANPPPConnection only)
+    #[serde(default)]
+    pub ppp_up_max_bitrate_bps: Option<u64>,
+    #[serde(default)]
+    pub ppp_down_max_bitrate_bps: Option<u64>,
+    /// Current upstream rate in bytes per second (AVM GetAddonInfos only)
+    #[serde(default)]
+    pub byte_send_rate: Option<u64>,
+    #[serde(default)]
+    pub byte_receive_rate: Option<u64>,
+    /// NewNATEnabled from GetNATRSIPStatus, `None` when the gateway lacks the action
+    #[serde(default)]
+    pub nat_enabled: Option<bool>,
+    /// NewRSIPAvailable from GetNATRSIPStatus
+    #[serde(default)]
+    pub rsip_available: Option<bool>,
+    /// NewConnectionType from GetConnectionTypeInfo, e.g. "IP_Routed" or "IP_Bridged"
+    #[serde(default)]
+    pub connection_type: Option<String>,
+    /// NewPossibleConnectionTypes, the types the connection could be switched to
+    #[serde(default)]
+    pub possible_connection_types: Vec<String>,
+    /// NewLinkType from GetDSLLinkInfo, e.g. "PPPoE" or "EoA" (WANDSLLinkConfig only)
+    #[serde(default)]
+    pub dsl_link_type: Option<String>,
+    /// NewLinkStatus from GetDSLLinkInfo: "Up", "Down", "Initializing" or "Unavailable"
+    #[serde(default)]
+    pub dsl_link_status: Option<String>,
+    /// NewAutoConfig from GetAutoConfig, whether the DSL link configures itself
+    #[serde(default)]
+    pub dsl_auto_config: Option<bool>,
+    /// NumberOfActiveConnections, or the number of connections GetActiveConnection
+    /// listed at setup; `None` when the gateway reports neither
+    #[serde(default)]
+    pub active_connections: Option<u64>,
+    /// NewCableLinkConfigState from GetCableLinkConfigInfo, the DOCSIS
+    /// initialization stage, e.g. "operational" (WANCableLinkConfig only)
+    #[serde(default)]
+    pub cable_link_state: Option<String>,
+    /// NewLinkType from GetCableLinkConfigInfo, e.g. "Ethernet"
+    #[serde(default)]
+    pub cable_link_type: Option<String>,
+    /// The WANDevice the gateway routes through, on gateways with a
+    /// WANPOTSLinkConfig backup uplink
+    #[serde(default)]
+    pub active_uplink: Option<Uplink>,
+    /// Core values not asked for because their action is disabled by
+    /// `upnp.disabled_actions` or not advertised by the gateway
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_fields: Vec<String>,
+}
+
+/// A WANDevice as the uplink the gateway currently routes through
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Uplink {
+    /// Position in `UpnpDevice::wan_interfaces`
+    pub index: usize,
+    /// friendlyName of the WANDevice
+    pub name: Option<String>,
+    /// `WanInterface::link_kind`, e.g. "dsl" or "pots"
+    pub link: String,
+}
+
+impl TrafficStats {
+    /// Names of the core WANCommonInterfaceConfig values the gateway did not
+    /// answer; values that were never asked for do not count
+    pub fn unavailable_fields(&self) -> Vec<&'static str> {
+        [
+            ("bytes_sent", self.bytes_sent.is_none()),
+            ("bytes_received", self.bytes_received.is_none()),
+            ("packets_sent", self.packets_sent.is_none()),
+            ("packets_received", self.packets_received.is_none()),
+            ("connection_status", self.connection_status.is_none()),
+        ]
+        .into_iter()
+        .filter(|(name, _)| !self.skipped_fields.iter().any(|skipped| skipped == name))
+        .filter_map(|(name, missing)| missing.then_some(name))
+        .collect()
+    }
+
+    pub fn is_link_up(&self) -> bool {
+        self.connection_status.as_deref() == Some("Up")
+    }
+}
+
+/// A WAN connection the gateway reports as active through GetActiveConnection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActiveConnection {
+    /// UDN of the WANConnectionDevice, e.g. "uuid:...:WANConnectionDevice:1"
+    pub device_container: String,
+    /// serviceId of the connection service, e.g. "urn:upnp-org:serviceId:WANPPPConn1"
+    pub service_id: String,
+}
+
+impl ActiveConnection {
+    /// Whether `connection` is the service this entry names
+    pub fn matches(&self, connection: &WanConnection) -> bool {
+        connection.is_default_connection(&format!("{},{}", self.device_container, self.service_id))
+    }
+}
+
+/// An entry of the gateway's port mapping table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortMapping {
+    /// Remote host the mapping is restricted to, `None` for any
+    pub remote_host: Option<String>,
+    pub external_port: u16,
+    /// "TCP" or "UDP"
+    pub protocol: String,
+    pub internal_port: u16,
+    pub internal_client: String,
+    pub enabled: bool,
+    pub description: String,
+    /// Remaining lease in seconds, 0 for a permanent mapping
+    pub lease_duration: u32,
+}
+
+/// Counters from the AVM GetAddonInfos vendor action
+struct AddonInfos {
+    bytes_sent: u64,
+    bytes_received: u64,
+    byte_send_rate: Option<u64>,
+    byte_receive_rate: Option<u64>,
+}
+
+/// The parts of GetCommonLinkProperties the exporter reports
+struct LinkProperties {
+    status: String,
+    up_max_bitrate_bps: Option<u64>,
+    down_max_bitrate_bps: Option<u64>,
+}
+
+/// DSL line state from WANDSLLinkConfig
+struct DslLinkInfo {
+    link_type: Option<String>,
+    link_status: Option<String>,
+    auto_config: Option<bool>,
+}
+
+/// DOCSIS modem state from WANCableLinkConfig
+struct CableLinkInfo {
+    state: Option<String>,
+    link_type: Option<String>,
+}
+
+/// Connection-level state from GetStatusInfo
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStatusInfo {
+    pub status: Option<String>,
+    pub uptime_seconds: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Failure to reach the gateway through the configured SOCKS5 proxy
+#[derive(Debug)]
+pub struct ProxyError {
+    pub proxy: String,
+    pub source: reqwest::Error,
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Proxy connection via {} failed: {}",
+            self.proxy, self.source
+        )
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Why discovery failed, as exposed in the `reason` label of the failure counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryFailure {
+    Timeout,
+    Socket,
+    Parse,
+    Http,
+    NoMatch,
+}
+
+impl DiscoveryFailure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Socket => "socket",
+            Self::Parse => "parse",
+            Self::Http => "http",
+            Self::NoMatch => "no_match",
+        }
+    }
+}
+
+/// A discovery error tagged with the stage that failed
+#[derive(Debug)]
+pub struct DiscoveryError {
+    pub reason: DiscoveryFailure,
+    pub source: anyhow::Error,
+}
+
+impl DiscoveryError {
+    fn msg(reason: DiscoveryFailure, message: impl fmt::Display) -> anyhow::Error {
+        Self::tag(reason, anyhow!("{}", message))
+    }
+
+    fn tag(reason: DiscoveryFailure, source: anyhow::Error) -> anyhow::Error {
+        Self { reason, source }.into()
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+fn discovery_failure_reason(error: &anyhow::Error) -> DiscoveryFailure {
+    if let Some(e) = error.downcast_ref::<DiscoveryError>() {
+        e.reason
+    } else if error.is::<reqwest::Error>()
+        || error.downcast_ref::<UpnpError>().is_some_and(|e| {
+            matches!(
+                e,
+                UpnpError::Http(_)
+                    | UpnpError::Proxy(_)
+                    | UpnpError::Timeout { .. }
+                    | UpnpError::AuthenticationFailed { .. }
+            )
+        })
+    {
+        DiscoveryFailure::Http
+    } else if error.is::<io::Error>() {
+        DiscoveryFailure::Socket
+    } else {
+        // Descriptions that lack the WAN services or do not parse
+        DiscoveryFailure::Parse
+    }
 }
 
-impl Default for TrafficStats {
-    fn default() -> Self {
-        Self {
-            bytes_sent: 0,
-            bytes_received: 0,
-            packets_sent: 0,
-            packets_received: 0,
-            connection_status: "Disconnected".to_string(),
+/// A discovery socket and the addresses to send M-SEARCH to from it
+struct SearchSocket {
+    socket: UdpSocket,
+    targets: Vec<String>,
+}
+
+/// Bind a non-blocking UDP socket on `local` or the unspecified address;
+/// IPv6 sockets are v6-only so both families can share a fixed source port
+fn bind_udp(ipv6: bool, port: u16, local: Option<IpAddr>) -> io::Result<UdpSocket> {
+    let (domain, addr) = match local {
+        Some(local) => (
+            if ipv6 { Domain::IPV6 } else { Domain::IPV4 },
+            SocketAddr::new(local, port),
+        ),
+        None if ipv6 => (
+            Domain::IPV6,
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ),
+        None => (
+            Domain::IPV4,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        ),
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if ipv6 {
+        socket.set_only_v6(true)?;
+    }
+    // Multicast leaves through the interface of the bound address, not the default route
+    if let Some(IpAddr::V4(local)) = local {
+        socket.set_multicast_if_v4(&local)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Receive the next datagram arriving on any of the discovery sockets
+async fn recv_from_any(
+    sockets: &[SearchSocket],
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    loop {
+        let socket = match sockets {
+            [] => return std::future::pending().await,
+            [only] => {
+                only.socket.readable().await?;
+                &only.socket
+            }
+            [first, second, ..] => tokio::select! {
+                ready = first.socket.readable() => { ready?; &first.socket }
+                ready = second.socket.readable() => { ready?; &second.socket }
+            },
+        };
+        match socket.try_recv_from(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+fn matches_selection(
+    selection: &DeviceSelection,
+    response: &SsdpResponse,
+    info: &DeviceInfo,
+) -> bool {
+    match selection {
+        DeviceSelection::First => true,
+        DeviceSelection::PreferIgd2 => info
+            .device_type
+            .as_deref()
+            .is_some_and(|t| t.contains("InternetGatewayDevice:2")),
+        DeviceSelection::Usn { usn } => response
+            .usn
+            .as_deref()
+            .is_some_and(|u| u == usn || ssdp::usn_uuid(u) == usn),
+        DeviceSelection::FriendlyName { pattern } => Regex::new(pattern).is_ok_and(|re| {
+            info.friendly_name
+                .as_deref()
+                .is_some_and(|name| re.is_match(name))
+        }),
+        DeviceSelection::Manufacturer { manufacturer } => info
+            .manufacturer
+            .as_deref()
+            .is_some_and(|m| m.eq_ignore_ascii_case(manufacturer)),
+    }
+}
+
+fn selection_reason(
+    selection: &DeviceSelection,
+    response: &SsdpResponse,
+    info: &DeviceInfo,
+) -> String {
+    if matches_selection(selection, response, info) {
+        format!("it is {}", selection)
+    } else {
+        format!("no device is {}, falling back to the first", selection)
+    }
+}
+
+/// The URL to hand to the HTTP client, which cannot carry an IPv6 zone;
+/// the kernel then picks the interface for link-local addresses
+fn request_url(url: &str) -> String {
+    description::split_zone_id(url).0
+}
+
+/// UPnP booleans are "1"/"0", though some gateways answer "true"/"false"
+fn parse_upnp_bool(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        value if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("yes") => {
+            Some(true)
+        }
+        value if value.eq_ignore_ascii_case("false") || value.eq_ignore_ascii_case("no") => {
+            Some(false)
+        }
+        _ => None,
+    }
+}
+
+/// An unsigned integer value. Some firmwares (Netgear) format 32-bit
+/// counters past 2^31 as a signed i4, e.g. "-1294967296"; those negative
+/// values are taken as the two's complement of the unsigned counter.
+fn parse_upnp_u64(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if value.starts_with('-') {
+        return value
+            .parse::<i32>()
+            .map(|signed| u64::from(signed as u32))
+            .map_err(|e| {
+                format!(
+                    "{} is neither unsigned nor a signed 32-bit value ({})",
+                    value, e
+                )
+            });
+    }
+    value.parse::<u64>().map_err(|e| e.to_string())
+}
+
+fn soap_fault(error: &anyhow::Error) -> Option<&SoapFault> {
+    match error.downcast_ref::<UpnpError>() {
+        Some(UpnpError::Soap(fault)) => Some(fault),
+        _ => None,
+    }
+}
+
+fn soap_error_kind(error: &anyhow::Error) -> &'static str {
+    error
+        .downcast_ref::<UpnpError>()
+        .map_or("other", UpnpError::soap_error_kind)
+}
+
+fn connection_service(interface: &WanInterface) -> Result<&UpnpService> {
+    interface
+        .connection
+        .as_ref()
+        .map(|connection| &connection.service)
+        .ok_or_else(|| UpnpError::ServiceNotFound("WANIPConnection/WANPPPConnection").into())
+}
+
+/// An external IP address worth reporting, skipping the placeholders of a disconnected WAN
+pub(crate) fn usable_external_ip(address: &str) -> Option<String> {
+    let address = address.trim();
+    match address.parse::<IpAddr>() {
+        Ok(ip) if !ip.is_unspecified() => Some(address.to_string()),
+        _ => None,
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    let (url, _) = description::split_zone_id(url);
+    Url::parse(&url).is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+/// Whether the host of a device location is one of this machine's own addresses
+fn is_local_location(location: &str) -> bool {
+    let (location, _) = description::split_zone_id(location);
+    let Ok(url) = Url::parse(&location) else {
+        return false;
+    };
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() else {
+        return host.eq_ignore_ascii_case("localhost");
+    };
+
+    ip.is_loopback()
+        || if_addrs::get_if_addrs()
+            .map(|interfaces| interfaces.iter().any(|interface| interface.ip() == ip))
+            .unwrap_or(false)
+}
+
+pub struct UpnpClient {
+    client: Client,
+    device: Option<UpnpDevice>,
+    device_expires_at: Option<Instant>,
+    config: UpnpConfig,
+    /// Set once the WANEthernetLinkConfig link status fallback has been logged
+    link_status_fallback_logged: AtomicBool,
+    /// Set once the gateway accepted `soap_action_format` after rejecting the standard form
+    soap_action_quirk_active: AtomicBool,
+    /// Location of the device whose GetAddonInfos failed for good; its byte
+    /// counters are read with the standard actions from then on
+    addon_infos_unusable: Mutex<Option<String>>,
+    /// Answers authentication challenges when credentials are configured
+    auth: Option<Authenticator>,
+    /// GENA subscription and evented values, empty unless subscribed
+    events: Arc<EventState>,
+    /// Permits for `max_concurrent_soap_requests`, shared by all scrapes
+    soap_slots: Arc<Semaphore>,
+    /// Where discoveries and SOAP requests are counted, once attached
+    metrics: Option<Arc<Metrics>>,
+    /// Value of the `device` label they are counted under
+    metrics_device: String,
+}
+
+impl Default for UpnpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures an `UpnpClient` beyond the defaults of `UpnpClient::new()`.
+/// Settings are checked together in `build`, so conflicts surface there
+/// rather than at the first request.
+#[derive(Debug, Clone, Default)]
+pub struct UpnpClientBuilder {
+    config: UpnpConfig,
+}
+
+impl UpnpClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from a whole `[upnp]` config section; later calls override it
+    pub fn config(mut self, config: UpnpConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Overall SSDP discovery deadline, rounded up to whole seconds
+    pub fn discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.config.discovery_timeout = whole_seconds(timeout);
+        self
+    }
+
+    /// Fetch the device description from `location` instead of running SSDP discovery
+    pub fn location(mut self, location: Url) -> Self {
+        self.config.location = Some(location.to_string());
+        self
+    }
+
+    /// Local address for discovery and HTTP requests
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.config.bind_address = Some(address);
+        self
+    }
+
+    /// Send M-SEARCH as unicast to `target` instead of the multicast group
+    pub fn search_target(mut self, target: SocketAddr) -> Self {
+        self.config.search_target_addr = Some(target.to_string());
+        self
+    }
+
+    /// Credentials for gateways requiring HTTP Basic or Digest authentication
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.config.username = Some(username.into());
+        self.config.password = Some(Secret::new(password));
+        self.config.password_file = None;
+        self
+    }
+
+    /// Time a description fetch or SOAP request may take in total, rounded up to whole seconds
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.config.http_request_timeout = whole_seconds(timeout);
+        self
+    }
+
+    /// Time to wait for a TCP connection to the gateway, rounded up to whole seconds
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.http_connect_timeout = whole_seconds(timeout);
+        self
+    }
+
+    pub fn build(self) -> UpnpResult<UpnpClient> {
+        self.config
+            .validate()
+            .map_err(|e| UpnpError::Config(format!("{:#}", e)))?;
+        UpnpClient::from_config(&self.config)
+    }
+}
+
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+impl UpnpClient {
+    pub fn new() -> Self {
+        Self::from_config(&UpnpConfig::default()).expect("HTTP client can be built")
+    }
+
+    pub fn builder() -> UpnpClientBuilder {
+        UpnpClientBuilder::new()
+    }
+
+    pub fn from_config(config: &UpnpConfig) -> UpnpResult<Self> {
+        let mut builder = Client::builder()
+            .redirect(redirect::Policy::limited(MAX_REDIRECTS))
+            .connect_timeout(Duration::from_secs(config.http_connect_timeout))
+            .timeout(Duration::from_secs(config.http_request_timeout))
+            .local_address(config.bind_address)
+            .user_agent(config.user_agent())
+            // Some firmwares refuse requests without it
+            .default_headers(HeaderMap::from_iter([(
+                header::ACCEPT,
+                HeaderValue::from_static("text/xml"),
+            )]));
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Proxy::all(proxy.expose())?);
+        }
+        if let Some(path) = &config.tls_ca_file {
+            let pem = std::fs::read(path).map_err(|e| {
+                UpnpError::Other(anyhow!(
+                    "Cannot read upnp.tls_ca_file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .ok()
+                .filter(|certificates| !certificates.is_empty())
+                .ok_or_else(|| {
+                    UpnpError::Other(anyhow!(
+                        "upnp.tls_ca_file {} holds no PEM certificate",
+                        path.display()
+                    ))
+                })?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if config.tls_insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if config.http10_compat {
+            builder = builder.http1_only().pool_max_idle_per_host(0);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            device: None,
+            device_expires_at: None,
+            config: config.clone(),
+            link_status_fallback_logged: AtomicBool::new(false),
+            soap_action_quirk_active: AtomicBool::new(false),
+            addon_infos_unusable: Mutex::new(None),
+            auth: config
+                .credentials()?
+                .map(|(username, password)| Authenticator::new(username, password)),
+            events: Arc::new(EventState::default()),
+            soap_slots: Arc::new(Semaphore::new(config.max_concurrent_soap_requests.max(1))),
+            metrics: None,
+            metrics_device: String::new(),
+        })
+    }
+
+    /// Whether a resolved device is cached and its SSDP max-age has not expired
+    pub fn has_valid_device(&self) -> bool {
+        let resolved = self
+            .device
+            .as_ref()
+            .is_some_and(|d| !d.wan_interfaces.is_empty());
+        let expired = self
+            .device_expires_at
+            .is_some_and(|expires_at| Instant::now() >= expires_at);
+        resolved && !expired
+    }
+
+    /// Replace the cached device with the one `other` resolved
+    pub fn adopt_device(&mut self, other: UpnpClient) {
+        self.device = other.device;
+        self.device_expires_at = other.device_expires_at;
+    }
+
+    /// Count discoveries and SOAP requests in `metrics` from now on, as
+    /// those of the gateway named `device`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>, device: &str) {
+        self.metrics = Some(metrics);
+        self.metrics_device = device.to_string();
+    }
+
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.clone()
+    }
+
+    /// The `device` label of the attached metrics
+    pub fn metrics_device(&self) -> &str {
+        &self.metrics_device
+    }
+
+    fn record(&self, update: impl FnOnce(&Metrics, &str)) {
+        if let Some(metrics) = &self.metrics {
+            update(metrics, &self.metrics_device);
+        }
+    }
+
+    /// GENA subscription state shared with the event route
+    pub fn events(&self) -> Arc<EventState> {
+        self.events.clone()
+    }
+
+    /// eventSubURL of the primary WAN connection service
+    pub fn event_sub_url(&self) -> Option<String> {
+        self.device()?
+            .primary_interface()?
+            .connection
+            .as_ref()?
+            .service
+            .event_sub_url
+            .clone()
+    }
+
+    /// Evented values for the connection service of `interface`, while subscribed to it
+    fn evented_values(&self, interface: &WanInterface) -> Option<EventedValues> {
+        let url = connection_service(interface)
+            .ok()?
+            .event_sub_url
+            .as_deref()?;
+        self.events.values_for(url)
+    }
+
+    /// Drop the cached device so the next call to `ensure_device` re-discovers it
+    pub fn invalidate_device(&mut self) {
+        if self.device.take().is_some() {
+            debug!("Invalidated cached UPnP device");
+        }
+        self.device_expires_at = None;
+    }
+
+    pub fn device(&self) -> Option<&UpnpDevice> {
+        self.device.as_ref()
+    }
+
+    /// The description document of the resolved device as last fetched,
+    /// e.g. for inspecting vendor quirks
+    pub fn device_description(&self) -> Option<&str> {
+        self.device.as_ref()?.description.as_deref()
+    }
+
+    /// Fetch the description of the resolved device again and keep it as
+    /// its cached copy, without re-reading the services from it
+    pub async fn refresh_device_description(&mut self) -> UpnpResult<&str> {
+        let location = match &self.device {
+            Some(device) if device.description.is_some() => device.location.clone(),
+            _ => {
+                return Err(UpnpError::Other(anyhow!(
+                    "No device description to refresh"
+                )));
+            }
+        };
+        let xml = self
+            .fetch_description(&location)
+            .await
+            .map_err(UpnpError::from_discovery)?;
+        let device = self
+            .device
+            .as_mut()
+            .ok_or_else(|| UpnpError::Other(anyhow!("No device description to refresh")))?;
+        Ok(device.description.insert(xml))
+    }
+
+    /// Keep the cached device for another `max_age` seconds
+    pub fn refresh_device_expiry(&mut self, max_age: u64) {
+        if self.device.is_some() && !self.config.is_static() {
+            self.device_expires_at = Some(Instant::now() + Duration::from_secs(max_age));
+        }
+    }
+
+    /// Re-resolve service URLs after the cached device moved to a new location
+    pub async fn relocate_device(&mut self, location: String) -> UpnpResult<()> {
+        let Some(device) = self.device.as_mut() else {
+            return Ok(());
+        };
+        device.is_local = is_local_location(&location);
+        device.location = location;
+        device.wan_interfaces.clear();
+
+        if let Err(e) = self.setup_service().await {
+            self.invalidate_device();
+            return Err(UpnpError::from_discovery(e));
+        }
+        Ok(())
+    }
+
+    /// Discover the device unless a still valid one is cached
+    pub async fn ensure_device(&mut self) -> UpnpResult<()> {
+        if self.has_valid_device() {
+            return Ok(());
+        }
+        self.discover_device().await
+    }
+
+    pub async fn discover_device(&mut self) -> UpnpResult<()> {
+        // Any policy but "first" needs to see every gateway that answers
+        let collect_all = self.config.selection != DeviceSelection::First;
+        self.discover(collect_all).await.map(|_| ())
+    }
+
+    /// Like `discover_device`, but listen for the whole discovery timeout and
+    /// also return every gateway that answered (none for configured devices)
+    pub async fn discover_all(&mut self) -> UpnpResult<Vec<(String, SsdpResponse)>> {
+        self.discover(true).await
+    }
+
+    async fn discover(&mut self, collect_all: bool) -> UpnpResult<Vec<(String, SsdpResponse)>> {
+        let started = Instant::now();
+        let result = self.resolve_device(collect_all).await;
+        self.record(|metrics, device| {
+            metrics.observe_discovery(
+                device,
+                started.elapsed(),
+                result.as_ref().err().map(discovery_failure_reason),
+            )
+        });
+        result.map_err(UpnpError::from_discovery)
+    }
+
+    async fn resolve_device(&mut self, collect_all: bool) -> Result<Vec<(String, SsdpResponse)>> {
+        self.invalidate_device();
+
+        if let Some(common_url) = self.config.wan_common_control_url.clone() {
+            debug!("Using configured control URL: {}", common_url);
+            let service_type = |name: &str| {
+                format!(
+                    "{}{}:{}",
+                    SERVICE_TYPE_PREFIX, name, self.config.service_version
+                )
+            };
+            let connection =
+                self.config
+                    .wan_ip_control_url
+                    .clone()
+                    .map(|control_url| WanConnection {
+                        kind: WanConnectionKind::Ip,
+                        service: UpnpService::new(
+                            &service_type(WanConnectionKind::Ip.service_name()),
+                            control_url,
+                        ),
+                        device_udn: None,
+                    });
+            let mut interface = WanInterface {
+                index: 0,
+                name: None,
+                common: UpnpService::new(
+                    &service_type("WANCommonInterfaceConfig"),
+                    common_url.clone(),
+                ),
+                connections: connection.iter().cloned().collect(),
+                connection,
+                ethernet_link: None,
+                dsl_link: None,
+                cable_link: None,
+                pots_link: None,
+                active_connections: None,
+                queries_active_connections: false,
+            };
+            self.load_active_connections(&mut interface).await;
+            self.device = Some(UpnpDevice {
+                is_local: is_local_location(&common_url),
+                location: common_url,
+                usn: None,
+                server: None,
+                info: DeviceInfo::default(),
+                wan_interfaces: vec![interface],
+                layer3_forwarding: None,
+                description: None,
+            });
+            return Ok(Vec::new());
+        }
+
+        if let Some(location) = self.config.location.clone() {
+            debug!("Using configured device location: {}", location);
+            self.device = Some(UpnpDevice {
+                is_local: is_local_location(&location),
+                location,
+                usn: None,
+                server: None,
+                info: DeviceInfo::default(),
+                wan_interfaces: Vec::new(),
+                layer3_forwarding: None,
+                description: None,
+            });
+            self.setup_service().await?;
+            return Ok(Vec::new());
+        }
+
+        debug!("Starting UPnP device discovery");
+        let candidates = self.ssdp_search(collect_all).await?;
+        let (location, response) = self.select_device(candidates.clone()).await?;
+        debug!("Found UPnP device at: {}", location);
+        let is_local = is_local_location(&location);
+        if is_local {
+            info!("UPnP device at {} is running on this host", location);
+        }
+        self.device = Some(UpnpDevice {
+            location,
+            usn: response.usn,
+            server: response.server,
+            is_local,
+            info: DeviceInfo::default(),
+            wan_interfaces: Vec::new(),
+            layer3_forwarding: None,
+            description: None,
+        });
+
+        // Get device description and find WAN service
+        self.setup_service().await?;
+
+        let max_age = response
+            .cache_control_max_age
+            .unwrap_or(SSDP_DEFAULT_MAX_AGE);
+        debug!("Caching UPnP device for {}s", max_age);
+        self.device_expires_at = Some(Instant::now() + Duration::from_secs(max_age));
+        Ok(candidates)
+    }
+
+    /// Apply the configured selection policy to the discovered devices
+    async fn select_device(
+        &self,
+        candidates: Vec<(String, SsdpResponse)>,
+    ) -> Result<(String, SsdpResponse)> {
+        let selection = &self.config.selection;
+        // A lone device is the answer to any policy that does not filter
+        if candidates.len() == 1 && selection.is_preference() {
+            return candidates
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No UPnP device found"));
+        }
+
+        let mut described = Vec::new();
+        for (location, response) in candidates {
+            let info = match self.fetch_description(&location).await {
+                Ok(xml) => description::parse(&xml, &location)
+                    .map(|d| d.info)
+                    .unwrap_or_default(),
+                Err(e) => {
+                    debug!("Could not describe device at {}: {}", location, e);
+                    DeviceInfo::default()
+                }
+            };
+            described.push((location, response, info));
+        }
+
+        let chosen = described
+            .iter()
+            .position(|(_, response, info)| matches_selection(selection, response, info))
+            .or_else(|| selection.is_preference().then_some(0));
+        match chosen {
+            Some(index) => {
+                let (location, response, info) = described.swap_remove(index);
+                info!(
+                    "Selected device {} ({}) because {}",
+                    location,
+                    info.friendly_name.as_deref().unwrap_or("unnamed"),
+                    selection_reason(selection, &response, &info)
+                );
+                Ok((location, response))
+            }
+            None => {
+                let found: Vec<String> = described
+                    .iter()
+                    .map(|(location, response, info)| {
+                        format!(
+                            "{} (usn {}, friendlyName {}, manufacturer {})",
+                            location,
+                            response.usn.as_deref().unwrap_or("-"),
+                            info.friendly_name.as_deref().unwrap_or("-"),
+                            info.manufacturer.as_deref().unwrap_or("-")
+                        )
+                    })
+                    .collect();
+                Err(DiscoveryError::msg(
+                    DiscoveryFailure::NoMatch,
+                    format!(
+                        "No device matches {}; found: {}",
+                        selection,
+                        found.join(", ")
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Send M-SEARCH, retransmitting with exponential backoff until a device
+    /// answers or the discovery deadline passes, and return its location
+    /// together with the full response. With `collect_all` it keeps listening
+    /// until the deadline and returns every distinct device that answered.
+    async fn ssdp_search(&self, collect_all: bool) -> Result<Vec<(String, SsdpResponse)>> {
+        let sockets = self
+            .discovery_sockets()
+            .await
+            .map_err(|e| DiscoveryError::tag(DiscoveryFailure::Socket, e))?;
+        let source_port = sockets[0].socket.local_addr()?.port();
+        debug!("Discovery socket bound to source port {}", source_port);
+
+        let mut buf = vec![0; SSDP_BUFFER_SIZE];
+        let deadline = Instant::now() + Duration::from_secs(self.config.discovery_timeout);
+        let attempts = self.config.discovery_attempts.max(1);
+        let mut wait = SSDP_INITIAL_RETRY_DELAY;
+        let mut found: Vec<(String, SsdpResponse)> = Vec::new();
+        // Why the last unusable response was skipped, reported if nothing better arrives
+        let mut rejected: Option<String> = None;
+
+        for attempt in 1..=attempts {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            for search in &sockets {
+                for target in &search.targets {
+                    debug!(
+                        "Sending M-SEARCH to {} (attempt {}/{})",
+                        target, attempt, attempts
+                    );
+                    // One unreachable group (e.g. no IPv6 route) must not stop the others
+                    if let Err(e) = search
+                        .socket
+                        .send_to(search_message(target).as_bytes(), target.as_str())
+                        .await
+                    {
+                        warn!("Failed to send M-SEARCH to {}: {}", target, e);
+                    }
+                }
+            }
+
+            // The last attempt listens for whatever time is left
+            let window = if attempt == attempts {
+                remaining
+            } else {
+                wait.min(remaining)
+            };
+
+            // Wait for responses until this attempt's window closes
+            let window_end = Instant::now() + window;
+            loop {
+                let remaining = window_end.saturating_duration_since(Instant::now());
+                match tokio::time::timeout(remaining, recv_from_any(&sockets, &mut buf)).await {
+                    Ok(Ok((len, addr))) => {
+                        // recv_from silently drops whatever does not fit into the buffer;
+                        // one misbehaving responder must not end the search for the gateway
+                        if len == buf.len() {
+                            let reason = format!(
+                                "SSDP response from {} exceeds {} bytes and was truncated",
+                                addr, SSDP_BUFFER_SIZE
+                            );
+                            debug!("Ignoring {}", reason);
+                            rejected = Some(reason);
+                            continue;
+                        }
+
+                        let response = String::from_utf8_lossy(&buf[..len]);
+                        debug!(
+                            "Received SSDP response on attempt {}: {}",
+                            attempt, response
+                        );
+
+                        let parsed = match SsdpResponse::parse(&response) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                debug!("Ignoring datagram from {}: {}", addr, e);
+                                continue;
+                            }
+                        };
+                        if !parsed.is_gateway() {
+                            debug!(
+                                "Skipping non-gateway responder {} (ST {}, SERVER {})",
+                                addr,
+                                parsed.st.as_deref().unwrap_or("-"),
+                                parsed.server.as_deref().unwrap_or("-")
+                            );
+                            continue;
+                        }
+                        let Some(location) = parsed.location.clone() else {
+                            let reason =
+                                format!("SSDP response from {} has no LOCATION header", addr);
+                            debug!("Ignoring {}", reason);
+                            rejected = Some(reason);
+                            continue;
+                        };
+                        if !is_http_url(&location) {
+                            let reason = format!(
+                                "SSDP response from {} has an invalid LOCATION: {}",
+                                addr, location
+                            );
+                            debug!("Ignoring {}", reason);
+                            rejected = Some(reason);
+                            continue;
+                        }
+                        if self.config.ignore_local_devices && is_local_location(&location) {
+                            debug!("Skipping device on this host at {}", location);
+                            continue;
+                        }
+                        if !collect_all {
+                            return Ok(vec![(location, parsed)]);
+                        }
+                        if !found.iter().any(|(known, _)| *known == location) {
+                            debug!("Collected SSDP response from {}", location);
+                            found.push((location, parsed));
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        error!("Socket error during discovery: {}", e);
+                        return Err(DiscoveryError::msg(
+                            DiscoveryFailure::Socket,
+                            format!("Socket error on source port {}: {}", source_port, e),
+                        ));
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            if found.is_empty() {
+                debug!("No SSDP response to attempt {}/{}", attempt, attempts);
+            }
+            wait *= 2;
+        }
+
+        if !found.is_empty() {
+            return Ok(found);
+        }
+
+        if let Some(reason) = rejected {
+            warn!("No usable UPnP device found within timeout: {}", reason);
+            return Err(DiscoveryError::msg(DiscoveryFailure::Parse, reason));
+        }
+        warn!("No UPnP devices found within timeout");
+        Err(DiscoveryError::msg(
+            DiscoveryFailure::Timeout,
+            format!(
+                "Discovery timeout (listening on source port {})",
+                source_port
+            ),
+        ))
+    }
+
+    /// Bind one socket per address family in use, each with the addresses
+    /// to send M-SEARCH to: the unicast target if configured, otherwise the
+    /// SSDP multicast groups of the configured IP versions
+    async fn discovery_sockets(&self) -> Result<Vec<SearchSocket>> {
+        let (v4_targets, v6_targets) = match &self.config.search_target_addr {
+            Some(addr) if addr.parse::<SocketAddr>().is_ok_and(|a| a.is_ipv6()) => {
+                (vec![], vec![addr.clone()])
+            }
+            Some(addr) => (vec![addr.clone()], vec![]),
+            None => {
+                let v4 = vec![UPNP_MULTICAST_ADDR.to_string()];
+                let v6 = UPNP_MULTICAST_ADDRS_V6
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                match self.config.ip_version {
+                    IpVersion::V4 => (v4, vec![]),
+                    IpVersion::V6 => (vec![], v6),
+                    IpVersion::Both => (v4, v6),
+                }
+            }
+        };
+
+        let mut sockets = Vec::new();
+        if !v4_targets.is_empty() {
+            let socket = self.bind_discovery_socket(false)?;
+            socket.set_broadcast(true)?;
+            if let Some(ttl) = self.config.multicast_ttl {
+                socket.set_multicast_ttl_v4(ttl)?;
+            }
+            debug!("IPv4 multicast TTL is {}", socket.multicast_ttl_v4()?);
+            sockets.push(SearchSocket {
+                socket,
+                targets: v4_targets,
+            });
+        }
+        if !v6_targets.is_empty() {
+            let socket = self.bind_discovery_socket(true)?;
+            let socket_ref = SockRef::from(&socket);
+            if let Some(hops) = self.config.multicast_ttl {
+                socket_ref.set_multicast_hops_v6(hops)?;
+            }
+            debug!(
+                "IPv6 multicast hop limit is {}",
+                socket_ref.multicast_hops_v6()?
+            );
+            sockets.push(SearchSocket {
+                socket,
+                targets: v6_targets,
+            });
+        }
+        Ok(sockets)
+    }
+
+    fn bind_discovery_socket(&self, ipv6: bool) -> Result<UdpSocket> {
+        let Some(range) = self.config.ssdp_source_port else {
+            return bind_udp(ipv6, 0, self.config.bind_address)
+                .map_err(|e| anyhow!("Failed to bind SSDP discovery socket: {}", e));
+        };
+
+        for port in range.start..=range.end {
+            match bind_udp(ipv6, port, self.config.bind_address) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                    debug!("SSDP source port {} is in use, trying next", port);
+                }
+                Err(e) => return Err(anyhow!("Failed to bind SSDP source port {}: {}", port, e)),
+            }
+        }
+
+        Err(anyhow!(
+            "SSDP source port {} is already in use (EADDRINUSE)",
+            range
+        ))
+    }
+
+    async fn fetch_description(&self, location: &str) -> Result<String> {
+        debug!("Fetching device description from: {}", location);
+        self.fetch_xml(location).await
+    }
+
+    /// GET an XML document, refusing bodies above `max_description_size`
+    /// and responses that are evidently not XML
+    async fn fetch_xml(&self, url: &str) -> Result<String> {
+        let response = self
+            .send_authenticated("GET", url, None, || self.client.get(request_url(url)))
+            .await?
+            .error_for_status()?;
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        // Routers label descriptions text/xml, application/xml or text/plain
+        if !content_type.is_empty()
+            && !content_type.contains("xml")
+            && !content_type.starts_with("text/")
+        {
+            return Err(anyhow!(
+                "{} returned {} instead of an XML document",
+                url,
+                content_type
+            ));
+        }
+
+        let limit = self.config.max_description_size;
+        let body = read_limited(response, limit, || {
+            anyhow!(
+                "{} is larger than the {} byte limit (upnp.max_description_size)",
+                url,
+                limit
+            )
+        })
+        .await?;
+
+        let text = charset::decode_xml(&body, charset::content_type_charset(&content_type));
+        if !text
+            .trim_start_matches('\u{feff}')
+            .trim_start()
+            .starts_with('<')
+        {
+            return Err(anyhow!("{} did not return an XML document", url));
+        }
+        Ok(text)
+    }
+
+    async fn setup_service(&mut self) -> Result<()> {
+        let device = self
+            .device
+            .as_ref()
+            .ok_or_else(|| anyhow!("No device found"))?;
+
+        let desc_xml = self.fetch_description(&device.location).await?;
+
+        // Parse XML to find the WAN interfaces and their service URLs
+        let description = description::parse(&desc_xml, &device.location)?;
+        if description.wan_interfaces.is_empty() {
+            return Err(UpnpError::ServiceNotFound("WANCommonInterfaceConfig").into());
+        }
+
+        let mut wan_interfaces = description.wan_interfaces;
+        let mut layer3_forwarding = description.layer3_forwarding;
+        if let Some(forwarding) = &mut layer3_forwarding {
+            self.load_actions(forwarding).await;
+            self.select_default_connection(forwarding, &mut wan_interfaces)
+                .await;
+        } else {
+            debug!("No Layer3Forwarding service, keeping the preferred WAN connection");
+        }
+        for interface in &mut wan_interfaces {
+            self.load_actions(&mut interface.common).await;
+            self.select_active_connection(interface).await;
+            if let Some(connection) = &mut interface.connection {
+                self.load_actions(&mut connection.service).await;
+            }
+            if let Some(ethernet_link) = &mut interface.ethernet_link {
+                self.load_actions(ethernet_link).await;
+            }
+            if let Some(dsl_link) = &mut interface.dsl_link {
+                self.load_actions(dsl_link).await;
+            }
+            if let Some(cable_link) = &mut interface.cable_link {
+                self.load_actions(cable_link).await;
+            }
+            if let Some(pots_link) = &mut interface.pots_link {
+                self.load_actions(pots_link).await;
+            }
+            // SOAP requests address the exact versions advertised here
+            debug!(
+                "WAN interface {} uses {} and {}",
+                interface.index,
+                interface.common.service_type,
+                interface
+                    .connection
+                    .as_ref()
+                    .map_or("no connection service", |c| c.service.service_type.as_str())
+            );
+        }
+
+        if let Some(ref mut dev) = self.device {
+            dev.info = description.info;
+            dev.wan_interfaces = wan_interfaces;
+            dev.layer3_forwarding = layer3_forwarding;
+            dev.description = Some(desc_xml);
+        }
+
+        Ok(())
+    }
+
+    /// Query the connection service the gateway routes through from
+    /// Layer3Forwarding and make it the one queried on its interface.
+    /// Without a usable answer the WANIPConnection-first choice stays.
+    async fn select_default_connection(
+        &self,
+        forwarding: &UpnpService,
+        wan_interfaces: &mut [WanInterface],
+    ) {
+        let default_connection = match self.get_default_connection_service(forwarding).await {
+            Ok(value) => value,
+            Err(e) => {
+                info!(
+                    "Layer3Forwarding default connection unavailable ({}), keeping the preferred WAN connection",
+                    e
+                );
+                return;
+            }
+        };
+
+        for interface in wan_interfaces.iter_mut() {
+            if let Some(connection) = interface
+                .connections
+                .iter()
+                .find(|c| c.is_default_connection(&default_connection))
+            {
+                info!(
+                    "Using {} at {} as the active WAN connection (Layer3Forwarding default {})",
+                    connection.kind.service_name(),
+                    connection.service.control_url,
+                    default_connection.trim()
+                );
+                interface.connection = Some(connection.clone());
+                return;
+            }
+        }
+        info!(
+            "Layer3Forwarding default connection {:?} matches no WAN connection service, keeping the preferred one",
+            default_connection
+        );
+    }
+
+    /// Fill in the actions a service implements; an unreadable SCPD leaves
+    /// them unknown so that every action is still attempted
+    async fn load_actions(&self, service: &mut UpnpService) {
+        match self.fetch_scpd(service).await {
+            Ok(actions) => {
+                debug!("{} implements {:?}", service.service_type, actions);
+                service.actions = Some(actions);
+            }
+            Err(e) => debug!("No action list for {}: {}", service.service_type, e),
+        }
+    }
+
+    /// Fetch a service description and return the names of its actions
+    pub async fn fetch_scpd(&self, service: &UpnpService) -> UpnpResult<Vec<String>> {
+        let scpd_url = service
+            .scpd_url
+            .as_ref()
+            .ok_or_else(|| UpnpError::Other(anyhow!("{} has no SCPDURL", service.service_type)))?;
+        let xml = self.fetch_xml(scpd_url).await?;
+
+        let actions = description::parse_scpd_actions(&xml);
+        if actions.is_empty() {
+            return Err(UpnpError::Other(anyhow!(
+                "SCPD at {} lists no actions",
+                scpd_url
+            )));
+        }
+        Ok(actions)
+    }
+
+    /// Traffic stats of the primary WAN interface
+    pub async fn get_traffic_stats(&self) -> UpnpResult<TrafficStats> {
+        let (stats, active_uplink) = tokio::join!(
+            self.get_interface_traffic_stats(0),
+            self.get_active_uplink()
+        );
+        let mut stats = stats?;
+        match active_uplink {
+            Ok(uplink) => stats.active_uplink = uplink,
+            Err(e) => debug!("No active uplink: {}", e),
+        }
+        Ok(stats)
+    }
+
+    /// The WANDevice carrying the Layer3Forwarding default connection, which
+    /// moves to the backup WANDevice during a failover. Only gateways with a
+    /// WANPOTSLinkConfig uplink are asked; `None` for all others.
+    pub async fn get_active_uplink(&self) -> UpnpResult<Option<Uplink>> {
+        let Some(device) = self.device() else {
+            return Ok(None);
+        };
+        let Some(forwarding) = &device.layer3_forwarding else {
+            return Ok(None);
+        };
+        if !device
+            .wan_interfaces
+            .iter()
+            .any(|interface| interface.pots_link.is_some())
+        {
+            return Ok(None);
+        }
+
+        let default_connection = self.get_default_connection_service(forwarding).await?;
+        Ok(device
+            .wan_interfaces
+            .iter()
+            .find(|interface| {
+                interface
+                    .connections
+                    .iter()
+                    .any(|c| c.is_default_connection(&default_connection))
+            })
+            .map(|interface| Uplink {
+                index: interface.index,
+                name: interface.name.clone(),
+                link: interface.link_kind().to_string(),
+            }))
+    }
+
+    /// Traffic stats of the WAN interface at `index` in `UpnpDevice::wan_interfaces`
+    pub async fn get_interface_traffic_stats(&self, index: usize) -> UpnpResult<TrafficStats> {
+        let interface = self.wan_interface(index)?;
+        let common = &interface.common;
+
+        // The calls are independent, so slow gateways only cost the slowest one
+        let (
+            packets_sent,
+            packets_received,
+            link_properties,
+            external_ip,
+            status_info,
+            addon_infos,
+            ppp_bitrates,
+            nat_rsip_status,
+            connection_type,
+            dsl_link_info,
+            cable_link_info,
+            active_connections,
+        ) = tokio::join!(
+            self.get_total_packets_sent(common),
+            self.get_total_packets_received(common),
+            self.get_link_properties(interface),
+            self.get_interface_external_ip(interface),
+            self.get_interface_status_info(interface),
+            self.get_addon_infos(common),
+            self.get_ppp_link_layer_bitrates(interface),
+            self.get_nat_rsip_status(interface),
+            self.get_connection_type_info(interface),
+            self.get_dsl_link_info(interface),
+            self.get_cable_link_info(interface),
+            self.get_active_connection_count(interface),
+        );
+
+        let mut stats = TrafficStats::default();
+        let mut answered = false;
+        let mut errors = Vec::new();
+
+        // The 64-bit AVM counters do not wrap at 4 GiB like the standard
+        // ones, which are only asked for when GetAddonInfos gives nothing
+        let addon_infos = match addon_infos {
+            Ok(addon) => addon,
+            Err(e) => {
+                self.note_addon_infos_failure(&e);
+                None
+            }
+        };
+        let (bytes_sent, bytes_received) = match &addon_infos {
+            Some(addon) => (Ok(addon.bytes_sent), Ok(addon.bytes_received)),
+            None => tokio::join!(
+                self.get_total_bytes_sent(common),
+                self.get_total_bytes_received(common)
+            ),
+        };
+        if let Some(addon) = addon_infos {
+            stats.byte_send_rate = addon.byte_send_rate;
+            stats.byte_receive_rate = addon.byte_receive_rate;
+        }
+
+        match bytes_sent {
+            Ok(bytes_sent) => {
+                stats.bytes_sent = Some(self.scale_bytes(bytes_sent));
+                answered = true;
+            }
+            Err(e) => errors.push(e),
+        }
+
+        match bytes_received {
+            Ok(bytes_received) => {
+                stats.bytes_received = Some(self.scale_bytes(bytes_received));
+                answered = true;
+            }
+            Err(e) => errors.push(e),
+        }
+
+        match packets_sent {
+            Ok(packets_sent) => {
+                stats.packets_sent = Some(packets_sent);
+                answered = true;
+            }
+            Err(e) => errors.push(e),
+        }
+
+        match packets_received {
+            Ok(packets_received) => {
+                stats.packets_received = Some(packets_received);
+                answered = true;
+            }
+            Err(e) => errors.push(e),
+        }
+
+        match link_properties {
+            Ok(link) => {
+                stats.connection_status = Some(link.status);
+                stats.link_up_max_bitrate_bps = link.up_max_bitrate_bps;
+                stats.link_down_max_bitrate_bps = link.down_max_bitrate_bps;
+                answered = true;
+            }
+            Err(e) => errors.push(e),
+        }
+
+        // These live on the connection service, so they do not vouch for the interface
+        match external_ip {
+            Ok(external_ip) => stats.external_ip = external_ip,
+            Err(e) => debug!("No external IP address: {}", e),
+        }
+
+        // Some firmwares answer this with a 401 fault despite listing it
+        match ppp_bitrates {
+            Ok(Some((up, down))) => {
+                stats.ppp_up_max_bitrate_bps = up;
+                stats.ppp_down_max_bitrate_bps = down;
+            }
+            Ok(None) => {}
+            Err(e) => debug!("No PPP link-layer bit rates: {}", e),
+        }
+
+        match status_info {
+            Ok(info) => {
+                stats.ip_connection_status = info.status;
+                stats.uptime_seconds = info.uptime_seconds;
+                stats.last_connection_error = info.last_error;
+            }
+            Err(e) => debug!("No connection status info: {}", e),
+        }
+
+        match nat_rsip_status {
+            Ok(Some((nat_enabled, rsip_available))) => {
+                stats.nat_enabled = nat_enabled;
+                stats.rsip_available = rsip_available;
+            }
+            Ok(None) => {}
+            Err(e) => debug!("No NAT/RSIP status: {}", e),
+        }
+
+        match connection_type {
+            Ok(Some((current, possible))) => {
+                stats.connection_type = current;
+                stats.possible_connection_types = possible;
+            }
+            Ok(None) => {}
+            Err(e) => debug!("No connection type info: {}", e),
+        }
+        if let Some(possible) = self
+            .evented_values(interface)
+            .and_then(|values| values.possible_connection_types)
+        {
+            stats.possible_connection_types = possible;
+        }
+
+        match dsl_link_info {
+            Ok(Some(dsl)) => {
+                stats.dsl_link_type = dsl.link_type;
+                stats.dsl_link_status = dsl.link_status;
+                stats.dsl_auto_config = dsl.auto_config;
+            }
+            Ok(None) => {}
+            Err(e) => debug!("No DSL link info: {}", e),
+        }
+
+        match cable_link_info {
+            Ok(Some(cable)) => {
+                stats.cable_link_state = cable.state;
+                stats.cable_link_type = cable.link_type;
+            }
+            Ok(None) => {}
+            Err(e) => debug!("No cable link info: {}", e),
+        }
+
+        stats.active_connections = active_connections;
+
+        for error in &errors {
+            debug!("SOAP request to {} failed: {}", common.control_url, error);
+        }
+        if !answered {
+            let error = match errors.into_iter().next() {
+                Some(error) => UpnpError::from(error),
+                None => UpnpError::Other(anyhow!("no request was sent")),
+            };
+            return Err(UpnpError::NoResponse {
+                url: common.control_url.clone(),
+                error: Box::new(error),
+            });
+        }
+
+        // Values that were never asked for are not the gateway's failure
+        let has_link_fallback = interface.ethernet_link.is_some() || interface.cable_link.is_some();
+        for (field, action, missing) in [
+            (
+                "bytes_sent",
+                "GetTotalBytesSent",
+                stats.bytes_sent.is_none(),
+            ),
+            (
+                "bytes_received",
+                "GetTotalBytesReceived",
+                stats.bytes_received.is_none(),
+            ),
+            (
+                "packets_sent",
+                "GetTotalPacketsSent",
+                stats.packets_sent.is_none(),
+            ),
+            (
+                "packets_received",
+                "GetTotalPacketsReceived",
+                stats.packets_received.is_none(),
+            ),
+            (
+                "connection_status",
+                "GetCommonLinkProperties",
+                stats.connection_status.is_none(),
+            ),
+        ] {
+            if missing
+                && !self.supports(common, action)
+                && (field != "connection_status" || !has_link_fallback)
+            {
+                stats.skipped_fields.push(field.to_string());
+            }
         }
+
+        Ok(stats)
     }
-}
 
-pub struct UpnpClient {
-    client: Client,
-    device: Option<UpnpDevice>,
-}
+    /// Read only the (bytes sent, bytes received) counters
+    pub async fn get_byte_counters(&self) -> UpnpResult<(u64, u64)> {
+        let common = &self.wan_interface(0)?.common;
 
-impl Default for UpnpClient {
-    fn default() -> Self {
-        Self::new()
+        let (sent, received) = tokio::join!(
+            self.get_total_bytes_sent(common),
+            self.get_total_bytes_received(common),
+        );
+        Ok((self.scale_bytes(sent?), self.scale_bytes(received?)))
     }
-}
 
-impl UpnpClient {
-    pub fn new() -> Self {
-        Self {
-            client: Client::new(),
-            device: None,
+    /// Public IP address of the primary WAN connection, `None` while the
+    /// gateway reports none (disconnected WANs answer "0.0.0.0" or nothing)
+    pub async fn get_external_ip(&self) -> UpnpResult<Option<String>> {
+        Ok(self
+            .get_interface_external_ip(self.wan_interface(0)?)
+            .await?)
+    }
+
+    async fn get_interface_external_ip(&self, interface: &WanInterface) -> Result<Option<String>> {
+        if let Some(address) = self
+            .evented_values(interface)
+            .and_then(|values| values.external_ip)
+        {
+            return Ok(usable_external_ip(&address));
         }
+        let response = self
+            .call(connection_service(interface)?, "GetExternalIPAddress")
+            .await?;
+
+        let address =
+            self.parse_string_response(&response, "GetExternalIPAddress", "NewExternalIPAddress")?;
+        Ok(usable_external_ip(&address))
     }
 
-    pub async fn discover_device(&mut self) -> Result<()> {
-        debug!("Starting UPnP device discovery");
+    /// Connection status, uptime and last error of the primary WAN connection
+    pub async fn get_status_info(&self) -> UpnpResult<ConnectionStatusInfo> {
+        Ok(self
+            .get_interface_status_info(self.wan_interface(0)?)
+            .await?)
+    }
+
+    async fn get_interface_status_info(
+        &self,
+        interface: &WanInterface,
+    ) -> Result<ConnectionStatusInfo> {
+        let response = self
+            .call(connection_service(interface)?, "GetStatusInfo")
+            .await?;
+        let mut values = self.parse_response_values(&response, "GetStatusInfo")?;
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.set_broadcast(true)?;
+        let status = values.remove("NewConnectionStatus");
+        if status.is_none() {
+            return Err(UpnpError::parse("NewConnectionStatus", "not found in response").into());
+        }
+        Ok(ConnectionStatusInfo {
+            status,
+            uptime_seconds: values
+                .remove("NewUptime")
+                .and_then(|uptime| uptime.parse().ok()),
+            last_error: values
+                .remove("NewLastConnectionError")
+                .filter(|error| !error.is_empty()),
+        })
+    }
 
-        // Send SSDP discovery message
-        socket
-            .send_to(UPNP_SEARCH_MSG.as_bytes(), UPNP_MULTICAST_ADDR)
+    /// (upstream, downstream) rates negotiated by a WANPPPConnection, `None`
+    /// when the interface has no PPP connection
+    async fn get_ppp_link_layer_bitrates(
+        &self,
+        interface: &WanInterface,
+    ) -> Result<Option<(Option<u64>, Option<u64>)>> {
+        let Some(connection) = interface
+            .connection
+            .as_ref()
+            .filter(|c| c.kind == WanConnectionKind::Ppp)
+        else {
+            return Ok(None);
+        };
+        let response = self
+            .call(&connection.service, "GetLinkLayerMaxBitRates")
             .await?;
+        let values = self.parse_response_values(&response, "GetLinkLayerMaxBitRates")?;
 
-        let mut buf = [0; 1024];
-
-        // Wait for responses with timeout
-        match tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await {
-            Ok(Ok((len, _addr))) => {
-                let response = String::from_utf8_lossy(&buf[..len]);
-                debug!("Received SSDP response: {}", response);
-
-                // Parse location from response
-                if let Some(location) = self.extract_location(&response) {
-                    debug!("Found UPnP device at: {}", location);
-                    self.device = Some(UpnpDevice {
-                        location: location.clone(),
-                        wan_common_service_url: None,
-                        wan_ip_service_url: None,
-                    });
+        let bitrate = |name: &str| {
+            values
+                .get(name)
+                .and_then(|rate| rate.parse::<u64>().ok())
+                .filter(|rate| *rate > 0)
+        };
+        Ok(Some((
+            bitrate("NewUpstreamMaxBitRate"),
+            bitrate("NewDownstreamMaxBitRate"),
+        )))
+    }
 
-                    // Get device description and find WAN service
-                    self.setup_service().await?;
-                }
-            }
-            Ok(Err(e)) => {
-                error!("Socket error during discovery: {}", e);
-                return Err(anyhow!("Socket error: {}", e));
-            }
-            Err(_) => {
-                warn!("No UPnP devices found within timeout");
-                return Err(anyhow!("Discovery timeout"));
-            }
+    /// (NAT enabled, RSIP available) of the interface's connection, `None`
+    /// when the connection service does not list GetNATRSIPStatus
+    async fn get_nat_rsip_status(
+        &self,
+        interface: &WanInterface,
+    ) -> Result<Option<(Option<bool>, Option<bool>)>> {
+        let service = connection_service(interface)?;
+        if !self.supports(service, "GetNATRSIPStatus") {
+            return Ok(None);
         }
+        let response = self.call(service, "GetNATRSIPStatus").await?;
+        let values = self.parse_response_values(&response, "GetNATRSIPStatus")?;
 
-        Ok(())
+        let flag = |name: &str| values.get(name).and_then(|value| parse_upnp_bool(value));
+        Ok(Some((flag("NewNATEnabled"), flag("NewRSIPAvailable"))))
     }
 
-    fn extract_location(&self, response: &str) -> Option<String> {
-        for line in response.lines() {
-            if line.to_lowercase().starts_with("location:") {
-                return line
-                    .split(':')
-                    .skip(1)
-                    .collect::<Vec<_>>()
-                    .join(":")
-                    .trim()
-                    .to_string()
-                    .into();
-            }
+    /// (current type, possible types) of the interface's connection, `None`
+    /// when the connection service does not list GetConnectionTypeInfo
+    async fn get_connection_type_info(
+        &self,
+        interface: &WanInterface,
+    ) -> Result<Option<(Option<String>, Vec<String>)>> {
+        let service = connection_service(interface)?;
+        if !self.supports(service, "GetConnectionTypeInfo") {
+            return Ok(None);
         }
-        None
+        let response = self.call(service, "GetConnectionTypeInfo").await?;
+        let mut values = self.parse_response_values(&response, "GetConnectionTypeInfo")?;
+
+        let current = values
+            .remove("NewConnectionType")
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+        // A CSV list per the spec
+        let possible = values
+            .remove("NewPossibleConnectionTypes")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Some((current, possible)))
     }
 
-    async fn setup_service(&mut self) -> Result<()> {
-        let device = self
-            .device
-            .as_ref()
-            .ok_or_else(|| anyhow!("No device found"))?;
+    /// DOCSIS state and link type of the interface's WANCableLinkConfig,
+    /// `None` on gateways without the service
+    async fn get_cable_link_info(&self, interface: &WanInterface) -> Result<Option<CableLinkInfo>> {
+        let Some(service) = &interface.cable_link else {
+            return Ok(None);
+        };
+        let response = self.call(service, "GetCableLinkConfigInfo").await?;
+        let mut values = self.parse_response_values(&response, "GetCableLinkConfigInfo")?;
+        let mut value = |name: &str| values.remove(name).filter(|value| !value.is_empty());
+        Ok(Some(CableLinkInfo {
+            state: value("NewCableLinkConfigState"),
+            link_type: value("NewLinkType"),
+        }))
+    }
 
-        debug!("Fetching device description from: {}", device.location);
-        let desc_response = self.client.get(&device.location).send().await?;
-        let desc_xml = desc_response.text().await?;
+    /// Link type, status and autoconfig state of the interface's
+    /// WANDSLLinkConfig, `None` on gateways without the service
+    async fn get_dsl_link_info(&self, interface: &WanInterface) -> Result<Option<DslLinkInfo>> {
+        let Some(service) = &interface.dsl_link else {
+            return Ok(None);
+        };
+        let response = self.call(service, "GetDSLLinkInfo").await?;
+        let mut values = self.parse_response_values(&response, "GetDSLLinkInfo")?;
+        let mut value = |name: &str| {
+            values
+                .remove(name)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let link_type = value("NewLinkType");
+        let link_status = value("NewLinkStatus");
 
-        // Parse XML to find WAN service URLs
-        let (wan_common_url, wan_ip_url) = self.parse_service_urls(&desc_xml, &device.location)?;
+        // Optional in the spec; its absence must not hide the link info
+        let auto_config = if self.supports(service, "GetAutoConfig") {
+            match self
+                .call(service, "GetAutoConfig")
+                .await
+                .and_then(|response| self.parse_response_values(&response, "GetAutoConfig"))
+            {
+                Ok(values) => values
+                    .get("NewAutoConfig")
+                    .and_then(|value| parse_upnp_bool(value)),
+                Err(e) => {
+                    debug!("No DSL autoconfig state: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
-        if let Some(ref mut dev) = self.device {
-            dev.wan_common_service_url = wan_common_url;
-            dev.wan_ip_service_url = wan_ip_url;
-        }
+        Ok(Some(DslLinkInfo {
+            link_type,
+            link_status,
+            auto_config,
+        }))
+    }
 
-        Ok(())
+    /// Active connections of the primary WAN interface, walking
+    /// GetActiveConnection until the gateway reports the end of the list
+    pub async fn list_active_connections(&self) -> UpnpResult<Vec<ActiveConnection>> {
+        let common = &self.wan_interface(0)?.common;
+        match self.get_active_connections(common).await? {
+            Some(connections) => Ok(connections),
+            None => Err(UpnpError::UnsupportedAction {
+                service_type: common.service_type.clone(),
+                action: "GetActiveConnection".to_string(),
+            }),
+        }
     }
 
-    fn parse_service_urls(
+    /// `None` when the service does not list GetActiveConnection
+    async fn get_active_connections(
         &self,
-        xml: &str,
-        _base_url: &str,
-    ) -> Result<(Option<String>, Option<String>)> {
-        let mut reader = EventReader::from_str(xml);
-        let mut wan_common_url: Option<String> = None;
-        let mut wan_ip_url: Option<String> = None;
-        let mut current_service_type = String::new();
-        let mut current_control_url = String::new();
-        let mut in_service = false;
-        let mut in_service_type = false;
-        let mut in_control_url = false;
+        common: &UpnpService,
+    ) -> Result<Option<Vec<ActiveConnection>>> {
+        if !self.supports(common, "GetActiveConnection") {
+            return Ok(None);
+        }
+        let mut connections = Vec::new();
 
-        loop {
-            match reader.next() {
-                Ok(XmlEvent::StartElement { name, .. }) => match name.local_name.as_str() {
-                    "service" => {
-                        in_service = true;
-                        current_service_type.clear();
-                        current_control_url.clear();
-                    }
-                    "serviceType" if in_service => in_service_type = true,
-                    "controlURL" if in_service => in_control_url = true,
-                    _ => {}
-                },
-                Ok(XmlEvent::EndElement { name }) => match name.local_name.as_str() {
-                    "service" => {
-                        if current_service_type.contains("WANCommonInterfaceConfig") {
-                            let full_url = if current_control_url.starts_with("http") {
-                                current_control_url.clone()
-                            } else {
-                                format!("http://192.168.3.1:1900{}", current_control_url)
-                            };
-                            debug!("Found WANCommonInterfaceConfig service at: {}", full_url);
-                            wan_common_url = Some(full_url);
-                        } else if current_service_type.contains("WANIPConnection") {
-                            let full_url = if current_control_url.starts_with("http") {
-                                current_control_url.clone()
-                            } else {
-                                format!("http://192.168.3.1:1900{}", current_control_url)
-                            };
-                            debug!("Found WANIPConnection service at: {}", full_url);
-                            wan_ip_url = Some(full_url);
-                        }
-                        in_service = false;
-                    }
-                    "serviceType" => in_service_type = false,
-                    "controlURL" => in_control_url = false,
-                    _ => {}
-                },
-                Ok(XmlEvent::Characters(text)) => {
-                    if in_service_type {
-                        current_service_type = text;
-                    } else if in_control_url {
-                        current_control_url = text;
+        for index in 0..MAX_ACTIVE_CONNECTIONS {
+            let action = Action::new(&common.service_type, "GetActiveConnection")
+                .arg("NewActiveConnectionIndex", index);
+            let response = match self.call_action(common, action).await {
+                Ok(response) => response,
+                Err(e)
+                    if soap_fault(&e)
+                        .is_some_and(|fault| fault.code == Some(soap::ARRAY_INDEX_INVALID)) =>
+                {
+                    return Ok(Some(connections));
+                }
+                // Some firmwares end the list with other faults (e.g. 402 Invalid Args)
+                Err(e) if index > 0 && soap_fault(&e).is_some() => {
+                    debug!("Active connection list ended at index {}: {}", index, e);
+                    return Ok(Some(connections));
+                }
+                Err(e) => return Err(e),
+            };
+            let mut values = self.parse_response_values(&response, "GetActiveConnection")?;
+            let mut required = |name: &str| {
+                values
+                    .remove(name)
+                    .ok_or_else(|| UpnpError::parse(name, "not found in response"))
+            };
+            connections.push(ActiveConnection {
+                device_container: required("NewActiveConnDeviceContainer")?,
+                service_id: required("NewActiveConnectionServiceID")?,
+            });
+        }
+
+        warn!(
+            "Stopped listing active connections after {} entries",
+            MAX_ACTIVE_CONNECTIONS
+        );
+        Ok(Some(connections))
+    }
+
+    /// Walk the active connections of `interface` once per setup, keeping
+    /// their number for the scrapes, and probe whether the gateway can also
+    /// report NumberOfActiveConnections directly
+    async fn load_active_connections(
+        &self,
+        interface: &mut WanInterface,
+    ) -> Option<Vec<ActiveConnection>> {
+        let active = match self.get_active_connections(&interface.common).await {
+            Ok(active) => active,
+            Err(e) => {
+                debug!("Active connections unavailable: {}", e);
+                None
+            }
+        };
+        interface.active_connections = active.as_ref().map(|list| list.len() as u64);
+        interface.queries_active_connections =
+            match self.query_active_connection_count(&interface.common).await {
+                Ok(count) => {
+                    if interface
+                        .active_connections
+                        .is_some_and(|listed| listed != count)
+                    {
+                        debug!(
+                            "NumberOfActiveConnections is {} but GetActiveConnection listed {:?}",
+                            count, interface.active_connections
+                        );
                     }
+                    true
                 }
-                Ok(XmlEvent::EndDocument) => break,
                 Err(e) => {
-                    error!("XML parsing error: {}", e);
-                    break;
+                    debug!("NumberOfActiveConnections cannot be queried: {}", e);
+                    false
                 }
-                _ => {}
-            }
-        }
+            };
+        active
+    }
 
-        if wan_common_url.is_none() {
-            return Err(anyhow!("WANCommonInterfaceConfig service not found"));
+    /// Poll a connection the gateway reports as active if the selected one
+    /// is not among them. Gateways without GetActiveConnection keep the
+    /// current choice.
+    async fn select_active_connection(&self, interface: &mut WanInterface) {
+        let active = match self.load_active_connections(interface).await {
+            Some(active) if !active.is_empty() => active,
+            _ => return,
+        };
+        let is_active = |connection: &WanConnection| active.iter().any(|a| a.matches(connection));
+        if interface.connection.as_ref().is_none_or(is_active) {
+            return;
         }
+        if let Some(connection) = interface.connections.iter().find(|c| is_active(c)) {
+            info!(
+                "Using {} at {}, the selected connection is not active",
+                connection.kind.service_name(),
+                connection.service.control_url
+            );
+            interface.connection = Some(connection.clone());
+        }
+    }
 
-        Ok((wan_common_url, wan_ip_url))
+    /// Number of active connections for a scrape: NumberOfActiveConnections
+    /// when the gateway answered it at setup, otherwise what the walk then found
+    async fn get_active_connection_count(&self, interface: &WanInterface) -> Option<u64> {
+        if interface.queries_active_connections {
+            match self.query_active_connection_count(&interface.common).await {
+                Ok(count) => return Some(count),
+                Err(e) => debug!("NumberOfActiveConnections query failed: {}", e),
+            }
+        }
+        interface.active_connections
     }
 
-    pub async fn get_traffic_stats(&self) -> Result<TrafficStats> {
-        let device = self
-            .device
-            .as_ref()
-            .ok_or_else(|| anyhow!("No device configured"))?;
-        let wan_common_url = device
-            .wan_common_service_url
-            .as_ref()
-            .ok_or_else(|| anyhow!("No WANCommonInterfaceConfig service URL"))?;
-        let _wan_ip_url = device.wan_ip_service_url.as_ref();
+    async fn query_active_connection_count(&self, common: &UpnpService) -> Result<u64> {
+        let value = self
+            .query_state_variable(common, "NumberOfActiveConnections")
+            .await?;
+        value.trim().parse().map_err(|e| {
+            UpnpError::parse(
+                "NumberOfActiveConnections",
+                format!("invalid count {:?}: {}", value, e),
+            )
+            .into()
+        })
+    }
 
-        let mut stats = TrafficStats::default();
+    /// Port mappings of the primary WAN connection, read in bulk with
+    /// GetListOfPortMappings on WANIPConnection:2, otherwise by walking
+    /// GetGenericPortMappingEntry until the gateway reports the end of the table
+    pub async fn list_port_mappings(&self) -> UpnpResult<Vec<PortMapping>> {
+        let service = connection_service(self.wan_interface(0)?)?;
+        if service.service_type.contains(":WANIPConnection:")
+            && !service.service_type.ends_with(":1")
+            && self.supports(service, "GetListOfPortMappings")
+        {
+            match self.get_list_of_port_mappings(service).await {
+                // 606: listing all mappings (NewManage) needs rights the walk does not
+                Err(e)
+                    if soap_fault(&e)
+                        .is_some_and(|fault| matches!(fault.code, Some(401 | 602 | 606))) =>
+                {
+                    debug!(
+                        "GetListOfPortMappings failed, walking the table instead: {}",
+                        e
+                    );
+                }
+                result => return Ok(result?),
+            }
+        }
+        Ok(self.walk_port_mappings(service).await?)
+    }
 
-        // Get bytes sent
-        if let Ok(bytes_sent) = self.get_total_bytes_sent(wan_common_url).await {
-            stats.bytes_sent = bytes_sent;
+    async fn get_list_of_port_mappings(&self, service: &UpnpService) -> Result<Vec<PortMapping>> {
+        let mut mappings = Vec::new();
+        for protocol in ["TCP", "UDP"] {
+            let action = Action::new(&service.service_type, "GetListOfPortMappings")
+                .arg("NewStartPort", 0)
+                .arg("NewEndPort", u16::MAX)
+                .arg("NewProtocol", protocol)
+                .arg("NewManage", 1)
+                .arg("NewNumberOfPorts", MAX_PORT_MAPPINGS);
+            let response = match self.call_action(service, action).await {
+                Ok(response) => response,
+                // No mapping in the requested range
+                Err(e)
+                    if soap_fault(&e)
+                        .is_some_and(|fault| fault.code == Some(soap::ARRAY_INDEX_INVALID)) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            // The listing is an XML document escaped into the string argument
+            let listing =
+                self.parse_string_response(&response, "GetListOfPortMappings", "NewPortListing")?;
+            mappings.extend(parse_port_mapping_list(&listing)?);
         }
+        Ok(mappings)
+    }
 
-        // Get bytes received
-        if let Ok(bytes_received) = self.get_total_bytes_received(wan_common_url).await {
-            stats.bytes_received = bytes_received;
+    async fn walk_port_mappings(&self, service: &UpnpService) -> Result<Vec<PortMapping>> {
+        let mut mappings = Vec::new();
+
+        for index in 0..MAX_PORT_MAPPINGS {
+            let action = Action::new(&service.service_type, "GetGenericPortMappingEntry")
+                .arg("NewPortMappingIndex", index);
+            let response = match self.call_action(service, action).await {
+                Ok(response) => response,
+                Err(e)
+                    if soap_fault(&e)
+                        .is_some_and(|fault| fault.code == Some(soap::ARRAY_INDEX_INVALID)) =>
+                {
+                    return Ok(mappings);
+                }
+                Err(e) => return Err(e),
+            };
+            let values = self.parse_response_values(&response, "GetGenericPortMappingEntry")?;
+            mappings.push(port_mapping_from_values(values)?);
         }
 
-        // Get packets sent
-        if let Ok(packets_sent) = self.get_total_packets_sent(wan_common_url).await {
-            stats.packets_sent = packets_sent;
+        warn!(
+            "Stopped listing port mappings after {} entries",
+            MAX_PORT_MAPPINGS
+        );
+        Ok(mappings)
+    }
+
+    /// Whether to prefer GetAddonInfos: as configured, otherwise for AVM gateways
+    fn use_addon_infos(&self) -> bool {
+        self.config.avm_addon_infos.unwrap_or_else(|| {
+            self.device
+                .as_ref()
+                .and_then(|d| d.info.manufacturer.as_deref())
+                .is_some_and(|m| m.starts_with("AVM"))
+        })
+    }
+
+    /// 64-bit byte counters and current rates, `None` if GetAddonInfos is not to be used
+    async fn get_addon_infos(&self, service: &UpnpService) -> Result<Option<AddonInfos>> {
+        if !self.use_addon_infos()
+            || !self.supports(service, "GetAddonInfos")
+            || self.addon_infos_unusable()
+        {
+            return Ok(None);
         }
+        let response = self.call(service, "GetAddonInfos").await?;
+        let values = self.parse_response_values(&response, "GetAddonInfos")?;
+
+        let counter = |name: &str| -> UpnpResult<u64> {
+            values
+                .get(name)
+                .ok_or_else(|| UpnpError::parse(name, "not found in response"))?
+                .parse()
+                .map_err(|e| UpnpError::parse(name, e))
+        };
+        let rate = |name: &str| values.get(name).and_then(|rate| rate.parse().ok());
+        Ok(Some(AddonInfos {
+            bytes_sent: counter("NewX_AVM_DE_TotalBytesSent64")?,
+            bytes_received: counter("NewX_AVM_DE_TotalBytesReceived64")?,
+            byte_send_rate: rate("NewByteSendRate"),
+            byte_receive_rate: rate("NewByteReceiveRate"),
+        }))
+    }
 
-        // Get packets received
-        if let Ok(packets_received) = self.get_total_packets_received(wan_common_url).await {
-            stats.packets_received = packets_received;
+    fn addon_infos_unusable(&self) -> bool {
+        let unusable = self.addon_infos_unusable.lock().unwrap();
+        unusable.is_some()
+            && unusable.as_deref() == self.device.as_ref().map(|d| d.location.as_str())
+    }
+
+    /// Stop asking the current device for GetAddonInfos once it answered with
+    /// a fault or something unreadable; timeouts and dropped connections only
+    /// cost this reading
+    fn note_addon_infos_failure(&self, error: &anyhow::Error) {
+        let deterministic = error.downcast_ref::<UpnpError>().is_some_and(|e| {
+            matches!(e, UpnpError::Soap(_) | UpnpError::Parse { .. }) && !e.is_transient()
+        });
+        if !deterministic {
+            debug!(
+                "GetAddonInfos failed, using the standard counters: {:#}",
+                error
+            );
+            return;
         }
+        if let Some(device) = &self.device {
+            info!(
+                "GetAddonInfos failed ({:#}), reading the standard byte counters of {} from now on",
+                error, device.location
+            );
+            *self.addon_infos_unusable.lock().unwrap() = Some(device.location.clone());
+        }
+    }
+
+    /// Convert a raw byte counter to bytes using the configured `counter_scale`
+    fn scale_bytes(&self, raw: u64) -> u64 {
+        raw.saturating_mul(self.config.counter_scale.max(1))
+    }
+
+    fn wan_interface(&self, index: usize) -> Result<&WanInterface> {
+        self.device
+            .as_ref()
+            .ok_or_else(|| anyhow!("No device configured"))?
+            .wan_interfaces
+            .get(index)
+            .ok_or_else(|| anyhow!("No WAN interface {}", index))
+    }
+
+    async fn get_total_bytes_sent(&self, service: &UpnpService) -> Result<u64> {
+        let response = self.call(service, "GetTotalBytesSent").await?;
+        self.parse_u64_response(&response, "GetTotalBytesSent", "NewTotalBytesSent")
+    }
+
+    async fn get_total_bytes_received(&self, service: &UpnpService) -> Result<u64> {
+        let response = self.call(service, "GetTotalBytesReceived").await?;
+        self.parse_u64_response(&response, "GetTotalBytesReceived", "NewTotalBytesReceived")
+    }
+
+    async fn get_total_packets_sent(&self, service: &UpnpService) -> Result<u64> {
+        let response = self.call(service, "GetTotalPacketsSent").await?;
+        self.parse_u64_response(&response, "GetTotalPacketsSent", "NewTotalPacketsSent")
+    }
+
+    async fn get_total_packets_received(&self, service: &UpnpService) -> Result<u64> {
+        let response = self.call(service, "GetTotalPacketsReceived").await?;
+        self.parse_u64_response(
+            &response,
+            "GetTotalPacketsReceived",
+            "NewTotalPacketsReceived",
+        )
+    }
 
-        // Get connection status
-        if let Ok(link_status) = self.get_physical_link_status(wan_common_url).await {
-            stats.connection_status = link_status;
+    /// GetCommonLinkProperties, or only the link status from
+    /// WANEthernetLinkConfig or WANCableLinkConfig when the gateway lacks or
+    /// rejects that action
+    async fn get_link_properties(&self, interface: &WanInterface) -> Result<LinkProperties> {
+        let error = match self.get_common_link_properties(&interface.common).await {
+            Ok(link) => return Ok(link),
+            Err(e) => e,
+        };
+        let (source, status) = match (&interface.ethernet_link, &interface.cable_link) {
+            (Some(ethernet_link), _) => (
+                "WANEthernetLinkConfig",
+                self.get_ethernet_link_status(ethernet_link).await,
+            ),
+            (None, Some(_)) => (
+                "WANCableLinkConfig",
+                self.get_cable_link_status(interface).await,
+            ),
+            (None, None) => return Err(error),
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("{} link status fallback failed: {}", source, e);
+                return Err(error);
+            }
+        };
+        if !self
+            .link_status_fallback_logged
+            .swap(true, Ordering::Relaxed)
+        {
+            info!(
+                "GetCommonLinkProperties unavailable ({}), using {} link status",
+                error, source
+            );
         }
+        Ok(LinkProperties {
+            status,
+            up_max_bitrate_bps: None,
+            down_max_bitrate_bps: None,
+        })
+    }
 
-        Ok(stats)
+    /// NewEthernetLinkStatus: "Up", "Down" or "Unavailable"
+    async fn get_ethernet_link_status(&self, service: &UpnpService) -> Result<String> {
+        let response = self.call(service, "GetEthernetLinkStatus").await?;
+        self.parse_string_response(&response, "GetEthernetLinkStatus", "NewEthernetLinkStatus")
     }
 
-    async fn get_total_bytes_sent(&self, service_url: &str) -> Result<u64> {
-        let soap_body = r#"<?xml version="1.0" encoding="utf-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-    <s:Body>
-        <u:GetTotalBytesSent xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1" />
-    </s:Body>
-</s:Envelope>"#;
+    /// "Up" once the cable modem is operational, "Down" in any earlier stage
+    async fn get_cable_link_status(&self, interface: &WanInterface) -> Result<String> {
+        let state = self
+            .get_cable_link_info(interface)
+            .await?
+            .and_then(|cable| cable.state)
+            .ok_or_else(|| UpnpError::parse("NewCableLinkConfigState", "not found in response"))?;
+        Ok(if state.eq_ignore_ascii_case("operational") {
+            "Up"
+        } else {
+            "Down"
+        }
+        .to_string())
+    }
 
-        let response = self
-            .soap_request(
-                service_url,
-                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1#GetTotalBytesSent",
-                soap_body,
-            )
-            .await?;
-        self.parse_u64_response(&response, "NewTotalBytesSent")
+    async fn get_common_link_properties(&self, service: &UpnpService) -> Result<LinkProperties> {
+        let response = self.call(service, "GetCommonLinkProperties").await?;
+        let mut values = self.parse_response_values(&response, "GetCommonLinkProperties")?;
+
+        // Ethernet uplinks report 0 for the layer-1 rates
+        let mut bitrate = |name: &str| {
+            values
+                .remove(name)
+                .and_then(|rate| rate.parse::<u64>().ok())
+                .filter(|rate| *rate > 0)
+        };
+        let up_max_bitrate_bps = bitrate("NewLayer1UpstreamMaxBitRate");
+        let down_max_bitrate_bps = bitrate("NewLayer1DownstreamMaxBitRate");
+        let status = values
+            .remove("NewPhysicalLinkStatus")
+            .ok_or_else(|| UpnpError::parse("NewPhysicalLinkStatus", "not found in response"))?;
+        Ok(LinkProperties {
+            status,
+            up_max_bitrate_bps,
+            down_max_bitrate_bps,
+        })
     }
 
-    async fn get_total_bytes_received(&self, service_url: &str) -> Result<u64> {
-        let soap_body = r#"<?xml version="1.0" encoding="utf-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-    <s:Body>
-        <u:GetTotalBytesReceived xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1" />
-    </s:Body>
-</s:Envelope>"#;
+    async fn get_default_connection_service(&self, service: &UpnpService) -> Result<String> {
+        let response = self.call(service, "GetDefaultConnectionService").await?;
+        self.parse_string_response(
+            &response,
+            "GetDefaultConnectionService",
+            "NewDefaultConnectionService",
+        )
+    }
 
-        let response = self
-            .soap_request(
-                service_url,
-                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1#GetTotalBytesReceived",
-                soap_body,
-            )
-            .await?;
-        self.parse_u64_response(&response, "NewTotalBytesReceived")
+    /// Whether `action` may be sent to `service`: advertised and not disabled
+    fn supports(&self, service: &UpnpService, action: &str) -> bool {
+        !self.is_disabled(action) && service.supports(action)
     }
 
-    async fn get_total_packets_sent(&self, service_url: &str) -> Result<u64> {
-        let soap_body = r#"<?xml version="1.0" encoding="utf-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-    <s:Body>
-        <u:GetTotalPacketsSent xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1" />
-    </s:Body>
-</s:Envelope>"#;
+    fn is_disabled(&self, action: &str) -> bool {
+        self.config.disabled_actions.iter().any(|a| a == action)
+    }
 
-        let response = self
-            .soap_request(
-                service_url,
-                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1#GetTotalPacketsSent",
-                soap_body,
-            )
-            .await?;
-        self.parse_u64_response(&response, "NewTotalPacketsSent")
+    /// Invoke an argument-less action
+    async fn call(&self, service: &UpnpService, action_name: &str) -> Result<String> {
+        self.call_action(service, Action::new(&service.service_type, action_name))
+            .await
     }
 
-    async fn get_total_packets_received(&self, service_url: &str) -> Result<u64> {
-        let soap_body = r#"<?xml version="1.0" encoding="utf-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-    <s:Body>
-        <u:GetTotalPacketsReceived xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1" />
-    </s:Body>
-</s:Envelope>"#;
+    /// Invoke an action using the advertised service version, retrying once
+    /// with the :1 URN if the device rejects that version
+    async fn call_action(&self, service: &UpnpService, action: Action) -> Result<String> {
+        if self.is_disabled(action.name()) {
+            return Err(anyhow!(
+                "{} is disabled by upnp.disabled_actions",
+                action.name()
+            ));
+        }
+        if !service.supports(action.name()) {
+            return Err(UpnpError::UnsupportedAction {
+                service_type: service.service_type.clone(),
+                action: action.name().to_string(),
+            }
+            .into());
+        }
+        self.send_action(service, action).await
+    }
 
-        let response = self
-            .soap_request(
-                service_url,
-                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1#GetTotalPacketsReceived",
-                soap_body,
-            )
-            .await?;
-        self.parse_u64_response(&response, "NewTotalPacketsReceived")
+    /// Read a state variable with the UPnP 1.0 QueryStateVariable action,
+    /// which services answer without listing it in their SCPD
+    async fn query_state_variable(&self, service: &UpnpService, variable: &str) -> Result<String> {
+        if self.is_disabled("QueryStateVariable") {
+            return Err(anyhow!(
+                "QueryStateVariable is disabled by upnp.disabled_actions"
+            ));
+        }
+        let action = Action::new(QUERY_SERVICE_TYPE, "QueryStateVariable").arg("varName", variable);
+        let response = self.send_action(service, action).await?;
+        let mut values = self.parse_response_values(&response, "QueryStateVariable")?;
+        values
+            .remove("return")
+            .ok_or_else(|| UpnpError::parse("return", "not found in response").into())
     }
 
-    async fn get_physical_link_status(&self, service_url: &str) -> Result<String> {
-        let soap_body = r#"<?xml version="1.0" encoding="utf-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-    <s:Body>
-        <u:GetCommonLinkProperties xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1" />
-    </s:Body>
-</s:Envelope>"#;
+    async fn send_action(&self, service: &UpnpService, action: Action) -> Result<String> {
+        self.record(|metrics, device| metrics.count_soap_request(device, action.name()));
+        let deadline = SCRAPE_DEADLINE
+            .try_with(|deadline| *deadline)
+            .unwrap_or_else(|_| Instant::now() + Duration::from_secs(self.config.scrape_timeout));
+        let mut response = self
+            .soap_request(&service.control_url, &action, deadline)
+            .await;
 
-        let response = self
-            .soap_request(
-                service_url,
-                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1#GetCommonLinkProperties",
-                soap_body,
-            )
-            .await?;
-        self.parse_string_response(&response, "NewPhysicalLinkStatus")
+        if let Err(e) = &response
+            && soap_fault(e).is_some_and(SoapFault::is_version_mismatch)
+            && let Some(fallback) = action.with_version_1()
+        {
+            debug!(
+                "{} rejected {}, retrying with {}",
+                service.control_url,
+                action.service_type(),
+                fallback.service_type()
+            );
+            response = self
+                .soap_request(&service.control_url, &fallback, deadline)
+                .await;
+        }
+
+        // 713 is how gateways end the lists walked by index
+        if let Err(e) = &response
+            && soap_fault(e).is_none_or(|fault| fault.code != Some(soap::ARRAY_INDEX_INVALID))
+        {
+            self.record(|metrics, device| {
+                metrics.count_soap_error(device, action.name(), soap_error_kind(e))
+            });
+        }
+        response
     }
 
+    /// Send a SOAP request, retrying connection failures and fault-less 5xx
+    /// responses with a growing delay. Faults and 4xx are deterministic and
+    /// returned at once; no retry starts at or past `deadline`.
     async fn soap_request(
         &self,
         service_url: &str,
-        soap_action: &str,
-        soap_body: &str,
+        action: &Action,
+        deadline: Instant,
+    ) -> Result<String> {
+        let attempts = self.config.soap_attempts.max(1);
+        let mut backoff = Duration::from_millis(self.config.soap_retry_backoff_ms);
+
+        let mut attempt = 1;
+        loop {
+            let result = self.soap_request_once(service_url, action).await;
+            match result {
+                Err(e)
+                    if attempt < attempts
+                        && e.downcast_ref::<UpnpError>()
+                            .is_some_and(UpnpError::is_transient)
+                        && Instant::now() + backoff < deadline =>
+                {
+                    debug!(
+                        "{} attempt {}/{} failed, retrying in {:?}: {:#}",
+                        action.name(),
+                        attempt,
+                        attempts,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Send a SOAP request with the standard SOAPAction header, switching to
+    /// the configured quirk form for good if the gateway rejects it
+    async fn soap_request_once(&self, service_url: &str, action: &Action) -> Result<String> {
+        let quirk = self.config.soap_action_format;
+        if quirk == SoapActionFormat::Quoted {
+            return self.send_soap_request(service_url, action, quirk).await;
+        }
+        if self.soap_action_quirk_active.load(Ordering::Relaxed) {
+            return self.send_soap_request(service_url, action, quirk).await;
+        }
+
+        match self
+            .send_soap_request(service_url, action, SoapActionFormat::Quoted)
+            .await
+        {
+            // A UPnP fault means the header was understood
+            Err(e) if soap_fault(&e).is_some_and(|fault| fault.code.is_none()) => {
+                debug!(
+                    "{} rejected the quoted SOAPAction ({}), retrying as {:?}",
+                    service_url, e, quirk
+                );
+                let result = self.send_soap_request(service_url, action, quirk).await;
+                if result.is_ok() && !self.soap_action_quirk_active.swap(true, Ordering::Relaxed) {
+                    info!(
+                        "Gateway accepts only the {:?} SOAPAction form, using it from now on",
+                        quirk
+                    );
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// Wait for a free slot among `max_concurrent_soap_requests`
+    async fn soap_slot(&self) -> Result<SoapSlot<'_>> {
+        let permit = self.soap_slots.acquire().await?;
+        self.record(|metrics, device| metrics.add_soap_requests_in_flight(device, 1.0));
+        Ok(SoapSlot {
+            _permit: permit,
+            metrics: self.metrics.as_deref(),
+            device: &self.metrics_device,
+        })
+    }
+
+    async fn send_soap_request(
+        &self,
+        service_url: &str,
+        action: &Action,
+        format: SoapActionFormat,
     ) -> Result<String> {
+        let _slot = self.soap_slot().await?;
+        let soap_action = format.header_value(&action.soap_action());
         debug!("SOAP request to {}: {}", service_url, soap_action);
+        trace!(
+            "SOAP request headers: Content-Type: {}, SOAPAction: {}",
+            SOAP_CONTENT_TYPE, soap_action
+        );
 
+        let envelope = action.envelope();
         let response = self
-            .client
-            .post(service_url)
-            .header("Content-Type", "text/xml; charset=\"utf-8\"")
-            .header("SOAPAction", format!("\"{}\";", soap_action))
-            .body(soap_body.to_string())
-            .send()
+            .send_authenticated("POST", service_url, Some(envelope.as_bytes()), || {
+                let request = self
+                    .client
+                    .post(request_url(service_url))
+                    .header("Content-Type", SOAP_CONTENT_TYPE)
+                    .header("SOAPAction", &soap_action);
+                let request = if self.config.http10_compat {
+                    request
+                        .version(Version::HTTP_10)
+                        .header(header::CONNECTION, "close")
+                        .header(header::CONTENT_LENGTH, envelope.len())
+                } else {
+                    request
+                };
+                request.body(envelope.clone())
+            })
             .await?;
 
-        let response_text = response.text().await?;
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = read_limited(response, soap::MAX_RESPONSE_BYTES, || {
+            UpnpError::parse(
+                "Envelope",
+                format!("response exceeds {} bytes", soap::MAX_RESPONSE_BYTES),
+            )
+            .into()
+        })
+        .await?;
+        let decoded = charset::decode_xml(&body, charset::content_type_charset(&content_type));
+        let response_text = soap::repair_response(&decoded).into_owned();
         debug!("SOAP response: {}", response_text);
 
+        if let Some(fault) =
+            SoapFault::from_response(action.name(), status.as_u16(), &response_text)
+        {
+            return Err(UpnpError::Soap(fault).into());
+        }
+        // Bodies without any response element are left for the value parser to report
+        let responses = soap::body_responses(&response_text);
+        let expected = format!("{}Response", action.name());
+        if !responses.is_empty() && !responses.contains(&expected) {
+            return Err(UpnpError::ResponseMismatch {
+                action: action.name().to_string(),
+                found: responses.join(", "),
+            }
+            .into());
+        }
         Ok(response_text)
     }
 
-    fn parse_u64_response(&self, xml: &str, element_name: &str) -> Result<u64> {
-        let mut reader = EventReader::from_str(xml);
-        let mut in_target_element = false;
+    /// SUBSCRIBE `callback` to the events of the service at `event_sub_url`,
+    /// asking for `timeout` seconds
+    pub async fn subscribe(
+        &self,
+        event_sub_url: &str,
+        callback: &str,
+        timeout: u64,
+    ) -> UpnpResult<Subscription> {
+        let response = self
+            .send_authenticated("SUBSCRIBE", event_sub_url, None, || {
+                self.client
+                    .request(gena_method("SUBSCRIBE"), request_url(event_sub_url))
+                    .header("CALLBACK", format!("<{}>", callback))
+                    .header("NT", "upnp:event")
+                    .header("TIMEOUT", format!("Second-{}", timeout))
+            })
+            .await?;
+        Ok(granted_subscription(event_sub_url, response, timeout)?)
+    }
 
-        loop {
-            match reader.next() {
-                Ok(XmlEvent::StartElement { name, .. }) => {
-                    if name.local_name == element_name {
-                        in_target_element = true;
-                    }
-                }
-                Ok(XmlEvent::Characters(text)) if in_target_element => {
-                    return text
-                        .parse::<u64>()
-                        .map_err(|e| anyhow!("Failed to parse {}: {}", element_name, e));
-                }
-                Ok(XmlEvent::EndElement { name }) => {
-                    if name.local_name == element_name {
-                        in_target_element = false;
-                    }
-                }
-                Ok(XmlEvent::EndDocument) => break,
-                Err(e) => {
-                    error!("XML parsing error: {}", e);
-                    break;
-                }
-                _ => {}
-            }
+    /// Extend `subscription` by another `timeout` seconds
+    pub async fn renew_subscription(
+        &self,
+        subscription: &Subscription,
+        timeout: u64,
+    ) -> UpnpResult<Subscription> {
+        let url = &subscription.event_sub_url;
+        let response = self
+            .send_authenticated("SUBSCRIBE", url, None, || {
+                self.client
+                    .request(gena_method("SUBSCRIBE"), request_url(url))
+                    .header("SID", &subscription.sid)
+                    .header("TIMEOUT", format!("Second-{}", timeout))
+            })
+            .await?;
+        Ok(granted_subscription(url, response, timeout)?)
+    }
+
+    pub async fn unsubscribe(&self, subscription: &Subscription) -> UpnpResult<()> {
+        let url = &subscription.event_sub_url;
+        self.send_authenticated("UNSUBSCRIBE", url, None, || {
+            self.client
+                .request(gena_method("UNSUBSCRIBE"), request_url(url))
+                .header("SID", &subscription.sid)
+        })
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Send the request `build` creates, answering a Basic or Digest
+    /// challenge with the configured credentials. Requests after the first
+    /// challenge carry the Authorization header up front.
+    async fn send_authenticated(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<&[u8]>,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        let url = request_url(url);
+        let authorized = |request: RequestBuilder| match self
+            .auth
+            .as_ref()
+            .and_then(|auth| auth.authorization(method, &url, body))
+        {
+            Some(authorization) => request.header(header::AUTHORIZATION, authorization),
+            None => request,
+        };
+
+        let response = authorized(build())
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let Some(challenge) = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Ok(response);
+        };
+        let failed = |credentials_configured| UpnpError::AuthenticationFailed {
+            url: url.clone(),
+            credentials_configured,
+        };
+        let Some(auth) = &self.auth else {
+            return Err(failed(false).into());
+        };
+
+        // The first challenge, or a fresh nonce after the cached one went stale
+        auth.set_challenge(challenge)?;
+        let response = authorized(build())
+            .send()
+            .await
+            .map_err(|e| self.http_error(e))?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(failed(true).into());
         }
+        Ok(response)
+    }
+
+    fn http_error(&self, error: reqwest::Error) -> anyhow::Error {
+        // reqwest flags connect timeouts as both, so check the timeout first
+        let error = if error.is_timeout() {
+            let (stage, seconds) = if error.is_connect() {
+                ("Connecting", self.config.http_connect_timeout)
+            } else {
+                ("Request", self.config.http_request_timeout)
+            };
+            UpnpError::Timeout {
+                stage,
+                seconds,
+                source: error,
+            }
+        } else {
+            match &self.config.proxy {
+                Some(proxy) if error.is_connect() => UpnpError::Proxy(ProxyError {
+                    proxy: proxy.to_string(),
+                    source: error,
+                }),
+                _ => UpnpError::Http(error),
+            }
+        };
+        error.into()
+    }
+
+    fn parse_u64_response(&self, xml: &str, action: &str, element_name: &str) -> Result<u64> {
+        parse_upnp_u64(&self.parse_string_response(xml, action, element_name)?).map_err(|e| {
+            self.record(|metrics, device| metrics.count_soap_error(device, action, "parse"));
+            UpnpError::parse(element_name, e).into()
+        })
+    }
 
-        Err(anyhow!("Element {} not found in response", element_name))
+    fn parse_string_response(&self, xml: &str, action: &str, element_name: &str) -> Result<String> {
+        self.parse_response_values(xml, action)?
+            .remove(element_name)
+            .ok_or_else(|| {
+                self.record(|metrics, device| metrics.count_soap_error(device, action, "parse"));
+                UpnpError::parse(element_name, format!("not found in {}Response", action)).into()
+            })
     }
 
-    fn parse_string_response(&self, xml: &str, element_name: &str) -> Result<String> {
-        let mut reader = EventReader::from_str(xml);
-        let mut in_target_element = false;
+    /// Trimmed text of the output arguments of `action`, i.e. the direct
+    /// children of its `<prefix:{action}Response>` element, by local name.
+    /// Like-named elements elsewhere in the envelope are ignored.
+    fn parse_response_values(&self, xml: &str, action: &str) -> Result<HashMap<String, String>> {
+        let response_element = format!("{}Response", action);
+        let malformed = |e: &dyn fmt::Display| -> anyhow::Error {
+            self.record(|metrics, device| metrics.count_soap_error(device, action, "parse"));
+            UpnpError::parse(
+                &response_element,
+                format!(
+                    "malformed XML ({}), payload starts with {:?}",
+                    e,
+                    soap::excerpt(xml)
+                ),
+            )
+            .into()
+        };
+        let mut reader = Reader::from_str(xml);
+        let mut values = HashMap::new();
+        let mut depth = 0;
+        // Depth of the response element once inside it
+        let mut response_depth: Option<usize> = None;
+        let mut argument: Option<(String, String)> = None;
 
         loop {
-            match reader.next() {
-                Ok(XmlEvent::StartElement { name, .. }) => {
-                    if name.local_name == element_name {
-                        in_target_element = true;
+            // Text borrows from `xml`; only the output arguments are copied
+            match reader.read_event().map_err(|e| malformed(&e))? {
+                Event::Start(element) => {
+                    depth += 1;
+                    let name = element.local_name();
+                    match response_depth {
+                        None if name.as_ref() == response_element.as_bytes() => {
+                            response_depth = Some(depth);
+                        }
+                        Some(level) if depth == level + 1 => {
+                            let name = str::from_utf8(name.as_ref()).map_err(|e| malformed(&e))?;
+                            argument = Some((name.to_string(), String::new()));
+                        }
+                        _ => {}
                     }
                 }
-                Ok(XmlEvent::Characters(text)) if in_target_element => {
-                    return Ok(text);
+                // An action without output arguments, or an empty one
+                // such as `<NewExternalIPAddress/>`
+                Event::Empty(element) => {
+                    let name = element.local_name();
+                    match response_depth {
+                        None if name.as_ref() == response_element.as_bytes() => break,
+                        Some(level) if depth == level => {
+                            let name = str::from_utf8(name.as_ref()).map_err(|e| malformed(&e))?;
+                            values.insert(name.to_string(), String::new());
+                        }
+                        _ => {}
+                    }
                 }
-                Ok(XmlEvent::EndElement { name }) => {
-                    if name.local_name == element_name {
-                        in_target_element = false;
+                Event::Text(text) => {
+                    if let (Some(level), Some((_, value))) = (response_depth, argument.as_mut())
+                        && depth == level + 1
+                    {
+                        value.push_str(&text.unescape().map_err(|e| malformed(&e))?);
                     }
                 }
-                Ok(XmlEvent::EndDocument) => break,
-                Err(e) => {
-                    error!("XML parsing error: {}", e);
-                    break;
+                Event::End(_) => {
+                    match response_depth {
+                        Some(level) if depth == level => break,
+                        Some(level) if depth == level + 1 => {
+                            if let Some((name, value)) = argument.take() {
+                                values.insert(name, value.trim().to_string());
+                            }
+                        }
+                        _ => {}
+                    }
+                    depth -= 1;
                 }
+                Event::Eof if depth > 0 => return Err(malformed(&"unexpected end of document")),
+                Event::Eof => break,
                 _ => {}
             }
         }
 
-        Err(anyhow!("Element {} not found in response", element_name))
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_http_locations() {
+        for location in [
+            "http://192.168.1.1:5000/rootDesc.xml",
+            "https://192.168.1.1:49443/igd.xml",
+            "http://[fe80::1%eth0]:49000/igddesc.xml",
+        ] {
+            assert!(is_http_url(location), "{location}");
+        }
+        for location in ["", "/rootDesc.xml", "ftp://192.168.1.1/desc.xml", "http://"] {
+            assert!(!is_http_url(location), "{location}");
+        }
+    }
+
+    #[test]
+    fn response_values_of_real_routers() {
+        let client = UpnpClient::new();
+        for (router, xml, expected) in [
+            (
+                "Huawei",
+                include_str!("../tests/fixtures/soap/huawei-bytes-sent.xml"),
+                3456789012,
+            ),
+            (
+                "miniupnpd",
+                include_str!("../tests/fixtures/soap/miniupnpd-bytes-sent.xml"),
+                1234567,
+            ),
+            (
+                "FRITZ!Box",
+                include_str!("../tests/fixtures/soap/fritzbox-bytes-sent.xml"),
+                4026531840,
+            ),
+            (
+                "TP-Link",
+                include_str!("../tests/fixtures/soap/tplink-bytes-sent.xml"),
+                98765,
+            ),
+        ] {
+            let value = client
+                .parse_u64_response(xml, "GetTotalBytesSent", "NewTotalBytesSent")
+                .unwrap_or_else(|e| panic!("{router}: {e:#}"));
+            assert_eq!(value, expected, "{router}");
+        }
+    }
+
+    #[test]
+    fn response_values_with_prefixed_arguments() {
+        let xml = r#"<soapenv:Envelope xmlns:soapenv="http://schemas.xmlsoap.org/soap/envelope/"><soapenv:Body><ns1:GetStatusInfoResponse xmlns:ns1="urn:schemas-upnp-org:service:WANPPPConnection:1"><ns1:NewConnectionStatus>Connected</ns1:NewConnectionStatus><ns1:NewUptime>42</ns1:NewUptime></ns1:GetStatusInfoResponse></soapenv:Body></soapenv:Envelope>"#;
+        let values = UpnpClient::new()
+            .parse_response_values(xml, "GetStatusInfo")
+            .unwrap();
+        assert_eq!(values["NewConnectionStatus"], "Connected");
+        assert_eq!(values["NewUptime"], "42");
+    }
+
+    #[test]
+    fn response_values_of_another_action_are_not_taken() {
+        let xml = include_str!("../tests/fixtures/soap/miniupnpd-bytes-sent.xml");
+        let error = UpnpClient::new()
+            .parse_u64_response(xml, "GetTotalBytesReceived", "NewTotalBytesSent")
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("not found in GetTotalBytesReceivedResponse"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn empty_response_element_has_no_values() {
+        let xml = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1"><NewExternalIPAddress/></u:GetExternalIPAddressResponse></s:Body></s:Envelope>"#;
+        let values = UpnpClient::new()
+            .parse_response_values(xml, "GetExternalIPAddress")
+            .unwrap();
+        assert_eq!(values["NewExternalIPAddress"], "");
+    }
+
+    #[test]
+    fn negative_counters_are_signed_32_bit() {
+        for (value, expected) in [
+            ("-1294967296", 3_000_000_000),
+            ("-1", u64::from(u32::MAX)),
+            ("-2147483648", 1 << 31),
+        ] {
+            assert_eq!(parse_upnp_u64(value), Ok(expected), "{value}");
+        }
+        let xml = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:GetTotalBytesReceivedResponse xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1"><NewTotalBytesReceived>-1294967296</NewTotalBytesReceived></u:GetTotalBytesReceivedResponse></s:Body></s:Envelope>"#;
+        let value = UpnpClient::new()
+            .parse_u64_response(xml, "GetTotalBytesReceived", "NewTotalBytesReceived")
+            .unwrap();
+        assert_eq!(value, 3_000_000_000);
+    }
+
+    #[test]
+    fn positive_counters_are_taken_as_they_are() {
+        for (value, expected) in [
+            ("0", 0),
+            ("3456789012", 3_456_789_012),
+            ("18446744073709551615", u64::MAX),
+            (" 42", 42),
+            ("\n\t42 \r\n", 42),
+            (" -1 ", u64::from(u32::MAX)),
+        ] {
+            assert_eq!(parse_upnp_u64(value), Ok(expected), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_counters_are_rejected() {
+        for value in [
+            "",
+            "-",
+            "18446744073709551616",
+            "99999999999999999999999",
+            "-2147483649",
+            "12abc",
+            "1.5",
+            "0x10",
+            "4 2",
+        ] {
+            assert!(parse_upnp_u64(value).is_err(), "{value:?}");
+        }
+    }
+
+    const PORT_MAPPING_LIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<p:PortMappingList xmlns:p="urn:schemas-upnp-org:gw:WANIPConnection">
+  <p:PortMappingEntry>
+    <p:NewRemoteHost></p:NewRemoteHost>
+    <p:NewExternalPort>8080</p:NewExternalPort>
+    <p:NewProtocol>TCP</p:NewProtocol>
+    <p:NewInternalPort>80</p:NewInternalPort>
+    <p:NewInternalClient>192.168.1.10</p:NewInternalClient>
+    <p:NewEnabled>1</p:NewEnabled>
+    <p:NewDescription>web &amp; more</p:NewDescription>
+    <p:NewLeaseTime>3600</p:NewLeaseTime>
+  </p:PortMappingEntry>
+  <p:PortMappingEntry>
+    <p:NewRemoteHost/>
+    <p:NewExternalPort>51413</p:NewExternalPort>
+    <p:NewProtocol>UDP</p:NewProtocol>
+    <p:NewInternalPort>51413</p:NewInternalPort>
+    <p:NewInternalClient>192.168.1.20</p:NewInternalClient>
+    <p:NewEnabled>0</p:NewEnabled>
+    <p:NewDescription/>
+    <p:NewLeaseTime>0</p:NewLeaseTime>
+  </p:PortMappingEntry>
+</p:PortMappingList>"#;
+
+    #[test]
+    fn port_mapping_list_entries() {
+        let mappings = parse_port_mapping_list(PORT_MAPPING_LIST).unwrap();
+        assert_eq!(
+            mappings,
+            [
+                PortMapping {
+                    remote_host: None,
+                    external_port: 8080,
+                    protocol: "TCP".to_string(),
+                    internal_port: 80,
+                    internal_client: "192.168.1.10".to_string(),
+                    enabled: true,
+                    description: "web & more".to_string(),
+                    lease_duration: 3600,
+                },
+                PortMapping {
+                    remote_host: None,
+                    external_port: 51413,
+                    protocol: "UDP".to_string(),
+                    internal_port: 51413,
+                    internal_client: "192.168.1.20".to_string(),
+                    enabled: false,
+                    description: String::new(),
+                    lease_duration: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_port_mapping_list() {
+        let listing = r#"<p:PortMappingList xmlns:p="urn:x"></p:PortMappingList>"#;
+        assert!(parse_port_mapping_list(listing).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_port_mapping_list() {
+        let listing = PORT_MAPPING_LIST.replace("</p:NewProtocol>", "</p:NewProto>");
+        let error = parse_port_mapping_list(&listing).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<UpnpError>(),
+            Some(UpnpError::Parse { .. })
+        ));
+        let truncated = &PORT_MAPPING_LIST[..PORT_MAPPING_LIST.len() / 2];
+        assert!(parse_port_mapping_list(truncated).is_err());
+    }
+
+    #[test]
+    fn port_mapping_entry_without_required_fields() {
+        let listing = PORT_MAPPING_LIST.replace("<p:NewProtocol>TCP</p:NewProtocol>", "");
+        assert!(parse_port_mapping_list(&listing).is_err());
     }
 }
@@ -0,0 +1,90 @@
+//! Active connections are walked with GetActiveConnection at setup only;
+//! scrapes read NumberOfActiveConnections or reuse the count found then
+mod common;
+
+use common::{FakeIgd, Reply, argument};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig};
+
+/// Answers GetActiveConnection for the first `count` indexes, then ends the list
+fn list_connections(igd: &FakeIgd, count: usize) {
+    igd.set_handler("GetActiveConnection", move |body| {
+        let index: usize = argument(body, "NewActiveConnectionIndex")
+            .unwrap()
+            .parse()
+            .unwrap();
+        if index >= count {
+            return Reply::Fault(713);
+        }
+        Reply::arguments(&[
+            (
+                "NewActiveConnDeviceContainer",
+                &format!("uuid:wan-connection-{index}"),
+            ),
+            (
+                "NewActiveConnectionServiceID",
+                "urn:upnp-org:serviceId:WANIPConn1",
+            ),
+        ])
+    });
+}
+
+async fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn scrapes_reuse_the_list_walked_at_setup() {
+    let igd = FakeIgd::start().await;
+    list_connections(&igd, 2);
+    let client = client(&igd).await;
+    // Indexes 0 and 1, then the 713 ending the list
+    assert_eq!(igd.requests("GetActiveConnection"), 3);
+
+    for _ in 0..3 {
+        let stats = client.get_traffic_stats().await.unwrap();
+        assert_eq!(stats.active_connections, Some(2));
+    }
+    assert_eq!(igd.requests("GetActiveConnection"), 3);
+}
+
+#[tokio::test]
+async fn scrapes_query_the_count_when_the_gateway_answers() {
+    let igd = FakeIgd::start().await;
+    list_connections(&igd, 1);
+    igd.set_response("QueryStateVariable", &[("return", "1")]);
+    let client = client(&igd).await;
+    let call = igd.soap_calls("QueryStateVariable").pop().unwrap();
+    assert_eq!(call.service_type, "urn:schemas-upnp-org:control-1-0");
+    assert_eq!(
+        argument(&call.body, "varName"),
+        Some("NumberOfActiveConnections")
+    );
+
+    igd.set_response("QueryStateVariable", &[("return", "2")]);
+    let stats = client.get_traffic_stats().await.unwrap();
+    assert_eq!(stats.active_connections, Some(2));
+    assert_eq!(igd.requests("QueryStateVariable"), 2);
+    assert_eq!(igd.requests("GetActiveConnection"), 2);
+
+    // A failed query falls back to the count of the setup walk
+    igd.remove_action("QueryStateVariable");
+    let stats = client.get_traffic_stats().await.unwrap();
+    assert_eq!(stats.active_connections, Some(1));
+    assert_eq!(igd.requests("GetActiveConnection"), 2);
+}
+
+#[tokio::test]
+async fn gateways_without_either_report_no_count() {
+    let igd = FakeIgd::start().await;
+    let client = client(&igd).await;
+
+    let stats = client.get_traffic_stats().await.unwrap();
+    assert_eq!(stats.active_connections, None);
+    assert_eq!(igd.requests("QueryStateVariable"), 1);
+}
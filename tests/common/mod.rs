@@ -0,0 +1,470 @@
+//! A fake Internet Gateway Device on localhost, serving the description in
+//! `tests/fixtures` and answering SOAP actions with canned output arguments,
+//! and a unicast SSDP responder to point discovery at it
+#![allow(dead_code)]
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+
+pub const DESCRIPTION: &str = include_str!("../fixtures/igd-description.xml");
+pub const DESCRIPTION_PATH: &str = "/igddesc.xml";
+
+#[derive(Default)]
+struct State {
+    description: Mutex<Option<Vec<u8>>>,
+    /// Responses sent as they are instead of the canned arguments, by action
+    raw_responses: Mutex<HashMap<String, RawResponse>>,
+    scpds: Mutex<HashMap<String, String>>,
+    responses: Mutex<HashMap<String, Vec<(String, String)>>>,
+    /// Actions answered depending on the request body
+    handlers: Mutex<HashMap<String, Handler>>,
+    delay: Mutex<Duration>,
+    /// Path of every GET and action name of every POST, in order
+    requests: Mutex<Vec<String>>,
+    /// Control path, service type and action of every POST, in order
+    soap_calls: Mutex<Vec<SoapCall>>,
+    /// Service types whose actions are all answered with UPnP error 401
+    rejected_service_types: Mutex<Vec<String>>,
+    /// POSTs being answered, and the most there ever were at once
+    soap_in_flight: AtomicUsize,
+    max_soap_in_flight: AtomicUsize,
+}
+
+/// Counts a POST as in flight until dropped
+struct SoapInFlight<'a>(&'a State);
+
+impl<'a> SoapInFlight<'a> {
+    fn enter(state: &'a State) -> Self {
+        let in_flight = state.soap_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        state
+            .max_soap_in_flight
+            .fetch_max(in_flight, Ordering::SeqCst);
+        Self(state)
+    }
+}
+
+impl Drop for SoapInFlight<'_> {
+    fn drop(&mut self) {
+        self.0.soap_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+type Handler = Arc<dyn Fn(&str) -> Reply + Send + Sync>;
+
+/// How a scripted action answers a request
+pub enum Reply {
+    /// The response element with these output arguments, values as they are
+    Arguments(Vec<(String, String)>),
+    /// A complete response envelope
+    Envelope(String),
+    /// A UPnP error with this code
+    Fault(u16),
+}
+
+impl Reply {
+    pub fn arguments(arguments: &[(&str, &str)]) -> Self {
+        Self::Arguments(
+            arguments
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+}
+
+/// Text of the input argument `name` in a request envelope
+pub fn argument<'a>(envelope: &'a str, name: &str) -> Option<&'a str> {
+    let start = envelope.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + envelope[start..].find(&format!("</{name}>"))?;
+    Some(&envelope[start..end])
+}
+
+#[derive(Clone)]
+struct RawResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// A SOAP request as the gateway received it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoapCall {
+    pub path: String,
+    /// From the SOAPAction header
+    pub service_type: String,
+    pub action: String,
+    pub body: String,
+}
+
+pub struct FakeIgd {
+    addr: SocketAddr,
+    state: Arc<State>,
+}
+
+impl FakeIgd {
+    /// A gateway answering the standard counter, link and status actions
+    pub async fn start() -> Self {
+        let state = Arc::new(State::default());
+        *state.description.lock().unwrap() = Some(DESCRIPTION.as_bytes().to_vec());
+        let igd = Self {
+            addr: serve(state.clone()).await,
+            state,
+        };
+        igd.set_response("GetTotalBytesSent", &[("NewTotalBytesSent", "1000")]);
+        igd.set_response(
+            "GetTotalBytesReceived",
+            &[("NewTotalBytesReceived", "2000")],
+        );
+        igd.set_response("GetTotalPacketsSent", &[("NewTotalPacketsSent", "10")]);
+        igd.set_response(
+            "GetTotalPacketsReceived",
+            &[("NewTotalPacketsReceived", "20")],
+        );
+        igd.set_response(
+            "GetCommonLinkProperties",
+            &[
+                ("NewWANAccessType", "DSL"),
+                ("NewLayer1UpstreamMaxBitRate", "40000000"),
+                ("NewLayer1DownstreamMaxBitRate", "100000000"),
+                ("NewPhysicalLinkStatus", "Up"),
+            ],
+        );
+        igd.set_response(
+            "GetStatusInfo",
+            &[
+                ("NewConnectionStatus", "Connected"),
+                ("NewLastConnectionError", "ERROR_NONE"),
+                ("NewUptime", "1000"),
+            ],
+        );
+        igd.set_response(
+            "GetExternalIPAddress",
+            &[("NewExternalIPAddress", "203.0.113.7")],
+        );
+        igd
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn location(&self) -> String {
+        format!("http://{}{}", self.addr, DESCRIPTION_PATH)
+    }
+
+    pub fn set_description(&self, description: Option<&str>) {
+        self.set_description_bytes(description.map(str::as_bytes));
+    }
+
+    /// Serve a description in an encoding other than UTF-8
+    pub fn set_description_bytes(&self, description: Option<&[u8]>) {
+        *self.state.description.lock().unwrap() = description.map(<[u8]>::to_vec);
+    }
+
+    pub fn set_scpd(&self, path: &str, scpd: &str) {
+        self.state
+            .scpds
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), scpd.to_string());
+    }
+
+    pub fn set_response(&self, action: &str, arguments: &[(&str, &str)]) {
+        let arguments = arguments
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.state
+            .responses
+            .lock()
+            .unwrap()
+            .insert(action.to_string(), arguments);
+    }
+
+    /// Answer `action` with `body` as it is, e.g. a differently encoded envelope
+    pub fn set_raw_response(&self, action: &str, body: &[u8]) {
+        self.set_http_response(action, 200, &[("Content-Type", "text/xml")], body);
+    }
+
+    /// Answer `action` with an arbitrary HTTP response, e.g. an error page
+    pub fn set_http_response(
+        &self,
+        action: &str,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) {
+        let response = RawResponse {
+            status: StatusCode::from_u16(status).unwrap(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_vec(),
+        };
+        self.state
+            .raw_responses
+            .lock()
+            .unwrap()
+            .insert(action.to_string(), response);
+    }
+
+    /// Answer `action` with whatever `handler` makes of the request envelope
+    pub fn set_handler(
+        &self,
+        action: &str,
+        handler: impl Fn(&str) -> Reply + Send + Sync + 'static,
+    ) {
+        self.state
+            .handlers
+            .lock()
+            .unwrap()
+            .insert(action.to_string(), Arc::new(handler));
+    }
+
+    /// Answer `action` with UPnP error 401 from now on
+    pub fn remove_action(&self, action: &str) {
+        self.state.responses.lock().unwrap().remove(action);
+    }
+
+    /// Answer every action of `service_type` with UPnP error 401, as
+    /// gateways rejecting a service version do
+    pub fn reject_service_type(&self, service_type: &str) {
+        self.state
+            .rejected_service_types
+            .lock()
+            .unwrap()
+            .push(service_type.to_string());
+    }
+
+    /// Hold every response back this long
+    pub fn set_delay(&self, delay: Duration) {
+        *self.state.delay.lock().unwrap() = delay;
+    }
+
+    /// Number of requests for a path (GET) or an action (POST)
+    pub fn requests(&self, path_or_action: &str) -> usize {
+        self.state
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| *request == path_or_action)
+            .count()
+    }
+
+    /// Most SOAP requests that were answered at the same time
+    pub fn max_soap_in_flight(&self) -> usize {
+        self.state.max_soap_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Every request for `action`, in order
+    pub fn soap_calls(&self, action: &str) -> Vec<SoapCall> {
+        self.state
+            .soap_calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.action == action)
+            .cloned()
+            .collect()
+    }
+}
+
+async fn serve(state: Arc<State>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().fallback(
+        move |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| {
+            let state = state.clone();
+            async move { respond(&state, method, uri, headers, body).await }
+        },
+    );
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+async fn respond(
+    state: &State,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let _in_flight = (method == Method::POST).then(|| SoapInFlight::enter(state));
+    let delay = *state.delay.lock().unwrap();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    if method == Method::GET {
+        let path = uri.path().to_string();
+        state.requests.lock().unwrap().push(path.clone());
+        let document = if path == DESCRIPTION_PATH {
+            state.description.lock().unwrap().clone()
+        } else {
+            state
+                .scpds
+                .lock()
+                .unwrap()
+                .get(&path)
+                .cloned()
+                .map(String::into_bytes)
+        };
+        return match document {
+            Some(document) => ([("Content-Type", "text/xml")], document).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+    }
+
+    let soap_action = headers
+        .get("SOAPAction")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .trim_matches(|c| c == '"' || c == ';');
+    let (service_type, action) = soap_action.split_once('#').unwrap_or_default();
+    state.requests.lock().unwrap().push(action.to_string());
+    state.soap_calls.lock().unwrap().push(SoapCall {
+        path: uri.path().to_string(),
+        service_type: service_type.to_string(),
+        action: action.to_string(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+    if let Some(raw) = state.raw_responses.lock().unwrap().get(action).cloned() {
+        let mut response = (raw.status, raw.body).into_response();
+        for (name, value) in raw.headers {
+            response.headers_mut().insert(
+                HeaderName::try_from(name).unwrap(),
+                HeaderValue::try_from(value).unwrap(),
+            );
+        }
+        return response;
+    }
+    let rejected = state
+        .rejected_service_types
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|rejected| rejected == service_type);
+    let handler = state.handlers.lock().unwrap().get(action).cloned();
+    let reply = if rejected {
+        Reply::Fault(401)
+    } else if let Some(handler) = handler {
+        handler(&String::from_utf8_lossy(&body))
+    } else {
+        match state.responses.lock().unwrap().get(action).cloned() {
+            Some(arguments) => Reply::Arguments(arguments),
+            None => Reply::Fault(401),
+        }
+    };
+    match reply {
+        Reply::Arguments(arguments) => {
+            let arguments: String = arguments
+                .iter()
+                .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+                .collect();
+            let body = format!(
+                r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:{action}Response xmlns:u="{service_type}">{arguments}</u:{action}Response></s:Body></s:Envelope>"#
+            );
+            ([("Content-Type", "text/xml; charset=\"utf-8\"")], body).into_response()
+        }
+        Reply::Envelope(body) => ([("Content-Type", "text/xml")], body).into_response(),
+        Reply::Fault(code) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [("Content-Type", "text/xml")],
+            include_str!("../fixtures/soap-fault.xml").replace(
+                "<errorCode>401</errorCode>",
+                &format!("<errorCode>{code}</errorCode>"),
+            ),
+        )
+            .into_response(),
+    }
+}
+
+/// Value of the sample starting with `series`, e.g. `name_sum` or `name_count{kind="write"}`
+pub fn sample(exposition: &str, series: &str) -> f64 {
+    exposition
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {series} in\n{exposition}"))
+        .parse()
+        .unwrap()
+}
+
+/// Answers the M-SEARCH requests sent to it, as a gateway would unicast.
+/// `answer` gets the number of the search, starting at 0, and returns the
+/// datagram to reply with, or `None` to drop the search as a lossy network would.
+pub struct SsdpResponder {
+    addr: SocketAddr,
+    searches: Arc<AtomicUsize>,
+}
+
+impl SsdpResponder {
+    pub async fn start(mut answer: impl FnMut(usize) -> Option<String> + Send + 'static) -> Self {
+        Self::start_many(move |search| answer(search).into_iter().collect()).await
+    }
+
+    /// Like `start`, but `answer` returns every datagram to reply with, as
+    /// several gateways on one network would
+    pub async fn start_many(mut answer: impl FnMut(usize) -> Vec<String> + Send + 'static) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let searches = Arc::new(AtomicUsize::new(0));
+        let counter = searches.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                if !buf[..len].starts_with(b"M-SEARCH") {
+                    continue;
+                }
+                let search = counter.fetch_add(1, Ordering::SeqCst);
+                for response in answer(search) {
+                    socket.send_to(response.as_bytes(), from).await.unwrap();
+                }
+            }
+        });
+        Self { addr, searches }
+    }
+
+    /// A responder answering every search with `response`
+    pub async fn always(response: String) -> Self {
+        Self::start(move |_| Some(response.clone())).await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of M-SEARCH requests received so far
+    pub fn searches(&self) -> usize {
+        self.searches.load(Ordering::SeqCst)
+    }
+}
+
+/// An IGD search response pointing at `location`, with `extra_headers`
+/// ("Name: value" lines) before the blank line
+pub fn ssdp_response(location: &str, extra_headers: &[&str]) -> String {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         CACHE-CONTROL: max-age=1800\r\n\
+         EXT:\r\n\
+         LOCATION: {location}\r\n\
+         SERVER: Linux/4.9 UPnP/1.0 FakeIgd/1.0\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:2\r\n\
+         USN: uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC::urn:schemas-upnp-org:device:InternetGatewayDevice:2\r\n"
+    );
+    for header in extra_headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response
+}
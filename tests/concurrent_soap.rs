@@ -0,0 +1,115 @@
+//! The SOAP calls of one reading run side by side, each failing on its own,
+//! and all readings together keep to `max_concurrent_soap_requests`
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use common::{FakeIgd, sample};
+use upnp_wan_exporter_rs::{Config, MetricsCollector, UpnpClient, UpnpConfig};
+
+/// Per-request latency of a slow gateway
+const LATENCY: Duration = Duration::from_millis(200);
+
+async fn client(igd: &FakeIgd, max_concurrent_soap_requests: usize) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        max_concurrent_soap_requests,
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn slow_gateway_costs_about_the_slowest_call() {
+    let igd = FakeIgd::start().await;
+    let concurrent = client(&igd, 32).await;
+    let sequential = client(&igd, 1).await;
+    igd.set_delay(LATENCY);
+
+    let started = Instant::now();
+    let stats = sequential.get_traffic_stats().await.unwrap();
+    let one_at_a_time = started.elapsed();
+    assert_eq!(stats.bytes_sent, Some(1000));
+
+    let started = Instant::now();
+    let stats = concurrent.get_traffic_stats().await.unwrap();
+    let side_by_side = started.elapsed();
+    assert_eq!(stats.bytes_sent, Some(1000));
+
+    // Five stat calls alone take 5 × LATENCY one after the other
+    assert!(one_at_a_time >= 5 * LATENCY, "{one_at_a_time:?}");
+    assert!(
+        side_by_side * 2 < one_at_a_time,
+        "{side_by_side:?} concurrently against {one_at_a_time:?} sequentially"
+    );
+}
+
+#[tokio::test]
+async fn failed_calls_leave_the_others_in_place() {
+    let igd = FakeIgd::start().await;
+    igd.remove_action("GetTotalPacketsSent");
+    igd.remove_action("GetCommonLinkProperties");
+    let client = client(&igd, 32).await;
+    igd.set_delay(LATENCY);
+
+    let stats = client.get_traffic_stats().await.unwrap();
+    assert_eq!(stats.bytes_sent, Some(1000));
+    assert!(stats.bytes_received.is_some());
+    assert!(stats.packets_received.is_some());
+    assert_eq!(stats.packets_sent, None);
+    assert_eq!(stats.link_up_max_bitrate_bps, None);
+}
+
+#[tokio::test]
+async fn concurrent_readings_share_the_cap() {
+    let igd = FakeIgd::start().await;
+    let client = client(&igd, 2).await;
+    igd.set_delay(Duration::from_millis(50));
+
+    let (first, second, third) = tokio::join!(
+        client.get_traffic_stats(),
+        client.get_traffic_stats(),
+        client.get_traffic_stats()
+    );
+    first.unwrap();
+    second.unwrap();
+    third.unwrap();
+    assert_eq!(igd.max_soap_in_flight(), 2);
+}
+
+#[tokio::test]
+async fn in_flight_gauge_follows_the_cap() {
+    const IN_FLIGHT: &str = "upnp_wan_soap_requests_in_flight{device=\"default\"}";
+    let igd = FakeIgd::start().await;
+    igd.set_delay(Duration::from_millis(50));
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    config.upnp.max_concurrent_soap_requests = 2;
+    let collector = MetricsCollector::new(&config).unwrap();
+    let metrics = collector.metrics();
+
+    // Watch the gauge for as long as one scrape takes
+    let done = AtomicBool::new(false);
+    let scrape = async {
+        let (_, failed) = Box::pin(collector.collect_metrics()).await;
+        done.store(true, Ordering::SeqCst);
+        failed
+    };
+    let watch = async {
+        let mut most = 0.0f64;
+        while !done.load(Ordering::SeqCst) {
+            most = most.max(sample(&metrics.encode().unwrap(), IN_FLIGHT));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        most
+    };
+    let (failed, most) = tokio::join!(scrape, watch);
+
+    assert!(!failed);
+    assert_eq!(most, 2.0);
+    assert_eq!(sample(&metrics.encode().unwrap(), IN_FLIGHT), 0.0);
+    assert_eq!(igd.max_soap_in_flight(), 2);
+}
@@ -0,0 +1,75 @@
+//! The connection service is picked from what the description offers
+mod common;
+
+use common::FakeIgd;
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, WanConnectionKind};
+
+const PPPOE_DESCRIPTION: &str = include_str!("fixtures/pppoe-description.xml");
+
+fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    UpnpClient::builder().config(config).build().unwrap()
+}
+
+#[tokio::test]
+async fn pppoe_gateway_uses_wanpppconnection() {
+    let igd = FakeIgd::start().await;
+    igd.set_description(Some(PPPOE_DESCRIPTION));
+    let mut client = client(&igd);
+
+    client.ensure_device().await.unwrap();
+    let interface = client.device().unwrap().primary_interface().unwrap();
+    let connection = interface.connection.as_ref().unwrap();
+    assert_eq!(connection.kind, WanConnectionKind::Ppp);
+    assert_eq!(
+        connection.service.service_type,
+        "urn:schemas-upnp-org:service:WANPPPConnection:1"
+    );
+    assert_eq!(
+        connection.service.control_url,
+        format!("http://{}/upnp/control/WANPPPConn1", igd.addr())
+    );
+
+    assert_eq!(
+        client.get_external_ip().await.unwrap().as_deref(),
+        Some("203.0.113.7")
+    );
+    let call = igd.soap_calls("GetExternalIPAddress").pop().unwrap();
+    assert_eq!(call.path, "/upnp/control/WANPPPConn1");
+    assert_eq!(
+        call.service_type,
+        "urn:schemas-upnp-org:service:WANPPPConnection:1"
+    );
+
+    let status = client.get_status_info().await.unwrap();
+    assert_eq!(status.uptime_seconds, Some(1000));
+    let call = igd.soap_calls("GetStatusInfo").pop().unwrap();
+    assert_eq!(
+        call.service_type,
+        "urn:schemas-upnp-org:service:WANPPPConnection:1"
+    );
+}
+
+#[tokio::test]
+async fn ip_gateway_uses_wanipconnection() {
+    let igd = FakeIgd::start().await;
+    let mut client = client(&igd);
+
+    client.ensure_device().await.unwrap();
+    let interface = client.device().unwrap().primary_interface().unwrap();
+    assert_eq!(
+        interface.connection.as_ref().unwrap().kind,
+        WanConnectionKind::Ip
+    );
+
+    client.get_external_ip().await.unwrap();
+    let call = igd.soap_calls("GetExternalIPAddress").pop().unwrap();
+    assert_eq!(call.path, "/igd2upnp/control/WANIPConn1");
+    assert_eq!(
+        call.service_type,
+        "urn:schemas-upnp-org:service:WANIPConnection:2"
+    );
+}
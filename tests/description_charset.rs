@@ -0,0 +1,69 @@
+//! Descriptions and SOAP responses in encodings other than UTF-8
+mod common;
+
+use common::FakeIgd;
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig};
+
+async fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn reads_a_latin1_description() {
+    let igd = FakeIgd::start().await;
+    igd.set_description_bytes(Some(include_bytes!("fixtures/igd-description-latin1.xml")));
+    let client = client(&igd).await;
+
+    let info = &client.device().unwrap().info;
+    assert_eq!(info.friendly_name.as_deref(), Some("Módem ZTE H298A"));
+    assert_eq!(info.model_name.as_deref(), Some("H298A Ñandú"));
+    assert_eq!(info.manufacturer.as_deref(), Some("ZTE Corporation"));
+}
+
+#[tokio::test]
+async fn reads_a_description_with_a_bom() {
+    let igd = FakeIgd::start().await;
+    igd.set_description_bytes(Some(include_bytes!("fixtures/igd-description-bom.xml")));
+    let client = client(&igd).await;
+
+    let device = client.device().unwrap();
+    assert_eq!(device.info.friendly_name.as_deref(), Some("Routeur Épée"));
+    assert!(device.primary_interface().is_some());
+}
+
+#[tokio::test]
+async fn reads_a_utf16_soap_response() {
+    let igd = FakeIgd::start().await;
+    let envelope = r#"<?xml version="1.0" encoding="UTF-16"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:2"><NewExternalIPAddress>198.51.100.4</NewExternalIPAddress></u:GetExternalIPAddressResponse></s:Body></s:Envelope>"#;
+    let mut body = vec![0xff, 0xfe];
+    for unit in envelope.encode_utf16() {
+        body.extend_from_slice(&unit.to_le_bytes());
+    }
+    igd.set_raw_response("GetExternalIPAddress", &body);
+    let client = client(&igd).await;
+
+    assert_eq!(
+        client.get_external_ip().await.unwrap().as_deref(),
+        Some("198.51.100.4")
+    );
+}
+
+#[tokio::test]
+async fn reads_a_latin1_soap_response() {
+    let igd = FakeIgd::start().await;
+    igd.set_raw_response(
+        "GetStatusInfo",
+        b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body><u:GetStatusInfoResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:2\"><NewConnectionStatus>Connected</NewConnectionStatus><NewLastConnectionError>ERROR_\xc9</NewLastConnectionError><NewUptime>42</NewUptime></u:GetStatusInfoResponse></s:Body></s:Envelope>",
+    );
+    let client = client(&igd).await;
+
+    let status = client.get_status_info().await.unwrap();
+    assert_eq!(status.uptime_seconds, Some(42));
+    assert_eq!(status.last_error.as_deref(), Some("ERROR_É"));
+}
@@ -0,0 +1,96 @@
+//! `upnp.max_description_size` bounds the description through the read
+//! shared with SOAP responses, whether the gateway announces the length or
+//! streams the body in chunks
+mod common;
+
+use common::{DESCRIPTION, FakeIgd};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, UpnpError};
+
+const LIMIT: usize = 16 * 1024;
+
+/// The fixture description, padded with a comment to `len` bytes
+fn description_of(len: usize) -> String {
+    let padding = len - DESCRIPTION.len() - "<!---->".len();
+    let mut description = DESCRIPTION.to_string();
+    description.insert_str(
+        description.find("<root").unwrap(),
+        &format!("<!--{}-->", "x".repeat(padding)),
+    );
+    assert_eq!(description.len(), len);
+    description
+}
+
+async fn set_up(location: String) -> Result<UpnpClient, UpnpError> {
+    let config = UpnpConfig {
+        location: Some(location),
+        max_description_size: LIMIT,
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await?;
+    Ok(client)
+}
+
+fn assert_too_large(result: Result<UpnpClient, UpnpError>) {
+    let error = result.err().expect("the description was accepted");
+    let message = error.to_string();
+    assert!(
+        message.contains(&format!("larger than the {LIMIT} byte limit")),
+        "{message}"
+    );
+}
+
+/// Serves `body` with chunked transfer encoding, so without a Content-Length
+async fn serve_chunked(body: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let mut response = "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\
+                    Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+                    .to_string();
+                for chunk in body.as_bytes().chunks(1024) {
+                    response.push_str(&format!(
+                        "{:x}\r\n{}\r\n",
+                        chunk.len(),
+                        String::from_utf8_lossy(chunk)
+                    ));
+                }
+                response.push_str("0\r\n\r\n");
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    format!("http://{addr}/igddesc.xml")
+}
+
+#[tokio::test]
+async fn description_up_to_the_limit_is_read() {
+    let igd = FakeIgd::start().await;
+    igd.set_description(Some(&description_of(LIMIT)));
+
+    let client = set_up(igd.location()).await.unwrap();
+    assert!(client.device().unwrap().primary_interface().is_some());
+}
+
+#[tokio::test]
+async fn oversized_description_is_rejected() {
+    let igd = FakeIgd::start().await;
+    igd.set_description(Some(&description_of(LIMIT + 1)));
+
+    assert_too_large(set_up(igd.location()).await);
+}
+
+#[tokio::test]
+async fn oversized_chunked_description_is_rejected() {
+    let location = serve_chunked(description_of(4 * LIMIT)).await;
+
+    assert_too_large(set_up(location).await);
+}
@@ -0,0 +1,98 @@
+//! The discovered device is cached for the CACHE-CONTROL max-age of its
+//! search response and re-discovered once that expires or reads fail
+mod common;
+
+use common::{DESCRIPTION_PATH, FakeIgd, SsdpResponder, ssdp_response};
+use std::time::Duration;
+use upnp_wan_exporter_rs::{Config, MetricsCollector, UpnpClient, UpnpConfig};
+
+fn collector(responder: &SsdpResponder) -> MetricsCollector {
+    let mut config = Config::default();
+    config.upnp.search_target_addr = Some(responder.addr().to_string());
+    config.upnp.discovery_timeout = 2;
+    MetricsCollector::new(&config).unwrap()
+}
+
+fn client(responder: &SsdpResponder) -> UpnpClient {
+    let config = UpnpConfig {
+        discovery_timeout: 2,
+        ..UpnpConfig::default()
+    };
+    UpnpClient::builder()
+        .config(config)
+        .search_target(responder.addr())
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn consecutive_fetches_within_max_age_discover_once() {
+    let igd = FakeIgd::start().await;
+    let responder = SsdpResponder::always(ssdp_response(&igd.location(), &[])).await;
+    let collector = collector(&responder);
+
+    let first = collector.get_stats().await.unwrap();
+    let second = collector.get_stats().await.unwrap();
+    assert_eq!(first.bytes_sent, Some(1000));
+    assert_eq!(second.bytes_sent, Some(1000));
+    assert_eq!(responder.searches(), 1);
+    assert_eq!(igd.requests(DESCRIPTION_PATH), 1);
+    assert_eq!(igd.requests("GetTotalBytesSent"), 2);
+}
+
+#[tokio::test]
+async fn rediscovers_once_max_age_expired() {
+    let igd = FakeIgd::start().await;
+    let response = ssdp_response(&igd.location(), &[]).replace("max-age=1800", "max-age=1");
+    let responder = SsdpResponder::always(response).await;
+    let mut client = client(&responder);
+
+    client.ensure_device().await.unwrap();
+    client.ensure_device().await.unwrap();
+    assert_eq!(responder.searches(), 1);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(!client.has_valid_device());
+    client.ensure_device().await.unwrap();
+    assert_eq!(responder.searches(), 2);
+    assert_eq!(igd.requests(DESCRIPTION_PATH), 2);
+}
+
+#[tokio::test]
+async fn invalidation_forces_a_rediscovery() {
+    let igd = FakeIgd::start().await;
+    let responder = SsdpResponder::always(ssdp_response(&igd.location(), &[])).await;
+    let mut client = client(&responder);
+
+    client.ensure_device().await.unwrap();
+    client.invalidate_device();
+    assert!(client.device().is_none());
+    client.ensure_device().await.unwrap();
+    assert_eq!(responder.searches(), 2);
+}
+
+#[tokio::test]
+async fn failed_read_rediscovers_the_device() {
+    let igd = FakeIgd::start().await;
+    let responder = SsdpResponder::always(ssdp_response(&igd.location(), &[])).await;
+    let collector = collector(&responder);
+
+    collector.get_stats().await.unwrap();
+    // A reading fails only when the common interface answers nothing at all
+    for action in [
+        "GetTotalBytesSent",
+        "GetTotalBytesReceived",
+        "GetTotalPacketsSent",
+        "GetTotalPacketsReceived",
+        "GetCommonLinkProperties",
+    ] {
+        igd.remove_action(action);
+    }
+    assert!(collector.get_stats().await.is_err());
+    assert_eq!(responder.searches(), 2);
+
+    // The gateway recovered; the device found by the re-discovery is kept
+    igd.set_response("GetTotalBytesSent", &[("NewTotalBytesSent", "1000")]);
+    collector.get_stats().await.unwrap();
+    assert_eq!(responder.searches(), 2);
+}
@@ -0,0 +1,50 @@
+//! `upnp_device_info` identifies gateways whose description was read, and
+//! nothing else
+mod common;
+
+use std::net::TcpListener;
+
+use common::{FakeIgd, sample};
+use upnp_wan_exporter_rs::{Config, MetricsCollector};
+
+const SCRAPE_ERROR: &str = "upnp_wan_scrape_error{device=\"default\"}";
+
+#[tokio::test]
+async fn reachable_gateway_is_identified() {
+    let igd = FakeIgd::start().await;
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    let (exposition, _) = Box::pin(collector.collect_metrics()).await;
+    assert_eq!(sample(&exposition, SCRAPE_ERROR), 0.0);
+    let info: Vec<_> = exposition
+        .lines()
+        .filter(|line| line.starts_with("upnp_device_info{"))
+        .collect();
+    assert_eq!(info.len(), 1, "{exposition}");
+    assert!(
+        info[0].contains("friendly_name=\"FRITZ!Box 7590\""),
+        "{}",
+        info[0]
+    );
+}
+
+#[tokio::test]
+async fn unreachable_location_has_no_device_info() {
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::default();
+    config.upnp.location = Some(format!("http://127.0.0.1:{closed_port}/igddesc.xml"));
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    // The placeholder device of the location stays cached across failed scrapes
+    for _ in 0..2 {
+        let (exposition, _) = Box::pin(collector.collect_metrics()).await;
+        assert_eq!(sample(&exposition, SCRAPE_ERROR), 1.0);
+        assert!(!exposition.contains("upnp_device_info{"), "{exposition}");
+    }
+}
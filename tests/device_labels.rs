@@ -0,0 +1,79 @@
+//! Gateways sharing one registry keep their discovery, SOAP and timing
+//! series apart by the `device` label
+mod common;
+
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use common::{FakeIgd, sample};
+use upnp_wan_exporter_rs::{Config, Metrics, MetricsCollector, UpnpClient};
+
+#[tokio::test]
+async fn a_failing_backup_is_told_apart_from_the_primary() {
+    let primary = FakeIgd::start().await;
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut config = Config::default();
+    config.upnp.location = Some(primary.location());
+    let mut backup_config = config.clone();
+    backup_config.upnp.name = Some("lte".to_string());
+    backup_config.upnp.location = Some(format!("http://127.0.0.1:{closed_port}/igddesc.xml"));
+
+    let metrics = Arc::new(Metrics::new(&config.metrics));
+    let backup = MetricsCollector::with_metrics(
+        UpnpClient::builder()
+            .config(backup_config.upnp.clone())
+            .build()
+            .unwrap(),
+        &backup_config,
+        metrics.clone(),
+    );
+    let collector = MetricsCollector::with_metrics(
+        UpnpClient::builder()
+            .config(config.upnp.clone())
+            .build()
+            .unwrap(),
+        &config,
+        metrics,
+    )
+    .with_devices(vec![Arc::new(backup)]);
+
+    let (exposition, _) = Box::pin(collector.collect_metrics()).await;
+    for (series, value) in [
+        ("upnp_discovery_attempts_total{device=\"default\"}", 1.0),
+        ("upnp_discovery_attempts_total{device=\"lte\"}", 1.0),
+        (
+            "upnp_discovery_failures_total{device=\"lte\",reason=\"http\"}",
+            1.0,
+        ),
+        (
+            "upnp_scrape_duration_seconds_count{device=\"default\"}",
+            1.0,
+        ),
+        ("upnp_scrape_duration_seconds_count{device=\"lte\"}", 1.0),
+        (
+            "upnp_soap_requests_total{action=\"GetTotalPacketsSent\",device=\"default\"}",
+            1.0,
+        ),
+        ("upnp_wan_soap_requests_in_flight{device=\"default\"}", 0.0),
+        ("upnp_wan_soap_requests_in_flight{device=\"lte\"}", 0.0),
+    ] {
+        assert_eq!(sample(&exposition, series), value, "{series}");
+    }
+    assert!(
+        !exposition.contains("upnp_discovery_failures_total{device=\"default\""),
+        "{exposition}"
+    );
+    // The backup was never reached, so it sent no SOAP request
+    assert!(
+        !exposition
+            .lines()
+            .any(|line| line.starts_with("upnp_soap_requests_total{")
+                && line.contains("device=\"lte\"")),
+        "{exposition}"
+    );
+}
@@ -0,0 +1,165 @@
+//! Choosing among three gateways that answer the same search
+mod common;
+
+use common::{DESCRIPTION, FakeIgd, SsdpResponder, ssdp_response};
+use upnp_wan_exporter_rs::config::DeviceSelection;
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig};
+
+const PPPOE_DESCRIPTION: &str = include_str!("fixtures/pppoe-description.xml");
+
+/// An OpenWrt IGD1, a Speedport IGD1 and a FRITZ!Box IGD2, answering in that order
+struct Network {
+    openwrt: FakeIgd,
+    speedport: FakeIgd,
+    fritzbox: FakeIgd,
+    responder: SsdpResponder,
+}
+
+impl Network {
+    async fn start() -> Self {
+        let openwrt = FakeIgd::start().await;
+        openwrt.set_description(Some(
+            &DESCRIPTION
+                .replace("<friendlyName>FRITZ!Box 7590", "<friendlyName>OpenWrt")
+                .replace("AVM Berlin", "OpenWrt")
+                .replace(
+                    "device:InternetGatewayDevice:2",
+                    "device:InternetGatewayDevice:1",
+                ),
+        ));
+        let speedport = FakeIgd::start().await;
+        speedport.set_description(Some(PPPOE_DESCRIPTION));
+        let fritzbox = FakeIgd::start().await;
+
+        let responses = vec![
+            response(&openwrt, "uuid:00000000-0000-0000-0000-00000000000a", 1),
+            response(&speedport, "uuid:00000000-0000-0000-0000-00000000000b", 1),
+            response(&fritzbox, "uuid:00000000-0000-0000-0000-00000000000c", 2),
+        ];
+        let responder = SsdpResponder::start_many(move |_| responses.clone()).await;
+        Self {
+            openwrt,
+            speedport,
+            fritzbox,
+            responder,
+        }
+    }
+
+    async fn select(&self, selection: DeviceSelection) -> (UpnpClient, anyhow::Result<()>) {
+        let config = UpnpConfig {
+            selection,
+            discovery_timeout: 1,
+            ..UpnpConfig::default()
+        };
+        let mut client = UpnpClient::builder()
+            .config(config)
+            .search_target(self.responder.addr())
+            .build()
+            .unwrap();
+        let result = client.ensure_device().await.map_err(anyhow::Error::from);
+        (client, result)
+    }
+
+    async fn selected(&self, selection: DeviceSelection) -> String {
+        let (client, result) = self.select(selection).await;
+        result.unwrap();
+        client.device().unwrap().location.clone()
+    }
+}
+
+fn response(igd: &FakeIgd, uuid: &str, version: u8) -> String {
+    ssdp_response(&igd.location(), &[])
+        .replace("uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC", uuid)
+        .replace(
+            "device:InternetGatewayDevice:2",
+            &format!("device:InternetGatewayDevice:{version}"),
+        )
+}
+
+#[tokio::test]
+async fn first_takes_the_first_answer() {
+    let network = Network::start().await;
+    assert_eq!(
+        network.selected(DeviceSelection::First).await,
+        network.openwrt.location()
+    );
+}
+
+#[tokio::test]
+async fn prefer_igd2_skips_igd1_devices() {
+    let network = Network::start().await;
+    assert_eq!(
+        network.selected(DeviceSelection::PreferIgd2).await,
+        network.fritzbox.location()
+    );
+}
+
+#[tokio::test]
+async fn usn_matches_the_full_usn_or_its_uuid() {
+    let network = Network::start().await;
+    let uuid = "uuid:00000000-0000-0000-0000-00000000000b";
+    assert_eq!(
+        network
+            .selected(DeviceSelection::Usn {
+                usn: uuid.to_string()
+            })
+            .await,
+        network.speedport.location()
+    );
+    assert_eq!(
+        network
+            .selected(DeviceSelection::Usn {
+                usn: format!("{uuid}::urn:schemas-upnp-org:device:InternetGatewayDevice:1")
+            })
+            .await,
+        network.speedport.location()
+    );
+}
+
+#[tokio::test]
+async fn friendly_name_matches_a_regex() {
+    let network = Network::start().await;
+    assert_eq!(
+        network
+            .selected(DeviceSelection::FriendlyName {
+                pattern: "^Speedport .* 3$".to_string()
+            })
+            .await,
+        network.speedport.location()
+    );
+}
+
+#[tokio::test]
+async fn manufacturer_matches_ignoring_case() {
+    let network = Network::start().await;
+    assert_eq!(
+        network
+            .selected(DeviceSelection::Manufacturer {
+                manufacturer: "avm berlin".to_string()
+            })
+            .await,
+        network.fritzbox.location()
+    );
+}
+
+#[tokio::test]
+async fn no_match_lists_what_was_found() {
+    let network = Network::start().await;
+    let (client, result) = network
+        .select(DeviceSelection::Manufacturer {
+            manufacturer: "Netgear".to_string(),
+        })
+        .await;
+
+    let message = format!("{:#}", result.unwrap_err());
+    assert!(client.device().is_none());
+    assert!(message.contains("No device matches"), "{message}");
+    for igd in [&network.openwrt, &network.speedport, &network.fritzbox] {
+        assert!(message.contains(&igd.location()), "{message}");
+    }
+    assert!(message.contains("friendlyName OpenWrt"), "{message}");
+    assert!(
+        message.contains("manufacturer Deutsche Telekom AG"),
+        "{message}"
+    );
+}
@@ -0,0 +1,110 @@
+//! Failed discoveries show in the discovery metrics with their reason
+mod common;
+
+use common::{FakeIgd, sample};
+use std::net::TcpListener;
+use tokio::net::UdpSocket;
+use upnp_wan_exporter_rs::{Config, MetricsCollector};
+
+/// A localhost port nothing listens on
+fn closed_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[tokio::test]
+async fn unanswered_search_counts_as_timeout() {
+    // Bound but never answering, so the search cannot fail any other way
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.upnp.search_target_addr = Some(silent.local_addr().unwrap().to_string());
+    config.upnp.discovery_timeout = 1;
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    assert!(collector.get_stats().await.is_err());
+    let exposition = collector.metrics().encode().unwrap();
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_attempts_total{device=\"default\"}"
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_failures_total{device=\"default\",reason=\"timeout\"}"
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_duration_seconds_count{device=\"default\"}"
+        ),
+        1.0
+    );
+    assert!(
+        sample(
+            &exposition,
+            "upnp_discovery_duration_seconds_sum{device=\"default\"}"
+        ) >= 1.0
+    );
+}
+
+#[tokio::test]
+async fn closed_description_port_counts_as_http() {
+    let mut config = Config::default();
+    config.upnp.location = Some(format!("http://127.0.0.1:{}/igddesc.xml", closed_port()));
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    assert!(collector.get_stats().await.is_err());
+    assert!(collector.get_stats().await.is_err());
+    let exposition = collector.metrics().encode().unwrap();
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_attempts_total{device=\"default\"}"
+        ),
+        2.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_failures_total{device=\"default\",reason=\"http\"}"
+        ),
+        2.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_duration_seconds_count{device=\"default\"}"
+        ),
+        2.0
+    );
+}
+
+#[tokio::test]
+async fn successful_discovery_counts_no_failure() {
+    let igd = FakeIgd::start().await;
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    collector.get_stats().await.unwrap();
+    let exposition = collector.metrics().encode().unwrap();
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_discovery_attempts_total{device=\"default\"}"
+        ),
+        1.0
+    );
+    assert!(
+        !exposition.contains("upnp_discovery_failures_total{"),
+        "{exposition}"
+    );
+}
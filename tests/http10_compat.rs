@@ -0,0 +1,129 @@
+//! `upnp.http10_compat`: SOAP requests as an HTTP/1.0 stack expects them
+mod common;
+
+use common::{DESCRIPTION_PATH, FakeIgd};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig};
+
+/// The bytes a client sent on one connection
+type Sent = Arc<Mutex<Vec<u8>>>;
+
+/// Passes connections on to `upstream`, keeping the bytes each one sent
+struct RecordingProxy {
+    addr: SocketAddr,
+    connections: Arc<Mutex<Vec<Sent>>>,
+}
+
+impl RecordingProxy {
+    async fn start(upstream: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let recorded = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let sent = Sent::default();
+                recorded.lock().unwrap().push(sent.clone());
+                tokio::spawn(async move {
+                    let upstream = TcpStream::connect(upstream).await.unwrap();
+                    let (mut client_read, mut client_write) = client.into_split();
+                    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+                    let requests = async {
+                        let mut buf = [0; 4096];
+                        loop {
+                            let len = client_read.read(&mut buf).await.unwrap_or(0);
+                            if len == 0 {
+                                let _ = upstream_write.shutdown().await;
+                                break;
+                            }
+                            sent.lock().unwrap().extend_from_slice(&buf[..len]);
+                            if upstream_write.write_all(&buf[..len]).await.is_err() {
+                                break;
+                            }
+                        }
+                    };
+                    let responses = tokio::io::copy(&mut upstream_read, &mut client_write);
+                    let _ = tokio::join!(requests, responses);
+                });
+            }
+        });
+        Self { addr, connections }
+    }
+
+    /// Everything sent on each connection, in order of connecting
+    fn connections(&self) -> Vec<String> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|sent| String::from_utf8_lossy(&sent.lock().unwrap()).into_owned())
+            .collect()
+    }
+}
+
+async fn read_through_proxy(http10_compat: bool) -> (FakeIgd, RecordingProxy) {
+    let igd = FakeIgd::start().await;
+    let proxy = RecordingProxy::start(igd.addr()).await;
+    let config = UpnpConfig {
+        location: Some(format!("http://{}{}", proxy.addr, DESCRIPTION_PATH)),
+        http10_compat,
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client.get_external_ip().await.unwrap();
+    client.get_status_info().await.unwrap();
+    (igd, proxy)
+}
+
+/// The lowercased head of the request for `action`, and its body
+fn soap_request<'a>(connections: &'a [String], action: &str) -> (String, &'a str) {
+    let request = connections
+        .iter()
+        .find(|sent| sent.contains(&format!("#{action}\"")))
+        .unwrap_or_else(|| panic!("no {action} request in {connections:#?}"));
+    let (head, body) = request.split_once("\r\n\r\n").unwrap();
+    (head.to_ascii_lowercase(), body)
+}
+
+#[tokio::test]
+async fn soap_requests_use_http10_semantics() {
+    let (_igd, proxy) = read_through_proxy(true).await;
+    let connections = proxy.connections();
+
+    for action in ["GetExternalIPAddress", "GetStatusInfo"] {
+        let (head, body) = soap_request(&connections, action);
+        assert!(
+            head.starts_with("post /igd2upnp/control/wanipconn1 http/1.0\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nconnection: close"), "{head}");
+        assert!(
+            head.contains(&format!("\r\ncontent-length: {}", body.len())),
+            "{head}"
+        );
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        assert!(!head.contains("accept-encoding"), "{head}");
+        assert!(!head.contains("keep-alive"), "{head}");
+    }
+    // A fresh connection for every request, the description and SCPDs included
+    assert!(connections.len() > 3, "{connections:#?}");
+    for sent in &connections {
+        assert_eq!(sent.matches(" HTTP/1.").count(), 1, "{sent}");
+    }
+}
+
+#[tokio::test]
+async fn soap_requests_reuse_connections_by_default() {
+    let (_igd, proxy) = read_through_proxy(false).await;
+    let connections = proxy.connections();
+
+    let (head, _) = soap_request(&connections, "GetExternalIPAddress");
+    assert!(head.contains(" http/1.1\r\n"), "{head}");
+    assert!(!head.contains("connection: close"), "{head}");
+    assert!(connections.len() < 3, "{connections:#?}");
+}
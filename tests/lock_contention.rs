@@ -0,0 +1,48 @@
+//! The device lock histograms record a slow re-discovery stalling readers
+mod common;
+
+use common::{FakeIgd, sample};
+use std::sync::Arc;
+use std::time::Duration;
+use upnp_wan_exporter_rs::{Config, MetricsCollector};
+
+#[tokio::test]
+async fn waiting_for_a_slow_discovery_is_recorded() {
+    let igd = FakeIgd::start().await;
+    let delay = Duration::from_millis(300);
+    igd.set_delay(delay);
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    let collector = Arc::new(MetricsCollector::new(&config).unwrap());
+
+    // The poll discovers the device under the write lock, holding it for
+    // at least one slow description fetch
+    let poll = tokio::spawn({
+        let collector = collector.clone();
+        async move { collector.poll().await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let info = collector.device_info().await;
+    poll.await.unwrap();
+    assert!(info.is_some(), "the reader got in before the discovery");
+
+    let exposition = collector.metrics().encode().unwrap();
+    let write_holds = sample(
+        &exposition,
+        "upnp_wan_device_lock_hold_seconds_count{device=\"default\",kind=\"write\"}",
+    );
+    let write_held = sample(
+        &exposition,
+        "upnp_wan_device_lock_hold_seconds_sum{device=\"default\",kind=\"write\"}",
+    );
+    let waited = sample(
+        &exposition,
+        "upnp_wan_device_lock_wait_seconds_sum{device=\"default\"}",
+    );
+    assert!(write_holds >= 1.0);
+    assert!(write_held >= delay.as_secs_f64(), "{write_held}");
+    assert!(
+        waited >= (delay - Duration::from_millis(100)).as_secs_f64(),
+        "{waited}"
+    );
+}
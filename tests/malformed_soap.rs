@@ -0,0 +1,72 @@
+//! SOAP responses that are not quite XML: repaired where possible,
+//! reported with an excerpt of the payload where not
+mod common;
+
+use common::FakeIgd;
+use upnp_wan_exporter_rs::{ScrapeErrorReason, UpnpClient, UpnpConfig, UpnpError};
+
+async fn status_info(response: &[u8]) -> Result<(Option<u64>, Option<String>), UpnpError> {
+    let igd = FakeIgd::start().await;
+    igd.set_raw_response("GetStatusInfo", response);
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    let status = client.get_status_info().await?;
+    Ok((status.uptime_seconds, status.last_error))
+}
+
+#[tokio::test]
+async fn bare_ampersand_is_escaped() {
+    let (uptime, last_error) =
+        status_info(include_bytes!("fixtures/soap/status-info-ampersand.xml"))
+            .await
+            .unwrap();
+    assert_eq!(uptime, Some(4242));
+    assert_eq!(
+        last_error.as_deref(),
+        Some("ERROR_ISP_TIME_OUT & retry & wait")
+    );
+}
+
+#[tokio::test]
+async fn trailing_garbage_is_dropped() {
+    let (uptime, last_error) = status_info(include_bytes!(
+        "fixtures/soap/status-info-trailing-garbage.xml"
+    ))
+    .await
+    .unwrap();
+    assert_eq!(uptime, Some(4242));
+    assert_eq!(last_error.as_deref(), Some("ERROR_NONE"));
+}
+
+#[tokio::test]
+async fn truncated_response_is_reported_with_an_excerpt() {
+    let error = status_info(include_bytes!("fixtures/soap/status-info-truncated.xml"))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, UpnpError::Parse { .. }), "{error:?}");
+    assert_eq!(error.reason(), ScrapeErrorReason::Parse);
+    let message = error.to_string();
+    assert!(message.contains("payload starts with"), "{message}");
+    assert!(message.contains("<?xml version"), "{message}");
+    // Only the first 200 bytes
+    assert!(!message.contains("NewUptime"), "{message}");
+}
+
+#[tokio::test]
+async fn over_long_response_is_cut_off() {
+    let mut response = include_str!("fixtures/soap/status-info-ampersand.xml").to_string();
+    response.insert_str(
+        response.find("<s:Body>").unwrap(),
+        &format!("<!--{}-->", "x".repeat(300 * 1024)),
+    );
+    let error = status_info(response.as_bytes()).await.unwrap_err();
+    assert!(matches!(error, UpnpError::Parse { .. }), "{error:?}");
+    assert!(
+        error.to_string().contains("response exceeds 262144 bytes"),
+        "{error}"
+    );
+}
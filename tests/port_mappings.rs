@@ -0,0 +1,212 @@
+//! Listing the port mapping table, by index walk or in bulk
+mod common;
+
+use common::{DESCRIPTION, FakeIgd, Reply, argument};
+use upnp_wan_exporter_rs::{PortMapping, UpnpClient, UpnpConfig, UpnpError};
+
+const IP_V1: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const IP_V2: &str = "urn:schemas-upnp-org:service:WANIPConnection:2";
+
+/// 713, SpecifiedArrayIndexInvalid, ends the walk
+const ARRAY_INDEX_INVALID: u16 = 713;
+
+async fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+/// A gateway with WANIPConnection:1, which has no GetListOfPortMappings
+async fn igd1() -> FakeIgd {
+    let igd = FakeIgd::start().await;
+    igd.set_description(Some(&DESCRIPTION.replace(IP_V2, IP_V1)));
+    igd
+}
+
+/// Answer GetGenericPortMappingEntry with `count` entries, then 713
+fn serve_entries(igd: &FakeIgd, count: usize) {
+    igd.set_handler("GetGenericPortMappingEntry", move |envelope| {
+        let index: usize = argument(envelope, "NewPortMappingIndex")
+            .unwrap()
+            .parse()
+            .unwrap();
+        if index >= count {
+            return Reply::Fault(ARRAY_INDEX_INVALID);
+        }
+        let port = (8080 + index).to_string();
+        let client = format!("192.168.1.{}", 10 + index);
+        let description = format!("mapping {index} &amp; more");
+        Reply::arguments(&[
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", &port),
+            (
+                "NewProtocol",
+                if index.is_multiple_of(2) {
+                    "TCP"
+                } else {
+                    "UDP"
+                },
+            ),
+            ("NewInternalPort", "80"),
+            ("NewInternalClient", &client),
+            ("NewEnabled", if index == 2 { "0" } else { "1" }),
+            ("NewPortMappingDescription", &description),
+            ("NewLeaseDuration", "3600"),
+        ])
+    });
+}
+
+#[tokio::test]
+async fn walks_the_table_until_713() {
+    let igd = igd1().await;
+    serve_entries(&igd, 3);
+    let client = client(&igd).await;
+
+    let mappings = client.list_port_mappings().await.unwrap();
+    assert_eq!(mappings.len(), 3);
+    assert_eq!(
+        mappings[0],
+        PortMapping {
+            remote_host: None,
+            external_port: 8080,
+            protocol: "TCP".to_string(),
+            internal_port: 80,
+            internal_client: "192.168.1.10".to_string(),
+            enabled: true,
+            description: "mapping 0 & more".to_string(),
+            lease_duration: 3600,
+        }
+    );
+    assert_eq!(mappings[1].protocol, "UDP");
+    assert!(!mappings[2].enabled);
+    // Three entries and the 713 after them
+    assert_eq!(igd.requests("GetGenericPortMappingEntry"), 4);
+    assert_eq!(igd.requests("GetListOfPortMappings"), 0);
+}
+
+#[tokio::test]
+async fn empty_table() {
+    let igd = igd1().await;
+    serve_entries(&igd, 0);
+    let client = client(&igd).await;
+
+    assert_eq!(client.list_port_mappings().await.unwrap(), []);
+}
+
+#[tokio::test]
+async fn walk_is_bounded() {
+    let igd = igd1().await;
+    serve_entries(&igd, usize::MAX);
+    let client = client(&igd).await;
+
+    let mappings = client.list_port_mappings().await.unwrap();
+    assert_eq!(mappings.len(), 1000);
+    assert_eq!(igd.requests("GetGenericPortMappingEntry"), 1000);
+}
+
+#[tokio::test]
+async fn other_faults_end_the_walk_with_an_error() {
+    let igd = igd1().await;
+    igd.set_handler("GetGenericPortMappingEntry", |_| Reply::Fault(501));
+    let client = client(&igd).await;
+
+    let error = client.list_port_mappings().await.unwrap_err();
+    let UpnpError::Soap(fault) = &error else {
+        panic!("{error:?}");
+    };
+    assert_eq!(fault.code, Some(501));
+}
+
+/// An IGD2 listing two TCP mappings in bulk and no UDP ones
+fn serve_listing(igd: &FakeIgd) {
+    igd.set_handler("GetListOfPortMappings", |envelope| {
+        match argument(envelope, "NewProtocol") {
+            Some("TCP") => Reply::Envelope(
+                include_str!("fixtures/soap/get-list-of-port-mappings-response.xml").to_string(),
+            ),
+            _ => Reply::Fault(ARRAY_INDEX_INVALID),
+        }
+    });
+}
+
+#[tokio::test]
+async fn igd2_lists_the_table_in_bulk() {
+    let igd = FakeIgd::start().await;
+    serve_listing(&igd);
+    serve_entries(&igd, 3);
+    let client = client(&igd).await;
+
+    let mappings = client.list_port_mappings().await.unwrap();
+    assert_eq!(
+        mappings,
+        [
+            PortMapping {
+                remote_host: None,
+                external_port: 51413,
+                protocol: "TCP".to_string(),
+                internal_port: 51413,
+                internal_client: "192.168.1.20".to_string(),
+                enabled: true,
+                description: "Transmission at 51413 & co".to_string(),
+                lease_duration: 0,
+            },
+            PortMapping {
+                remote_host: Some("203.0.113.99".to_string()),
+                external_port: 2222,
+                protocol: "TCP".to_string(),
+                internal_port: 22,
+                internal_client: "192.168.1.5".to_string(),
+                enabled: false,
+                description: "ssh".to_string(),
+                lease_duration: 86400,
+            },
+        ]
+    );
+    // One request per protocol, and no walk
+    assert_eq!(igd.requests("GetListOfPortMappings"), 2);
+    assert_eq!(igd.requests("GetGenericPortMappingEntry"), 0);
+}
+
+#[tokio::test]
+async fn bulk_listing_falls_back_to_the_walk() {
+    for code in [401, 602, 606] {
+        let igd = FakeIgd::start().await;
+        igd.set_handler("GetListOfPortMappings", move |_| Reply::Fault(code));
+        serve_entries(&igd, 3);
+        let client = client(&igd).await;
+
+        let mappings = client.list_port_mappings().await.unwrap();
+        assert_eq!(mappings.len(), 3, "after {code}");
+        assert_eq!(
+            igd.requests("GetGenericPortMappingEntry"),
+            4,
+            "after {code}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn other_bulk_listing_faults_are_errors() {
+    let igd = FakeIgd::start().await;
+    igd.set_handler("GetListOfPortMappings", |_| Reply::Fault(501));
+    serve_entries(&igd, 3);
+    let client = client(&igd).await;
+
+    assert!(client.list_port_mappings().await.is_err());
+    assert_eq!(igd.requests("GetGenericPortMappingEntry"), 0);
+}
+
+#[tokio::test]
+async fn igd1_never_lists_in_bulk() {
+    let igd = igd1().await;
+    serve_listing(&igd);
+    serve_entries(&igd, 2);
+    let client = client(&igd).await;
+
+    assert_eq!(client.list_port_mappings().await.unwrap().len(), 2);
+    assert_eq!(igd.requests("GetListOfPortMappings"), 0);
+}
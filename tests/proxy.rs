@@ -0,0 +1,126 @@
+//! Reading a gateway through a SOCKS5 proxy, as `ssh -D` provides
+mod common;
+
+use common::FakeIgd;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use tokio::net::{TcpListener, TcpStream};
+use upnp_wan_exporter_rs::config::ProxyUrl;
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, UpnpError};
+
+/// A SOCKS5 server without authentication that only knows CONNECT,
+/// counting the connections it tunnelled
+async fn socks5_proxy() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tunnels = Arc::new(AtomicUsize::new(0));
+    let counter = tunnels.clone();
+    tokio::spawn(async move {
+        loop {
+            let (client, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let _ = tunnel(client, &counter).await;
+            });
+        }
+    });
+    (addr, tunnels)
+}
+
+async fn tunnel(mut client: TcpStream, tunnels: &AtomicUsize) -> std::io::Result<()> {
+    // Greeting: version, methods; we pick "no authentication"
+    let mut header = [0; 2];
+    client.read_exact(&mut header).await?;
+    let mut methods = vec![0; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    client.write_all(&[5, 0]).await?;
+
+    // Request: version, CONNECT, reserved, address type, address, port
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        _ => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+    };
+    let port = client.read_u16().await?;
+    let mut upstream = TcpStream::connect((host.as_str(), port)).await?;
+    tunnels.fetch_add(1, Ordering::SeqCst);
+    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+fn proxied_config(igd: &FakeIgd, proxy: &str) -> UpnpConfig {
+    UpnpConfig {
+        location: Some(igd.location()),
+        proxy: Some(ProxyUrl::new(proxy)),
+        ..UpnpConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn reads_the_gateway_through_the_proxy() {
+    let igd = FakeIgd::start().await;
+    let (proxy, tunnels) = socks5_proxy().await;
+    let config = proxied_config(&igd, &format!("socks5://{}", proxy));
+    config.validate().unwrap();
+
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    let stats = client.get_traffic_stats().await.unwrap();
+
+    assert_eq!(stats.bytes_sent, Some(1000));
+    assert_eq!(stats.connection_status.as_deref(), Some("Up"));
+    assert!(tunnels.load(Ordering::SeqCst) > 0);
+    assert_eq!(igd.requests(common::DESCRIPTION_PATH), 1);
+}
+
+#[tokio::test]
+async fn unreachable_proxy_is_a_proxy_error() {
+    let igd = FakeIgd::start().await;
+    // Bound and dropped, so nothing listens there
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = proxied_config(&igd, &format!("socks5://user:secret@{}", closed));
+
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    let error = client.ensure_device().await.unwrap_err();
+
+    assert!(matches!(error, UpnpError::Proxy(_)), "{error:?}");
+    assert_eq!(error.kind(), "Proxy");
+    assert!(!error.to_string().contains("secret"), "{error}");
+    assert_eq!(igd.requests(common::DESCRIPTION_PATH), 0);
+}
+
+#[test]
+fn proxy_requires_a_static_device() {
+    let config = UpnpConfig {
+        proxy: Some(ProxyUrl::new("socks5://127.0.0.1:1080")),
+        ..UpnpConfig::default()
+    };
+    assert!(config.validate().is_err());
+    let config = UpnpConfig {
+        location: Some("http://192.168.1.1:49000/igddesc.xml".to_string()),
+        proxy: Some(ProxyUrl::new("http://127.0.0.1:3128")),
+        ..UpnpConfig::default()
+    };
+    assert!(config.validate().is_err());
+}
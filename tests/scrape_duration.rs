@@ -0,0 +1,115 @@
+//! Every scrape lands in the duration histograms, split by phase
+mod common;
+
+use std::net::TcpListener;
+use std::time::Duration;
+
+use common::{FakeIgd, sample};
+use upnp_wan_exporter_rs::{Config, MetricsCollector};
+
+/// Per-request latency of the gateway
+const LATENCY: Duration = Duration::from_millis(150);
+
+async fn scrape(collector: &MetricsCollector) -> (String, bool) {
+    // Boxed, as a whole scrape is too large a future for the test thread's stack
+    Box::pin(collector.collect_metrics()).await
+}
+
+#[tokio::test]
+async fn slow_scrape_lands_above_the_first_bucket() {
+    let igd = FakeIgd::start().await;
+    igd.set_delay(LATENCY);
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    let (_, failed) = scrape(&collector).await;
+    assert!(!failed);
+    let (exposition, _) = scrape(&collector).await;
+
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_count{device=\"default\"}"
+        ),
+        2.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_bucket{device=\"default\",le=\"0.1\"}"
+        ),
+        0.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_bucket{device=\"default\",le=\"30\"}"
+        ),
+        2.0
+    );
+    assert!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_sum{device=\"default\"}"
+        ) >= 4.0 * LATENCY.as_secs_f64()
+    );
+
+    // Discovery only ran for the first scrape, SOAP calls for both
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_phase_duration_seconds_count{device=\"default\",phase=\"discovery\"}"
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_phase_duration_seconds_count{device=\"default\",phase=\"soap\"}"
+        ),
+        2.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_phase_duration_seconds_bucket{device=\"default\",phase=\"soap\",le=\"0.1\"}"
+        ),
+        0.0
+    );
+}
+
+#[tokio::test]
+async fn failed_scrape_is_observed() {
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = Config::default();
+    config.upnp.location = Some(format!("http://127.0.0.1:{closed_port}/igddesc.xml"));
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    let (exposition, _) = scrape(&collector).await;
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_count{device=\"default\"}"
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_duration_seconds_bucket{device=\"default\",le=\"30\"}"
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &exposition,
+            "upnp_scrape_phase_duration_seconds_count{device=\"default\",phase=\"discovery\"}"
+        ),
+        1.0
+    );
+}
@@ -0,0 +1,141 @@
+//! Actions use the URN of the advertised service version and fall back to
+//! the :1 URN when the gateway rejects it
+mod common;
+
+use common::{DESCRIPTION, FakeIgd};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig};
+
+const IP_V1: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const IP_V2: &str = "urn:schemas-upnp-org:service:WANIPConnection:2";
+const COMMON_V1: &str = "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1";
+const COMMON_V2: &str = "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:2";
+const PPPOE_DESCRIPTION: &str = include_str!("fixtures/pppoe-description.xml");
+
+/// A gateway advertising version 2 of both the common and the connection service
+async fn igd2() -> FakeIgd {
+    let igd = FakeIgd::start().await;
+    igd.set_description(Some(&DESCRIPTION.replace(COMMON_V1, COMMON_V2)));
+    igd
+}
+
+async fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn uses_the_advertised_version() {
+    let igd = igd2().await;
+    let client = client(&igd).await;
+
+    assert_eq!(client.get_byte_counters().await.unwrap(), (1000, 2000));
+    let calls = igd.soap_calls("GetTotalBytesSent");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].service_type, COMMON_V2);
+    assert!(
+        calls[0].body.contains(&format!("xmlns:u=\"{COMMON_V2}\"")),
+        "{}",
+        calls[0].body
+    );
+
+    client.get_external_ip().await.unwrap();
+    let calls = igd.soap_calls("GetExternalIPAddress");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].service_type, IP_V2);
+    assert!(
+        calls[0].body.contains(&format!("xmlns:u=\"{IP_V2}\"")),
+        "{}",
+        calls[0].body
+    );
+}
+
+#[tokio::test]
+async fn falls_back_to_version_1_when_rejected() {
+    let igd = igd2().await;
+    igd.reject_service_type(IP_V2);
+    igd.reject_service_type(COMMON_V2);
+    let client = client(&igd).await;
+
+    assert_eq!(client.get_byte_counters().await.unwrap(), (1000, 2000));
+    let types: Vec<_> = igd
+        .soap_calls("GetTotalBytesSent")
+        .into_iter()
+        .map(|call| call.service_type)
+        .collect();
+    assert_eq!(types, [COMMON_V2, COMMON_V1]);
+
+    assert_eq!(
+        client.get_external_ip().await.unwrap().as_deref(),
+        Some("203.0.113.7")
+    );
+    let calls = igd.soap_calls("GetExternalIPAddress");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].service_type, IP_V1);
+    assert!(
+        calls[1].body.contains(&format!("xmlns:u=\"{IP_V1}\"")),
+        "{}",
+        calls[1].body
+    );
+}
+
+#[tokio::test]
+async fn falls_back_only_once() {
+    let igd = igd2().await;
+    igd.reject_service_type(IP_V2);
+    igd.reject_service_type(IP_V1);
+    let client = client(&igd).await;
+
+    assert!(client.get_external_ip().await.is_err());
+    assert_eq!(igd.requests("GetExternalIPAddress"), 2);
+}
+
+#[tokio::test]
+async fn every_discovered_version_is_addressed() {
+    for (base, connection) in [
+        (DESCRIPTION, "WANIPConnection"),
+        (PPPOE_DESCRIPTION, "WANPPPConnection"),
+    ] {
+        for common_version in 1..=2 {
+            for connection_version in 1..=2 {
+                let common = format!(
+                    "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:{common_version}"
+                );
+                let connection =
+                    format!("urn:schemas-upnp-org:service:{connection}:{connection_version}");
+                let description = base
+                    .replace(COMMON_V1, &common)
+                    .replace(IP_V2, &connection)
+                    .replace(
+                        "urn:schemas-upnp-org:service:WANPPPConnection:1",
+                        &connection,
+                    );
+                let igd = FakeIgd::start().await;
+                igd.set_description(Some(&description));
+                let client = client(&igd).await;
+
+                client.get_byte_counters().await.unwrap();
+                client.get_external_ip().await.unwrap();
+                for (action, service_type) in [
+                    ("GetTotalBytesSent", &common),
+                    ("GetExternalIPAddress", &connection),
+                ] {
+                    let calls = igd.soap_calls(action);
+                    assert_eq!(calls.len(), 1, "{action} of {service_type}");
+                    assert_eq!(&calls[0].service_type, service_type);
+                    assert!(
+                        calls[0]
+                            .body
+                            .contains(&format!("<u:{action} xmlns:u=\"{service_type}\"")),
+                        "{}",
+                        calls[0].body
+                    );
+                }
+            }
+        }
+    }
+}
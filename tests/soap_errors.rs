@@ -0,0 +1,147 @@
+//! Error responses of the gateway surface as typed errors with a scrape error reason
+mod common;
+
+use common::{FakeIgd, sample};
+use upnp_wan_exporter_rs::{
+    Config, MetricsCollector, ScrapeErrorReason, UpnpClient, UpnpConfig, UpnpError,
+};
+
+const FAULT_606: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>
+<detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>606</errorCode><errorDescription>Action not authorized</errorDescription></UPnPError></detail>
+</s:Fault></s:Body></s:Envelope>"#;
+
+async fn client(igd: &FakeIgd) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(igd.location()),
+        soap_retry_backoff_ms: 10,
+        ..UpnpConfig::default()
+    };
+    let mut client = UpnpClient::builder().config(config).build().unwrap();
+    client.ensure_device().await.unwrap();
+    client
+}
+
+async fn external_ip_error(igd: &FakeIgd) -> UpnpError {
+    client(igd).await.get_external_ip().await.unwrap_err()
+}
+
+#[tokio::test]
+async fn http_500_with_a_fault() {
+    let igd = FakeIgd::start().await;
+    igd.set_http_response(
+        "GetExternalIPAddress",
+        500,
+        &[("Content-Type", "text/xml")],
+        FAULT_606.as_bytes(),
+    );
+
+    let error = external_ip_error(&igd).await;
+    let UpnpError::Soap(fault) = &error else {
+        panic!("{error:?}");
+    };
+    assert_eq!(fault.code, Some(606));
+    assert_eq!(
+        error.to_string(),
+        "UPnP error 606: Action not authorized (GetExternalIPAddress)"
+    );
+    assert_eq!(error.reason(), ScrapeErrorReason::SoapFault);
+    // Faults are deterministic, so the request is not repeated
+    assert_eq!(igd.requests("GetExternalIPAddress"), 1);
+}
+
+#[tokio::test]
+async fn upnp_error_401() {
+    let igd = FakeIgd::start().await;
+    igd.remove_action("GetExternalIPAddress");
+
+    let error = external_ip_error(&igd).await;
+    let UpnpError::Soap(fault) = &error else {
+        panic!("{error:?}");
+    };
+    assert_eq!(fault.code, Some(401));
+    assert_eq!(
+        error.to_string(),
+        "UPnP error 401: Invalid Action (GetExternalIPAddress)"
+    );
+    assert_eq!(error.reason(), ScrapeErrorReason::SoapFault);
+}
+
+#[tokio::test]
+async fn http_401_challenge() {
+    let igd = FakeIgd::start().await;
+    igd.set_http_response(
+        "GetExternalIPAddress",
+        401,
+        &[("WWW-Authenticate", "Basic realm=\"gateway\"")],
+        b"",
+    );
+
+    let error = external_ip_error(&igd).await;
+    assert!(
+        matches!(
+            error,
+            UpnpError::AuthenticationFailed {
+                credentials_configured: false,
+                ..
+            }
+        ),
+        "{error:?}"
+    );
+    assert!(error.to_string().contains("set upnp.username"), "{error}");
+    assert_eq!(error.reason(), ScrapeErrorReason::Auth);
+}
+
+#[tokio::test]
+async fn http_500_without_xml() {
+    let igd = FakeIgd::start().await;
+    igd.set_http_response(
+        "GetExternalIPAddress",
+        500,
+        &[("Content-Type", "text/html")],
+        b"<html><body>Internal Server Error</body></html>",
+    );
+
+    let error = external_ip_error(&igd).await;
+    let UpnpError::Soap(fault) = &error else {
+        panic!("{error:?}");
+    };
+    assert_eq!(fault.code, None);
+    assert_eq!(error.to_string(), "HTTP 500 (GetExternalIPAddress)");
+    assert_eq!(error.reason(), ScrapeErrorReason::Http);
+    // A fault-less 5xx may be transient and is retried
+    assert_eq!(igd.requests("GetExternalIPAddress"), 2);
+}
+
+#[tokio::test]
+async fn fault_reason_reaches_the_scrape_error_counter() {
+    let igd = FakeIgd::start().await;
+    for action in [
+        "GetTotalBytesSent",
+        "GetTotalBytesReceived",
+        "GetTotalPacketsSent",
+        "GetTotalPacketsReceived",
+        "GetCommonLinkProperties",
+    ] {
+        igd.set_http_response(action, 500, &[], FAULT_606.as_bytes());
+    }
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    let collector = MetricsCollector::new(&config).unwrap();
+
+    let error = collector.get_stats().await.unwrap_err();
+    assert!(
+        error.contains("UPnP error 606: Action not authorized"),
+        "{error}"
+    );
+    let exposition = collector.metrics().encode().unwrap();
+    let series = |reason: &str| {
+        format!(
+            "upnp_wan_scrape_errors_total{{device=\"{}\",reason=\"{reason}\"}}",
+            config.upnp.device_name()
+        )
+    };
+    assert_eq!(sample(&exposition, &series("soap_fault")), 1.0);
+    assert_eq!(sample(&exposition, &series("http")), 0.0);
+}
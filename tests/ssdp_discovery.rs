@@ -0,0 +1,150 @@
+//! Discovery against a unicast SSDP responder and a fake gateway
+mod common;
+
+use common::{FakeIgd, SsdpResponder, ssdp_response};
+use std::time::{Duration, Instant};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, UpnpError};
+
+fn discovering_client(responder: &SsdpResponder, discovery_timeout: u64) -> UpnpClient {
+    retrying_client(
+        responder,
+        discovery_timeout,
+        UpnpConfig::default().discovery_attempts,
+    )
+}
+
+fn retrying_client(
+    responder: &SsdpResponder,
+    discovery_timeout: u64,
+    discovery_attempts: u32,
+) -> UpnpClient {
+    let config = UpnpConfig {
+        discovery_timeout,
+        discovery_attempts,
+        ..UpnpConfig::default()
+    };
+    UpnpClient::builder()
+        .config(config)
+        .search_target(responder.addr())
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn response_larger_than_1024_bytes_keeps_its_location() {
+    let igd = FakeIgd::start().await;
+    let server = format!(
+        "SERVER: FRITZ!Box 7590 UPnP/1.0 AVM FRITZ!Box 7590 {}",
+        "154.07.57".repeat(100)
+    );
+    let response = ssdp_response(
+        &igd.location(),
+        &[
+            &server,
+            "BOOTID.UPNP.ORG: 1712345678",
+            "CONFIGID.UPNP.ORG: 1337",
+        ],
+    );
+    // LOCATION comes first, SERVER pushes the datagram past 1024 bytes
+    assert!(response.len() > 1024);
+    let responder = SsdpResponder::always(response).await;
+    let mut client = discovering_client(&responder, 2);
+
+    client.ensure_device().await.unwrap();
+    assert_eq!(client.device().unwrap().location, igd.location());
+}
+
+#[tokio::test]
+async fn device_carries_usn_and_server() {
+    let igd = FakeIgd::start().await;
+    let responder = SsdpResponder::always(ssdp_response(&igd.location(), &[])).await;
+    let mut client = discovering_client(&responder, 2);
+
+    client.ensure_device().await.unwrap();
+    let device = client.device().unwrap();
+    assert_eq!(
+        device.usn.as_deref(),
+        Some(
+            "uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC::urn:schemas-upnp-org:device:InternetGatewayDevice:2"
+        )
+    );
+    assert_eq!(
+        device.server.as_deref(),
+        Some("Linux/4.9 UPnP/1.0 FakeIgd/1.0")
+    );
+}
+
+#[tokio::test]
+async fn response_without_location_is_reported() {
+    let response = ssdp_response("", &[]).replace("LOCATION: \r\n", "");
+    let responder = SsdpResponder::always(response).await;
+    let mut client = discovering_client(&responder, 1);
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(
+        !matches!(error, UpnpError::DiscoveryTimeout(_)),
+        "{error:?}"
+    );
+    assert!(error.to_string().contains("no LOCATION header"), "{error}");
+    assert!(client.device().is_none());
+}
+
+#[tokio::test]
+async fn response_with_an_invalid_location_is_reported() {
+    let responder = SsdpResponder::always(ssdp_response("not a url", &[])).await;
+    let mut client = discovering_client(&responder, 1);
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(error.to_string().contains("invalid LOCATION"), "{error}");
+}
+
+/// A responder that loses the first `lost` searches and then answers for `igd`
+async fn lossy_responder(igd: &FakeIgd, lost: usize) -> SsdpResponder {
+    let response = ssdp_response(&igd.location(), &[]);
+    SsdpResponder::start(move |search| (search >= lost).then(|| response.clone())).await
+}
+
+#[tokio::test]
+async fn retransmits_lost_searches() {
+    let igd = FakeIgd::start().await;
+    let responder = lossy_responder(&igd, 2).await;
+    let mut client = retrying_client(&responder, 10, 3);
+
+    let started = Instant::now();
+    client.ensure_device().await.unwrap();
+    assert_eq!(client.device().unwrap().location, igd.location());
+    assert_eq!(responder.searches(), 3);
+    // The retries back off by 1s and then 2s
+    assert!(
+        started.elapsed() >= Duration::from_secs(3),
+        "{:?}",
+        started.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn gives_up_after_the_configured_attempts() {
+    let igd = FakeIgd::start().await;
+    let responder = lossy_responder(&igd, 2).await;
+    let mut client = retrying_client(&responder, 3, 2);
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(matches!(error, UpnpError::DiscoveryTimeout(_)), "{error:?}");
+    assert_eq!(responder.searches(), 2);
+}
+
+#[tokio::test]
+async fn retries_stay_within_the_discovery_timeout() {
+    let igd = FakeIgd::start().await;
+    let responder = lossy_responder(&igd, usize::MAX).await;
+    let mut client = retrying_client(&responder, 2, 5);
+
+    let started = Instant::now();
+    let error = client.ensure_device().await.unwrap_err();
+    let elapsed = started.elapsed();
+    assert!(matches!(error, UpnpError::DiscoveryTimeout(_)), "{error:?}");
+    assert!(elapsed >= Duration::from_secs(2), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(2500), "{elapsed:?}");
+    // Searches at 0s and 1s; the third would start at the deadline
+    assert_eq!(responder.searches(), 2);
+}
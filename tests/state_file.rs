@@ -0,0 +1,166 @@
+//! Wrap-corrected totals survive a restart of the exporter through `metrics.state_file`
+mod common;
+
+use std::path::PathBuf;
+
+use common::{DESCRIPTION, FakeIgd, sample};
+use upnp_wan_exporter_rs::{Config, MetricsCollector};
+
+const BYTES_SENT: &str = "upnp_wan_bytes_sent_total{device=\"default\"}";
+/// One wrap of the 32-bit counters
+const WRAP: f64 = 4_294_967_296.0;
+
+/// A state file of this test alone, removed when dropped
+struct StateFile(PathBuf);
+
+impl StateFile {
+    fn new(test: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "upnp-exporter-{}-{}.json",
+            std::process::id(),
+            test
+        ));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for StateFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn collector(igd: &FakeIgd, state: &StateFile) -> MetricsCollector {
+    let mut config = Config::default();
+    config.upnp.location = Some(igd.location());
+    config.metrics.state_file = Some(state.0.clone());
+    MetricsCollector::new(&config).unwrap()
+}
+
+/// A collector reading the counters from the configured control URL,
+/// without a description and so without a UDN
+fn static_collector(igd: &FakeIgd, state: &StateFile) -> MetricsCollector {
+    let mut config = Config::default();
+    config.upnp.wan_common_control_url = Some(format!(
+        "http://{}/igdupnp/control/WANCommonIFC1",
+        igd.addr()
+    ));
+    config.metrics.state_file = Some(state.0.clone());
+    MetricsCollector::new(&config).unwrap()
+}
+
+fn set_reading(igd: &FakeIgd, bytes_sent: &str, uptime: &str) {
+    igd.set_response("GetTotalBytesSent", &[("NewTotalBytesSent", bytes_sent)]);
+    igd.set_response(
+        "GetStatusInfo",
+        &[
+            ("NewConnectionStatus", "Connected"),
+            ("NewLastConnectionError", "ERROR_NONE"),
+            ("NewUptime", uptime),
+        ],
+    );
+}
+
+async fn bytes_sent(collector: &MetricsCollector) -> f64 {
+    // Boxed, as a whole scrape is too large a future for the test thread's stack
+    let (exposition, failed) = Box::pin(collector.collect_metrics()).await;
+    assert!(!failed);
+    sample(&exposition, BYTES_SENT)
+}
+
+/// Run an exporter through one wrap of the bytes sent counter and stop it
+async fn run_through_a_wrap(igd: &FakeIgd, state: &StateFile) {
+    let first_run = collector(igd, state);
+    set_reading(igd, "4000000000", "1000");
+    assert_eq!(bytes_sent(&first_run).await, 4_000_000_000.0);
+    set_reading(igd, "100", "1100");
+    assert_eq!(bytes_sent(&first_run).await, WRAP + 100.0);
+    first_run.save_state();
+}
+
+#[tokio::test]
+async fn restart_continues_the_totals() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("restart");
+    run_through_a_wrap(&igd, &state).await;
+
+    let second_run = collector(&igd, &state);
+    set_reading(&igd, "200", "1200");
+    assert_eq!(bytes_sent(&second_run).await, WRAP + 200.0);
+}
+
+#[tokio::test]
+async fn restart_across_a_router_reboot_continues_the_totals() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("reboot");
+    run_through_a_wrap(&igd, &state).await;
+
+    // The router came back while the exporter was down: uptime and counters restarted
+    let second_run = collector(&igd, &state);
+    set_reading(&igd, "50", "30");
+    assert_eq!(bytes_sent(&second_run).await, WRAP + 150.0);
+}
+
+#[tokio::test]
+async fn state_of_another_device_is_discarded() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("other-device");
+    run_through_a_wrap(&igd, &state).await;
+
+    // The gateway was replaced by one with a different UDN
+    igd.set_description(Some(&DESCRIPTION.replacen(
+        "uuid:75802409-bccb-40e7-8e6c-3810D5AABBCC",
+        "uuid:00000000-0000-4000-8000-000000000001",
+        1,
+    )));
+    let second_run = collector(&igd, &state);
+    set_reading(&igd, "200", "1200");
+    assert_eq!(bytes_sent(&second_run).await, 200.0);
+}
+
+#[tokio::test]
+async fn corrupt_state_is_discarded() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("corrupt");
+    std::fs::write(&state.0, b"{\"device\": \"uuid:75802409").unwrap();
+
+    let collector = collector(&igd, &state);
+    set_reading(&igd, "200", "1200");
+    assert_eq!(bytes_sent(&collector).await, 200.0);
+}
+
+#[tokio::test]
+async fn inconsistent_state_is_discarded() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("inconsistent");
+    run_through_a_wrap(&igd, &state).await;
+
+    // A total below its raw reading cannot come from this exporter
+    let saved = std::fs::read_to_string(&state.0).unwrap();
+    let total = (WRAP as u64 + 100).to_string();
+    assert!(saved.contains(&total), "{saved}");
+    std::fs::write(&state.0, saved.replacen(&total, "1", 1)).unwrap();
+
+    let second_run = collector(&igd, &state);
+    set_reading(&igd, "200", "1200");
+    assert_eq!(bytes_sent(&second_run).await, 200.0);
+}
+
+#[tokio::test]
+async fn restart_without_a_description_continues_the_totals() {
+    let igd = FakeIgd::start().await;
+    let state = StateFile::new("static");
+    let first_run = static_collector(&igd, &state);
+    set_reading(&igd, "4000000000", "1000");
+    assert_eq!(bytes_sent(&first_run).await, 4_000_000_000.0);
+    set_reading(&igd, "100", "1100");
+    assert_eq!(bytes_sent(&first_run).await, WRAP + 100.0);
+    first_run.save_state();
+    assert!(state.0.exists());
+
+    let second_run = static_collector(&igd, &state);
+    set_reading(&igd, "200", "1200");
+    assert_eq!(bytes_sent(&second_run).await, WRAP + 200.0);
+    assert_eq!(igd.requests(common::DESCRIPTION_PATH), 0);
+}
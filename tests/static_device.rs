@@ -0,0 +1,77 @@
+//! `upnp.location`: the description is fetched from the configured URL
+//! and SSDP is never used
+mod common;
+
+use common::FakeIgd;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, UpnpError};
+
+fn static_client(location: String, search_target: &UdpSocket) -> UpnpClient {
+    let config = UpnpConfig {
+        location: Some(location),
+        ..UpnpConfig::default()
+    };
+    UpnpClient::builder()
+        .config(config)
+        .search_target(search_target.local_addr().unwrap())
+        .build()
+        .unwrap()
+}
+
+/// Fails if an M-SEARCH arrives at `socket` within a moment
+async fn assert_no_search(socket: &UdpSocket) {
+    let mut buf = [0; 2048];
+    let received = tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf)).await;
+    assert!(received.is_err(), "an M-SEARCH was sent");
+}
+
+#[tokio::test]
+async fn reads_the_configured_description() {
+    let igd = FakeIgd::start().await;
+    let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut client = static_client(igd.location(), &ssdp);
+
+    client.ensure_device().await.unwrap();
+    let device = client.device().unwrap();
+    assert_eq!(device.location, igd.location());
+    assert_eq!(device.info.model_name.as_deref(), Some("FRITZ!Box 7590"));
+    assert_eq!(
+        device.wan_interfaces[0].common.control_url,
+        format!("http://{}/igdupnp/control/WANCommonIFC1", igd.addr())
+    );
+    let stats = client.get_traffic_stats().await.unwrap();
+    assert_eq!(stats.bytes_received, Some(2000));
+
+    assert_eq!(igd.requests(common::DESCRIPTION_PATH), 1);
+    assert_no_search(&ssdp).await;
+}
+
+#[tokio::test]
+async fn unreachable_location_is_a_description_fetch_error() {
+    // Bound and dropped, so nothing listens there
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut client = static_client(format!("http://{}/igddesc.xml", closed), &ssdp);
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(matches!(error, UpnpError::DescriptionFetch(_)), "{error:?}");
+    assert!(!client.has_valid_device());
+    assert_no_search(&ssdp).await;
+}
+
+#[tokio::test]
+async fn missing_description_is_a_description_fetch_error() {
+    let igd = FakeIgd::start().await;
+    igd.set_description(None);
+    let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut client = static_client(igd.location(), &ssdp);
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(matches!(error, UpnpError::DescriptionFetch(_)), "{error:?}");
+    assert_no_search(&ssdp).await;
+}
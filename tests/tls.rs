@@ -0,0 +1,165 @@
+//! https gateways: a TLS proxy in front of the fake gateway, with a
+//! certificate for 127.0.0.1 issued by the CA in `tests/fixtures/tls`
+//! (made with openssl, valid until 2126; the CA key was not kept)
+mod common;
+
+use common::{DESCRIPTION_PATH, FakeIgd};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use upnp_wan_exporter_rs::{UpnpClient, UpnpConfig, UpnpError, UpnpResult};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/tls")
+        .join(name)
+}
+
+/// Terminate TLS on a localhost port and pass the plain bytes on to `upstream`
+async fn tls_proxy(upstream: SocketAddr) -> SocketAddr {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(
+        &include_bytes!("fixtures/tls/gateway.pem")[..],
+    ))
+    .unwrap()
+    .into_iter()
+    .map(Certificate)
+    .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(
+        &include_bytes!("fixtures/tls/gateway.key")[..],
+    ))
+    .unwrap()
+    .remove(0);
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, PrivateKey(key))
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Clients rejecting the certificate abort the handshake
+                let Ok(mut tls) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut plain = TcpStream::connect(upstream).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut tls, &mut plain).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn https_gateway() -> (FakeIgd, String) {
+    let igd = FakeIgd::start().await;
+    let proxy = tls_proxy(igd.addr()).await;
+    (igd, format!("https://{proxy}{DESCRIPTION_PATH}"))
+}
+
+fn client(config: UpnpConfig) -> UpnpResult<UpnpClient> {
+    UpnpClient::builder().config(config).build()
+}
+
+#[tokio::test]
+async fn custom_ca_verifies_the_gateway() {
+    let (igd, location) = https_gateway().await;
+    let mut client = client(UpnpConfig {
+        location: Some(location.clone()),
+        tls_ca_file: Some(fixture("ca.pem")),
+        ..UpnpConfig::default()
+    })
+    .unwrap();
+
+    client.ensure_device().await.unwrap();
+    let device = client.device().unwrap();
+    assert!(
+        device.wan_interfaces[0]
+            .common
+            .control_url
+            .starts_with("https://"),
+        "{}",
+        device.wan_interfaces[0].common.control_url
+    );
+    assert_eq!(
+        client.get_external_ip().await.unwrap().as_deref(),
+        Some("203.0.113.7")
+    );
+    assert_eq!(igd.requests("GetExternalIPAddress"), 1);
+}
+
+#[tokio::test]
+async fn unknown_ca_is_rejected() {
+    let (igd, location) = https_gateway().await;
+    let mut client = client(UpnpConfig {
+        location: Some(location),
+        ..UpnpConfig::default()
+    })
+    .unwrap();
+
+    let error = client.ensure_device().await.unwrap_err();
+    assert!(matches!(error, UpnpError::DescriptionFetch(_)), "{error:?}");
+    assert_eq!(igd.requests(DESCRIPTION_PATH), 0);
+}
+
+#[tokio::test]
+async fn insecure_skip_verify_accepts_the_gateway() {
+    let (_igd, location) = https_gateway().await;
+    let mut client = client(UpnpConfig {
+        location: Some(location),
+        tls_insecure_skip_verify: true,
+        ..UpnpConfig::default()
+    })
+    .unwrap();
+
+    client.ensure_device().await.unwrap();
+    client.get_external_ip().await.unwrap();
+}
+
+#[test]
+fn ca_file_without_certificates_is_rejected() {
+    let error = client(UpnpConfig {
+        tls_ca_file: Some(fixture("gateway.key")),
+        ..UpnpConfig::default()
+    })
+    .err()
+    .unwrap();
+    assert!(
+        error.to_string().contains("holds no PEM certificate"),
+        "{error}"
+    );
+}
+
+#[test]
+fn missing_ca_file_is_rejected() {
+    let error = client(UpnpConfig {
+        tls_ca_file: Some(fixture("missing.pem")),
+        ..UpnpConfig::default()
+    })
+    .err()
+    .unwrap();
+    assert!(matches!(error, UpnpError::Config(_)), "{error:?}");
+    assert!(
+        error.to_string().contains("is not a readable file"),
+        "{error}"
+    );
+}
+
+#[test]
+fn ca_file_and_skip_verify_are_exclusive() {
+    let config = UpnpConfig {
+        tls_ca_file: Some(fixture("ca.pem")),
+        tls_insecure_skip_verify: true,
+        ..UpnpConfig::default()
+    };
+    let error = config.validate().unwrap_err();
+    assert!(error.to_string().contains("has no effect"), "{error}");
+}